@@ -0,0 +1,84 @@
+//! The cost of a frame while an answer streams in, for transcripts of different lengths. With the layout cache it
+//! should stay flat: only the streaming message is laid out again, and only the messages in view are drawn.
+
+use std::hint::black_box;
+
+use agent::{
+    transcript::Transcript,
+    ui_state::{
+        ChatUIModification,
+        ChatUIState,
+    },
+};
+use criterion::{
+    BenchmarkId,
+    Criterion,
+    criterion_group,
+    criterion_main,
+};
+use ratatui::{
+    buffer::Buffer,
+    layout::Rect,
+    widgets::{
+        Paragraph,
+        Widget,
+        Wrap,
+    },
+};
+
+const ANSWER: &str = "Here's the fix:\n\n```rust\nfn main() {\n    println!(\"Hello, world!\");\n}\n```\n\nThat \
+                      prints a **greeting**, see [the docs](https://doc.rust-lang.org/std/macro.println.html).\n\n";
+
+/// `turns` questions and answers, and a last answer that's just started streaming.
+fn chat(turns: usize) -> ChatUIState {
+    let mut chat = ChatUIState::new();
+    for turn in 0..=turns {
+        chat.apply(ChatUIModification::AddUserMessage {
+            text: format!("Question {turn}"),
+            attachments: vec![],
+        })
+        .unwrap();
+        let text = if turn < turns { ANSWER.repeat(3) } else { String::new() };
+        chat.apply(ChatUIModification::AddSystemMessage { text }).unwrap();
+    }
+    chat
+}
+
+fn streaming_frame(c: &mut Criterion) {
+    let area = Rect::new(0, 0, 100, 40);
+    let mut group = c.benchmark_group("streaming_frame");
+    for turns in [10, 100, 1000] {
+        group.bench_with_input(BenchmarkId::from_parameter(turns), &turns, |b, &turns| {
+            let mut chat = chat(turns);
+            let index = chat.messages().len() - 1;
+            let mut transcript = Transcript::new(false);
+            transcript.resize(area.width, area.height);
+            transcript.update(chat.messages());
+            let mut buffer = Buffer::empty(area);
+            let mut tokens = ANSWER.split_inclusive(' ').cycle();
+
+            b.iter(|| {
+                let text = tokens.next().unwrap().to_string();
+                chat.apply(ChatUIModification::AppendSystemMessage { index, text })
+                    .unwrap();
+                transcript.invalidate(index);
+                transcript.update(chat.messages());
+
+                let mut y = 0;
+                for visible in transcript.visible() {
+                    let rows = Rect::new(0, y, area.width, visible.rows as u16);
+                    Paragraph::new(visible.layout.lines.clone())
+                        .wrap(Wrap { trim: false })
+                        .scroll((visible.skip as u16, 0))
+                        .render(rows, &mut buffer);
+                    y += visible.rows as u16;
+                }
+                black_box(&buffer);
+            });
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, streaming_frame);
+criterion_main!(benches);
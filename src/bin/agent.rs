@@ -1,17 +1,45 @@
-#![feature(try_blocks)]
-
-use std::io::Stdout;
+use std::{
+    collections::BTreeMap,
+    io::Stdout,
+    path::PathBuf,
+    sync::Arc,
+};
 
 use agent::{
-    server,
+    cassette::{
+        Cassette,
+        CassetteRecorder,
+        ReplayServer,
+        ToolCassette,
+    },
+    commands::CommandRegistry,
+    config::{
+        self,
+        Config,
+        ConfigFiles,
+        Settings,
+    },
+    editor::History,
+    llm_provider::LLMProvider,
+    server::{
+        self,
+        SessionConfig,
+    },
     tools,
     ui,
 };
-use async_openai::types::ReasoningEffort;
 use clap::Parser;
+use crossterm::event::{
+    DisableBracketedPaste,
+    DisableMouseCapture,
+    EnableBracketedPaste,
+    EnableMouseCapture,
+    KeyboardEnhancementFlags,
+    PopKeyboardEnhancementFlags,
+    PushKeyboardEnhancementFlags,
+};
 use ratatui::prelude::CrosstermBackend;
 use tokio::{
-    fs,
     sync::mpsc,
     task::JoinSet,
 };
@@ -23,55 +51,147 @@ struct Cli {
     /// The user prompt/query (optional)
     prompt: Option<String>,
 
+    /// The config profile to use
+    #[arg(long, env = "AGENT_PROFILE")]
+    profile: Option<String>,
+
     /// The LLM model to use
-    #[arg(long)]
-    model: String,
+    #[arg(long, env = "AGENT_MODEL")]
+    model: Option<String>,
 
     /// The API key to use
-    #[arg(long)]
-    api_key: String,
+    #[arg(long, env = "AGENT_API_KEY", hide_env_values = true)]
+    api_key: Option<String>,
 
     /// The base URL to use
-    #[arg(long)]
-    base_url: String,
+    #[arg(long, env = "AGENT_BASE_URL")]
+    base_url: Option<String>,
 
     /// The reasoning effort level (low, medium, high)
-    #[arg(long)]
+    #[arg(long, env = "AGENT_REASONING_EFFORT")]
     reasoning_effort: Option<String>,
+
+    /// Send the model's reasoning back to the provider with later requests (needed by some providers to keep
+    /// reasoning across tool calls)
+    #[arg(long)]
+    send_reasoning: bool,
+
+    /// The maximum number of tokens to generate per request
+    #[arg(long, env = "AGENT_MAX_TOKENS")]
+    max_tokens: Option<u32>,
+
+    /// The model's context window size, in tokens [default: 128000]
+    #[arg(long)]
+    context_window: Option<u32>,
+
+    /// Fraction of the context window at which older turns get compacted [default: 0.8]
+    #[arg(long)]
+    compaction_threshold: Option<f64>,
+
+    /// Number of most recent turns that are never compacted [default: 2]
+    #[arg(long)]
+    keep_recent_turns: Option<usize>,
+
+    /// Use the system prompt template in this file instead of the built-in prompt
+    #[arg(long, env = "AGENT_SYSTEM_PROMPT_FILE")]
+    system_prompt_file: Option<PathBuf>,
+
+    /// Append the system prompt from the config or `--system-prompt-file` to the built-in prompt instead of replacing
+    /// it
+    #[arg(long)]
+    append_system_prompt: bool,
+
+    /// Record every LLM request, response, and tool result of the session into this directory
+    #[arg(long, conflicts_with = "replay")]
+    record: Option<PathBuf>,
+
+    /// Replay a session recorded with `--record` instead of talking to a real endpoint
+    #[arg(long)]
+    replay: Option<PathBuf>,
+}
+
+impl Cli {
+    /// The settings given on the command line or through the environment, which take precedence over config files.
+    fn settings(&self) -> anyhow::Result<Settings> {
+        let system_prompt = match &self.system_prompt_file {
+            Some(path) => Some(
+                std::fs::read_to_string(path)
+                    .map_err(|e| anyhow::anyhow!("Failed to read the system prompt {}: {e}", path.display()))?,
+            ),
+            None => None,
+        };
+        Ok(Settings {
+            model: self.model.clone(),
+            base_url: self.base_url.clone(),
+            api_key: self.api_key.clone(),
+            reasoning_effort: self.reasoning_effort.clone(),
+            send_reasoning: self.send_reasoning.then_some(true),
+            max_tokens: self.max_tokens,
+            context_window: self.context_window,
+            compaction_threshold: self.compaction_threshold,
+            keep_recent_turns: self.keep_recent_turns,
+            system_prompt,
+            append_system_prompt: self.append_system_prompt.then_some(true),
+            ..Default::default()
+        })
+    }
 }
 
 async fn start_session(
     terminal: ratatui::Terminal<CrosstermBackend<Stdout>>,
     prompt: Option<String>,
-    model: String,
-    api_key: String,
-    base_url: String,
-    reasoning_effort: Option<ReasoningEffort>,
+    config: Config,
+    profiles: BTreeMap<String, Settings>,
+    commands: CommandRegistry,
+    recorder: Option<Arc<CassetteRecorder>>,
+    tool_cassette: ToolCassette,
 ) -> anyhow::Result<()> {
+    let llm_provider = LLMProvider::new(config.model.clone(), recorder)?;
     let (ui_tx, ui_rx) = mpsc::unbounded_channel();
     let (control_tx, control_rx) = mpsc::unbounded_channel();
     let (tool_req_tx, tool_req_rx) = mpsc::unbounded_channel();
     let (tool_resp_tx, tool_resp_rx) = mpsc::unbounded_channel();
+    let history = match config::history_path().map(History::load) {
+        Some(Ok(history)) => history,
+        Some(Err(e)) => {
+            tracing::warn!("Failed to load the prompt history: {e:?}");
+            History::default()
+        }
+        None => History::default(),
+    };
 
+    let workspace = std::env::current_dir()?;
     let mut join_set = JoinSet::new();
-    join_set.spawn(ui::ui_loop(terminal, ui_rx, control_tx, prompt));
+    join_set.spawn(ui::ui_loop(
+        terminal, ui_rx, control_tx, prompt, config.ui, commands, history,
+    ));
     join_set.spawn(server::server_loop(
         ui_tx,
         control_rx,
         tool_req_tx,
         tool_resp_rx,
-        model,
-        api_key,
-        base_url,
-        reasoning_effort,
+        llm_provider,
+        SessionConfig {
+            compaction: config.compaction,
+            profiles,
+            system_prompt: config.system_prompt,
+            dir: config::new_session_dir(&workspace)?,
+            workspace: workspace.clone(),
+            user_config_dir: config::user_config_dir(),
+            watch_files: true,
+        },
+    ));
+    join_set.spawn(tools::executor::run_executor(
+        tool_req_rx,
+        tool_resp_tx,
+        tool_cassette,
+        config.model.tools,
+        workspace,
     ));
-    join_set.spawn(tools::executor::run_executor(tool_req_rx, tool_resp_tx));
 
     let first_result = join_set.join_next().await;
-    if let Some(result) = first_result {
-        if let Ok(Err(e)) = result {
-            tracing::error!("Task failed: {e:?}");
-        }
+    if let Some(Ok(Err(e))) = first_result {
+        tracing::error!("Task failed: {e:?}");
     }
     join_set.abort_all();
     while let Some(result) = join_set.join_next().await {
@@ -86,16 +206,13 @@ async fn start_session(
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
+    // Loaded before parsing the command line, so `.env` can supply `AGENT_*` variables.
+    if let Err(e) = dotenvy::dotenv()
+        && !e.not_found()
+    {
+        return Err(e.into());
+    }
     let cli = Cli::parse();
-    let reasoning_effort = match cli.reasoning_effort {
-        Some(effort) => match effort.as_str() {
-            "low" => Some(ReasoningEffort::Low),
-            "medium" => Some(ReasoningEffort::Medium),
-            "high" => Some(ReasoningEffort::High),
-            _ => anyhow::bail!("Invalid reasoning effort: {effort}"),
-        },
-        None => None,
-    };
 
     let log_file = std::fs::OpenOptions::new()
         .create(true)
@@ -108,21 +225,61 @@ async fn main() -> anyhow::Result<()> {
         .with_writer(log_file)
         .init();
 
-    if fs::try_exists(".env").await? {
-        dotenvy::dotenv()?;
+    let workspace = std::env::current_dir()?;
+    let config_files = ConfigFiles::load(&workspace)?;
+    let mut overrides = cli.settings()?;
+
+    let mut replay_server = None;
+    let (recorder, tool_cassette) = match (&cli.record, &cli.replay) {
+        (Some(dir), _) => {
+            let recorder = Arc::new(CassetteRecorder::create(dir)?);
+            (Some(recorder.clone()), ToolCassette::Record(recorder))
+        }
+        (None, Some(dir)) => {
+            let cassette = Cassette::load(dir)?;
+            let tool_cassette = cassette.tool_cassette();
+            let server = ReplayServer::start(cassette).await?;
+            overrides.base_url = Some(server.base_url());
+            replay_server = Some(server);
+            (None, tool_cassette)
+        }
+        (None, None) => (None, ToolCassette::Off),
+    };
+    let mut settings = config_files.settings(overrides.clone(), cli.profile.as_deref())?;
+    if replay_server.is_some() {
+        settings.model.get_or_insert_with(|| "replay".to_string());
     }
+    let config = settings.resolve()?;
+    // Everything that can fail is loaded before the terminal is taken over, which only gets restored at the end.
+    let profiles = config_files.profiles(&overrides);
+    let commands = CommandRegistry::load(&workspace)?;
 
     let terminal = ratatui::init();
+    crossterm::execute!(std::io::stdout(), EnableBracketedPaste, EnableMouseCapture)?;
+    // Lets terminals that support it report Shift-Enter apart from Enter.
+    let keyboard_enhancement = crossterm::terminal::supports_keyboard_enhancement().unwrap_or(false);
+    if keyboard_enhancement {
+        crossterm::execute!(
+            std::io::stdout(),
+            PushKeyboardEnhancementFlags(KeyboardEnhancementFlags::DISAMBIGUATE_ESCAPE_CODES)
+        )?;
+    }
     let result = start_session(
         terminal,
         cli.prompt,
-        cli.model,
-        cli.api_key,
-        cli.base_url,
-        reasoning_effort,
+        config,
+        profiles,
+        commands,
+        recorder,
+        tool_cassette,
     )
     .await;
+    if keyboard_enhancement {
+        crossterm::execute!(std::io::stdout(), PopKeyboardEnhancementFlags)?;
+    }
+    crossterm::execute!(std::io::stdout(), DisableBracketedPaste, DisableMouseCapture)?;
     ratatui::restore();
+    drop(replay_server);
 
     result
 }
@@ -0,0 +1,240 @@
+//! Branches of a conversation.
+//!
+//! Rewinding to an earlier prompt can fork the conversation instead of dropping everything after the prompt: the
+//! conversation as it was is kept as a branch, and the session goes on in a new branch that shares everything before
+//! the prompt. Every branch is saved to the session directory as `branches/<n>.json`, along with the branch it was
+//! forked from and where, so the tree can be followed there too.
+
+use std::path::PathBuf;
+
+use async_openai::types::{
+    ChatCompletionRequestMessage,
+    ChatCompletionRequestUserMessageContent,
+    ChatCompletionRequestUserMessageContentPart,
+};
+use serde::{
+    Deserialize,
+    Serialize,
+};
+
+use crate::{
+    compaction,
+    ui_state::ChatUIModification,
+};
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Branch {
+    /// The branch this one was forked from, counting from 1. `None` for the first branch.
+    pub parent: Option<usize>,
+    /// How many messages it shares with its parent.
+    pub forked_at: usize,
+    /// Indices of the messages that are prompts the user typed, rather than notes the agent added.
+    pub prompts: Vec<usize>,
+    /// The conversation, without the preamble.
+    pub messages: Vec<ChatCompletionRequestMessage>,
+}
+
+impl Branch {
+    /// The first prompt of the branch that it doesn't share with its parent.
+    pub fn title(&self) -> String {
+        self.prompts
+            .iter()
+            .find(|index| **index >= self.forked_at)
+            .and_then(|index| self.messages.get(*index))
+            .map(|message| prompt_text(message).lines().next().unwrap_or_default().to_string())
+            .unwrap_or_else(|| "(no prompts yet)".to_string())
+    }
+}
+
+pub struct Branches {
+    dir: PathBuf,
+    branches: Vec<Branch>,
+    /// Index of the branch the conversation is on. Its `messages` are only brought up to date when leaving it.
+    current: usize,
+}
+
+impl Branches {
+    /// Starts out with one empty branch. Branches are saved to `dir`, which is created when the first one is.
+    pub fn new(dir: PathBuf) -> Self {
+        Self {
+            dir,
+            branches: vec![Branch::default()],
+            current: 0,
+        }
+    }
+
+    pub fn list(&self) -> &[Branch] {
+        &self.branches
+    }
+
+    /// The number of the branch the conversation is on, counting from 1.
+    pub fn current(&self) -> usize {
+        self.current + 1
+    }
+
+    /// Keeps the conversation as it is, `messages` with `prompts`, as the current branch, and starts a new branch
+    /// from its first `len` messages. Returns the new branch's number.
+    pub async fn fork(
+        &mut self,
+        messages: &[ChatCompletionRequestMessage],
+        prompts: &[usize],
+        len: usize,
+    ) -> anyhow::Result<usize> {
+        self.save_current(messages, prompts).await?;
+        let branch = Branch {
+            parent: Some(self.current()),
+            forked_at: len,
+            prompts: prompts.iter().copied().filter(|index| *index < len).collect(),
+            messages: messages[..len].to_vec(),
+        };
+        self.branches.push(branch);
+        self.current = self.branches.len() - 1;
+        self.save(self.current).await?;
+        Ok(self.current())
+    }
+
+    /// Keeps the conversation as it is as the current branch, and switches to branch `number`. Returns that branch.
+    pub async fn switch(
+        &mut self,
+        messages: &[ChatCompletionRequestMessage],
+        prompts: &[usize],
+        number: usize,
+    ) -> anyhow::Result<&Branch> {
+        anyhow::ensure!(
+            (1..=self.branches.len()).contains(&number),
+            "There's no branch {number}, see /branches"
+        );
+        self.save_current(messages, prompts).await?;
+        self.current = number - 1;
+        Ok(&self.branches[self.current])
+    }
+
+    async fn save_current(
+        &mut self,
+        messages: &[ChatCompletionRequestMessage],
+        prompts: &[usize],
+    ) -> anyhow::Result<()> {
+        let branch = &mut self.branches[self.current];
+        branch.messages = messages.to_vec();
+        branch.prompts = prompts.to_vec();
+        self.save(self.current).await
+    }
+
+    async fn save(&self, index: usize) -> anyhow::Result<()> {
+        tokio::fs::create_dir_all(&self.dir).await?;
+        let path = self.dir.join(format!("{}.json", index + 1));
+        tokio::fs::write(path, serde_json::to_string_pretty(&self.branches[index])?).await?;
+        Ok(())
+    }
+}
+
+/// The transcript of a branch's conversation, to show when switching to it: the prompts, the assistant's answers, and
+/// its tool calls with their results. Also returns, for each prompt, the index of its message in the transcript.
+pub fn transcript(branch: &Branch) -> (Vec<ChatUIModification>, Vec<usize>) {
+    let mut modifications = vec![];
+    let mut ui_indices = vec![];
+    let mut ui_len = 0;
+    for (index, message) in branch.messages.iter().enumerate() {
+        match message {
+            ChatCompletionRequestMessage::User(_) if branch.prompts.contains(&index) => {
+                ui_indices.push(ui_len);
+                modifications.push(ChatUIModification::AddUserMessage {
+                    text: prompt_text(message),
+                    attachments: vec![],
+                });
+                ui_len += 1;
+            }
+            ChatCompletionRequestMessage::Assistant(assistant) => {
+                let text = compaction::message_text(message);
+                if !text.is_empty() {
+                    modifications.push(ChatUIModification::AddSystemMessage { text });
+                    ui_len += 1;
+                }
+                for tool_call in assistant.tool_calls.iter().flatten() {
+                    let result = branch.messages[index..]
+                        .iter()
+                        .find_map(|message| match message {
+                            ChatCompletionRequestMessage::Tool(tool) if tool.tool_call_id == tool_call.id => {
+                                let text = compaction::message_text(message);
+                                Some(match text.strip_prefix("Error: ") {
+                                    Some(error) => Err(error.to_string()),
+                                    None => Ok(text),
+                                })
+                            }
+                            _ => None,
+                        })
+                        .unwrap_or_else(|| Err("no result".to_string()));
+                    modifications.extend([
+                        ChatUIModification::StartToolCall {
+                            name: tool_call.function.name.clone(),
+                            args: tool_call.function.arguments.clone(),
+                        },
+                        ChatUIModification::StartToolCallExecution { index: ui_len },
+                        ChatUIModification::CompleteToolCall { index: ui_len, result },
+                    ]);
+                    ui_len += 1;
+                }
+            }
+            _ => {}
+        }
+    }
+    (modifications, ui_indices)
+}
+
+/// The prompt as typed, without the files attached to it.
+fn prompt_text(message: &ChatCompletionRequestMessage) -> String {
+    match message {
+        ChatCompletionRequestMessage::User(user) => match &user.content {
+            ChatCompletionRequestUserMessageContent::Text(text) => text.clone(),
+            ChatCompletionRequestUserMessageContent::Array(parts) => parts
+                .iter()
+                .find_map(|part| match part {
+                    ChatCompletionRequestUserMessageContentPart::Text(part) => Some(part.text.clone()),
+                    _ => None,
+                })
+                .unwrap_or_default(),
+        },
+        _ => String::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_messages::{
+        tool_call,
+        tool_result,
+        user,
+    };
+
+    #[test]
+    fn test_transcript() {
+        let branch = Branch {
+            parent: Some(1),
+            forked_at: 2,
+            // The note at 4 was added by the agent, not typed.
+            prompts: vec![0, 3],
+            messages: vec![
+                user("List the files"),
+                tool_call("1", "list_dir"),
+                tool_result("1", "README.md"),
+                user("Read it"),
+                user("Note: the files changed"),
+                tool_call("2", "read_file"),
+                tool_result("2", "Error: no such file"),
+            ],
+        };
+        assert_eq!(branch.title(), "Read it");
+
+        let (modifications, ui_indices) = transcript(&branch);
+        assert_eq!(ui_indices, [0, 2]);
+        assert!(matches!(
+            &modifications[3],
+            ChatUIModification::CompleteToolCall { index: 1, result: Ok(output) } if output == "README.md"
+        ));
+        assert!(matches!(
+            modifications.last(),
+            Some(ChatUIModification::CompleteToolCall { index: 3, result: Err(error) }) if error == "no such file"
+        ));
+    }
+}
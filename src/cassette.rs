@@ -0,0 +1,332 @@
+//! Recording and replaying of LLM sessions.
+//!
+//! A cassette is a directory holding every `/chat/completions` exchange of a session, in order, plus the results of
+//! every tool call:
+//!
+//! ```text
+//! 0000-request.json    request body
+//! 0000-response.sse    raw event stream for streaming requests...
+//! 0001-request.json
+//! 0001-response.json   ...or the response body for non-streaming ones
+//! tool-results.jsonl   one `RecordedToolResult` per line
+//! ```
+//!
+//! Replaying serves the recorded responses from a local HTTP stand-in, so `LLMProvider` and `server_loop` run
+//! unmodified against it.
+
+use std::{
+    collections::HashMap,
+    io::Write,
+    net::SocketAddr,
+    path::{
+        Path,
+        PathBuf,
+    },
+    sync::{
+        Arc,
+        Mutex,
+        atomic::{
+            AtomicUsize,
+            Ordering,
+        },
+    },
+};
+
+use serde::{
+    Deserialize,
+    Serialize,
+};
+use tokio::{
+    io::{
+        AsyncReadExt,
+        AsyncWriteExt,
+    },
+    net::{
+        TcpListener,
+        TcpStream,
+    },
+    task::JoinHandle,
+};
+
+const TOOL_RESULTS_FILE: &str = "tool-results.jsonl";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RecordedToolResult {
+    pub id: String,
+    pub name: String,
+    pub args: String,
+    pub result: Result<String, String>,
+}
+
+/// Where the tool executor gets its results from.
+#[derive(Clone, Default)]
+pub enum ToolCassette {
+    /// Run tools for real.
+    #[default]
+    Off,
+    /// Run tools for real and record their results.
+    Record(Arc<CassetteRecorder>),
+    /// Don't run anything, answer with the recorded result for each tool call id.
+    Replay(Arc<HashMap<String, Result<String, String>>>),
+}
+
+pub struct CassetteRecorder {
+    dir: PathBuf,
+    next_interaction: AtomicUsize,
+    tool_results: Mutex<std::fs::File>,
+}
+
+impl CassetteRecorder {
+    pub fn create(dir: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let dir = dir.as_ref().to_owned();
+        std::fs::create_dir_all(&dir)?;
+        let tool_results = std::fs::File::create(dir.join(TOOL_RESULTS_FILE))?;
+        Ok(Self {
+            dir,
+            next_interaction: AtomicUsize::new(0),
+            tool_results: Mutex::new(tool_results),
+        })
+    }
+
+    /// Records a request body and returns a handle for recording its response.
+    pub fn start_interaction(&self, request: &serde_json::Value) -> anyhow::Result<InteractionRecorder> {
+        let index = self.next_interaction.fetch_add(1, Ordering::SeqCst);
+        std::fs::write(
+            self.dir.join(format!("{index:04}-request.json")),
+            serde_json::to_string_pretty(request)?,
+        )?;
+        let streaming = request.get("stream").and_then(|s| s.as_bool()).unwrap_or(false);
+        let extension = if streaming { "sse" } else { "json" };
+        let response = std::fs::File::create(self.dir.join(format!("{index:04}-response.{extension}")))?;
+        Ok(InteractionRecorder { response })
+    }
+
+    pub fn record_tool_result(&self, tool_result: &RecordedToolResult) -> anyhow::Result<()> {
+        let mut line = serde_json::to_string(tool_result)?;
+        line.push('\n');
+        let mut file = self
+            .tool_results
+            .lock()
+            .map_err(|_| anyhow::anyhow!("Tool results file lock poisoned"))?;
+        file.write_all(line.as_bytes())?;
+        Ok(())
+    }
+}
+
+pub struct InteractionRecorder {
+    response: std::fs::File,
+}
+
+impl InteractionRecorder {
+    /// Appends one server-sent event to a streaming response.
+    pub fn record_event(&mut self, data: &str) -> anyhow::Result<()> {
+        self.response.write_all(format!("data: {data}\n\n").as_bytes())?;
+        Ok(())
+    }
+
+    /// Records the full body of a non-streaming response.
+    pub fn record_body(&mut self, body: &str) -> anyhow::Result<()> {
+        self.response.write_all(body.as_bytes())?;
+        Ok(())
+    }
+}
+
+struct RecordedInteraction {
+    request: serde_json::Value,
+    response: String,
+    streaming: bool,
+}
+
+pub struct Cassette {
+    interactions: Vec<RecordedInteraction>,
+    tool_results: Arc<HashMap<String, Result<String, String>>>,
+}
+
+impl Cassette {
+    pub fn load(dir: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let dir = dir.as_ref();
+        let mut interactions = vec![];
+        loop {
+            let index = interactions.len();
+            let request_path = dir.join(format!("{index:04}-request.json"));
+            if !request_path.exists() {
+                break;
+            }
+            let request = serde_json::from_str(&std::fs::read_to_string(&request_path)?)?;
+            let sse_path = dir.join(format!("{index:04}-response.sse"));
+            let (response, streaming) = if sse_path.exists() {
+                (std::fs::read_to_string(sse_path)?, true)
+            } else {
+                (
+                    std::fs::read_to_string(dir.join(format!("{index:04}-response.json")))?,
+                    false,
+                )
+            };
+            interactions.push(RecordedInteraction {
+                request,
+                response,
+                streaming,
+            });
+        }
+        anyhow::ensure!(!interactions.is_empty(), "No interactions found in {}", dir.display());
+
+        let mut tool_results = HashMap::new();
+        let tool_results_path = dir.join(TOOL_RESULTS_FILE);
+        if tool_results_path.exists() {
+            for line in std::fs::read_to_string(tool_results_path)?.lines() {
+                if line.trim().is_empty() {
+                    continue;
+                }
+                let tool_result: RecordedToolResult = serde_json::from_str(line)?;
+                tool_results.insert(tool_result.id, tool_result.result);
+            }
+        }
+
+        Ok(Self {
+            interactions,
+            tool_results: Arc::new(tool_results),
+        })
+    }
+
+    pub fn tool_cassette(&self) -> ToolCassette {
+        ToolCassette::Replay(self.tool_results.clone())
+    }
+}
+
+/// A local HTTP server that answers each request with the next recorded response.
+pub struct ReplayServer {
+    addr: SocketAddr,
+    task: JoinHandle<()>,
+}
+
+impl ReplayServer {
+    pub async fn start(cassette: Cassette) -> anyhow::Result<Self> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let cassette = Arc::new(cassette);
+        let next_interaction = Arc::new(AtomicUsize::new(0));
+        let task = tokio::spawn(async move {
+            loop {
+                let Ok((stream, _)) = listener.accept().await else {
+                    break;
+                };
+                let cassette = cassette.clone();
+                let next_interaction = next_interaction.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = Self::serve(stream, &cassette, &next_interaction).await {
+                        tracing::warn!("Replay server failed to serve request: {e:?}");
+                    }
+                });
+            }
+        });
+        tracing::info!("Replay server listening on {addr}");
+        Ok(Self { addr, task })
+    }
+
+    pub fn base_url(&self) -> String {
+        format!("http://{}", self.addr)
+    }
+
+    async fn serve(mut stream: TcpStream, cassette: &Cassette, next_interaction: &AtomicUsize) -> anyhow::Result<()> {
+        let body = read_http_request(&mut stream).await?;
+        let request: serde_json::Value = serde_json::from_slice(&body)?;
+
+        let index = next_interaction.fetch_add(1, Ordering::SeqCst);
+        let Some(interaction) = cassette.interactions.get(index) else {
+            let message = format!("Cassette exhausted after {} interactions", cassette.interactions.len());
+            return write_http_response(&mut stream, "500 Internal Server Error", "text/plain", &message).await;
+        };
+        let expected = request_tail(&interaction.request);
+        let actual = request_tail(&request);
+        if expected != actual {
+            let message = format!(
+                "Request {index} doesn't match the cassette.\nExpected: {}\nActual: {}",
+                serde_json::to_string_pretty(&expected)?,
+                serde_json::to_string_pretty(&actual)?,
+            );
+            return write_http_response(&mut stream, "409 Conflict", "text/plain", &message).await;
+        }
+
+        let content_type = if interaction.streaming {
+            "text/event-stream"
+        } else {
+            "application/json"
+        };
+        write_http_response(&mut stream, "200 OK", content_type, &interaction.response).await
+    }
+}
+
+impl Drop for ReplayServer {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+/// The part of a request that replay checks against the recording: the number of messages, and everything the agent
+/// added since the model last spoke. Earlier messages were already checked by previous requests, and the preamble
+/// (workspace path, shell, project layout) legitimately differs between machines.
+fn request_tail(request: &serde_json::Value) -> serde_json::Value {
+    let messages = request
+        .get("messages")
+        .and_then(|m| m.as_array())
+        .cloned()
+        .unwrap_or_default();
+    let tail_start = match messages
+        .iter()
+        .rposition(|m| m.get("role").and_then(|r| r.as_str()) == Some("assistant"))
+    {
+        Some(i) => i + 1,
+        None => messages.len().saturating_sub(1),
+    };
+    serde_json::json!({
+        "message_count": messages.len(),
+        "tail": messages[tail_start..],
+    })
+}
+
+/// Reads a single HTTP/1.1 request and returns its body. Only understands `Content-Length` bodies, which is all
+/// `reqwest` sends for JSON.
+pub async fn read_http_request(stream: &mut TcpStream) -> anyhow::Result<Vec<u8>> {
+    let mut buf = vec![];
+    let header_end = loop {
+        let mut chunk = [0; 4096];
+        let n = stream.read(&mut chunk).await?;
+        anyhow::ensure!(n > 0, "Connection closed before the request headers ended");
+        buf.extend_from_slice(&chunk[..n]);
+        if let Some(pos) = buf.windows(4).position(|w| w == b"\r\n\r\n") {
+            break pos + 4;
+        }
+    };
+    let headers = String::from_utf8_lossy(&buf[..header_end]).to_string();
+    let content_length = headers
+        .lines()
+        .find_map(|line| {
+            let (name, value) = line.split_once(':')?;
+            name.eq_ignore_ascii_case("content-length")
+                .then(|| value.trim().parse::<usize>().ok())?
+        })
+        .unwrap_or(0);
+    let mut body = buf.split_off(header_end);
+    while body.len() < content_length {
+        let mut chunk = [0; 4096];
+        let n = stream.read(&mut chunk).await?;
+        anyhow::ensure!(n > 0, "Connection closed before the request body ended");
+        body.extend_from_slice(&chunk[..n]);
+    }
+    Ok(body)
+}
+
+pub async fn write_http_response(
+    stream: &mut TcpStream,
+    status: &str,
+    content_type: &str,
+    body: &str,
+) -> anyhow::Result<()> {
+    let response = format!(
+        "HTTP/1.1 {status}\r\nContent-Type: {content_type}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
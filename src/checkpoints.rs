@@ -0,0 +1,254 @@
+//! Checkpoints of the files the agent edits, so its edits can be undone.
+//!
+//! Before a mutating tool touches a file, the file is snapshotted as it was. The snapshots of one turn, from the user's
+//! message until the agent is done, make up a checkpoint. Restoring a checkpoint puts back every file the turn touched,
+//! and deletes the ones it created. Checkpoints are written to the session directory as they're taken, as
+//! `checkpoints/<n>.json`, so edits can still be recovered by hand if the session crashes.
+
+use std::path::{
+    Path,
+    PathBuf,
+};
+
+use serde::{
+    Deserialize,
+    Serialize,
+};
+
+/// A file as it was before a turn first touched it.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FileSnapshot {
+    pub path: PathBuf,
+    /// `None` if the file didn't exist yet.
+    pub contents: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Checkpoint {
+    /// The user's message that started the turn.
+    pub prompt: String,
+    /// How many messages the conversation had before the turn, to truncate it back to. `None` once the conversation
+    /// has been compacted or cleared, which leaves nothing to truncate to.
+    pub conversation_len: Option<usize>,
+    /// How many messages the transcript had before the turn.
+    pub ui_len: usize,
+    pub files: Vec<FileSnapshot>,
+}
+
+impl Checkpoint {
+    /// The files of the checkpoint, marking the ones the turn created.
+    pub fn describe_files(&self) -> String {
+        self.files
+            .iter()
+            .map(|file| match file.contents {
+                Some(_) => format!("`{}`", file.path.display()),
+                None => format!("`{}` (new)", file.path.display()),
+            })
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+}
+
+/// Where a turn started, kept until it touches a file and needs a checkpoint.
+struct Turn {
+    prompt: String,
+    /// `None` once the conversation was compacted or cleared during the turn.
+    conversation_len: Option<usize>,
+    ui_len: usize,
+    /// The checkpoint of this turn, once there is one.
+    checkpoint: Option<usize>,
+}
+
+pub struct Checkpoints {
+    dir: PathBuf,
+    checkpoints: Vec<Checkpoint>,
+    turn: Option<Turn>,
+}
+
+impl Checkpoints {
+    /// Checkpoints kept in `dir`, which is created when the first one is taken.
+    pub fn new(dir: PathBuf) -> Self {
+        Self {
+            dir,
+            checkpoints: vec![],
+            turn: None,
+        }
+    }
+
+    pub fn list(&self) -> &[Checkpoint] {
+        &self.checkpoints
+    }
+
+    /// Starts a turn. Files it touches go into a new checkpoint.
+    pub fn begin_turn(&mut self, prompt: &str, conversation_len: usize, ui_len: usize) {
+        self.turn = Some(Turn {
+            prompt: prompt.to_string(),
+            conversation_len: Some(conversation_len),
+            ui_len,
+            checkpoint: None,
+        });
+    }
+
+    /// The conversation was compacted or cleared, so the checkpoints can't truncate it anymore. The current turn
+    /// goes on, so what it edits from here on can still be undone.
+    pub fn forget_conversation(&mut self) {
+        for checkpoint in &mut self.checkpoints {
+            checkpoint.conversation_len = None;
+        }
+        if let Some(turn) = &mut self.turn {
+            turn.conversation_len = None;
+        }
+    }
+
+    /// The conversation went back to `len` messages, so checkpoints of later turns can't truncate it anymore.
+    pub fn forget_conversation_after(&mut self, len: usize) {
+        for checkpoint in &mut self.checkpoints {
+            checkpoint.conversation_len = checkpoint
+                .conversation_len
+                .filter(|conversation_len| *conversation_len <= len);
+        }
+    }
+
+    /// Snapshots `path` before the current turn changes it, unless the turn already did.
+    pub async fn snapshot(&mut self, path: &Path) -> anyhow::Result<()> {
+        let Some(turn) = &mut self.turn else {
+            anyhow::bail!("A file was edited outside of a turn");
+        };
+        let index = *turn.checkpoint.get_or_insert_with(|| {
+            self.checkpoints.push(Checkpoint {
+                prompt: turn.prompt.clone(),
+                conversation_len: turn.conversation_len,
+                ui_len: turn.ui_len,
+                files: vec![],
+            });
+            self.checkpoints.len() - 1
+        });
+        let checkpoint = &mut self.checkpoints[index];
+        if checkpoint.files.iter().any(|file| file.path == path) {
+            return Ok(());
+        }
+        let contents = match tokio::fs::read_to_string(path).await {
+            Ok(contents) => Some(contents),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+            Err(e) => return Err(e.into()),
+        };
+        checkpoint.files.push(FileSnapshot {
+            path: path.to_path_buf(),
+            contents,
+        });
+
+        let json = serde_json::to_string_pretty(checkpoint)?;
+        tokio::fs::create_dir_all(&self.dir).await?;
+        tokio::fs::write(self.file(index), json).await?;
+        Ok(())
+    }
+
+    /// Reverts the edits of the last checkpoint and drops it. Returns it, or `None` if there are no checkpoints.
+    pub async fn undo(&mut self) -> anyhow::Result<Option<Checkpoint>> {
+        if self.checkpoints.is_empty() {
+            return Ok(None);
+        }
+        self.restore(self.checkpoints.len()).await.map(Some)
+    }
+
+    /// Puts the workspace back the way it was before checkpoint `number` (counting from 1), reverting it and every
+    /// checkpoint after it, which are dropped. Returns the checkpoint.
+    pub async fn restore(&mut self, number: usize) -> anyhow::Result<Checkpoint> {
+        anyhow::ensure!(
+            (1..=self.checkpoints.len()).contains(&number),
+            "There's no checkpoint {number}, see /checkpoints"
+        );
+        // Newest first, so every file ends up as the earliest checkpoint found it.
+        while self.checkpoints.len() >= number {
+            let index = self.checkpoints.len() - 1;
+            for file in self.checkpoints[index].files.iter().rev() {
+                restore_file(file).await?;
+            }
+            let checkpoint = self.checkpoints.remove(index);
+            remove_file(&self.file(index)).await?;
+            if index == number - 1 {
+                self.turn = None;
+                return Ok(checkpoint);
+            }
+        }
+        unreachable!("the checkpoint was in range")
+    }
+
+    fn file(&self, index: usize) -> PathBuf {
+        self.dir.join(format!("{}.json", index + 1))
+    }
+}
+
+async fn restore_file(file: &FileSnapshot) -> anyhow::Result<()> {
+    match &file.contents {
+        Some(contents) => {
+            if let Some(parent) = file.path.parent()
+                && !parent.as_os_str().is_empty()
+            {
+                tokio::fs::create_dir_all(parent).await?;
+            }
+            tokio::fs::write(&file.path, contents).await?;
+        }
+        None => remove_file(&file.path).await?,
+    }
+    Ok(())
+}
+
+async fn remove_file(path: &Path) -> anyhow::Result<()> {
+    match tokio::fs::remove_file(path).await {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_undo_and_restore() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let dir = temp_dir.path();
+        let workspace = dir.join("workspace");
+        std::fs::create_dir_all(&workspace).unwrap();
+        let (a, b) = (workspace.join("a.txt"), workspace.join("b.txt"));
+        std::fs::write(&a, "a0").unwrap();
+        let mut checkpoints = Checkpoints::new(dir.join("checkpoints"));
+
+        // Turn 1 edits a twice and creates b.
+        checkpoints.begin_turn("first", 4, 0);
+        checkpoints.snapshot(&a).await.unwrap();
+        std::fs::write(&a, "a1").unwrap();
+        checkpoints.snapshot(&a).await.unwrap();
+        std::fs::write(&a, "a2").unwrap();
+        checkpoints.snapshot(&b).await.unwrap();
+        std::fs::write(&b, "b1").unwrap();
+        // Turn 2 doesn't touch anything, turn 3 edits b.
+        checkpoints.begin_turn("second", 8, 3);
+        checkpoints.begin_turn("third", 10, 4);
+        checkpoints.snapshot(&b).await.unwrap();
+        std::fs::write(&b, "b2").unwrap();
+
+        assert_eq!(checkpoints.list().len(), 2);
+        assert_eq!(checkpoints.list()[0].files.len(), 2);
+        assert!(dir.join("checkpoints").join("2.json").exists());
+
+        let undone = checkpoints.undo().await.unwrap().unwrap();
+        assert_eq!(undone.prompt, "third");
+        assert_eq!(std::fs::read_to_string(&b).unwrap(), "b1");
+        assert!(!dir.join("checkpoints").join("2.json").exists());
+
+        assert!(checkpoints.restore(2).await.is_err());
+        let restored = checkpoints.restore(1).await.unwrap();
+        assert_eq!(restored.conversation_len, Some(4));
+        assert_eq!(std::fs::read_to_string(&a).unwrap(), "a0");
+        assert!(!b.exists());
+        assert!(checkpoints.undo().await.unwrap().is_none());
+
+        // Compacting during a turn keeps it going, without a conversation to truncate to.
+        checkpoints.begin_turn("fourth", 12, 5);
+        checkpoints.forget_conversation();
+        checkpoints.snapshot(&a).await.unwrap();
+        assert_eq!(checkpoints.list()[0].conversation_len, None);
+    }
+}
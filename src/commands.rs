@@ -0,0 +1,357 @@
+//! Slash commands typed into the input box.
+//!
+//! Built-in commands map to a `ControlMessage`. Custom commands are prompt templates, one markdown file per command in
+//! the workspace's `.agent/commands/`: `.agent/commands/review.md` defines `/review`, and `$ARGUMENTS` in the file is
+//! replaced with whatever follows the command name. A file can start with a front matter block holding a
+//! `description:` line for `/help`:
+//!
+//! ```markdown
+//! ---
+//! description: Review the current changes
+//! ---
+//! Review the uncommitted changes in this repository. Focus on $ARGUMENTS.
+//! ```
+
+use std::path::{
+    Path,
+    PathBuf,
+};
+
+use crate::control::ControlMessage;
+
+pub struct BuiltinCommand {
+    pub name: &'static str,
+    pub usage: &'static str,
+    pub description: &'static str,
+}
+
+pub const BUILTIN_COMMANDS: &[BuiltinCommand] = &[
+    BuiltinCommand {
+        name: "branch",
+        usage: "<n>",
+        description: "Switch to another branch of the conversation",
+    },
+    BuiltinCommand {
+        name: "branches",
+        usage: "",
+        description: "List the branches of the conversation",
+    },
+    BuiltinCommand {
+        name: "checkpoints",
+        usage: "",
+        description: "List the checkpoints of the agent's file edits",
+    },
+    BuiltinCommand {
+        name: "clear",
+        usage: "",
+        description: "Start over with an empty conversation",
+    },
+    BuiltinCommand {
+        name: "commit",
+        usage: "[--no-verify]",
+        description: "Commit the agent's edits with a drafted message, with --no-verify skipping the git hooks",
+    },
+    BuiltinCommand {
+        name: "compact",
+        usage: "",
+        description: "Summarize older turns of the conversation",
+    },
+    BuiltinCommand {
+        name: "context",
+        usage: "",
+        description: "Show the system prompt and the rest of the context the conversation starts with",
+    },
+    BuiltinCommand {
+        name: "cost",
+        usage: "",
+        description: "Show the tokens used so far",
+    },
+    BuiltinCommand {
+        name: "help",
+        usage: "",
+        description: "List the available commands",
+    },
+    BuiltinCommand {
+        name: "model",
+        usage: "[name or profile] [effort]",
+        description: "Show or switch the model",
+    },
+    BuiltinCommand {
+        name: "restore",
+        usage: "<n> [--truncate]",
+        description: "Put the files back as they were before checkpoint n, with --truncate the conversation too",
+    },
+    BuiltinCommand {
+        name: "save",
+        usage: "[path]",
+        description: "Save the conversation history as JSON",
+    },
+    BuiltinCommand {
+        name: "tools",
+        usage: "",
+        description: "List the tools the model can call",
+    },
+    BuiltinCommand {
+        name: "undo",
+        usage: "",
+        description: "Revert the file edits of the last turn",
+    },
+];
+
+const ARGUMENTS_PLACEHOLDER: &str = "$ARGUMENTS";
+
+/// A prompt template defined in `.agent/commands/<name>.md`.
+#[derive(Debug, Clone)]
+pub struct CustomCommand {
+    pub name: String,
+    pub description: Option<String>,
+    template: String,
+}
+
+impl CustomCommand {
+    fn parse(name: String, text: &str) -> Self {
+        let mut description = None;
+        let mut template = text;
+        if let Some(rest) = text.strip_prefix("---\n")
+            && let Some((front_matter, body)) = rest.split_once("\n---\n")
+        {
+            description = front_matter.lines().find_map(|line| {
+                let value = line.strip_prefix("description:")?;
+                Some(value.trim().to_string())
+            });
+            template = body;
+        }
+        Self {
+            name,
+            description,
+            template: template.trim().to_string(),
+        }
+    }
+
+    /// The prompt to send for `/<name> <args>`. Arguments are appended if the template has no placeholder for them.
+    pub fn expand(&self, args: &str) -> String {
+        if self.template.contains(ARGUMENTS_PLACEHOLDER) {
+            self.template.replace(ARGUMENTS_PLACEHOLDER, args)
+        } else if args.is_empty() {
+            self.template.clone()
+        } else {
+            format!("{}\n\n{args}", self.template)
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct CommandRegistry {
+    custom: Vec<CustomCommand>,
+}
+
+impl CommandRegistry {
+    /// Loads the custom commands of `workspace`, if it has any.
+    pub fn load(workspace: &Path) -> anyhow::Result<Self> {
+        let dir = workspace.join(".agent").join("commands");
+        let entries = match std::fs::read_dir(&dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Self::default()),
+            Err(e) => return Err(e.into()),
+        };
+        let mut paths = entries
+            .map(|entry| Ok(entry?.path()))
+            .collect::<anyhow::Result<Vec<PathBuf>>>()?;
+        paths.sort();
+
+        let mut custom = vec![];
+        for path in paths {
+            if path.extension().is_none_or(|extension| extension != "md") {
+                continue;
+            }
+            let Some(name) = path.file_stem().and_then(|stem| stem.to_str()) else {
+                continue;
+            };
+            if BUILTIN_COMMANDS.iter().any(|command| command.name == name) {
+                tracing::warn!("Ignoring {}, /{name} is a built-in command", path.display());
+                continue;
+            }
+            let text = std::fs::read_to_string(&path)?;
+            custom.push(CustomCommand::parse(name.to_string(), &text));
+        }
+        Ok(Self { custom })
+    }
+
+    /// Turns a slash command into the message it stands for. Returns `None` for input that isn't a command, and an
+    /// error message for commands that don't exist or are used wrong.
+    pub fn parse(&self, input: &str) -> Option<Result<ControlMessage, String>> {
+        let input = input.trim();
+        let command = input.strip_prefix('/')?;
+        let (name, args) = match command.split_once(char::is_whitespace) {
+            Some((name, args)) => (name, args.trim()),
+            None => (command, ""),
+        };
+        // Absolute paths like `/usr/bin` are text, not commands.
+        if name.is_empty() || name.contains('/') {
+            return None;
+        }
+        let words = args.split_whitespace().collect::<Vec<_>>();
+
+        let message = match (name, words.as_slice()) {
+            ("branch", [number]) if number.parse::<usize>().is_ok() => ControlMessage::SwitchBranch {
+                number: number.parse().unwrap_or_default(),
+            },
+            ("branches", []) => ControlMessage::ListBranches,
+            ("checkpoints", []) => ControlMessage::ListCheckpoints,
+            ("clear", []) => ControlMessage::Clear,
+            ("commit", [] | ["--no-verify"]) => ControlMessage::DraftCommit {
+                run_hooks: words.is_empty(),
+            },
+            ("compact", []) => ControlMessage::Compact,
+            ("context", []) => ControlMessage::ShowContext,
+            ("cost", []) => ControlMessage::ShowCost,
+            ("help", []) => ControlMessage::Notice(self.help()),
+            ("model", [] | [_] | [_, _]) => ControlMessage::SetModel {
+                name: words.first().map(|name| name.to_string()),
+                reasoning_effort: words.get(1).map(|effort| effort.to_string()),
+            },
+            ("save", []) => ControlMessage::Save { path: None },
+            ("save", _) => ControlMessage::Save {
+                path: Some(PathBuf::from(args)),
+            },
+            ("restore", [number] | [number, "--truncate"]) if number.parse::<usize>().is_ok() => {
+                ControlMessage::Restore {
+                    number: number.parse().unwrap_or_default(),
+                    truncate: words.len() == 2,
+                }
+            }
+            ("tools", []) => ControlMessage::ListTools,
+            ("undo", []) => ControlMessage::Undo,
+            _ => {
+                if let Some(command) = BUILTIN_COMMANDS.iter().find(|command| command.name == name) {
+                    let usage = format!("Usage: /{} {}", command.name, command.usage);
+                    return Some(Err(usage.trim_end().to_string()));
+                }
+                match self.custom.iter().find(|command| command.name == name) {
+                    Some(command) => ControlMessage::UserMessage(command.expand(args)),
+                    None => return Some(Err(format!("Unknown command /{name}, see /help"))),
+                }
+            }
+        };
+        Some(Ok(message))
+    }
+
+    /// All commands starting with `prefix` (including its leading `/`), in order.
+    pub fn complete(&self, prefix: &str) -> Vec<String> {
+        let Some(prefix) = prefix.strip_prefix('/') else {
+            return vec![];
+        };
+        let mut names = BUILTIN_COMMANDS
+            .iter()
+            .map(|command| command.name)
+            .chain(self.custom.iter().map(|command| command.name.as_str()))
+            .filter(|name| name.starts_with(prefix))
+            .map(|name| format!("/{name}"))
+            .collect::<Vec<_>>();
+        names.sort();
+        names
+    }
+
+    fn help(&self) -> String {
+        let mut help = "Commands:\n\n".to_string();
+        for command in BUILTIN_COMMANDS {
+            let usage = match command.usage {
+                "" => format!("/{}", command.name),
+                usage => format!("/{} {usage}", command.name),
+            };
+            help.push_str(&format!("- `{usage}`: {}\n", command.description));
+        }
+        for command in &self.custom {
+            let description = command.description.as_deref().unwrap_or("Custom command");
+            help.push_str(&format!("- `/{}`: {description}\n", command.name));
+        }
+        help
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn registry() -> CommandRegistry {
+        CommandRegistry {
+            custom: vec![
+                CustomCommand::parse(
+                    "review".to_string(),
+                    "---\ndescription: Review changes\n---\nReview the changes, focusing on $ARGUMENTS.\n",
+                ),
+                CustomCommand::parse("explain".to_string(), "Explain this code."),
+            ],
+        }
+    }
+
+    #[test]
+    fn test_parse_builtins() {
+        let registry = registry();
+        assert_eq!(registry.parse("hello"), None);
+        assert_eq!(registry.parse("/usr/bin is empty"), None);
+        assert_eq!(registry.parse(" /clear "), Some(Ok(ControlMessage::Clear)));
+        assert_eq!(
+            registry.parse("/model big high"),
+            Some(Ok(ControlMessage::SetModel {
+                name: Some("big".to_string()),
+                reasoning_effort: Some("high".to_string()),
+            }))
+        );
+        assert_eq!(
+            registry.parse("/save notes/session.json"),
+            Some(Ok(ControlMessage::Save {
+                path: Some(PathBuf::from("notes/session.json")),
+            }))
+        );
+        assert_eq!(
+            registry.parse("/restore 2 --truncate"),
+            Some(Ok(ControlMessage::Restore {
+                number: 2,
+                truncate: true,
+            }))
+        );
+        assert_eq!(
+            registry.parse("/restore last"),
+            Some(Err("Usage: /restore <n> [--truncate]".to_string()))
+        );
+        assert_eq!(
+            registry.parse("/commit --no-verify"),
+            Some(Ok(ControlMessage::DraftCommit { run_hooks: false }))
+        );
+        assert_eq!(registry.parse("/clear now"), Some(Err("Usage: /clear".to_string())));
+        assert_eq!(
+            registry.parse("/frobnicate"),
+            Some(Err("Unknown command /frobnicate, see /help".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_custom_commands() {
+        let registry = registry();
+        assert_eq!(
+            registry.parse("/review error handling"),
+            Some(Ok(ControlMessage::UserMessage(
+                "Review the changes, focusing on error handling.".to_string()
+            )))
+        );
+        assert_eq!(
+            registry.parse("/explain src/main.rs"),
+            Some(Ok(ControlMessage::UserMessage(
+                "Explain this code.\n\nsrc/main.rs".to_string()
+            )))
+        );
+        assert!(registry.help().contains("- `/review`: Review changes"));
+    }
+
+    #[test]
+    fn test_complete() {
+        let registry = registry();
+        assert_eq!(
+            registry.complete("/c"),
+            vec!["/checkpoints", "/clear", "/commit", "/compact", "/context", "/cost"]
+        );
+        assert_eq!(registry.complete("/e"), vec!["/explain"]);
+        assert!(registry.complete("c").is_empty());
+    }
+}
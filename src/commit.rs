@@ -0,0 +1,435 @@
+//! Committing the agent's edits, from `/commit`.
+//!
+//! The model drafts a message from the diff the files the agent edited would stage, which the user reviews and can edit
+//! before the files are staged and the commit is made. Until then the repository's index is left alone. The
+//! repository's `pre-commit`, `commit-msg` and `post-commit` hooks run as they would for `git commit`, since libgit2
+//! doesn't run hooks itself. Every commit gets an `Agent-Session` trailer naming the session that made it.
+
+use std::{
+    collections::BTreeSet,
+    path::{
+        Path,
+        PathBuf,
+    },
+    process::{
+        Command,
+        Stdio,
+    },
+};
+
+use anyhow::Context;
+use async_openai::types::{
+    ChatCompletionRequestMessage,
+    ChatCompletionRequestSystemMessage,
+    ChatCompletionRequestSystemMessageContent,
+    ChatCompletionRequestUserMessage,
+    ChatCompletionRequestUserMessageContent,
+};
+use git2::{
+    Delta,
+    Index,
+    Oid,
+    Repository,
+};
+
+use crate::{
+    llm_provider::LLMProvider,
+    prompts,
+    tools::{
+        git,
+        prompts::GitLogArgs,
+    },
+};
+
+/// The trailer naming the session a commit came from.
+pub const SESSION_TRAILER: &str = "Agent-Session";
+
+/// How many recent commits the model gets to see for their style.
+const RECENT_COMMITS: usize = 10;
+
+/// How much of a failing hook's output is shown.
+const MAX_HOOK_OUTPUT_BYTES: usize = 4_000;
+
+/// The changes staged for a commit.
+#[derive(Debug, Clone)]
+pub struct StagedChanges {
+    pub branch: String,
+    /// The staged files, like "modified: src/main.rs".
+    pub files: Vec<String>,
+    /// The staged diff, bounded like `git_diff`'s.
+    pub diff: String,
+    /// The latest commits on the branch, one per line.
+    pub recent_commits: String,
+}
+
+/// A commit that was made.
+#[derive(Debug, Clone)]
+pub struct Committed {
+    pub id: Oid,
+    pub branch: String,
+    pub summary: String,
+}
+
+/// Returns what staging `files`, which the agent edited, in the repository `workspace` is in would stage, without
+/// writing the index. Files outside of the repository or ignored by it are left alone, and files that are gone are
+/// removed from the index. Anything the user staged before goes into the commit too, but only along with the agent's
+/// edits.
+pub fn stage(workspace: &Path, files: &[PathBuf]) -> anyhow::Result<StagedChanges> {
+    anyhow::ensure!(
+        !files.is_empty(),
+        "Nothing to commit, the agent hasn't edited any files"
+    );
+    let repo = open(workspace)?;
+    let mut index = repo.index()?;
+    let paths = add_files(&repo, workspace, &mut index, files)?;
+
+    let head = repo.head().ok().and_then(|head| head.peel_to_tree().ok());
+    let diff = repo.diff_tree_to_index(head.as_ref(), Some(&index), None)?;
+    anyhow::ensure!(
+        diff.deltas().any(|delta| {
+            [delta.old_file().path(), delta.new_file().path()]
+                .into_iter()
+                .flatten()
+                .any(|path| paths.contains(path))
+        }),
+        "Nothing to commit, the agent's edits are already committed or were undone"
+    );
+    let files = diff
+        .deltas()
+        .map(|delta| {
+            let change = match delta.status() {
+                Delta::Added => "new file",
+                Delta::Deleted => "deleted",
+                Delta::Renamed => "renamed",
+                _ => "modified",
+            };
+            let path = delta
+                .new_file()
+                .path()
+                .or(delta.old_file().path())
+                .unwrap_or(Path::new(""));
+            format!("{change}: {}", path.display())
+        })
+        .collect();
+    let recent_commits = git::log(
+        &repo,
+        workspace,
+        GitLogArgs {
+            max_count: Some(RECENT_COMMITS),
+            ..Default::default()
+        },
+    )
+    // A branch without commits has no log.
+    .unwrap_or_default();
+    Ok(StagedChanges {
+        branch: git::head_name(&repo),
+        files,
+        diff: git::describe_diff(&diff)?,
+        recent_commits,
+    })
+}
+
+/// Has the model draft a commit message for `staged`, which were made for the user's `requests`.
+pub async fn draft_message(
+    llm_provider: &LLMProvider,
+    staged: &StagedChanges,
+    requests: &[String],
+) -> anyhow::Result<String> {
+    let mut context = String::new();
+    if !staged.recent_commits.is_empty() {
+        context.push_str(&format!(
+            "<recent_commits>\n{}</recent_commits>\n",
+            staged.recent_commits
+        ));
+    }
+    if !requests.is_empty() {
+        context.push_str("<user_requests>\n");
+        for request in requests {
+            context.push_str(&format!("- {request}\n"));
+        }
+        context.push_str("</user_requests>\n");
+    }
+    context.push_str(&format!("<staged_changes>\n{}</staged_changes>\n", staged.diff));
+    let request = vec![
+        ChatCompletionRequestMessage::System(ChatCompletionRequestSystemMessage {
+            content: ChatCompletionRequestSystemMessageContent::Text(prompts::COMMIT_MESSAGE_PROMPT.to_string()),
+            name: None,
+        }),
+        ChatCompletionRequestMessage::User(ChatCompletionRequestUserMessage {
+            content: ChatCompletionRequestUserMessageContent::Text(context),
+            name: None,
+        }),
+    ];
+    let message = llm_provider.complete(request).await?;
+    Ok(strip_code_fence(message.trim()).to_string())
+}
+
+/// Stages `files` like `stage` and commits what's staged with `message` on the current branch, running the repository's
+/// hooks unless `run_hooks` is off. `session` goes into the `Agent-Session` trailer.
+pub fn commit(
+    workspace: &Path,
+    files: &[PathBuf],
+    message: &str,
+    session: &str,
+    run_hooks: bool,
+) -> anyhow::Result<Committed> {
+    anyhow::ensure!(
+        !files.is_empty(),
+        "Nothing to commit, the agent hasn't edited any files"
+    );
+    let repo = open(workspace)?;
+    let mut index = repo.index()?;
+    add_files(&repo, workspace, &mut index, files)?;
+    index.write()?;
+    let mut message = git2::message_prettify(format!("{}\n\n{SESSION_TRAILER}: {session}\n", message.trim()), None)?;
+    if run_hooks {
+        run_hook(&repo, "pre-commit", &[])?;
+        // Like `git commit`, the hook gets the message in a file it can change.
+        let path = repo.path().join("COMMIT_EDITMSG");
+        std::fs::write(&path, &message)?;
+        run_hook(&repo, "commit-msg", &[&path])?;
+        message = std::fs::read_to_string(&path)?;
+    }
+    anyhow::ensure!(!message.trim().is_empty(), "The commit message is empty");
+
+    // The pre-commit hook may have staged more, so the index is read again.
+    index.read(true)?;
+    let tree = repo.find_tree(index.write_tree()?)?;
+    let parent = repo.head().ok().map(|head| head.peel_to_commit()).transpose()?;
+    anyhow::ensure!(
+        parent.as_ref().is_none_or(|parent| parent.tree_id() != tree.id()),
+        "Nothing to commit, nothing is staged"
+    );
+    let signature = repo
+        .signature()
+        .context("Set user.name and user.email in the git config to commit")?;
+    let id = repo.commit(
+        Some("HEAD"),
+        &signature,
+        &signature,
+        &message,
+        &tree,
+        &parent.iter().collect::<Vec<_>>(),
+    )?;
+    if run_hooks && let Err(e) = run_hook(&repo, "post-commit", &[]) {
+        tracing::warn!("{e:?}");
+    }
+    Ok(Committed {
+        id,
+        branch: git::head_name(&repo),
+        summary: message.lines().next().unwrap_or_default().to_string(),
+    })
+}
+
+/// Adds `files` to `index`, or removes the ones that are gone, and returns their paths in the repository. Files outside
+/// of the repository or ignored by it are skipped.
+fn add_files(
+    repo: &Repository,
+    workspace: &Path,
+    index: &mut Index,
+    files: &[PathBuf],
+) -> anyhow::Result<BTreeSet<PathBuf>> {
+    let workdir = repo.workdir().context("The repository has no working tree")?;
+    let mut paths = BTreeSet::new();
+    for file in files {
+        let Ok(path) = git::repo_path(repo, workspace, file) else {
+            continue;
+        };
+        if repo.status_should_ignore(&path)? {
+            continue;
+        }
+        if workdir.join(&path).exists() {
+            index.add_path(&path)?;
+        } else if index.get_path(&path, 0).is_some() {
+            index.remove_path(&path)?;
+        }
+        paths.insert(path);
+    }
+    Ok(paths)
+}
+
+/// Opens the repository `workspace` is in, if it's in a state to commit to: on a branch, and not in the middle of a
+/// merge or the like.
+fn open(workspace: &Path) -> anyhow::Result<Repository> {
+    let repo = Repository::discover(workspace).context("The workspace isn't in a git repository")?;
+    anyhow::ensure!(
+        !repo.head_detached()?,
+        "HEAD is detached, check out a branch to commit to"
+    );
+    if let Some(operation) = git::operation_in_progress(&repo) {
+        anyhow::bail!("A {operation} is in progress, finish or abort it before committing");
+    }
+    Ok(repo)
+}
+
+/// Runs the hook called `name` from the repository's hooks directory, if there's one, in the root of the working tree.
+fn run_hook(repo: &Repository, name: &str, args: &[&Path]) -> anyhow::Result<()> {
+    let workdir = repo.workdir().context("The repository has no working tree")?;
+    let dir = match repo.config()?.get_path("core.hooksPath") {
+        Ok(dir) => workdir.join(dir),
+        Err(_) => repo.path().join("hooks"),
+    };
+    let hook = dir.join(name);
+    if !is_executable(&hook) {
+        return Ok(());
+    }
+    tracing::info!("Running the {name} hook");
+    let output = Command::new(&hook)
+        .args(args)
+        .current_dir(workdir)
+        .env("GIT_INDEX_FILE", repo.path().join("index"))
+        .stdin(Stdio::null())
+        .output()
+        .with_context(|| format!("Failed to run {}", hook.display()))?;
+    if !output.status.success() {
+        let mut text = String::from_utf8_lossy(&output.stdout).into_owned();
+        text.push_str(&String::from_utf8_lossy(&output.stderr));
+        let text = text.trim();
+        let text = &text[text.ceil_char_boundary(text.len().saturating_sub(MAX_HOOK_OUTPUT_BYTES))..];
+        anyhow::bail!("The {name} hook failed ({}):\n{text}", output.status);
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+fn is_executable(path: &Path) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+    std::fs::metadata(path).is_ok_and(|metadata| metadata.is_file() && metadata.permissions().mode() & 0o111 != 0)
+}
+
+#[cfg(not(unix))]
+fn is_executable(path: &Path) -> bool {
+    path.is_file()
+}
+
+/// The message without the code fence models sometimes wrap it in anyway.
+fn strip_code_fence(message: &str) -> &str {
+    message
+        .strip_prefix("```")
+        .and_then(|rest| rest.strip_suffix("```"))
+        .and_then(|rest| rest.split_once('\n'))
+        .map_or(message, |(_, inner)| inner.trim())
+}
+
+#[cfg(test)]
+mod tests {
+    use git2::RepositoryInitOptions;
+
+    use super::*;
+
+    fn init(workspace: &Path) -> Repository {
+        let repo = Repository::init_opts(workspace, RepositoryInitOptions::new().initial_head("main")).unwrap();
+        let mut config = repo.config().unwrap();
+        config.set_str("user.name", "Ada").unwrap();
+        config.set_str("user.email", "ada@example.com").unwrap();
+        repo
+    }
+
+    #[cfg(unix)]
+    fn write_hook(repo: &Repository, name: &str, script: &str) {
+        use std::os::unix::fs::PermissionsExt;
+        let path = repo.path().join("hooks").join(name);
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(&path, script).unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+    }
+
+    #[test]
+    fn test_stage_and_commit() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let workspace = temp_dir.path();
+        let repo = init(workspace);
+        std::fs::write(workspace.join(".gitignore"), "target/\n").unwrap();
+        std::fs::create_dir(workspace.join("target")).unwrap();
+        std::fs::write(workspace.join("target").join("out.txt"), "built").unwrap();
+        std::fs::write(workspace.join("notes.md"), "notes\n").unwrap();
+        std::fs::write(workspace.join("other.md"), "not the agent's\n").unwrap();
+
+        let files = [PathBuf::from("notes.md"), workspace.join("target").join("out.txt")];
+        let staged = stage(workspace, &files).unwrap();
+        assert_eq!(staged.branch, "main");
+        assert_eq!(staged.files, ["new file: notes.md"]);
+        assert!(staged.diff.contains("+notes\n"), "{}", staged.diff);
+        assert!(staged.recent_commits.is_empty());
+        // Nothing is staged until the commit is made.
+        let mut index = repo.index().unwrap();
+        index.read(true).unwrap();
+        assert!(index.is_empty());
+
+        let committed = commit(workspace, &files, "Add notes\n", "1760000000", true).unwrap();
+        assert_eq!(committed.summary, "Add notes");
+        let head = repo.head().unwrap().peel_to_commit().unwrap();
+        assert_eq!(head.id(), committed.id);
+        assert_eq!(head.message().unwrap(), "Add notes\n\nAgent-Session: 1760000000\n");
+        assert!(head.tree().unwrap().get_name("other.md").is_none());
+        assert!(stage(workspace, &files).is_err());
+
+        // What the user staged alone isn't committed as the agent's.
+        let mut index = repo.index().unwrap();
+        index.add_path(Path::new("other.md")).unwrap();
+        index.write().unwrap();
+        let error = stage(workspace, &[]).unwrap_err();
+        assert!(error.to_string().contains("hasn't edited any files"), "{error}");
+        let error = stage(workspace, &files).unwrap_err();
+        assert!(error.to_string().contains("already committed"), "{error}");
+        assert!(commit(workspace, &[], "Add other", "1760000000", true).is_err());
+
+        // Detached, there's no branch to commit to.
+        repo.set_head_detached(head.id()).unwrap();
+        let error = stage(workspace, &files).unwrap_err();
+        assert!(error.to_string().contains("HEAD is detached"), "{error}");
+        repo.set_head("refs/heads/main").unwrap();
+
+        // Mid-merge, the merge has to be finished first.
+        std::fs::write(repo.path().join("MERGE_HEAD"), format!("{}\n", head.id())).unwrap();
+        let error = commit(workspace, &files, "Edit", "1760000000", true).unwrap_err();
+        assert!(error.to_string().contains("A merge is in progress"), "{error}");
+        std::fs::remove_file(repo.path().join("MERGE_HEAD")).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_hooks() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let workspace = temp_dir.path();
+        let repo = init(workspace);
+        std::fs::write(workspace.join("a.txt"), "a\n").unwrap();
+        write_hook(
+            &repo,
+            "pre-commit",
+            "#!/bin/sh\necho 'a.txt is not formatted' >&2\nexit 1\n",
+        );
+        write_hook(&repo, "commit-msg", "#!/bin/sh\necho 'Reviewed-by: hook' >> \"$1\"\n");
+
+        let files = [PathBuf::from("a.txt")];
+        stage(workspace, &files).unwrap();
+        let error = commit(workspace, &files, "Add a", "1", true).unwrap_err();
+        assert!(
+            error.to_string().contains("The pre-commit hook failed") && error.to_string().contains("not formatted"),
+            "{error}"
+        );
+        assert!(repo.head().is_err());
+
+        write_hook(&repo, "pre-commit", "#!/bin/sh\nexit 0\n");
+        commit(workspace, &files, "Add a", "1", true).unwrap();
+        let head = repo.head().unwrap().peel_to_commit().unwrap();
+        assert_eq!(
+            head.message().unwrap(),
+            "Add a\n\nAgent-Session: 1\nReviewed-by: hook\n"
+        );
+
+        // Skipping the hooks skips the failing one too.
+        write_hook(&repo, "pre-commit", "#!/bin/sh\nexit 1\n");
+        std::fs::write(workspace.join("a.txt"), "b\n").unwrap();
+        stage(workspace, &files).unwrap();
+        commit(workspace, &files, "Change a", "1", false).unwrap();
+        let head = repo.head().unwrap().peel_to_commit().unwrap();
+        assert_eq!(head.summary().unwrap(), Some("Change a"));
+    }
+
+    #[test]
+    fn test_strip_code_fence() {
+        assert_eq!(strip_code_fence("```text\nFix it\n```"), "Fix it");
+        assert_eq!(strip_code_fence("Fix it"), "Fix it");
+    }
+}
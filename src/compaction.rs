@@ -0,0 +1,331 @@
+use std::collections::HashMap;
+
+use async_openai::types::{
+    ChatCompletionRequestAssistantMessageContent,
+    ChatCompletionRequestAssistantMessageContentPart,
+    ChatCompletionRequestMessage,
+    ChatCompletionRequestSystemMessage,
+    ChatCompletionRequestSystemMessageContent,
+    ChatCompletionRequestSystemMessageContentPart,
+    ChatCompletionRequestToolMessageContent,
+    ChatCompletionRequestToolMessageContentPart,
+    ChatCompletionRequestUserMessage,
+    ChatCompletionRequestUserMessageContent,
+    ChatCompletionRequestUserMessageContentPart,
+};
+
+use crate::{
+    llm_provider::LLMProvider,
+    prompts,
+};
+
+/// Tool results smaller than this are cheap enough to keep around verbatim.
+const ELIDE_MIN_BYTES: usize = 1024;
+
+const SUMMARY_TAG: &str = "<conversation_summary>";
+
+/// How much of each tool result to include in the transcript we send off for summarization.
+const TRANSCRIPT_TOOL_RESULT_BYTES: usize = 2000;
+
+#[derive(Debug, Clone)]
+pub struct CompactionConfig {
+    /// Size of the model's context window, in tokens.
+    pub context_window: u32,
+    /// Fraction of the context window that, once used, triggers an automatic compaction.
+    pub threshold: f64,
+    /// Number of most recent user turns that are never summarized.
+    pub keep_recent_turns: usize,
+}
+
+impl CompactionConfig {
+    pub fn should_compact(&self, context_tokens: u32) -> bool {
+        context_tokens as f64 >= self.context_window as f64 * self.threshold
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct CompactionOutcome {
+    pub summarized_turns: usize,
+    pub elided_tool_results: usize,
+    pub tokens_before: u32,
+    pub tokens_after: u32,
+}
+
+/// Rough token count for `messages`, for when the provider hasn't told us the real one.
+pub fn estimate_tokens(messages: &[ChatCompletionRequestMessage]) -> u32 {
+    let bytes: usize = messages
+        .iter()
+        .map(|message| {
+            let tool_call_bytes = match message {
+                ChatCompletionRequestMessage::Assistant(assistant) => assistant
+                    .tool_calls
+                    .iter()
+                    .flatten()
+                    .map(|tool_call| tool_call.function.name.len() + tool_call.function.arguments.len())
+                    .sum(),
+                _ => 0,
+            };
+            message_text(message).len() + tool_call_bytes
+        })
+        .sum();
+    (bytes / 4) as u32
+}
+
+/// Compacts `messages` in place, leaving the first `preamble_len` messages (system prompt, project layout, etc.) and
+/// the current turn untouched.
+///
+/// `turns` are the indices of the prompts the user typed, each of which starts a turn. Other user messages, like
+/// instructions or notes the agent adds along the way, belong to the turn they're in.
+///
+/// Bulky tool results outside the current turn are replaced with stubs, and all but the most recent
+/// `keep_recent_turns` turns are replaced by a single summary produced with a side LLM call. Returns `None` if there
+/// was nothing to compact.
+pub async fn compact(
+    llm_provider: &LLMProvider,
+    messages: &mut Vec<ChatCompletionRequestMessage>,
+    preamble_len: usize,
+    turns: &[usize],
+    config: &CompactionConfig,
+) -> anyhow::Result<Option<CompactionOutcome>> {
+    let tokens_before = estimate_tokens(messages);
+    let Some(&current_turn) = turns.last() else {
+        return Ok(None);
+    };
+
+    let elided_tool_results = elide_tool_results(messages, preamble_len, current_turn);
+
+    let kept_turns = config.keep_recent_turns.max(1);
+    let mut summarized_turns = 0;
+    if turns.len() > kept_turns {
+        let summary_end = turns[turns.len() - kept_turns];
+        match summarize(llm_provider, &messages[preamble_len..summary_end]).await {
+            Ok(summary) => {
+                let summary_message = ChatCompletionRequestMessage::User(ChatCompletionRequestUserMessage {
+                    content: ChatCompletionRequestUserMessageContent::Text(format!(
+                        "{SUMMARY_TAG}\nEarlier turns of this conversation were compacted into the following \
+                         summary.\n\n{}\n</conversation_summary>\n",
+                        summary.trim()
+                    )),
+                    name: None,
+                });
+                messages.splice(preamble_len..summary_end, [summary_message]);
+                summarized_turns = turns.len() - kept_turns;
+            }
+            Err(e) => {
+                tracing::warn!("Failed to summarize conversation, only eliding tool results: {e:?}");
+            }
+        }
+    }
+
+    if summarized_turns == 0 && elided_tool_results == 0 {
+        return Ok(None);
+    }
+    let outcome = CompactionOutcome {
+        summarized_turns,
+        elided_tool_results,
+        tokens_before,
+        tokens_after: estimate_tokens(messages),
+    };
+    tracing::info!("Compacted conversation: {outcome:?}");
+    Ok(Some(outcome))
+}
+
+/// Replaces large tool results in `messages[start..end]` with a short stub. Returns how many were replaced.
+fn elide_tool_results(messages: &mut [ChatCompletionRequestMessage], start: usize, end: usize) -> usize {
+    let mut tool_names = HashMap::new();
+    for message in &messages[..end] {
+        if let ChatCompletionRequestMessage::Assistant(assistant) = message {
+            for tool_call in assistant.tool_calls.iter().flatten() {
+                tool_names.insert(tool_call.id.clone(), tool_call.function.name.clone());
+            }
+        }
+    }
+
+    let mut elided = 0;
+    for message in &mut messages[start..end] {
+        let ChatCompletionRequestMessage::Tool(tool_message) = message else {
+            continue;
+        };
+        let text = tool_content_text(&tool_message.content);
+        if text.len() < ELIDE_MIN_BYTES {
+            continue;
+        }
+        let name = tool_names
+            .get(&tool_message.tool_call_id)
+            .map(String::as_str)
+            .unwrap_or("tool");
+        tool_message.content =
+            ChatCompletionRequestToolMessageContent::Text(format!("{name} output elided, re-read if needed"));
+        elided += 1;
+    }
+    elided
+}
+
+async fn summarize(llm_provider: &LLMProvider, messages: &[ChatCompletionRequestMessage]) -> anyhow::Result<String> {
+    let request = vec![
+        ChatCompletionRequestMessage::System(ChatCompletionRequestSystemMessage {
+            content: ChatCompletionRequestSystemMessageContent::Text(prompts::COMPACTION_PROMPT.to_string()),
+            name: None,
+        }),
+        ChatCompletionRequestMessage::User(ChatCompletionRequestUserMessage {
+            content: ChatCompletionRequestUserMessageContent::Text(render_transcript(messages)),
+            name: None,
+        }),
+    ];
+    llm_provider.complete(request).await
+}
+
+fn render_transcript(messages: &[ChatCompletionRequestMessage]) -> String {
+    let mut transcript = String::from("<transcript>\n");
+    for message in messages {
+        match message {
+            ChatCompletionRequestMessage::User(_) => {
+                transcript.push_str(&format!("User: {}\n\n", message_text(message)));
+            }
+            ChatCompletionRequestMessage::Assistant(assistant) => {
+                let text = message_text(message);
+                if !text.is_empty() {
+                    transcript.push_str(&format!("Assistant: {text}\n\n"));
+                }
+                for tool_call in assistant.tool_calls.iter().flatten() {
+                    transcript.push_str(&format!(
+                        "Assistant called {}({})\n\n",
+                        tool_call.function.name, tool_call.function.arguments
+                    ));
+                }
+            }
+            ChatCompletionRequestMessage::Tool(_) => {
+                let mut text = message_text(message);
+                if text.len() > TRANSCRIPT_TOOL_RESULT_BYTES {
+                    text.truncate(text.floor_char_boundary(TRANSCRIPT_TOOL_RESULT_BYTES));
+                    text.push('…');
+                }
+                transcript.push_str(&format!("Tool result: {text}\n\n"));
+            }
+            _ => {
+                transcript.push_str(&format!("{}\n\n", message_text(message)));
+            }
+        }
+    }
+    transcript.push_str("</transcript>\n");
+    transcript
+}
+
+pub fn message_text(message: &ChatCompletionRequestMessage) -> String {
+    match message {
+        ChatCompletionRequestMessage::System(system) => match &system.content {
+            ChatCompletionRequestSystemMessageContent::Text(text) => text.clone(),
+            ChatCompletionRequestSystemMessageContent::Array(parts) => parts
+                .iter()
+                .map(|ChatCompletionRequestSystemMessageContentPart::Text(part)| part.text.as_str())
+                .collect(),
+        },
+        ChatCompletionRequestMessage::User(user) => match &user.content {
+            ChatCompletionRequestUserMessageContent::Text(text) => text.clone(),
+            ChatCompletionRequestUserMessageContent::Array(parts) => parts
+                .iter()
+                .filter_map(|part| match part {
+                    ChatCompletionRequestUserMessageContentPart::Text(part) => Some(part.text.as_str()),
+                    _ => None,
+                })
+                .collect(),
+        },
+        ChatCompletionRequestMessage::Assistant(assistant) => match &assistant.content {
+            Some(ChatCompletionRequestAssistantMessageContent::Text(text)) => text.clone(),
+            Some(ChatCompletionRequestAssistantMessageContent::Array(parts)) => parts
+                .iter()
+                .filter_map(|part| match part {
+                    ChatCompletionRequestAssistantMessageContentPart::Text(part) => Some(part.text.as_str()),
+                    _ => None,
+                })
+                .collect(),
+            None => String::new(),
+        },
+        ChatCompletionRequestMessage::Tool(tool) => tool_content_text(&tool.content),
+        _ => String::new(),
+    }
+}
+
+fn tool_content_text(content: &ChatCompletionRequestToolMessageContent) -> String {
+    match content {
+        ChatCompletionRequestToolMessageContent::Text(text) => text.clone(),
+        ChatCompletionRequestToolMessageContent::Array(parts) => parts
+            .iter()
+            .map(|ChatCompletionRequestToolMessageContentPart::Text(part)| part.text.as_str())
+            .collect(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        llm_provider::ModelConfig,
+        test_messages::{
+            tool_call,
+            tool_result,
+            user,
+        },
+    };
+
+    #[tokio::test]
+    async fn test_compact_keeps_current_turn() {
+        let big = "x".repeat(ELIDE_MIN_BYTES);
+        let mut messages = vec![
+            user("layout"),
+            user("a"),
+            tool_call("1", "read_file"),
+            tool_result("1", &big),
+            user("b"),
+            tool_call("2", "read_file"),
+            tool_result("2", &big),
+            // Added by the agent after the tool result, not typed by the user.
+            user("<instructions>"),
+        ];
+        let llm_provider = LLMProvider::new(
+            ModelConfig {
+                model: "test".to_string(),
+                api_key: String::new(),
+                base_url: "http://127.0.0.1:9".to_string(),
+                reasoning_effort: None,
+                send_reasoning: false,
+                max_tokens: None,
+                tools: vec![],
+            },
+            None,
+        )
+        .unwrap();
+        let config = CompactionConfig {
+            context_window: 1,
+            threshold: 0.8,
+            keep_recent_turns: 2,
+        };
+        let outcome = compact(&llm_provider, &mut messages, 1, &[1, 4], &config)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(outcome.summarized_turns, 0);
+        assert_eq!(outcome.elided_tool_results, 1);
+        assert_eq!(message_text(&messages[3]), "read_file output elided, re-read if needed");
+        assert_eq!(message_text(&messages[6]), big);
+    }
+
+    #[test]
+    fn test_elide_tool_results() {
+        let big = "x".repeat(ELIDE_MIN_BYTES);
+        let mut messages = vec![
+            user("a"),
+            tool_call("1", "read_file"),
+            tool_result("1", &big),
+            tool_call("2", "list_dir"),
+            tool_result("2", "small"),
+            user("b"),
+            tool_call("3", "read_file"),
+            tool_result("3", &big),
+        ];
+        assert_eq!(elide_tool_results(&mut messages, 0, 5), 1);
+        assert_eq!(message_text(&messages[2]), "read_file output elided, re-read if needed");
+        assert_eq!(message_text(&messages[4]), "small");
+        assert_eq!(message_text(&messages[7]), big);
+    }
+}
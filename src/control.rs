@@ -1,3 +1,62 @@
+use std::path::PathBuf;
+
+#[derive(Debug, Clone, PartialEq)]
 pub enum ControlMessage {
     UserMessage(String),
+    /// Compact the conversation history now, regardless of how full the context window is.
+    Compact,
+    /// Switch to another model, or to a config profile of that name, for the rest of the session. Without a name, just
+    /// report the current model and the available profiles.
+    SetModel {
+        name: Option<String>,
+        reasoning_effort: Option<String>,
+    },
+    /// Drop the whole conversation and start over.
+    Clear,
+    /// Save the conversation history, to a generated file name if no path is given.
+    Save {
+        path: Option<PathBuf>,
+    },
+    /// List the tools the model can call.
+    ListTools,
+    /// Show the tokens used so far.
+    ShowCost,
+    /// Show the messages the conversation starts with: the system prompt, the environment, the project layout and
+    /// the project's instructions.
+    ShowContext,
+    /// Show some text in the transcript without sending it to the model.
+    Notice(String),
+    /// Go back to the prompt at `ui_index` in the transcript and send `text` in its place. With `fork`, the
+    /// conversation as it was is kept as a branch; without, everything from the prompt on is dropped.
+    Rewind {
+        ui_index: usize,
+        text: String,
+        fork: bool,
+    },
+    /// List the branches of the conversation.
+    ListBranches,
+    /// Switch to another branch of the conversation.
+    SwitchBranch {
+        number: usize,
+    },
+    /// Revert the file edits of the last turn that made any.
+    Undo,
+    /// List the checkpoints of file edits.
+    ListCheckpoints,
+    /// Put the files back the way they were before checkpoint `number`, and with `truncate`, drop the conversation
+    /// from that turn on too.
+    Restore {
+        number: usize,
+        truncate: bool,
+    },
+    /// Have the model draft a commit message for the files the agent edited, to be confirmed in the UI.
+    /// Without `run_hooks`, the repository's commit hooks are skipped.
+    DraftCommit {
+        run_hooks: bool,
+    },
+    /// Stage the files the agent edited and commit them with `message`, once the draft was confirmed.
+    Commit {
+        message: String,
+        run_hooks: bool,
+    },
 }
@@ -0,0 +1,320 @@
+//! Diffs rendered for the terminal: removed and added lines on red and green backgrounds with their line numbers, the
+//! words that changed within a line marked more strongly, and the code syntax highlighted.
+//!
+//! Both edit tool results and ```diff blocks in markdown are unified diffs, so that's what gets rendered.
+
+use std::ops::Range;
+
+use ratatui::{
+    style::{
+        Color,
+        Style,
+        Stylize,
+    },
+    text::{
+        Line,
+        Span,
+    },
+};
+use similar::{
+    ChangeTag,
+    TextDiff,
+};
+
+use crate::syntax_highlight::SyntaxHighlighter;
+
+const REMOVED_BACKGROUND: Color = Color::Rgb(64, 24, 24);
+const REMOVED_WORD_BACKGROUND: Color = Color::Rgb(128, 40, 40);
+const ADDED_BACKGROUND: Color = Color::Rgb(24, 56, 24);
+const ADDED_WORD_BACKGROUND: Color = Color::Rgb(40, 112, 40);
+
+/// How similar two versions of a line have to be for the words that changed to be marked. Below that, marking them
+/// would just mark most of the line.
+const MIN_WORD_DIFF_RATIO: f32 = 0.5;
+
+/// A unified diff of a file at `path` from `before` to `after`, with three lines of context.
+pub fn unified_diff(path: &str, before: &str, after: &str) -> String {
+    TextDiff::from_lines(before, after)
+        .unified_diff()
+        .context_radius(3)
+        .header(path, path)
+        .to_string()
+}
+
+/// A removed or added line waiting for the rest of its block, so removed and added lines can be paired up.
+struct Change<'a> {
+    number: Option<usize>,
+    text: &'a str,
+}
+
+/// Renders a unified diff. The language to highlight is that of `path`, or else of the file in the diff's `+++` header.
+/// Diffs without `@@` hunk headers, as models tend to write them, render without line numbers.
+pub fn render_diff(diff: &str, path: Option<&str>) -> Vec<Line<'static>> {
+    let mut language = path.map(str::to_string);
+    let mut lines = vec![];
+    let (mut old_number, mut new_number) = (None, None);
+    let (mut removed, mut added) = (vec![], vec![]);
+    let mut in_hunk = false;
+    for line in diff.lines() {
+        match line.chars().next() {
+            Some('-') if in_hunk || !line.starts_with("--- ") => {
+                // A removed line after added ones starts a new block.
+                if !added.is_empty() {
+                    flush(&mut lines, &mut removed, &mut added, language.as_deref());
+                }
+                removed.push(Change {
+                    number: advance(&mut old_number),
+                    text: &line[1..],
+                });
+                continue;
+            }
+            Some('+') if in_hunk || !line.starts_with("+++ ") => {
+                added.push(Change {
+                    number: advance(&mut new_number),
+                    text: &line[1..],
+                });
+                continue;
+            }
+            _ => {}
+        }
+        flush(&mut lines, &mut removed, &mut added, language.as_deref());
+
+        if let Some((old, new)) = hunk_start(line) {
+            (old_number, new_number) = (Some(old), Some(new));
+            in_hunk = true;
+            lines.push(Line::from(line.to_string()).cyan());
+        } else if let Some(text) = line.strip_prefix(' ').or((in_hunk && line.is_empty()).then_some("")) {
+            let numbers = (advance(&mut old_number), advance(&mut new_number));
+            lines.push(diff_line(numbers, ' ', text, language.as_deref(), None));
+        } else if line.starts_with("--- ") || line.starts_with("+++ ") {
+            if let Some(file) = line.strip_prefix("+++ ")
+                && language.is_none()
+            {
+                language = header_path(file);
+            }
+            lines.push(Line::from(line.to_string()).bold());
+        } else {
+            // `diff --git`, `index` and `\ No newline at end of file` lines, or text around the diff.
+            in_hunk &= line.starts_with('\\');
+            lines.push(Line::from(line.to_string()).dark_gray());
+        }
+    }
+    flush(&mut lines, &mut removed, &mut added, language.as_deref());
+    lines
+}
+
+/// Renders a block of removed lines followed by the added lines that replaced them. Pairs of lines that are much alike
+/// get the words that changed between them marked.
+fn flush(
+    lines: &mut Vec<Line<'static>>,
+    removed: &mut Vec<Change<'_>>,
+    added: &mut Vec<Change<'_>>,
+    language: Option<&str>,
+) {
+    let words = removed
+        .iter()
+        .zip(added.iter())
+        .map(|(old, new)| changed_words(old.text, new.text))
+        .collect::<Vec<_>>();
+    for (index, change) in removed.iter().enumerate() {
+        let changed = words.get(index).map(|(old, _)| old.as_slice()).unwrap_or_default();
+        let background = (REMOVED_BACKGROUND, REMOVED_WORD_BACKGROUND, changed);
+        lines.push(diff_line(
+            (change.number, None),
+            '-',
+            change.text,
+            language,
+            Some(background),
+        ));
+    }
+    for (index, change) in added.iter().enumerate() {
+        let changed = words.get(index).map(|(_, new)| new.as_slice()).unwrap_or_default();
+        let background = (ADDED_BACKGROUND, ADDED_WORD_BACKGROUND, changed);
+        lines.push(diff_line(
+            (None, change.number),
+            '+',
+            change.text,
+            language,
+            Some(background),
+        ));
+    }
+    removed.clear();
+    added.clear();
+}
+
+/// One line of the diff with its old and new line numbers. Changed lines have a background, with the byte ranges of
+/// the words that changed on a stronger one.
+fn diff_line(
+    (old, new): (Option<usize>, Option<usize>),
+    sign: char,
+    text: &str,
+    language: Option<&str>,
+    background: Option<(Color, Color, &[Range<usize>])>,
+) -> Line<'static> {
+    let number = |number: Option<usize>| number.map(|number| number.to_string()).unwrap_or_default();
+    let mut line = Line::from(vec![
+        format!("{:>4} {:>4} ", number(old), number(new)).dark_gray(),
+        Span::from(sign.to_string()),
+    ]);
+    let spans = highlight_line(text, language);
+    match background {
+        Some((background, word_background, words)) => {
+            line.spans
+                .extend(with_background(spans, background, words, word_background));
+            line.style = Style::new().bg(background);
+        }
+        None => line.spans.extend(spans),
+    }
+    line
+}
+
+fn highlight_line(text: &str, language: Option<&str>) -> Vec<Span<'static>> {
+    if language.is_none() {
+        return vec![Span::from(text.to_string())];
+    }
+    SyntaxHighlighter::shared()
+        .highlight_code(text, language)
+        .lines
+        .into_iter()
+        .flat_map(|line| line.spans)
+        .map(|span| Span::styled(span.content.trim_end_matches('\n').to_string(), span.style))
+        .collect()
+}
+
+/// Puts `spans` on `background`, splitting them where the `words` ranges start and end to put those on
+/// `word_background`.
+fn with_background(
+    spans: Vec<Span<'static>>,
+    background: Color,
+    words: &[Range<usize>],
+    word_background: Color,
+) -> Vec<Span<'static>> {
+    let mut result = vec![];
+    let mut offset = 0;
+    for span in spans {
+        let content = span.content.as_ref();
+        let end = offset + content.len();
+        let mut cuts = vec![offset, end];
+        cuts.extend(
+            words
+                .iter()
+                .flat_map(|word| [word.start, word.end])
+                .filter(|cut| (offset + 1..end).contains(cut)),
+        );
+        cuts.sort_unstable();
+        cuts.dedup();
+        for cut in cuts.windows(2) {
+            let changed = words.iter().any(|word| word.start <= cut[0] && cut[1] <= word.end);
+            let style = span.style.bg(if changed { word_background } else { background });
+            result.push(Span::styled(
+                content[cut[0] - offset..cut[1] - offset].to_string(),
+                style,
+            ));
+        }
+        offset = end;
+    }
+    result
+}
+
+/// The byte ranges of the words that differ between two versions of a line, in the old one and in the new one. Empty
+/// when the lines have too little in common.
+fn changed_words(old: &str, new: &str) -> (Vec<Range<usize>>, Vec<Range<usize>>) {
+    let diff = TextDiff::from_unicode_words(old, new);
+    let (mut old_words, mut new_words) = (vec![], vec![]);
+    if diff.ratio() < MIN_WORD_DIFF_RATIO {
+        return (old_words, new_words);
+    }
+    let (mut old_offset, mut new_offset) = (0, 0);
+    for change in diff.iter_all_changes() {
+        let len = change.value().len();
+        match change.tag() {
+            ChangeTag::Equal => {
+                old_offset += len;
+                new_offset += len;
+            }
+            ChangeTag::Delete => {
+                old_words.push(old_offset..old_offset + len);
+                old_offset += len;
+            }
+            ChangeTag::Insert => {
+                new_words.push(new_offset..new_offset + len);
+                new_offset += len;
+            }
+        }
+    }
+    (old_words, new_words)
+}
+
+/// The first old and new line numbers of a `@@ -1,4 +1,5 @@` hunk header.
+fn hunk_start(line: &str) -> Option<(usize, usize)> {
+    let mut ranges = line.strip_prefix("@@ ")?.split_whitespace();
+    let mut start = |sign| -> Option<usize> { ranges.next()?.strip_prefix(sign)?.split(',').next()?.parse().ok() };
+    Some((start('-')?, start('+')?))
+}
+
+/// The path in a `+++` header, without git's `b/` prefix or a timestamp.
+fn header_path(header: &str) -> Option<String> {
+    let path = header.split('\t').next()?.trim();
+    let path = path.strip_prefix("b/").unwrap_or(path);
+    (path != "/dev/null").then(|| path.to_string())
+}
+
+/// Returns the line number and moves on to the next one.
+fn advance(number: &mut Option<usize>) -> Option<usize> {
+    let current = *number;
+    *number = current.map(|number| number + 1);
+    current
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_diff() {
+        let before = "fn main() {\n    let x = 1;\n    println!(\"{x}\");\n}\n";
+        let after = "fn main() {\n    let x = 2;\n    println!(\"{x}\");\n    done();\n}\n";
+        let diff = unified_diff("src/main.rs", before, after);
+        let lines = render_diff(&diff, None);
+        let text = lines.iter().map(|line| line.to_string()).collect::<Vec<_>>();
+        assert_eq!(
+            text,
+            [
+                "--- src/main.rs",
+                "+++ src/main.rs",
+                "@@ -1,4 +1,5 @@",
+                "   1    1  fn main() {",
+                "   2      -    let x = 1;",
+                "        2 +    let x = 2;",
+                "   3    3      println!(\"{x}\");",
+                "        4 +    done();",
+                "   4    5  }",
+            ]
+        );
+
+        // Only the number that changed is marked, on the stronger background.
+        let marked = |line: &Line| {
+            line.spans
+                .iter()
+                .filter(|span| {
+                    span.style.bg == Some(ADDED_WORD_BACKGROUND) || span.style.bg == Some(REMOVED_WORD_BACKGROUND)
+                })
+                .map(|span| span.content.to_string())
+                .collect::<String>()
+        };
+        assert_eq!(marked(&lines[4]), "1");
+        assert_eq!(marked(&lines[5]), "2");
+        assert_eq!(marked(&lines[7]), "");
+        assert_eq!(lines[7].style.bg, Some(ADDED_BACKGROUND));
+    }
+
+    #[test]
+    fn test_render_diff_without_hunks() {
+        let lines = render_diff("-old line\n+new line\n context", None);
+        let text = lines.iter().map(|line| line.to_string()).collect::<Vec<_>>();
+        assert_eq!(
+            text,
+            ["          -old line", "          +new line", "           context"]
+        );
+    }
+}
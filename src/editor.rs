@@ -0,0 +1,484 @@
+//! The text editor behind the input box: multi-line editing, emacs-style word motions and kill/yank, and a prompt
+//! history that's kept on disk between sessions.
+//!
+//! The cursor moves by grapheme cluster, so `é` written as `e` plus a combining accent, or an emoji made of several
+//! code points, is a single step. Columns are counted in terminal cells, where CJK characters and most emoji take two.
+
+use std::{
+    io::Write,
+    ops::Range,
+    path::PathBuf,
+};
+
+use unicode_segmentation::UnicodeSegmentation;
+use unicode_width::UnicodeWidthStr;
+
+/// Tabs have no width of their own in a terminal, so pasted ones become this many spaces.
+const TAB_WIDTH: usize = 4;
+
+/// How many prompts the history file keeps.
+const MAX_HISTORY: usize = 1000;
+
+#[derive(Debug, Default)]
+pub struct InputEditor {
+    text: String,
+    /// Byte offset into `text`, always on a grapheme cluster boundary.
+    cursor: usize,
+    /// The text last killed with Ctrl-k, Ctrl-u, Ctrl-w or Alt-d, for Ctrl-y.
+    kill_buffer: String,
+    history: History,
+}
+
+impl InputEditor {
+    pub fn new(history: History) -> Self {
+        Self {
+            history,
+            ..Default::default()
+        }
+    }
+
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+
+    pub fn cursor(&self) -> usize {
+        self.cursor
+    }
+
+    /// Replaces the whole text, leaving the cursor at the end.
+    pub fn set_text(&mut self, text: String) {
+        self.text = text;
+        self.cursor = self.text.len();
+    }
+
+    /// Replaces `range` of the text, leaving the cursor after the replacement.
+    pub fn replace_range(&mut self, range: Range<usize>, replacement: &str) {
+        let start = range.start;
+        self.text.replace_range(range, replacement);
+        self.cursor = start + replacement.len();
+    }
+
+    pub fn clear(&mut self) {
+        self.text.clear();
+        self.cursor = 0;
+    }
+
+    /// Takes the text out of the editor and remembers it in the history.
+    pub fn submit(&mut self) -> String {
+        let text = std::mem::take(&mut self.text);
+        self.cursor = 0;
+        self.history.push(&text);
+        text
+    }
+
+    pub fn insert_char(&mut self, ch: char) {
+        self.text.insert(self.cursor, ch);
+        self.cursor += ch.len_utf8();
+    }
+
+    /// Inserts pasted text, with line endings normalized to `\n` and tabs expanded.
+    pub fn insert_str(&mut self, text: &str) {
+        let text = text
+            .replace("\r\n", "\n")
+            .replace('\r', "\n")
+            .replace('\t', &" ".repeat(TAB_WIDTH));
+        self.text.insert_str(self.cursor, &text);
+        self.cursor += text.len();
+    }
+
+    pub fn delete_backward(&mut self) {
+        let start = self.prev_boundary();
+        self.text.replace_range(start..self.cursor, "");
+        self.cursor = start;
+    }
+
+    pub fn delete_forward(&mut self) {
+        let end = self.next_boundary();
+        self.text.replace_range(self.cursor..end, "");
+    }
+
+    pub fn move_left(&mut self) {
+        self.cursor = self.prev_boundary();
+    }
+
+    pub fn move_right(&mut self) {
+        self.cursor = self.next_boundary();
+    }
+
+    pub fn move_word_left(&mut self) {
+        self.cursor = self.word_start();
+    }
+
+    pub fn move_word_right(&mut self) {
+        self.cursor = self.word_end();
+    }
+
+    pub fn move_line_start(&mut self) {
+        self.cursor = self.line_start();
+    }
+
+    pub fn move_line_end(&mut self) {
+        self.cursor = self.line_end();
+    }
+
+    /// Moves to the line above, or to the previous prompt in the history on the first line.
+    pub fn move_up(&mut self) {
+        let line_start = self.line_start();
+        if line_start == 0 {
+            self.history_previous();
+            return;
+        }
+        let column = self.text[line_start..self.cursor].width();
+        let previous_start = self.text[..line_start - 1].rfind('\n').map_or(0, |i| i + 1);
+        self.cursor = self.offset_in_line(previous_start, column);
+    }
+
+    /// Moves to the line below, or to the next prompt in the history on the last line.
+    pub fn move_down(&mut self) {
+        let line_end = self.line_end();
+        if line_end == self.text.len() {
+            self.history_next();
+            return;
+        }
+        let column = self.text[self.line_start()..self.cursor].width();
+        self.cursor = self.offset_in_line(line_end + 1, column);
+    }
+
+    /// Kills to the end of the line, or the line break itself when already there.
+    pub fn kill_to_line_end(&mut self) {
+        let end = match self.line_end() {
+            end if end == self.cursor => self.next_boundary(),
+            end => end,
+        };
+        self.kill(self.cursor..end);
+    }
+
+    pub fn kill_to_line_start(&mut self) {
+        self.kill(self.line_start()..self.cursor);
+    }
+
+    pub fn kill_word_backward(&mut self) {
+        self.kill(self.word_start()..self.cursor);
+    }
+
+    pub fn kill_word_forward(&mut self) {
+        self.kill(self.cursor..self.word_end());
+    }
+
+    pub fn yank(&mut self) {
+        let text = self.kill_buffer.clone();
+        self.text.insert_str(self.cursor, &text);
+        self.cursor += text.len();
+    }
+
+    fn kill(&mut self, range: Range<usize>) {
+        if range.is_empty() {
+            return;
+        }
+        self.kill_buffer = self.text[range.clone()].to_string();
+        self.cursor = range.start;
+        self.text.replace_range(range, "");
+    }
+
+    fn history_previous(&mut self) {
+        if let Some(entry) = self.history.previous(&self.text) {
+            self.set_text(entry);
+        }
+    }
+
+    fn history_next(&mut self) {
+        if let Some(entry) = self.history.next() {
+            self.set_text(entry);
+        }
+    }
+
+    fn prev_boundary(&self) -> usize {
+        self.text[..self.cursor]
+            .grapheme_indices(true)
+            .next_back()
+            .map_or(0, |(i, _)| i)
+    }
+
+    fn next_boundary(&self) -> usize {
+        self.text[self.cursor..]
+            .graphemes(true)
+            .next()
+            .map_or(self.cursor, |grapheme| self.cursor + grapheme.len())
+    }
+
+    fn line_start(&self) -> usize {
+        self.text[..self.cursor].rfind('\n').map_or(0, |i| i + 1)
+    }
+
+    fn line_end(&self) -> usize {
+        self.text[self.cursor..]
+            .find('\n')
+            .map_or(self.text.len(), |i| self.cursor + i)
+    }
+
+    /// The offset of the first grapheme at or past `column` cells into the line starting at `line_start`, or the end
+    /// of that line if it's shorter.
+    fn offset_in_line(&self, line_start: usize, column: usize) -> usize {
+        let line = self.text[line_start..].split('\n').next().unwrap_or_default();
+        let mut width = 0;
+        for (i, grapheme) in line.grapheme_indices(true) {
+            if width >= column {
+                return line_start + i;
+            }
+            width += grapheme.width();
+        }
+        line_start + line.len()
+    }
+
+    /// The text wrapped into rows of at most `width` cells, and the row and column of the cursor. A cursor right at
+    /// the end of a full row goes to the start of the next one, where the next character would be typed.
+    pub fn layout(&self, width: usize) -> (Vec<String>, (usize, usize)) {
+        let width = width.max(1);
+        let mut rows = vec![];
+        let mut cursor = (0, 0);
+        let mut line_start = 0;
+        for line in self.text.split('\n') {
+            let mut row = String::new();
+            let mut row_width = 0;
+            for (i, grapheme) in line.grapheme_indices(true) {
+                let grapheme_width = grapheme.width();
+                if row_width + grapheme_width > width && !row.is_empty() {
+                    rows.push(std::mem::take(&mut row));
+                    row_width = 0;
+                }
+                if line_start + i == self.cursor {
+                    cursor = (rows.len(), row_width);
+                }
+                row.push_str(grapheme);
+                row_width += grapheme_width;
+            }
+            if line_start + line.len() == self.cursor {
+                if row_width >= width {
+                    rows.push(std::mem::take(&mut row));
+                    row_width = 0;
+                }
+                cursor = (rows.len(), row_width);
+            }
+            rows.push(row);
+            line_start += line.len() + 1;
+        }
+        (rows, cursor)
+    }
+
+    /// The start of the word before the cursor, skipping any non-word chars in between.
+    fn word_start(&self) -> usize {
+        let before = &self.text[..self.cursor];
+        let end_of_gap = before.trim_end_matches(|ch| !is_word_char(ch)).len();
+        before[..end_of_gap].trim_end_matches(is_word_char).len()
+    }
+
+    /// The end of the word after the cursor, skipping any non-word chars in between.
+    fn word_end(&self) -> usize {
+        let after = &self.text[self.cursor..];
+        let word = after.trim_start_matches(|ch| !is_word_char(ch));
+        let rest = word.trim_start_matches(is_word_char);
+        self.text.len() - rest.len()
+    }
+}
+
+fn is_word_char(ch: char) -> bool {
+    ch.is_alphanumeric() || ch == '_'
+}
+
+/// Submitted prompts, oldest first, browsed with Up and Down.
+#[derive(Debug, Default)]
+pub struct History {
+    entries: Vec<String>,
+    /// The entry being shown while browsing, `None` while writing a new prompt.
+    position: Option<usize>,
+    /// The new prompt as it was when browsing started, restored when browsing past the newest entry.
+    draft: String,
+    /// Where entries are appended, one JSON string per line so prompts can span lines.
+    path: Option<PathBuf>,
+}
+
+impl History {
+    /// Loads the history kept at `path`, if there is one yet.
+    pub fn load(path: PathBuf) -> anyhow::Result<Self> {
+        let mut entries = match std::fs::read_to_string(&path) {
+            Ok(text) => text
+                .lines()
+                .filter_map(|line| serde_json::from_str::<String>(line).ok())
+                .collect::<Vec<_>>(),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => vec![],
+            Err(e) => return Err(e.into()),
+        };
+        if entries.len() > MAX_HISTORY {
+            entries.drain(..entries.len() - MAX_HISTORY);
+            // Rewrite the file so it doesn't grow forever.
+            let text = entries
+                .iter()
+                .map(|entry| serde_json::to_string(entry).map(|line| line + "\n"))
+                .collect::<Result<String, _>>()?;
+            std::fs::write(&path, text)?;
+        }
+        Ok(Self {
+            entries,
+            path: Some(path),
+            ..Default::default()
+        })
+    }
+
+    fn push(&mut self, entry: &str) {
+        self.position = None;
+        self.draft.clear();
+        if entry.trim().is_empty() || self.entries.last().is_some_and(|last| last == entry) {
+            return;
+        }
+        self.entries.push(entry.to_string());
+        if let Some(path) = &self.path
+            && let Err(e) = append_line(path, entry)
+        {
+            tracing::warn!("Failed to save the prompt history to {}: {e}", path.display());
+        }
+    }
+
+    fn previous(&mut self, current: &str) -> Option<String> {
+        let position = match self.position {
+            None if self.entries.is_empty() => return None,
+            None => {
+                self.draft = current.to_string();
+                self.entries.len() - 1
+            }
+            Some(0) => return None,
+            Some(position) => position - 1,
+        };
+        self.position = Some(position);
+        Some(self.entries[position].clone())
+    }
+
+    fn next(&mut self) -> Option<String> {
+        let position = self.position? + 1;
+        if position == self.entries.len() {
+            self.position = None;
+            return Some(std::mem::take(&mut self.draft));
+        }
+        self.position = Some(position);
+        Some(self.entries[position].clone())
+    }
+}
+
+fn append_line(path: &PathBuf, entry: &str) -> anyhow::Result<()> {
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    let mut file = std::fs::OpenOptions::new().create(true).append(true).open(path)?;
+    writeln!(file, "{}", serde_json::to_string(entry)?)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn editor(text: &str) -> InputEditor {
+        let mut editor = InputEditor::default();
+        editor.set_text(text.to_string());
+        editor
+    }
+
+    #[test]
+    fn test_word_motions() {
+        let mut editor = editor("fn main() {\n    println!(\"héllo\");");
+        editor.move_word_left();
+        assert_eq!(&editor.text()[editor.cursor()..], "héllo\");");
+        editor.move_word_left();
+        editor.move_word_left();
+        assert_eq!(&editor.text()[editor.cursor()..], "main() {\n    println!(\"héllo\");");
+        editor.move_word_right();
+        assert_eq!(&editor.text()[editor.cursor()..], "() {\n    println!(\"héllo\");");
+        editor.move_down();
+        assert_eq!(&editor.text()[editor.cursor()..], "ntln!(\"héllo\");");
+        editor.move_up();
+        editor.move_line_end();
+        assert_eq!(&editor.text()[editor.cursor()..], "\n    println!(\"héllo\");");
+    }
+
+    #[test]
+    fn test_kill_and_yank() {
+        let mut editor = editor("first line\nsecond line");
+        editor.kill_word_backward();
+        assert_eq!(editor.text(), "first line\nsecond ");
+        editor.move_line_start();
+        editor.kill_to_line_end();
+        assert_eq!(editor.text(), "first line\n");
+        editor.move_up();
+        editor.move_line_start();
+        editor.yank();
+        assert_eq!(editor.text(), "second first line\n");
+        editor.kill_word_forward();
+        assert_eq!(editor.text(), "second  line\n");
+        editor.move_line_end();
+        editor.kill_to_line_end();
+        assert_eq!(editor.text(), "second  line");
+        editor.insert_str("\r\npasted\rtext");
+        assert_eq!(editor.text(), "second  line\npasted\ntext");
+    }
+
+    #[test]
+    fn test_graphemes() {
+        // An `e` with a combining accent and a family emoji joined from several code points.
+        let mut editor = editor("cafe\u{301} 👨‍👩‍👧!");
+        editor.move_left();
+        editor.move_left();
+        assert_eq!(&editor.text()[editor.cursor()..], "👨‍👩‍👧!");
+        editor.delete_forward();
+        assert_eq!(editor.text(), "cafe\u{301} !");
+        editor.move_left();
+        editor.delete_backward();
+        assert_eq!(editor.text(), "caf !");
+    }
+
+    #[test]
+    fn test_layout() {
+        // Wide characters take two cells and don't get split across rows.
+        let mut editor = editor("a中b😀c\n日本");
+        assert_eq!(
+            editor.layout(5),
+            (vec!["a中b".to_string(), "😀c".to_string(), "日本".to_string()], (2, 4))
+        );
+        assert_eq!(editor.layout(3).0, vec!["a中", "b😀", "c", "日", "本"]);
+
+        // Up keeps the column in cells, not chars.
+        editor.move_up();
+        assert_eq!(&editor.text()[editor.cursor()..], "😀c\n日本");
+        assert_eq!(editor.layout(5).1, (1, 0));
+        editor.move_line_start();
+        editor.move_right();
+        editor.move_right();
+        assert_eq!(editor.layout(4).1, (0, 3));
+
+        // A cursor after a full row goes on the next row.
+        editor.set_text("abcd\nx".to_string());
+        editor.move_up();
+        editor.move_line_end();
+        assert_eq!(
+            editor.layout(4),
+            (vec!["abcd".to_string(), String::new(), "x".to_string()], (1, 0))
+        );
+    }
+
+    #[test]
+    fn test_history() {
+        let mut editor = editor("");
+        for prompt in ["one", "two", "two", "  "] {
+            editor.set_text(prompt.to_string());
+            editor.submit();
+        }
+        editor.set_text("draft".to_string());
+        editor.move_up();
+        assert_eq!(editor.text(), "two");
+        editor.move_up();
+        assert_eq!(editor.text(), "one");
+        editor.move_up();
+        assert_eq!(editor.text(), "one");
+        editor.move_down();
+        editor.move_down();
+        assert_eq!(editor.text(), "draft");
+    }
+}
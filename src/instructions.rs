@@ -0,0 +1,193 @@
+//! Instructions for the agent kept with the project: `AGENTS.md` and `CLAUDE.md` files, and markdown files in
+//! `.agent/rules/`.
+//!
+//! The ones in the workspace, in the directories above it up to the git root, and in the user's config directory apply
+//! to the whole session and go into the preamble. The ones in subdirectories of the workspace only apply to the files
+//! there, so they're added to the conversation when the agent first touches one of those files.
+
+use std::{
+    collections::HashMap,
+    path::{
+        Path,
+        PathBuf,
+    },
+};
+
+/// The instruction files looked for in every directory, besides the rules.
+const INSTRUCTION_FILES: &[&str] = &["AGENTS.md", "CLAUDE.md"];
+
+const INSTRUCTIONS_TAG: &str = "<instructions source=";
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct InstructionFile {
+    pub path: PathBuf,
+    pub contents: String,
+}
+
+impl InstructionFile {
+    /// The file as a context message, labeled with where it's from.
+    pub fn message_text(&self) -> String {
+        format!(
+            "{INSTRUCTIONS_TAG}\"{}\">\n{}\n</instructions>\n",
+            self.path.display(),
+            self.contents.trim_end()
+        )
+    }
+}
+
+/// Whether `text` is the context message of an instruction file.
+pub fn is_instructions(text: &str) -> bool {
+    text.starts_with(INSTRUCTIONS_TAG)
+}
+
+pub struct Instructions {
+    workspace: PathBuf,
+    /// The user's config directory, `~/.config/agent`.
+    user_dir: Option<PathBuf>,
+    /// The instruction files of the subdirectories looked in so far.
+    scoped: HashMap<PathBuf, Vec<InstructionFile>>,
+}
+
+impl Instructions {
+    pub fn new(workspace: PathBuf, user_dir: Option<PathBuf>) -> Self {
+        Self {
+            workspace,
+            user_dir,
+            scoped: HashMap::new(),
+        }
+    }
+
+    /// The instructions for the whole session: the user's, then those from the git root down to the workspace, so the
+    /// more specific ones come later.
+    pub async fn global(&self) -> anyhow::Result<Vec<InstructionFile>> {
+        let mut files = vec![];
+        if let Some(user_dir) = &self.user_dir {
+            files.extend(load_dir(user_dir, &user_dir.join("rules")).await?);
+        }
+        let root = self
+            .workspace
+            .ancestors()
+            .find(|dir| dir.join(".git").exists())
+            .unwrap_or(&self.workspace);
+        let mut dirs = self
+            .workspace
+            .ancestors()
+            .take_while(|dir| dir.starts_with(root))
+            .collect::<Vec<_>>();
+        dirs.reverse();
+        for dir in dirs {
+            files.extend(load_dir(dir, &dir.join(".agent").join("rules")).await?);
+        }
+        Ok(files)
+    }
+
+    /// The instructions of the subdirectories of the workspace that `path` is in, outermost first. Relative paths are
+    /// taken to be relative to the workspace; paths outside of it have none.
+    pub async fn scoped(&mut self, path: &Path) -> anyhow::Result<Vec<InstructionFile>> {
+        let path = self.workspace.join(path);
+        let Ok(relative) = path.strip_prefix(&self.workspace) else {
+            return Ok(vec![]);
+        };
+        // A directory's own instructions apply to it, a file's are those of the directory it's in.
+        let relative = match tokio::fs::metadata(&path).await {
+            Ok(metadata) if metadata.is_dir() => relative,
+            _ => relative.parent().unwrap_or(relative),
+        };
+        let mut files = vec![];
+        let mut dir = self.workspace.clone();
+        for component in relative.components() {
+            dir.push(component);
+            if !self.scoped.contains_key(&dir) {
+                let loaded = load_dir(&dir, &dir.join(".agent").join("rules")).await?;
+                self.scoped.insert(dir.clone(), loaded);
+            }
+            files.extend(self.scoped[&dir].iter().cloned());
+        }
+        Ok(files)
+    }
+}
+
+/// The instruction files in `dir`, then the markdown files in `rules`, in order of their names.
+async fn load_dir(dir: &Path, rules: &Path) -> anyhow::Result<Vec<InstructionFile>> {
+    let mut paths = INSTRUCTION_FILES.iter().map(|name| dir.join(name)).collect::<Vec<_>>();
+    let mut rule_paths = vec![];
+    match tokio::fs::read_dir(rules).await {
+        Ok(mut entries) => {
+            while let Some(entry) = entries.next_entry().await? {
+                let path = entry.path();
+                if path.extension().is_some_and(|extension| extension == "md") {
+                    rule_paths.push(path);
+                }
+            }
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e.into()),
+    }
+    rule_paths.sort();
+    paths.extend(rule_paths);
+
+    let mut files = vec![];
+    for path in paths {
+        match tokio::fs::read_to_string(&path).await {
+            Ok(contents) if !contents.trim().is_empty() => files.push(InstructionFile { path, contents }),
+            Ok(_) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(anyhow::Error::from(e).context(format!("Failed to read {}", path.display()))),
+        }
+    }
+    Ok(files)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_global_and_scoped() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let dir = temp_dir.path();
+        let repo = dir.join("repo");
+        let workspace = repo.join("crates").join("app");
+        let user_dir = dir.join("config");
+        for subdir in [
+            repo.join(".git"),
+            workspace.join(".agent").join("rules"),
+            workspace.join("src").join("db"),
+            user_dir.join("rules"),
+        ] {
+            std::fs::create_dir_all(subdir).unwrap();
+        }
+        let files = [
+            (dir.join("AGENTS.md"), "Above the repo"),
+            (repo.join("AGENTS.md"), "Repo"),
+            (workspace.join("CLAUDE.md"), "Workspace"),
+            (workspace.join(".agent").join("rules").join("b.md"), "Rule b"),
+            (workspace.join(".agent").join("rules").join("a.md"), "Rule a"),
+            (workspace.join(".agent").join("rules").join("notes.txt"), "Not a rule"),
+            (workspace.join("src").join("db").join("AGENTS.md"), "Database"),
+            (user_dir.join("rules").join("style.md"), "User"),
+        ];
+        for (path, contents) in &files {
+            std::fs::write(path, contents).unwrap();
+        }
+        let mut instructions = Instructions::new(workspace.clone(), Some(user_dir));
+        let contents = |files: Vec<InstructionFile>| files.into_iter().map(|file| file.contents).collect::<Vec<_>>();
+
+        let global = instructions.global().await.unwrap();
+        assert_eq!(contents(global), ["User", "Repo", "Workspace", "Rule a", "Rule b"]);
+
+        let scoped = instructions.scoped(&workspace.join("src/db/schema.rs")).await.unwrap();
+        assert_eq!(contents(scoped.clone()), ["Database"]);
+        assert!(
+            scoped[0]
+                .message_text()
+                .contains("src/db/AGENTS.md\">\nDatabase\n</instructions>")
+        );
+        assert_eq!(
+            contents(instructions.scoped(Path::new("src/db")).await.unwrap()),
+            ["Database"]
+        );
+        assert!(instructions.scoped(Path::new("src/main.rs")).await.unwrap().is_empty());
+        assert!(instructions.scoped(&repo.join("README.md")).await.unwrap().is_empty());
+    }
+}
@@ -0,0 +1,411 @@
+//! The snapshot of the workspace's file tree that goes into the preamble.
+//!
+//! The workspace is walked once, skipping what's gitignored, and laid out within a token budget: the top level is
+//! always listed, and then the directories nearest the top and most recently modified are opened up for as long as
+//! their entries fit. The rest are summarized by how many files they hold and how big those are. Manifests like
+//! `Cargo.toml` are listed up front wherever they are, since they say the most about how the project is put together.
+
+use std::{
+    cmp::Reverse,
+    collections::{
+        BinaryHeap,
+        HashMap,
+        HashSet,
+    },
+    path::{
+        Path,
+        PathBuf,
+    },
+    time::SystemTime,
+};
+
+use ignore::{
+    DirEntry,
+    WalkBuilder,
+};
+
+/// Roughly how many tokens the layout may take.
+const TOKEN_BUDGET: usize = 2_000;
+
+/// Where the walk stops, so that huge workspaces don't hold up the start of the session.
+const MAX_ENTRIES: usize = 50_000;
+
+/// How many manifests are listed up front.
+const MAX_MANIFESTS: usize = 20;
+
+const MANIFESTS: &[&str] = &[
+    "Cargo.toml",
+    "package.json",
+    "pyproject.toml",
+    "setup.py",
+    "requirements.txt",
+    "go.mod",
+    "pom.xml",
+    "build.gradle",
+    "build.gradle.kts",
+    "Gemfile",
+    "composer.json",
+    "mix.exs",
+    "Package.swift",
+    "CMakeLists.txt",
+    "Makefile",
+    "deno.json",
+];
+
+struct Node {
+    name: String,
+    parent: Option<usize>,
+    depth: usize,
+    is_dir: bool,
+    /// The file's size, or the total size of the files under the directory.
+    size: u64,
+    /// How many files are under the directory, at any depth.
+    files: usize,
+    /// When the file, or anything under the directory, was last modified.
+    modified: Option<SystemTime>,
+    children: Vec<usize>,
+}
+
+impl Node {
+    fn is_manifest(&self) -> bool {
+        !self.is_dir && MANIFESTS.contains(&self.name.as_str())
+    }
+}
+
+/// The workspace's file tree, with the nodes in the order they were walked, so parents come before their children.
+struct Tree {
+    nodes: Vec<Node>,
+    /// Whether the walk stopped at `MAX_ENTRIES`.
+    truncated: bool,
+}
+
+/// The layout of `workspace`, telling the model whether the files that change later are reported as they do.
+pub async fn get_project_layout(workspace: &Path, watching: bool) -> anyhow::Result<String> {
+    let root = workspace.to_path_buf();
+    let tree = tokio::task::spawn_blocking(move || walk(&root)).await??;
+    Ok(render(workspace, &tree, TOKEN_BUDGET, watching))
+}
+
+/// The files and directories under `root` that aren't hidden or gitignored, each directory before what's in it.
+/// Entries that can't be read are left out, like the ones that are ignored.
+pub(crate) fn entries(root: &Path) -> impl Iterator<Item = DirEntry> {
+    WalkBuilder::new(root).build().skip(1).filter_map(Result::ok)
+}
+
+fn walk(root: &Path) -> anyhow::Result<Tree> {
+    let mut nodes = vec![Node {
+        name: String::new(),
+        parent: None,
+        depth: 0,
+        is_dir: true,
+        size: 0,
+        files: 0,
+        modified: None,
+        children: vec![],
+    }];
+    let mut dirs = HashMap::from([(root.to_path_buf(), 0)]);
+    let mut truncated = false;
+    for entry in entries(root) {
+        if nodes.len() >= MAX_ENTRIES {
+            truncated = true;
+            break;
+        }
+        let Some(&parent) = entry.path().parent().and_then(|parent| dirs.get(parent)) else {
+            continue;
+        };
+        let metadata = entry.metadata().ok();
+        let is_dir = entry.file_type().is_some_and(|file_type| file_type.is_dir());
+        let index = nodes.len();
+        if is_dir {
+            dirs.insert(entry.path().to_path_buf(), index);
+        }
+        nodes.push(Node {
+            name: entry.file_name().to_string_lossy().to_string(),
+            parent: Some(parent),
+            depth: nodes[parent].depth + 1,
+            is_dir,
+            size: if is_dir {
+                0
+            } else {
+                metadata.as_ref().map_or(0, |metadata| metadata.len())
+            },
+            files: usize::from(!is_dir),
+            modified: metadata.and_then(|metadata| metadata.modified().ok()),
+            children: vec![],
+        });
+        nodes[parent].children.push(index);
+    }
+
+    // Children come after their parents, so going backwards totals up each directory before its parent needs it.
+    for index in (1..nodes.len()).rev() {
+        let (size, files, modified) = (nodes[index].size, nodes[index].files, nodes[index].modified);
+        let Some(parent) = nodes[index].parent else {
+            continue;
+        };
+        let parent = &mut nodes[parent];
+        parent.size += size;
+        parent.files += files;
+        parent.modified = parent.modified.max(modified);
+    }
+    for index in 0..nodes.len() {
+        let mut children = std::mem::take(&mut nodes[index].children);
+        children.sort_by(|a, b| nodes[*a].name.cmp(&nodes[*b].name));
+        nodes[index].children = children;
+    }
+    Ok(Tree { nodes, truncated })
+}
+
+/// Lays out `tree` in about `budget` tokens.
+fn render(workspace: &Path, tree: &Tree, budget: usize, watching: bool) -> String {
+    let nodes = &tree.nodes;
+    let updates = if watching {
+        "Files that change later are listed as the conversation goes on."
+    } else {
+        "This snapshot will NOT update during the conversation."
+    };
+    let mut header = format!(
+        "<project_layout>\nBelow is a snapshot of the current workspace's file structure at the start of the \
+         conversation. {updates} Directories that didn't fit are summarized by their file count and total size.\n\n"
+    );
+    let manifests = manifest_paths(tree);
+    if !manifests.is_empty() {
+        header.push_str(&format!("Manifests: {}\n\n", manifests.join(", ")));
+    }
+    header.push_str(&format!("{}\n", workspace.display()));
+    let footer = "</project_layout>\n";
+    let mut used = tokens(&header) + tokens(footer);
+
+    // The top level is always listed, as much of it as fits.
+    let root_children = top_level(tree, budget.saturating_sub(used));
+    used += root_children
+        .iter()
+        .map(|child| tokens(&node_line(&nodes[*child], false)))
+        .sum::<usize>();
+
+    // Then directories open up by depth, most recently modified first, as long as their entries fit.
+    let mut expanded = HashSet::from([0]);
+    let mut queue = BinaryHeap::new();
+    let push_dirs = |queue: &mut BinaryHeap<_>, children: &[usize]| {
+        for child in children {
+            let node = &nodes[*child];
+            if node.is_dir && !node.children.is_empty() {
+                queue.push((Reverse(node.depth), node.modified, Reverse(*child)));
+            }
+        }
+    };
+    push_dirs(&mut queue, &root_children);
+    while let Some((_, _, Reverse(index))) = queue.pop() {
+        let node = &nodes[index];
+        // Its own line only gets shorter when it opens, so just its entries count.
+        let cost = node
+            .children
+            .iter()
+            .map(|child| tokens(&node_line(&nodes[*child], false)))
+            .sum::<usize>();
+        if used + cost > budget {
+            continue;
+        }
+        used += cost;
+        expanded.insert(index);
+        push_dirs(&mut queue, &node.children);
+    }
+
+    let mut result = header;
+    let mut stack = root_children.iter().rev().copied().collect::<Vec<_>>();
+    while let Some(index) = stack.pop() {
+        let node = &nodes[index];
+        let is_expanded = expanded.contains(&index);
+        result.push_str(&node_line(node, is_expanded));
+        if is_expanded {
+            stack.extend(node.children.iter().rev());
+        }
+    }
+    let hidden = nodes[0].children.len() - root_children.len();
+    if hidden > 0 {
+        let (files, size) = nodes[0]
+            .children
+            .iter()
+            .filter(|child| !root_children.contains(child))
+            .fold((0, 0), |(files, size), child| {
+                (files + nodes[*child].files, size + nodes[*child].size)
+            });
+        result.push_str(&format!(
+            "  - … {} not listed ({}, {})\n",
+            plural(hidden, "entry"),
+            plural(files, "file"),
+            humansize::format_size(size, humansize::DECIMAL)
+        ));
+    }
+    if tree.truncated {
+        result.push_str(&format!("  (the walk stopped after {MAX_ENTRIES} entries)\n"));
+    }
+    result.push_str(footer);
+    result
+}
+
+/// The top-level entries to list when they don't all fit in `budget`: directories and manifests first, then the most
+/// recently modified files. In name order either way.
+fn top_level(tree: &Tree, budget: usize) -> Vec<usize> {
+    let nodes = &tree.nodes;
+    let all = &nodes[0].children;
+    let cost = |index: &usize| tokens(&node_line(&nodes[*index], false));
+    if all.iter().map(cost).sum::<usize>() <= budget {
+        return all.clone();
+    }
+    let mut by_priority = all.clone();
+    by_priority.sort_by_key(|index| {
+        let node = &nodes[*index];
+        (Reverse(node.is_dir || node.is_manifest()), Reverse(node.modified))
+    });
+    let mut used = 0;
+    let mut shown = by_priority
+        .into_iter()
+        .take_while(|index| {
+            used += cost(index);
+            used <= budget
+        })
+        .collect::<Vec<_>>();
+    shown.sort_by(|a, b| nodes[*a].name.cmp(&nodes[*b].name));
+    shown
+}
+
+/// Relative paths of the manifests in the tree, shallowest first.
+fn manifest_paths(tree: &Tree) -> Vec<String> {
+    let mut manifests = tree
+        .nodes
+        .iter()
+        .filter(|node| node.is_manifest())
+        .map(|node| (node.depth, relative_path(tree, node)))
+        .collect::<Vec<_>>();
+    manifests.sort();
+    manifests
+        .into_iter()
+        .take(MAX_MANIFESTS)
+        .map(|(_, path)| path.display().to_string())
+        .collect()
+}
+
+fn relative_path(tree: &Tree, node: &Node) -> PathBuf {
+    let mut names = vec![node.name.as_str()];
+    let mut parent = node.parent;
+    while let Some(index) = parent
+        && index != 0
+    {
+        names.push(&tree.nodes[index].name);
+        parent = tree.nodes[index].parent;
+    }
+    names.iter().rev().collect()
+}
+
+/// The node's line in the layout. A directory that isn't expanded gets a summary of what's in it.
+fn node_line(node: &Node, expanded: bool) -> String {
+    let indent = "  ".repeat(node.depth);
+    let size = humansize::format_size(node.size, humansize::DECIMAL);
+    let details = if node.is_dir {
+        if expanded || node.children.is_empty() {
+            "/".to_string()
+        } else {
+            format!("/ ({}, {size})", plural(node.files, "file"))
+        }
+    } else if node.is_manifest() {
+        format!(" ({size}, manifest)")
+    } else {
+        format!(" ({size})")
+    };
+    format!("{indent}- {}{details}\n", node.name)
+}
+
+pub(crate) fn plural(count: usize, noun: &str) -> String {
+    match (count, noun.strip_suffix('y')) {
+        (1, _) => format!("1 {noun}"),
+        (_, Some(stem)) => format!("{count} {stem}ies"),
+        _ => format!("{count} {noun}s"),
+    }
+}
+
+/// Roughly how many tokens `text` takes, by the same rule of thumb as compaction.
+fn tokens(text: &str) -> usize {
+    text.len().div_ceil(4)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_layout() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let dir = temp_dir.path();
+        let workspace = dir.join("workspace");
+        for subdir in ["src/parser", "target/debug", "web", "assets"] {
+            std::fs::create_dir_all(workspace.join(subdir)).unwrap();
+        }
+        std::fs::write(workspace.join(".gitignore"), "target/\n").unwrap();
+        std::fs::write(workspace.join("Cargo.toml"), "[package]\n").unwrap();
+        std::fs::write(workspace.join("src/main.rs"), "fn main() {}\n").unwrap();
+        std::fs::write(workspace.join("src/parser/mod.rs"), "").unwrap();
+        std::fs::write(workspace.join("target/debug/agent"), "binary").unwrap();
+        std::fs::write(workspace.join("web/package.json"), "{}").unwrap();
+        for i in 0..200 {
+            std::fs::write(workspace.join(format!("assets/icon-{i:03}.svg")), "<svg/>").unwrap();
+        }
+        // .gitignore files only count in git repositories.
+        std::fs::create_dir(workspace.join(".git")).unwrap();
+
+        let tree = walk(&workspace).unwrap();
+        let layout = render(&workspace, &tree, 400, false);
+        assert!(layout.contains("Manifests: Cargo.toml, web/package.json\n"), "{layout}");
+        let listing = layout
+            .lines()
+            .skip_while(|line| !line.starts_with('/'))
+            .skip(1)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            listing,
+            [
+                "  - Cargo.toml (10 B, manifest)",
+                "  - assets/ (200 files, 1.20 kB)",
+                "  - src/",
+                "    - main.rs (13 B)",
+                "    - parser/",
+                "      - mod.rs (0 B)",
+                "  - web/",
+                "    - package.json (2 B, manifest)",
+                "</project_layout>",
+            ]
+        );
+
+        // With almost no room, the top level keeps the most recently modified directories, and what's left of it is
+        // summarized.
+        let later = SystemTime::now() + std::time::Duration::from_secs(60);
+        std::fs::File::options()
+            .write(true)
+            .open(workspace.join("web/package.json"))
+            .unwrap()
+            .set_modified(later)
+            .unwrap();
+        let tree = walk(&workspace).unwrap();
+        // The header names the workspace, which is longer or shorter depending on where temporary files go.
+        let budget = 102 + tokens(&workspace.display().to_string());
+        let layout = render(&workspace, &tree, budget, false);
+        assert!(layout.contains("  - web/ (1 file, 2 B)\n"), "{layout}");
+        assert!(
+            layout.contains("  - … 1 entry not listed (2 files, 13 B)\n"),
+            "{layout}"
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_get_project_layout() {
+        let layout = get_project_layout(&std::env::current_dir().unwrap(), false)
+            .await
+            .unwrap();
+        assert!(layout.contains("  - Cargo.toml ("), "{layout}");
+        assert!(layout.contains("will NOT update"), "{layout}");
+        let layout = get_project_layout(&std::env::current_dir().unwrap(), true)
+            .await
+            .unwrap();
+        assert!(layout.contains("Files that change later are listed"), "{layout}");
+        assert!(!layout.contains("  - target/"), "{layout}");
+    }
+}
@@ -1,12 +1,29 @@
 #![feature(try_blocks)]
 
+pub mod branches;
+pub mod cassette;
+pub mod checkpoints;
+pub mod commands;
+pub mod commit;
+pub mod compaction;
+pub mod config;
 pub mod control;
+pub mod diff_render;
+pub mod editor;
+pub mod instructions;
+pub mod layout;
 pub mod llm_provider;
 pub mod markdown_render;
+pub mod mentions;
 pub mod prompts;
+pub mod selection;
 pub mod server;
 pub mod syntax_highlight;
+#[cfg(test)]
+mod test_messages;
 pub mod tools;
+pub mod transcript;
 pub mod types;
 pub mod ui;
 pub mod ui_state;
+pub mod watcher;
@@ -1,4 +1,8 @@
-use std::time::Duration;
+use std::{
+    collections::HashMap,
+    sync::Arc,
+    time::Duration,
+};
 
 use async_openai::types::{
     ChatCompletionRequestMessage,
@@ -16,39 +20,74 @@ use tokio::{
 };
 
 use crate::{
+    cassette::CassetteRecorder,
     tools::prompts as tool_prompts,
     types::{
         FinishReason,
         PerformanceStats,
         Response,
         StreamResponse,
+        Usage,
     },
 };
 
+/// Which model to talk to, and how.
+#[derive(Debug, Clone)]
+pub struct ModelConfig {
+    pub model: String,
+    pub api_key: String,
+    pub base_url: String,
+    pub reasoning_effort: Option<ReasoningEffort>,
+    /// Whether to send the model's reasoning back with the assistant messages it belongs to. Some providers need this
+    /// to carry reasoning across tool calls; most are better off without it.
+    pub send_reasoning: bool,
+    /// Upper bound on the number of tokens generated per request, if any.
+    pub max_tokens: Option<u32>,
+    /// Names of the tools offered to the model.
+    pub tools: Vec<String>,
+}
+
+impl ModelConfig {
+    /// The reasoning effort as it's spelled in configs and `/model`, if one is set.
+    pub fn reasoning_effort_name(&self) -> Option<&'static str> {
+        self.reasoning_effort.as_ref().map(|effort| match effort {
+            ReasoningEffort::Minimal => "minimal",
+            ReasoningEffort::Low => "low",
+            ReasoningEffort::Medium => "medium",
+            ReasoningEffort::High => "high",
+        })
+    }
+}
+
 pub struct LLMProvider {
-    model: String,
-    api_key: String,
-    base_url: String,
-    reasoning_effort: Option<ReasoningEffort>,
+    config: ModelConfig,
+    /// Where to record requests and responses, if we're recording a cassette.
+    recorder: Option<Arc<CassetteRecorder>>,
     http_client: reqwest::Client,
 }
 
 impl LLMProvider {
-    pub fn new(
-        model: String,
-        api_key: String,
-        base_url: String,
-        reasoning_effort: Option<ReasoningEffort>,
-    ) -> anyhow::Result<Self> {
+    pub fn new(config: ModelConfig, recorder: Option<Arc<CassetteRecorder>>) -> anyhow::Result<Self> {
         Ok(Self {
-            model,
-            api_key,
-            base_url,
-            reasoning_effort,
+            config,
+            recorder,
             http_client: reqwest::Client::new(),
         })
     }
 
+    pub fn config(&self) -> &ModelConfig {
+        &self.config
+    }
+
+    /// Switches to another model or provider. Only affects requests made from now on.
+    pub fn set_config(&mut self, config: ModelConfig) {
+        self.config = config;
+    }
+
+    pub fn max_tokens(&self) -> Option<u32> {
+        self.config.max_tokens
+    }
+
     pub async fn chat(
         &self,
         messages: Vec<ChatCompletionRequestMessage>,
@@ -57,34 +96,51 @@ impl LLMProvider {
             let start = Instant::now();
             let mut args = CreateChatCompletionRequestArgs::default();
 
-            if let Some(effort) = self.reasoning_effort.clone() {
+            if let Some(effort) = self.config.reasoning_effort.clone() {
                 args.reasoning_effort(effort);
             }
+            if let Some(max_tokens) = self.config.max_tokens {
+                args.max_completion_tokens(max_tokens);
+            }
+
+            let tools = tool_prompts::tools(&self.config.tools);
+            if !tools.is_empty() {
+                args.tools(tools).parallel_tool_calls(true);
+            }
 
             let args = args
-                .model(&self.model)
+                .model(&self.config.model)
                 .messages(messages)
-                .tools(vec![tool_prompts::read_file_tool(), tool_prompts::list_dir_tool()])
-                .parallel_tool_calls(true)
                 .stream(false)
-                .build()?;
+                .build()
+                .map_err(anyhow::Error::from)?;
+            let body = serde_json::to_value(&args).map_err(anyhow::Error::from)?;
+            let mut interaction = match &self.recorder {
+                Some(recorder) => Some(recorder.start_interaction(&body)?),
+                None => None,
+            };
             let build_args = Instant::now();
 
-            let url = format!("{}/chat/completions", self.base_url);
+            let url = format!("{}/chat/completions", self.config.base_url);
             let response = self
                 .http_client
                 .post(url)
-                .json(&args)
-                .header("Authorization", format!("Bearer {}", self.api_key))
+                .json(&body)
+                .header("Authorization", format!("Bearer {}", self.config.api_key))
                 .send()
-                .await?;
+                .await
+                .map_err(anyhow::Error::from)?;
             let receive_headers = Instant::now();
             let status = response.status();
             if !status.is_success() {
                 Err(anyhow::anyhow!("Failed to get stream: {}", status))?;
             }
 
-            let response: Response = response.json().await?;
+            let response_text = response.text().await.map_err(anyhow::Error::from)?;
+            if let Some(interaction) = &mut interaction {
+                interaction.record_body(&response_text)?;
+            }
+            let response: Response = serde_json::from_str(&response_text).map_err(anyhow::Error::from)?;
             let receive_body = Instant::now();
 
             tracing::info!("Client timeline:");
@@ -102,16 +158,21 @@ impl LLMProvider {
                 tracing::info!("  Total time: {:?}", Duration::from_secs_f64(time_info.total_time));
             }
 
+            let mut result = vec![];
             if let Some(usage) = response.usage {
                 tracing::info!("Usage: {:?}", usage);
+                result.push(StreamChunk::Usage(usage));
             }
 
             if response.choices.len() != 1 {
                 Err(anyhow::anyhow!("Expected 1 choice, got {}", response.choices.len()))?;
             }
             let choice = response.choices[0].clone();
-            let mut result = vec![];
             let mut total_bytes = 0;
+            if let Some(reasoning) = choice.message.reasoning {
+                total_bytes += reasoning.len();
+                result.push(StreamChunk::Reasoning(reasoning));
+            }
             if let Some(content) = choice.message.content {
                 total_bytes += content.len();
                 result.push(StreamChunk::SystemMessage(content));
@@ -137,6 +198,7 @@ impl LLMProvider {
                 match finish_reason {
                     FinishReason::Stop => {}
                     FinishReason::ToolCalls => {}
+                    FinishReason::Length => result.push(StreamChunk::Truncated),
                     _ => Err(anyhow::anyhow!("Unexpected finish reason: {:?}", finish_reason))?,
                 }
             }
@@ -157,15 +219,80 @@ impl LLMProvider {
         rx
     }
 
+    /// Makes a one-off, non-streaming request without any tools attached and returns the assistant's text. Used for
+    /// side calls (e.g. summarizing the conversation) that shouldn't show up in the transcript.
+    pub async fn complete(&self, messages: Vec<ChatCompletionRequestMessage>) -> anyhow::Result<String> {
+        let start = Instant::now();
+        let mut args = CreateChatCompletionRequestArgs::default();
+        if let Some(effort) = self.config.reasoning_effort.clone() {
+            args.reasoning_effort(effort);
+        }
+        let args = args
+            .model(&self.config.model)
+            .messages(messages)
+            .stream(false)
+            .build()?;
+        let body = serde_json::to_value(&args)?;
+        let mut interaction = match &self.recorder {
+            Some(recorder) => Some(recorder.start_interaction(&body)?),
+            None => None,
+        };
+
+        let url = format!("{}/chat/completions", self.config.base_url);
+        let response = self
+            .http_client
+            .post(url)
+            .json(&body)
+            .header("Authorization", format!("Bearer {}", self.config.api_key))
+            .send()
+            .await?;
+        let status = response.status();
+        if !status.is_success() {
+            let text = response.text().await?;
+            anyhow::bail!("Invalid status code: {status}: {text}");
+        }
+        let response_text = response.text().await?;
+        if let Some(interaction) = &mut interaction {
+            interaction.record_body(&response_text)?;
+        }
+        let response: Response = serde_json::from_str(&response_text)?;
+        tracing::info!("Side request finished in {:?}", start.elapsed());
+        if let Some(usage) = response.usage {
+            tracing::info!("Usage: {:?}", usage);
+        }
+
+        let Some(choice) = response.choices.into_iter().next() else {
+            anyhow::bail!("Expected 1 choice, got 0");
+        };
+        if let Some(finish_reason) = choice.finish_reason
+            && finish_reason != FinishReason::Stop
+        {
+            anyhow::bail!("Unexpected finish reason: {:?}", finish_reason);
+        }
+        choice
+            .message
+            .content
+            .ok_or_else(|| anyhow::anyhow!("Response has no content"))
+    }
+
+    /// Streams a response to `messages`. `reasoning` maps the first tool call id of earlier assistant messages to the
+    /// reasoning that produced them, and is only sent along if the provider was configured to need it.
     pub async fn stream(
         &self,
         messages: Vec<ChatCompletionRequestMessage>,
+        reasoning: HashMap<String, String>,
     ) -> mpsc::UnboundedReceiver<anyhow::Result<StreamChunk>> {
-        let model = self.model.clone();
+        let ModelConfig {
+            model,
+            api_key,
+            base_url,
+            reasoning_effort,
+            send_reasoning,
+            max_tokens,
+            tools,
+        } = self.config.clone();
         let http_client = self.http_client.clone();
-        let base_url = self.base_url.clone();
-        let api_key = self.api_key.clone();
-        let reasoning_effort = self.reasoning_effort.clone();
+        let recorder = self.recorder.clone();
 
         let (tx, rx) = mpsc::unbounded_channel();
         let stream_generator = async move {
@@ -173,24 +300,35 @@ impl LLMProvider {
                 tracing::debug!("Sending message: {:#?}", messages);
                 let start = Instant::now();
                 let mut args = CreateChatCompletionRequestArgs::default();
-                args.model(&model)
-                    .messages(messages)
-                    .tools(vec![tool_prompts::read_file_tool(), tool_prompts::list_dir_tool()])
-                    .parallel_tool_calls(true)
-                    .stream(true);
+                args.model(&model).messages(messages).stream(true);
+                let tools = tool_prompts::tools(&tools);
+                if !tools.is_empty() {
+                    args.tools(tools).parallel_tool_calls(true);
+                }
                 if let Some(effort) = reasoning_effort {
                     args.reasoning_effort(effort);
                 }
-                let args = args.build()?;
+                if let Some(max_tokens) = max_tokens {
+                    args.max_completion_tokens(max_tokens);
+                }
+                let args = args.build().map_err(anyhow::Error::from)?;
+                let mut body = serde_json::to_value(&args).map_err(anyhow::Error::from)?;
+                if send_reasoning {
+                    attach_reasoning(&mut body, &reasoning);
+                }
+                let mut interaction = match &recorder {
+                    Some(recorder) => Some(recorder.start_interaction(&body)?),
+                    None => None,
+                };
                 let build_args = Instant::now();
 
                 let url = format!("{}/chat/completions", base_url);
                 let request_builder = http_client
                     .post(url)
-                    .json(&args)
+                    .json(&body)
                     .header("Authorization", format!("Bearer {}", api_key));
 
-                let sse = EventSource::new(request_builder)?;
+                let sse = EventSource::new(request_builder).map_err(anyhow::Error::from)?;
                 tokio::pin!(sse);
 
                 let mut chunk_timestamps = vec![];
@@ -205,16 +343,19 @@ impl LLMProvider {
                         Err(e) => {
                             if let reqwest_eventsource::Error::InvalidStatusCode(_, response) = e {
                                 let status = response.status();
-                                let text = response.text().await?;
+                                let text = response.text().await.map_err(anyhow::Error::from)?;
                                 Err(anyhow::anyhow!("Invalid status code: {status}: {text}"))?;
                                 unreachable!();
                             }
-                            Err(e)?;
+                            Err(anyhow::Error::from(e))?;
                             unreachable!();
                         }
                     };
                     tracing::debug!("Received event: {}", message.data);
-                    let mut resp: StreamResponse = serde_json::from_str(&message.data)?;
+                    if let Some(interaction) = &mut interaction {
+                        interaction.record_event(&message.data)?;
+                    }
+                    let mut resp: StreamResponse = serde_json::from_str(&message.data).map_err(anyhow::Error::from)?;
                     if let Some(u) = resp.usage {
                         usage = Some(u);
                     }
@@ -227,11 +368,13 @@ impl LLMProvider {
                     let choice = resp.choices.remove(0);
                     if let Some(content) = choice.delta.content {
                         useful_bytes += content.len();
-                        tx.send(Ok(StreamChunk::SystemMessage(content)))?;
+                        tx.send(Ok(StreamChunk::SystemMessage(content)))
+                            .map_err(anyhow::Error::from)?;
                     }
                     if let Some(reasoning) = choice.delta.reasoning {
                         useful_bytes += reasoning.len();
-                        tx.send(Ok(StreamChunk::SystemMessage(reasoning)))?;
+                        tx.send(Ok(StreamChunk::Reasoning(reasoning)))
+                            .map_err(anyhow::Error::from)?;
                     }
                     if let Some(tool_calls) = choice.delta.tool_calls {
                         for tool_call in tool_calls {
@@ -248,7 +391,7 @@ impl LLMProvider {
                                         .name
                                         .ok_or_else(|| anyhow::anyhow!("Tool call name is missing"))?,
                                 };
-                                tx.send(Ok(chunk))?;
+                                tx.send(Ok(chunk)).map_err(anyhow::Error::from)?;
                             }
                             if let Some(args) = function.arguments {
                                 useful_bytes += args.len();
@@ -256,7 +399,7 @@ impl LLMProvider {
                                     index: tool_call.index,
                                     text: args,
                                 };
-                                tx.send(Ok(chunk))?;
+                                tx.send(Ok(chunk)).map_err(anyhow::Error::from)?;
                             }
                         }
                     }
@@ -267,6 +410,11 @@ impl LLMProvider {
                                 tracing::info!("Stopping stream because of finish reason: {:?}", finish_reason);
                                 break;
                             }
+                            FinishReason::Length => {
+                                tracing::info!("Response was truncated at the token limit");
+                                tx.send(Ok(StreamChunk::Truncated)).map_err(anyhow::Error::from)?;
+                                break;
+                            }
                             _ => Err(anyhow::anyhow!("Unexpected finish reason: {:?}", finish_reason))?,
                         }
                     }
@@ -288,6 +436,7 @@ impl LLMProvider {
                 }
                 if let Some(usage) = usage {
                     tracing::info!("Usage: {:#?}", usage);
+                    tx.send(Ok(StreamChunk::Usage(usage))).map_err(anyhow::Error::from)?;
                 }
                 if !chunk_timestamps.is_empty() {
                     let ttft = chunk_timestamps[0].0 - start;
@@ -297,7 +446,8 @@ impl LLMProvider {
                     let bytes_per_sec = all_bytes as f64 / req_duration.as_secs_f64();
                     let stats = PerformanceStats { ttft, bytes_per_sec };
                     tracing::info!("Performance stats: {:?}", stats);
-                    tx.send(Ok(StreamChunk::PerformanceStats(stats)))?;
+                    tx.send(Ok(StreamChunk::PerformanceStats(stats)))
+                        .map_err(anyhow::Error::from)?;
                 }
             };
             if let Err(e) = r {
@@ -309,10 +459,40 @@ impl LLMProvider {
     }
 }
 
+/// Adds a `reasoning` field to each assistant message in a serialized request whose first tool call has an entry in
+/// `reasoning`.
+fn attach_reasoning(body: &mut serde_json::Value, reasoning: &HashMap<String, String>) {
+    let Some(messages) = body.get_mut("messages").and_then(|m| m.as_array_mut()) else {
+        return;
+    };
+    for message in messages {
+        if message.get("role").and_then(|r| r.as_str()) != Some("assistant") {
+            continue;
+        }
+        let Some(id) = message.pointer("/tool_calls/0/id").and_then(|id| id.as_str()) else {
+            continue;
+        };
+        if let Some(text) = reasoning.get(id) {
+            message["reasoning"] = serde_json::Value::String(text.clone());
+        }
+    }
+}
+
 #[derive(Debug)]
 pub enum StreamChunk {
     SystemMessage(String),
-    StartToolCall { index: u32, id: String, name: String },
-    AppendToolCallArgs { index: u32, text: String },
+    Reasoning(String),
+    StartToolCall {
+        index: u32,
+        id: String,
+        name: String,
+    },
+    AppendToolCallArgs {
+        index: u32,
+        text: String,
+    },
     PerformanceStats(PerformanceStats),
+    Usage(Usage),
+    /// The response hit the token limit before the model finished.
+    Truncated,
 }
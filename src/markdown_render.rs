@@ -225,7 +225,10 @@ use ratatui::{
 };
 use tracing::debug;
 
-use crate::syntax_highlight::SyntaxHighlighter;
+use crate::{
+    diff_render::render_diff,
+    syntax_highlight::SyntaxHighlighter,
+};
 
 // use crate::citation_regex::CITATION_REGEX;
 
@@ -274,7 +277,7 @@ where I: Iterator<Item = Event<'a>>
     in_code_block: bool,
     code_block_lang: Option<String>,
     code_block_content: String,
-    syntax_highlighter: SyntaxHighlighter,
+    syntax_highlighter: &'static SyntaxHighlighter,
 }
 
 impl<'a, I> Writer<'a, I>
@@ -296,7 +299,7 @@ where I: Iterator<Item = Event<'a>>
             in_code_block: false,
             code_block_lang: None,
             code_block_content: String::new(),
-            syntax_highlighter: SyntaxHighlighter::new(),
+            syntax_highlighter: SyntaxHighlighter::shared(),
         }
     }
 
@@ -351,15 +354,8 @@ where I: Iterator<Item = Event<'a>>
             Tag::Strong => self.push_inline_style(Style::new().bold()),
             Tag::Strikethrough => self.push_inline_style(Style::new().crossed_out()),
             Tag::Link { dest_url, .. } => self.push_link(dest_url.to_string()),
-            Tag::HtmlBlock
-            | Tag::FootnoteDefinition(_)
-            | Tag::Table(_)
-            | Tag::TableHead
-            | Tag::TableRow
-            | Tag::TableCell
-            | Tag::Image { .. }
-            | Tag::MetadataBlock(_)
-            | _ => {}
+            // Tag::HtmlBlock, Tag::FootnoteDefinition, Tag::Table*, Tag::Image, Tag::MetadataBlock, ...
+            _ => {}
         }
     }
 
@@ -376,15 +372,8 @@ where I: Iterator<Item = Event<'a>>
             }
             TagEnd::Emphasis | TagEnd::Strong | TagEnd::Strikethrough => self.pop_inline_style(),
             TagEnd::Link => self.pop_link(),
-            TagEnd::HtmlBlock
-            | TagEnd::FootnoteDefinition
-            | TagEnd::Table
-            | TagEnd::TableHead
-            | TagEnd::TableRow
-            | TagEnd::TableCell
-            | TagEnd::Image
-            | TagEnd::MetadataBlock(_)
-            | _ => {}
+            // TagEnd::HtmlBlock, TagEnd::FootnoteDefinition, TagEnd::Table*, TagEnd::Image, ...
+            _ => {}
         }
     }
 
@@ -580,29 +569,34 @@ where I: Iterator<Item = Event<'a>>
         );
         debug!("Code block content:\n{}", self.code_block_content);
 
-        // Apply syntax highlighting to the collected code block content
-        let highlighted_text = self
-            .syntax_highlighter
-            .highlight_code(&self.code_block_content, self.code_block_lang.as_deref());
-
-        debug!("Highlighted text has {} lines", highlighted_text.lines.len());
-
-        // Convert the highlighted text to static spans by cloning the content
-        let static_lines: Vec<Line<'static>> = highlighted_text
-            .lines
-            .into_iter()
-            .map(|line| {
-                let static_spans: Vec<Span<'static>> = line
-                    .spans
-                    .into_iter()
-                    .map(|span| {
-                        debug!("Converting span: '{}' with style: {:?}", span.content, span.style);
-                        Span::styled(span.content.to_string(), span.style)
-                    })
-                    .collect();
-                Line::from(static_spans)
-            })
-            .collect();
+        // Diffs get line numbers and their changed words marked, everything else plain syntax highlighting
+        let static_lines: Vec<Line<'static>> = if matches!(self.code_block_lang.as_deref(), Some("diff" | "patch")) {
+            render_diff(&self.code_block_content, None)
+        } else {
+            // Apply syntax highlighting to the collected code block content
+            let highlighted_text = self
+                .syntax_highlighter
+                .highlight_code(&self.code_block_content, self.code_block_lang.as_deref());
+
+            debug!("Highlighted text has {} lines", highlighted_text.lines.len());
+
+            // Convert the highlighted text to static spans by cloning the content
+            highlighted_text
+                .lines
+                .into_iter()
+                .map(|line| {
+                    let static_spans: Vec<Span<'static>> = line
+                        .spans
+                        .into_iter()
+                        .map(|span| {
+                            debug!("Converting span: '{}' with style: {:?}", span.content, span.style);
+                            Span::styled(span.content.to_string(), span.style)
+                        })
+                        .collect();
+                    Line::from(static_spans)
+                })
+                .collect()
+        };
 
         debug!("Converted to {} static lines", static_lines.len());
 
@@ -0,0 +1,219 @@
+//! `@file` mentions in user messages.
+//!
+//! `@src/ui.rs` attaches the whole file to the message, `@src/ui.rs:100-200` just those lines and `@src/ui.rs:100`
+//! just one. Mentions of paths that don't exist are left alone as plain text.
+
+use std::path::Path;
+
+use fuzzy_matcher::{
+    FuzzyMatcher,
+    skim::SkimMatcherV2,
+};
+
+use crate::layout;
+
+/// Files bigger than this are attached only up to here.
+const MAX_ATTACHMENT_BYTES: usize = 100_000;
+/// How many files the file picker offers, so a huge monorepo doesn't take forever to list.
+const MAX_LISTED_FILES: usize = 50_000;
+
+/// Characters that end a sentence rather than a path, like the period in "look at @README.md."
+const TRAILING_PUNCTUATION: &[char] = &['.', ',', ';', ':', '!', '?', ')', ']', '\'', '"'];
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Mention {
+    pub path: String,
+    /// 1-based, inclusive.
+    pub lines: Option<(usize, usize)>,
+}
+
+impl Mention {
+    pub fn label(&self) -> String {
+        match self.lines {
+            Some((start, end)) if start == end => format!("{}:{start}", self.path),
+            Some((start, end)) => format!("{}:{start}-{end}", self.path),
+            None => self.path.clone(),
+        }
+    }
+}
+
+/// A mentioned file, rendered for the model.
+#[derive(Debug, Clone)]
+pub struct Attachment {
+    pub label: String,
+    pub content: String,
+}
+
+/// Every `@path` and `@path:start-end` in `text`, whether or not the file exists.
+pub fn parse_mentions(text: &str) -> Vec<Mention> {
+    text.split_whitespace()
+        .filter_map(|word| word.strip_prefix('@'))
+        .filter_map(|mention| {
+            let mention = mention.trim_end_matches(TRAILING_PUNCTUATION);
+            if mention.is_empty() {
+                return None;
+            }
+            if let Some((path, range)) = mention.rsplit_once(':')
+                && let Some(lines) = parse_line_range(range)
+            {
+                return Some(Mention {
+                    path: path.to_string(),
+                    lines: Some(lines),
+                });
+            }
+            Some(Mention {
+                path: mention.to_string(),
+                lines: None,
+            })
+        })
+        .collect()
+}
+
+fn parse_line_range(range: &str) -> Option<(usize, usize)> {
+    let (start, end) = match range.split_once('-') {
+        Some((start, end)) => (start.parse().ok()?, end.parse().ok()?),
+        None => {
+            let line = range.parse().ok()?;
+            (line, line)
+        }
+    };
+    (start >= 1 && start <= end).then_some((start, end))
+}
+
+/// Reads the files mentioned in `text`, relative to `root`. Mentions of anything that isn't a readable file are
+/// skipped, since `@` shows up in plenty of text that isn't a mention.
+pub async fn attachments(text: &str, root: &Path) -> Vec<Attachment> {
+    let mut attachments: Vec<Attachment> = vec![];
+    for mention in parse_mentions(text) {
+        let label = mention.label();
+        if attachments.iter().any(|attachment| attachment.label == label) {
+            continue;
+        }
+        let path = root.join(&mention.path);
+        let Ok(bytes) = tokio::fs::read(&path).await else {
+            continue;
+        };
+        let content = match String::from_utf8(bytes) {
+            Ok(text) => render_attachment(&mention, &text),
+            Err(_) => format!(
+                "<attached_file path=\"{}\">\n(binary file, not shown)\n</attached_file>",
+                mention.path
+            ),
+        };
+        attachments.push(Attachment { label, content });
+    }
+    attachments
+}
+
+fn render_attachment(mention: &Mention, text: &str) -> String {
+    let (lines_attr, mut body) = match mention.lines {
+        Some((start, end)) => {
+            let selected = text.lines().skip(start - 1).take(end - start + 1).collect::<Vec<_>>();
+            let end = start - 1 + selected.len();
+            let body = if selected.is_empty() {
+                format!("(the file has only {} lines)", text.lines().count())
+            } else {
+                selected.join("\n")
+            };
+            (format!(" lines=\"{start}-{end}\""), body)
+        }
+        None => (String::new(), text.trim_end().to_string()),
+    };
+    if body.len() > MAX_ATTACHMENT_BYTES {
+        body.truncate(body.floor_char_boundary(MAX_ATTACHMENT_BYTES));
+        body.push_str("\n(truncated, read the rest with read_file if needed)");
+    }
+    format!(
+        "<attached_file path=\"{}\"{lines_attr}>\n{body}\n</attached_file>",
+        mention.path
+    )
+}
+
+/// The files under `root` that aren't hidden or ignored, relative to `root`, up to `MAX_LISTED_FILES` of them.
+pub fn list_files(root: &Path) -> Vec<String> {
+    let mut files = vec![];
+    for entry in layout::entries(root) {
+        if files.len() >= MAX_LISTED_FILES {
+            break;
+        }
+        if !entry.file_type().is_some_and(|file_type| file_type.is_file()) {
+            continue;
+        }
+        if let Ok(path) = entry.path().strip_prefix(root) {
+            files.push(path.to_string_lossy().to_string());
+        }
+    }
+    files.sort();
+    files
+}
+
+/// The best `limit` fuzzy matches for `query` among `files`, best first.
+pub fn fuzzy_match_files(files: &[String], query: &str, limit: usize) -> Vec<String> {
+    let matcher = SkimMatcherV2::default();
+    let mut matches = files
+        .iter()
+        .filter_map(|file| Some((matcher.fuzzy_match(file, query)?, file)))
+        .collect::<Vec<_>>();
+    // Shorter paths first among equally good matches.
+    matches.sort_by(|(a_score, a), (b_score, b)| b_score.cmp(a_score).then(a.len().cmp(&b.len())));
+    matches.into_iter().take(limit).map(|(_, file)| file.clone()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_mentions() {
+        assert_eq!(
+            parse_mentions("look at @src/ui.rs:100-200 and @README.md. Mail me@example.com or @ someone"),
+            vec![
+                Mention {
+                    path: "src/ui.rs".to_string(),
+                    lines: Some((100, 200)),
+                },
+                Mention {
+                    path: "README.md".to_string(),
+                    lines: None,
+                },
+            ]
+        );
+        assert_eq!(
+            parse_mentions("@Cargo.toml:7"),
+            vec![Mention {
+                path: "Cargo.toml".to_string(),
+                lines: Some((7, 7)),
+            }]
+        );
+        // Not a line range, so it's part of the path.
+        assert_eq!(parse_mentions("@a:b")[0].path, "a:b");
+    }
+
+    #[test]
+    fn test_render_attachment() {
+        let text = "one\ntwo\nthree\n";
+        let mention = Mention {
+            path: "f.txt".to_string(),
+            lines: Some((2, 5)),
+        };
+        assert_eq!(
+            render_attachment(&mention, text),
+            "<attached_file path=\"f.txt\" lines=\"2-3\">\ntwo\nthree\n</attached_file>"
+        );
+        let mention = Mention {
+            path: "f.txt".to_string(),
+            lines: None,
+        };
+        assert_eq!(
+            render_attachment(&mention, text),
+            "<attached_file path=\"f.txt\">\none\ntwo\nthree\n</attached_file>"
+        );
+    }
+
+    #[test]
+    fn test_fuzzy_match_files() {
+        let files = ["src/ui.rs", "src/ui_state.rs", "src/server.rs", "README.md"].map(str::to_string);
+        assert_eq!(fuzzy_match_files(&files, "uist", 5), vec!["src/ui_state.rs"]);
+        assert_eq!(fuzzy_match_files(&files, "ui", 1), vec!["src/ui.rs"]);
+    }
+}
@@ -1,7 +1,9 @@
-use std::collections::HashSet;
+use std::{
+    collections::HashMap,
+    path::Path,
+};
 
-use ignore::Walk;
-use tokio::fs;
+use crate::tools::git;
 
 pub const SYSTEM_PROMPT: &str = r#"
 You are a powerful agentic AI coding assistant that optimizes for SPEED. Use tools as necessary but make
@@ -10,99 +12,166 @@ gather more information by using additional tool calls or asking for clarificati
 the user for help if you can find the answer yourself.
 "#;
 
-pub fn user_info() -> String {
-    format!(
-        r#"<user_info>
-Arch: {arch}
-OS: {os}
-Shell: {shell}
-Workspace Path: {workspace_path}
+pub const USER_INFO: &str = r#"<user_info>
+Arch: {{arch}}
+OS: {{os}}
+Shell: {{shell}}
+Workspace Path: {{workspace}}
+Git Branch: {{git_branch}}
+Git Status: {{git_status}}
 Note: Prefer using absolute paths over relative paths as tool call args when possible.
 </user_info>
-"#,
-        arch = std::env::consts::ARCH,
-        os = std::env::consts::OS,
-        shell = std::env::var("SHELL").unwrap_or_else(|_| "unknown".to_string()),
-        workspace_path = std::env::current_dir()
-            .map(|p| p.display().to_string())
-            .unwrap_or_else(|_| "unknown".to_string()),
-    )
+"#;
+
+/// A system prompt from the config, in place of the built-in one or after it.
+#[derive(Debug, Clone, Default)]
+pub struct SystemPromptConfig {
+    /// A template, see `render_template`. The built-in prompt if unset.
+    pub template: Option<String>,
+    /// Whether the template goes after the built-in prompt rather than replacing it.
+    pub append: bool,
 }
 
-pub const RULES: &str = r#"
-Be sure to include language specifiers in Markdown code blocks.
-"#;
+/// The variables templates can use.
+pub const TEMPLATE_VARIABLES: &[&str] = &[
+    "arch",
+    "os",
+    "shell",
+    "workspace",
+    "date",
+    "git_branch",
+    "git_status",
+    "tools",
+];
 
-pub async fn get_project_layout() -> anyhow::Result<String> {
-    let current_dir = std::env::current_dir()?;
+/// The values of the template variables, for a session in `workspace` offering `tools`.
+pub fn template_variables(workspace: &Path, tools: &[String]) -> HashMap<&'static str, String> {
+    HashMap::from([
+        ("arch", std::env::consts::ARCH.to_string()),
+        ("os", std::env::consts::OS.to_string()),
+        (
+            "shell",
+            std::env::var("SHELL").unwrap_or_else(|_| "unknown".to_string()),
+        ),
+        ("workspace", workspace.display().to_string()),
+        ("date", chrono::Local::now().format("%Y-%m-%d").to_string()),
+        (
+            "git_branch",
+            git::branch(workspace).unwrap_or_else(|| "unknown".to_string()),
+        ),
+        (
+            "git_status",
+            git::summary(workspace).unwrap_or_else(|| "not a git repository".to_string()),
+        ),
+        ("tools", tools.join(", ")),
+    ])
+}
 
+/// Replaces every `{{name}}` in `template` with the variable of that name. Fails on variables that don't exist, so
+/// typos don't go to the model unnoticed. `\{{` is a literal `{{`.
+pub fn render_template(template: &str, variables: &HashMap<&str, String>) -> anyhow::Result<String> {
     let mut result = String::new();
-    result.push_str(&format!(
-        "<project_layout>\nBelow is a snapshot of the current workspace's file structure at the start of the \
-         conversation. This snapshot will NOT update during the conversation.\n\n"
-    ));
-    result.push_str(&format!("{}\n", current_dir.display()));
-
-    // TODO: Merge these into a single pass.
-    let mut non_ignored_paths = HashSet::new();
-    tokio::task::block_in_place(|| {
-        let mut iterator = Walk::new(&current_dir);
-        while let Some(entry) = iterator.next() {
-            if non_ignored_paths.len() > 100 {
-                break;
-            }
-            non_ignored_paths.insert(entry?.path().to_owned());
-        }
-        anyhow::Ok(())
-    })?;
-
-    let mut processed = 0;
-    let root_metadata = fs::metadata(&current_dir).await?;
-    let mut stack = vec![(0, current_dir, root_metadata)];
-    while let Some((depth, path, metadata)) = stack.pop() {
-        processed += 1;
-        if processed > 100 {
-            break;
-        }
-        if !non_ignored_paths.contains(&path) {
+    let mut rest = template;
+    while let Some(start) = rest.find("{{") {
+        if let Some(before) = rest[..start].strip_suffix('\\') {
+            result.push_str(before);
+            result.push_str("{{");
+            rest = &rest[start + 2..];
             continue;
         }
-        if depth > 0 {
-            let space = "  ".repeat(depth);
-            let suffix = if metadata.is_dir() {
-                "/".to_string()
-            } else {
-                format!(" ({})", humansize::format_size(metadata.len(), humansize::DECIMAL))
-            };
-            if let Some(name) = path.file_name() {
-                result.push_str(&format!(
-                    "{space}- {name}{suffix}\n",
-                    space = space,
-                    name = name.display(),
-                    suffix = suffix
-                ));
-            }
-        }
-        if metadata.is_dir() {
-            let mut entries = fs::read_dir(&path).await?;
-            let mut dir_entries = vec![];
-            while let Some(entry) = entries.next_entry().await? {
-                dir_entries.push(entry);
-            }
-            dir_entries.sort_by_key(|e| e.file_name());
-            for entry in dir_entries.into_iter().rev() {
-                let metadata = entry.metadata().await?;
-                stack.push((depth + 1, entry.path(), metadata));
-            }
-        }
+        let Some(end) = rest[start..].find("}}") else {
+            break;
+        };
+        let name = rest[start + 2..start + end].trim();
+        let Some(value) = variables.get(name) else {
+            anyhow::bail!(
+                "Unknown variable {{{{{name}}}}} in the system prompt, expected one of {}",
+                TEMPLATE_VARIABLES.join(", ")
+            );
+        };
+        result.push_str(&rest[..start]);
+        result.push_str(value);
+        rest = &rest[start + end + 2..];
     }
-
-    result.push_str(&format!("</project_layout>\n"));
+    result.push_str(rest);
     Ok(result)
 }
 
-#[tokio::test]
-async fn test_get_project_layout() {
-    let result = get_project_layout().await.unwrap();
-    println!("{}", result);
+/// Checks that `template` only uses variables that exist.
+pub fn check_template(template: &str) -> anyhow::Result<()> {
+    let variables = TEMPLATE_VARIABLES.iter().map(|name| (*name, String::new())).collect();
+    render_template(template, &variables).map(|_| ())
+}
+
+/// The system prompt for the session: the built-in one, the configured one, or both.
+pub fn system_prompt(config: &SystemPromptConfig, variables: &HashMap<&str, String>) -> anyhow::Result<String> {
+    let built_in = render_template(SYSTEM_PROMPT, variables)?;
+    Ok(match &config.template {
+        None => built_in,
+        Some(template) if config.append => format!("{built_in}\n{}", render_template(template, variables)?),
+        Some(template) => render_template(template, variables)?,
+    })
+}
+
+pub const RULES: &str = r#"
+Be sure to include language specifiers in Markdown code blocks.
+"#;
+
+pub const CONTINUE_PROMPT: &str = r#"
+Your previous response was cut off because it hit the output token limit. Continue exactly where you left off,
+without repeating anything you already wrote.
+"#;
+
+pub const COMPACTION_PROMPT: &str = r#"
+You are compacting the history of a conversation between a user and an AI coding assistant so that it fits in the
+assistant's context window. Write a concise summary of the transcript below that the assistant can continue from.
+
+Preserve:
+- The user's goals, requests, and any constraints or preferences they stated.
+- Decisions made, conclusions reached, and work that is finished or still pending.
+- Important facts learned from tool calls, including file paths, function names, and line numbers.
+
+Omit pleasantries and raw file contents; the assistant can re-read files if it needs them. Respond with only the
+summary.
+"#;
+
+pub const COMMIT_MESSAGE_PROMPT: &str = r#"
+You write git commit messages for changes an AI coding assistant made at a user's request. Write a commit message for
+the staged changes below: a summary line of at most 72 characters in the imperative mood, then, if the change needs
+explaining, a blank line and a body wrapped at 72 characters that says what changed and why.
+
+Match the style of the repository's recent commits. Respond with only the commit message, without code fences.
+"#;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_template() {
+        let variables = HashMap::from([
+            ("os", "linux".to_string()),
+            ("tools", "read_file, list_dir".to_string()),
+        ]);
+        assert_eq!(
+            render_template("On {{os}} with {{ tools }}. {{", &variables).unwrap(),
+            "On linux with read_file, list_dir. {{"
+        );
+        let error = render_template("{{branch}}", &variables).unwrap_err().to_string();
+        assert!(error.starts_with("Unknown variable {{branch}}"), "{error}");
+        assert_eq!(
+            render_template("Jinja writes \\{{ name }} on {{os}}.", &variables).unwrap(),
+            "Jinja writes {{ name }} on linux."
+        );
+        assert!(check_template("Go templates use \\{{.Name}}").is_ok());
+
+        assert!(check_template("Today is {{date}} on {{git_branch}}").is_ok());
+        let config = SystemPromptConfig {
+            template: Some("Review code on {{os}}.".to_string()),
+            append: true,
+        };
+        let prompt = system_prompt(&config, &variables).unwrap();
+        assert!(prompt.starts_with(SYSTEM_PROMPT));
+        assert!(prompt.ends_with("\nReview code on linux."));
+    }
 }
@@ -0,0 +1,123 @@
+//! Selecting transcript text with the mouse and copying it to the clipboard.
+//!
+//! While the mouse is captured the terminal can't select text itself, so the selection is tracked here, the text is
+//! read back from a rendering of the screen, and it's handed to the terminal's clipboard with an OSC 52 escape
+//! sequence. That works over SSH too, in terminals that allow it.
+
+use std::ops::Range;
+
+use base64::{
+    Engine,
+    engine::general_purpose::STANDARD,
+};
+use ratatui::{
+    buffer::Buffer,
+    layout::{
+        Position,
+        Rect,
+    },
+};
+use unicode_width::UnicodeWidthStr;
+
+/// A range of screen cells in reading order, from where the drag started to where the mouse is now.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Selection {
+    anchor: Position,
+    head: Position,
+}
+
+impl Selection {
+    pub fn new(at: Position) -> Self {
+        Self { anchor: at, head: at }
+    }
+
+    pub fn extend(&mut self, to: Position) {
+        self.head = to;
+    }
+
+    /// Whether the mouse hasn't moved since the button went down, which makes it a click.
+    pub fn is_empty(&self) -> bool {
+        self.anchor == self.head
+    }
+
+    /// The selected cells of each row within `area`, top to bottom.
+    pub fn rows(&self, area: Rect) -> Vec<(u16, Range<u16>)> {
+        if area.is_empty() {
+            return vec![];
+        }
+        let clamp = |position: Position| Position {
+            x: position.x.clamp(area.left(), area.right() - 1),
+            y: position.y.clamp(area.top(), area.bottom() - 1),
+        };
+        let (anchor, head) = (clamp(self.anchor), clamp(self.head));
+        let (start, end) = if (anchor.y, anchor.x) <= (head.y, head.x) {
+            (anchor, head)
+        } else {
+            (head, anchor)
+        };
+        (start.y..=end.y)
+            .map(|y| {
+                let from = if y == start.y { start.x } else { area.left() };
+                let to = if y == end.y { end.x + 1 } else { area.right() };
+                (y, from..to)
+            })
+            .collect()
+    }
+
+    /// The text of the selected cells of `buffer` within `area`, one line per row.
+    pub fn text(&self, buffer: &Buffer, area: Rect) -> String {
+        let mut lines = vec![];
+        for (y, columns) in self.rows(area) {
+            let mut line = String::new();
+            // The cells a wide character covers after its first hold nothing of their own.
+            let mut covered_until = columns.start;
+            for x in columns {
+                if x < covered_until {
+                    continue;
+                }
+                let symbol = buffer[(x, y)].symbol();
+                line.push_str(symbol);
+                covered_until = x + (symbol.width() as u16).max(1);
+            }
+            lines.push(line.trim_end().to_string());
+        }
+        lines.join("\n")
+    }
+}
+
+/// The escape sequence that puts `text` on the terminal's clipboard.
+pub fn osc52_copy(text: &str) -> String {
+    format!("\x1b]52;c;{}\x07", STANDARD.encode(text))
+}
+
+#[cfg(test)]
+mod tests {
+    use ratatui::text::Line;
+
+    use super::*;
+
+    #[test]
+    fn test_selected_text() {
+        let area = Rect::new(0, 0, 10, 3);
+        let mut buffer = Buffer::empty(area);
+        buffer.set_line(0, 0, &Line::from("first row"), 10);
+        buffer.set_line(0, 1, &Line::from("日本語 ok"), 10);
+        buffer.set_line(0, 2, &Line::from("last"), 10);
+
+        // Dragged backwards, from the middle of the last row to the middle of the first.
+        let mut selection = Selection::new(Position::new(1, 2));
+        selection.extend(Position::new(6, 0));
+        assert!(!selection.is_empty());
+        assert_eq!(selection.text(&buffer, area), "row\n日本語 ok\nla");
+
+        // Only the part of the selection inside the area counts.
+        let mut selection = Selection::new(Position::new(3, 0));
+        selection.extend(Position::new(8, 2));
+        assert_eq!(selection.text(&buffer, Rect::new(2, 0, 4, 3)), "st\n本語\nst");
+    }
+
+    #[test]
+    fn test_osc52_copy() {
+        assert_eq!(osc52_copy("hi"), "\x1b]52;c;aGk=\x07");
+    }
+}
@@ -1,5 +1,10 @@
 use std::{
-    collections::HashMap,
+    collections::{
+        BTreeMap,
+        BTreeSet,
+        HashMap,
+    },
+    path::PathBuf,
     time::Duration,
 };
 
@@ -8,15 +13,16 @@ use async_openai::types::{
     ChatCompletionRequestAssistantMessage,
     ChatCompletionRequestAssistantMessageContent,
     ChatCompletionRequestMessage,
+    ChatCompletionRequestMessageContentPartText,
     ChatCompletionRequestSystemMessage,
     ChatCompletionRequestSystemMessageContent,
     ChatCompletionRequestToolMessage,
     ChatCompletionRequestToolMessageContent,
     ChatCompletionRequestUserMessage,
     ChatCompletionRequestUserMessageContent,
+    ChatCompletionRequestUserMessageContentPart,
     ChatCompletionToolType,
     FunctionCall,
-    ReasoningEffort,
 };
 use tokio::{
     sync::{
@@ -27,44 +33,130 @@ use tokio::{
 };
 
 use crate::{
+    branches::{
+        self,
+        Branches,
+    },
+    checkpoints::{
+        Checkpoint,
+        Checkpoints,
+    },
+    commit,
+    compaction::{
+        self,
+        CompactionConfig,
+    },
+    config::{
+        self,
+        Settings,
+    },
     control::ControlMessage,
+    instructions::{
+        self,
+        InstructionFile,
+        Instructions,
+    },
+    layout,
     llm_provider::{
         LLMProvider,
+        ModelConfig,
         StreamChunk,
     },
-    prompts,
-    tools::protocol::{
-        ToolRequest,
-        ToolResponse,
+    mentions,
+    prompts::{
+        self,
+        SystemPromptConfig,
     },
+    tools::{
+        executor,
+        prompts as tool_prompts,
+        protocol::{
+            ToolRequest,
+            ToolResponse,
+        },
+    },
+    types::Usage,
     ui_state::{
+        ChatUIModel,
         ChatUIModification,
         ChatUIState,
         GeneratingState,
     },
+    watcher::WorkspaceWatcher,
 };
 
+/// How many times we'll ask the model to pick up where it left off after hitting the token limit in a single response.
+const MAX_CONTINUATIONS: usize = 4;
+
+/// A text response that was cut off at the token limit and is being continued by a follow-up request.
+struct Continuation {
+    /// The UI message the continuation gets appended to.
+    ui_index: usize,
+    /// Everything the model has written so far.
+    text: String,
+}
+
+/// What a session runs with, besides the model.
+pub struct SessionConfig {
+    pub compaction: CompactionConfig,
+    /// Config profiles that `/model` can switch to.
+    pub profiles: BTreeMap<String, Settings>,
+    pub system_prompt: SystemPromptConfig,
+    /// Where the session keeps its files, like the checkpoints of file edits.
+    pub dir: PathBuf,
+    pub workspace: PathBuf,
+    /// The user's config directory, with instructions for every project.
+    pub user_config_dir: Option<PathBuf>,
+    /// Whether to watch the workspace and tell the model which files changed with each prompt.
+    pub watch_files: bool,
+}
+
 pub async fn server_loop(
     ui_tx: mpsc::UnboundedSender<ChatUIModification>,
     mut control_rx: mpsc::UnboundedReceiver<ControlMessage>,
     tool_req_tx: mpsc::UnboundedSender<ToolRequest>,
     mut tool_resp_rx: mpsc::UnboundedReceiver<ToolResponse>,
-    model: String,
-    api_key: String,
-    base_url: String,
-    reasoning_effort: Option<ReasoningEffort>,
+    mut llm_provider: LLMProvider,
+    session_config: SessionConfig,
 ) -> anyhow::Result<()> {
-    let llm_provider = LLMProvider::new(model, api_key, base_url, reasoning_effort)?;
+    let SessionConfig {
+        compaction: mut compaction_config,
+        profiles,
+        system_prompt,
+        dir: session_dir,
+        workspace,
+        user_config_dir,
+        watch_files,
+    } = session_config;
+    let mut checkpoints = Checkpoints::new(session_dir.join("checkpoints"));
+    let mut branches = Branches::new(session_dir.join("branches"));
     let ui_state = ChatUIState::new();
     let mut ui_batcher = UIBatcher::new(ui_tx, ui_state);
+    ui_batcher.apply(ChatUIModification::SetModel {
+        model: ui_model(llm_provider.config()),
+    })?;
 
+    let mut watcher = if watch_files {
+        WorkspaceWatcher::new(&workspace)
+            .inspect_err(|e| tracing::warn!("Not watching the workspace for changes: {e:?}"))
+            .ok()
+    } else {
+        None
+    };
+    let variables = prompts::template_variables(&workspace, &llm_provider.config().tools);
     let mut messages = vec![
         ChatCompletionRequestMessage::System(ChatCompletionRequestSystemMessage {
-            content: ChatCompletionRequestSystemMessageContent::Text(prompts::SYSTEM_PROMPT.to_string()),
+            content: ChatCompletionRequestSystemMessageContent::Text(prompts::system_prompt(
+                &system_prompt,
+                &variables,
+            )?),
             name: None,
         }),
         ChatCompletionRequestMessage::User(ChatCompletionRequestUserMessage {
-            content: ChatCompletionRequestUserMessageContent::Text(prompts::user_info()),
+            content: ChatCompletionRequestUserMessageContent::Text(prompts::render_template(
+                prompts::USER_INFO,
+                &variables,
+            )?),
             name: None,
         }),
         ChatCompletionRequestMessage::User(ChatCompletionRequestUserMessage {
@@ -72,28 +164,362 @@ pub async fn server_loop(
             name: None,
         }),
         ChatCompletionRequestMessage::User(ChatCompletionRequestUserMessage {
-            content: ChatCompletionRequestUserMessageContent::Text(prompts::get_project_layout().await?),
+            content: ChatCompletionRequestUserMessageContent::Text(
+                layout::get_project_layout(&workspace, watcher.is_some()).await?,
+            ),
             name: None,
         }),
     ];
+    // What each message of the preamble is, for `/context`.
+    let mut preamble_labels = ["System prompt", "Environment", "Rules", "Project layout"]
+        .map(String::from)
+        .to_vec();
+    let mut instructions = Instructions::new(workspace.clone(), user_config_dir);
+    for file in instructions.global().await? {
+        tracing::info!("Loaded instructions from {}", file.path.display());
+        preamble_labels.push(format!("Instructions from `{}`", file.path.display()));
+        messages.push(instructions_message(&file));
+    }
+    // Everything up to here is kept verbatim when compacting.
+    let preamble_len = messages.len();
 
     let mut last_request_start: Option<tokio::time::Instant> = None;
+    // Total tokens used by the most recent request, as reported by the provider.
+    let mut last_usage_tokens: Option<u32> = None;
+    let mut session_usage = SessionUsage::default();
+    let mut prompts: Vec<Prompt> = vec![];
+    // The files the agent edited that `/commit` hasn't committed yet.
+    let mut edited_files = BTreeSet::new();
+    // The files the agent edited since the workspace's changes were last taken, which it knows about already.
+    let mut agent_edits = BTreeSet::new();
 
     'shutdown: loop {
-        let Some(ControlMessage::UserMessage(user_message)) = control_rx.recv().await else {
+        let Some(control_message) = control_rx.recv().await else {
             break;
         };
+        let user_message = match control_message {
+            ControlMessage::UserMessage(user_message) => user_message,
+            ControlMessage::Compact => {
+                compact_messages(
+                    &llm_provider,
+                    &mut messages,
+                    &mut prompts,
+                    preamble_len,
+                    &compaction_config,
+                    &mut ui_batcher,
+                )
+                .await?;
+                checkpoints.forget_conversation();
+                last_usage_tokens = None;
+                continue;
+            }
+            ControlMessage::Clear => {
+                messages.truncate(preamble_len);
+                prompts.clear();
+                checkpoints.forget_conversation();
+                last_usage_tokens = None;
+                ui_batcher.apply(ChatUIModification::Clear)?;
+                continue;
+            }
+            ControlMessage::Save { path } => {
+                let text = match save_history(&messages[preamble_len..], path).await {
+                    Ok(path) => format!("Saved the conversation to `{}`.", path.display()),
+                    Err(e) => format!("**Error:** {e}"),
+                };
+                ui_batcher.apply(ChatUIModification::AddSystemMessage { text })?;
+                continue;
+            }
+            ControlMessage::ListTools => {
+                let mut text = "Tools:\n\n".to_string();
+                for tool in tool_prompts::tools(&llm_provider.config().tools) {
+                    let description = tool.function.description.unwrap_or_default();
+                    let summary = description.lines().find(|line| !line.trim().is_empty()).unwrap_or("");
+                    text.push_str(&format!("- `{}`: {summary}\n", tool.function.name));
+                }
+                ui_batcher.apply(ChatUIModification::AddSystemMessage { text })?;
+                continue;
+            }
+            ControlMessage::ShowContext => {
+                ui_batcher.apply(ChatUIModification::AddSystemMessage {
+                    text: describe_context(&preamble_labels, &messages[..preamble_len]),
+                })?;
+                continue;
+            }
+            ControlMessage::ShowCost => {
+                ui_batcher.apply(ChatUIModification::AddSystemMessage {
+                    text: session_usage.describe(),
+                })?;
+                continue;
+            }
+            ControlMessage::Notice(text) => {
+                ui_batcher.apply(ChatUIModification::AddSystemMessage { text })?;
+                continue;
+            }
+            ControlMessage::Undo => {
+                let text = match checkpoints.undo().await {
+                    Ok(Some(checkpoint)) => {
+                        tell_model_about_revert(&mut messages, &checkpoint);
+                        format!("Reverted the edits to {}.", checkpoint.describe_files())
+                    }
+                    Ok(None) => "There are no edits to undo.".to_string(),
+                    Err(e) => format!("**Error:** {e}"),
+                };
+                ui_batcher.apply(ChatUIModification::AddSystemMessage { text })?;
+                continue;
+            }
+            ControlMessage::ListCheckpoints => {
+                let text = describe_checkpoints(checkpoints.list());
+                ui_batcher.apply(ChatUIModification::AddSystemMessage { text })?;
+                continue;
+            }
+            ControlMessage::Restore { number, truncate } => {
+                let text = match checkpoints.restore(number).await {
+                    Ok(checkpoint) => match checkpoint.conversation_len {
+                        Some(conversation_len) if truncate => {
+                            messages.truncate(conversation_len);
+                            prompts.retain(|prompt| prompt.message_index < conversation_len);
+                            last_usage_tokens = None;
+                            ui_batcher.apply(ChatUIModification::Truncate { len: checkpoint.ui_len })?;
+                            format!(
+                                "Restored checkpoint {number} and went back to before \"{}\".",
+                                first_line(&checkpoint.prompt)
+                            )
+                        }
+                        _ => {
+                            tell_model_about_revert(&mut messages, &checkpoint);
+                            let mut text = format!("Restored the files as they were before checkpoint {number}.");
+                            if truncate {
+                                text.push_str(" The conversation was compacted or cleared since, so it stays.");
+                            }
+                            text
+                        }
+                    },
+                    Err(e) => format!("**Error:** {e}"),
+                };
+                ui_batcher.apply(ChatUIModification::AddSystemMessage { text })?;
+                continue;
+            }
+            ControlMessage::Rewind { ui_index, text, fork } => {
+                let Some(position) = prompts.iter().position(|prompt| prompt.ui_index == ui_index) else {
+                    ui_batcher.apply(ChatUIModification::AddSystemMessage {
+                        text: "**Error:** That prompt was compacted away, so the conversation can't go back to it."
+                            .to_string(),
+                    })?;
+                    continue;
+                };
+                let message_index = prompts[position].message_index;
+                let mut notice = None;
+                if fork {
+                    let forked = branches
+                        .fork(
+                            &messages[preamble_len..],
+                            &branch_prompts(&prompts, preamble_len),
+                            message_index - preamble_len,
+                        )
+                        .await;
+                    match forked {
+                        Ok(number) => {
+                            notice = Some(format!(
+                                "Forked into branch {number}, branch {} keeps the conversation as it was. See \
+                                 `/branches`.",
+                                branches.list()[number - 1].parent.unwrap_or_default()
+                            ));
+                        }
+                        Err(e) => {
+                            ui_batcher.apply(ChatUIModification::AddSystemMessage {
+                                text: format!("**Error:** {e}"),
+                            })?;
+                            continue;
+                        }
+                    }
+                }
+                messages.truncate(message_index);
+                prompts.truncate(position);
+                checkpoints.forget_conversation_after(message_index);
+                last_usage_tokens = None;
+                ui_batcher.apply(ChatUIModification::Truncate { len: ui_index })?;
+                if let Some(text) = notice {
+                    ui_batcher.apply(ChatUIModification::AddSystemMessage { text })?;
+                }
+                text
+            }
+            ControlMessage::ListBranches => {
+                ui_batcher.apply(ChatUIModification::AddSystemMessage {
+                    text: describe_branches(&branches),
+                })?;
+                continue;
+            }
+            ControlMessage::SwitchBranch { number } => {
+                let switched = branches
+                    .switch(
+                        &messages[preamble_len..],
+                        &branch_prompts(&prompts, preamble_len),
+                        number,
+                    )
+                    .await;
+                let branch = match switched {
+                    Ok(branch) => branch,
+                    Err(e) => {
+                        ui_batcher.apply(ChatUIModification::AddSystemMessage {
+                            text: format!("**Error:** {e}"),
+                        })?;
+                        continue;
+                    }
+                };
+                let (transcript, ui_indices) = branches::transcript(branch);
+                messages.truncate(preamble_len);
+                messages.extend(branch.messages.iter().cloned());
+                prompts = branch
+                    .prompts
+                    .iter()
+                    .zip(ui_indices)
+                    .map(|(index, ui_index)| Prompt {
+                        ui_index,
+                        message_index: preamble_len + index,
+                    })
+                    .collect();
+                checkpoints.forget_conversation();
+                last_usage_tokens = None;
+                ui_batcher.apply(ChatUIModification::Clear)?;
+                for modification in transcript {
+                    ui_batcher.apply(modification)?;
+                }
+                ui_batcher.apply(ChatUIModification::AddSystemMessage {
+                    text: format!("Switched to branch {number}."),
+                })?;
+                continue;
+            }
+            ControlMessage::DraftCommit { run_hooks } => {
+                ui_batcher.apply(ChatUIModification::SetGeneratingState {
+                    state: GeneratingState::Generating,
+                })?;
+                let requests = prompts
+                    .iter()
+                    .map(|prompt| first_line(&compaction::message_text(&messages[prompt.message_index])).to_string())
+                    .collect::<Vec<_>>();
+                let files = edited_files.iter().cloned().collect::<Vec<_>>();
+                let drafted: anyhow::Result<_> = try {
+                    let staged = tokio::task::spawn_blocking({
+                        let workspace = workspace.clone();
+                        move || commit::stage(&workspace, &files)
+                    })
+                    .await
+                    .map_err(anyhow::Error::from)??;
+                    let message = commit::draft_message(&llm_provider, &staged, &requests).await?;
+                    (staged, message)
+                };
+                ui_batcher.apply(ChatUIModification::SetGeneratingState {
+                    state: GeneratingState::Idle,
+                })?;
+                match drafted {
+                    Ok((staged, message)) => ui_batcher.apply(ChatUIModification::ProposeCommit {
+                        message,
+                        branch: staged.branch,
+                        files: staged.files,
+                        run_hooks,
+                    })?,
+                    Err(e) => ui_batcher.apply(ChatUIModification::AddSystemMessage {
+                        text: format!("**Error:** {e}"),
+                    })?,
+                }
+                continue;
+            }
+            ControlMessage::Commit { message, run_hooks } => {
+                let session = session_dir
+                    .file_name()
+                    .map(|name| name.to_string_lossy().into_owned())
+                    .unwrap_or_default();
+                let files = edited_files.iter().cloned().collect::<Vec<_>>();
+                // The hooks may take a while.
+                ui_batcher.apply(ChatUIModification::SetGeneratingState {
+                    state: GeneratingState::Generating,
+                })?;
+                let committed = tokio::task::spawn_blocking({
+                    let workspace = workspace.clone();
+                    move || commit::commit(&workspace, &files, &message, &session, run_hooks)
+                })
+                .await?;
+                ui_batcher.apply(ChatUIModification::SetGeneratingState {
+                    state: GeneratingState::Idle,
+                })?;
+                let text = match committed {
+                    Ok(committed) => {
+                        edited_files.clear();
+                        format!(
+                            "Committed `{}` on `{}`: {}",
+                            &committed.id.to_string()[..7],
+                            committed.branch,
+                            committed.summary
+                        )
+                    }
+                    Err(e) => format!("**Error:** {e:#}"),
+                };
+                ui_batcher.apply(ChatUIModification::AddSystemMessage { text })?;
+                continue;
+            }
+            ControlMessage::SetModel { name, reasoning_effort } => {
+                let text = match set_model(&mut llm_provider, &profiles, name, reasoning_effort) {
+                    Ok((text, compaction)) => {
+                        if let Some(compaction) = compaction {
+                            compaction_config = compaction;
+                        }
+                        text
+                    }
+                    Err(e) => format!("**Error:** {e}"),
+                };
+                ui_batcher.apply(ChatUIModification::AddSystemMessage { text })?;
+                ui_batcher.apply(ChatUIModification::SetModel {
+                    model: ui_model(llm_provider.config()),
+                })?;
+                continue;
+            }
+        };
 
+        let attachments = mentions::attachments(&user_message, &workspace).await;
+        // The files that changed since the last prompt go with this one rather than between tool calls, where they'd
+        // be taken for a turn of their own.
+        let changes = watcher.as_mut().and_then(|watcher| watcher.take_changes(&agent_edits));
+        agent_edits.clear();
+        let content = if attachments.is_empty() && changes.is_none() {
+            ChatCompletionRequestUserMessageContent::Text(user_message.clone())
+        } else {
+            // The message as typed, then one part per attached file, then the changed files.
+            let parts = std::iter::once(user_message.clone())
+                .chain(attachments.iter().map(|attachment| attachment.content.clone()))
+                .chain(changes)
+                .map(|text| {
+                    ChatCompletionRequestUserMessageContentPart::Text(ChatCompletionRequestMessageContentPartText {
+                        text,
+                    })
+                })
+                .collect();
+            ChatCompletionRequestUserMessageContent::Array(parts)
+        };
+        let ui_index = ui_batcher.ui_state.next_message_index();
+        checkpoints.begin_turn(&user_message, messages.len(), ui_index);
+        prompts.push(Prompt {
+            ui_index,
+            message_index: messages.len(),
+        });
         messages.push(ChatCompletionRequestMessage::User(ChatCompletionRequestUserMessage {
-            content: ChatCompletionRequestUserMessageContent::Text(user_message.clone()),
+            content,
             name: None,
         }));
         let modification = ChatUIModification::AddUserMessage {
             text: user_message.clone(),
+            attachments: attachments.into_iter().map(|attachment| attachment.label).collect(),
         };
         ui_batcher.apply(modification)?;
 
         let mut in_progress_tool_calls = HashMap::new();
+        // The files the in-progress tool calls edit, which count as edited once the call succeeds.
+        let mut in_progress_edits: HashMap<String, PathBuf> = HashMap::new();
+        // Files and directories this turn's tool calls touched, whose instructions haven't been looked for yet.
+        let mut touched_paths: Vec<PathBuf> = vec![];
+        // Reasoning behind this turn's tool-calling assistant messages, keyed by their first tool call id.
+        let mut turn_reasoning = HashMap::new();
+        let mut continuation: Option<Continuation> = None;
+        let mut continuations = 0;
 
         loop {
             while !in_progress_tool_calls.is_empty() {
@@ -103,6 +529,12 @@ pub async fn server_loop(
                 let Some(message_index) = in_progress_tool_calls.remove(&id) else {
                     anyhow::bail!("Tool call {id} is not in progress");
                 };
+                if let Some(path) = in_progress_edits.remove(&id)
+                    && result.is_ok()
+                {
+                    agent_edits.insert(path.clone());
+                    edited_files.insert(path);
+                }
                 let formatted_result = match result {
                     Ok(ref result) => result.clone(),
                     Err(ref error) => format!("Error: {error}"),
@@ -117,6 +549,39 @@ pub async fn server_loop(
                 };
                 ui_batcher.apply(modification.clone())?;
             }
+            // After all of the tool results, since those have to follow the tool calls.
+            for path in touched_paths.drain(..) {
+                match instructions.scoped(&path).await {
+                    Ok(files) => {
+                        for file in files {
+                            let message = instructions_message(&file);
+                            if !messages.contains(&message) {
+                                tracing::info!("Loaded instructions from {}", file.path.display());
+                                messages.push(message);
+                            }
+                        }
+                    }
+                    Err(e) => tracing::warn!("Failed to load the instructions for {}: {e:?}", path.display()),
+                }
+            }
+
+            let context_tokens = last_usage_tokens
+                .unwrap_or(0)
+                .max(compaction::estimate_tokens(&messages));
+            if compaction_config.should_compact(context_tokens) {
+                tracing::info!("Context at ~{context_tokens} tokens, compacting");
+                compact_messages(
+                    &llm_provider,
+                    &mut messages,
+                    &mut prompts,
+                    preamble_len,
+                    &compaction_config,
+                    &mut ui_batcher,
+                )
+                .await?;
+                checkpoints.forget_conversation();
+                last_usage_tokens = None;
+            }
 
             tracing::info!("Streaming LLM response");
             for (i, message) in messages.iter().enumerate() {
@@ -141,13 +606,38 @@ pub async fn server_loop(
             };
             ui_batcher.apply(modification)?;
 
-            let stream = llm_provider.stream(messages.clone()).await;
+            let mut request_messages = messages.clone();
+            let (mut current_system_message_index, mut current_system_message_text) = match continuation.take() {
+                Some(Continuation { ui_index, text }) => {
+                    request_messages.push(ChatCompletionRequestMessage::Assistant(
+                        ChatCompletionRequestAssistantMessage {
+                            content: Some(ChatCompletionRequestAssistantMessageContent::Text(text.clone())),
+                            refusal: None,
+                            name: None,
+                            audio: None,
+                            tool_calls: None,
+                            #[allow(deprecated)]
+                            function_call: None,
+                        },
+                    ));
+                    request_messages.push(ChatCompletionRequestMessage::User(ChatCompletionRequestUserMessage {
+                        content: ChatCompletionRequestUserMessageContent::Text(prompts::CONTINUE_PROMPT.to_string()),
+                        name: None,
+                    }));
+                    (Some(ui_index), text)
+                }
+                None => (None, String::new()),
+            };
+
+            let stream = llm_provider.stream(request_messages, turn_reasoning.clone()).await;
             tokio::pin!(stream);
 
-            let mut current_system_message_index = None;
-            let mut current_system_message_text = String::new();
+            let mut current_reasoning_index = None;
+            let mut current_reasoning_text = String::new();
 
-            let mut streaming_tool_calls = HashMap::new();
+            // Ordered by index so tool calls are executed and reported in the order the model made them.
+            let mut streaming_tool_calls = BTreeMap::new();
+            let mut truncated = false;
 
             #[allow(unused)]
             struct StreamingToolCall {
@@ -159,8 +649,15 @@ pub async fn server_loop(
                 ui_index: usize,
             }
 
+            let mut stream_error = None;
             while let Some(chunk_r) = stream.recv().await {
-                let chunk = chunk_r?;
+                let chunk = match chunk_r {
+                    Ok(chunk) => chunk,
+                    Err(e) => {
+                        stream_error = Some(e);
+                        break;
+                    }
+                };
                 tracing::debug!("Received chunk: {:?}", chunk);
                 match chunk {
                     StreamChunk::SystemMessage(text) => {
@@ -178,6 +675,18 @@ pub async fn server_loop(
                             }
                         }
                     }
+                    StreamChunk::Reasoning(text) => {
+                        current_reasoning_text.push_str(&text);
+                        match current_reasoning_index {
+                            Some(index) => {
+                                ui_batcher.apply(ChatUIModification::AppendReasoning { index, text })?;
+                            }
+                            None => {
+                                current_reasoning_index = Some(ui_batcher.ui_state.next_message_index());
+                                ui_batcher.apply(ChatUIModification::AddReasoning { text })?;
+                            }
+                        }
+                    }
                     StreamChunk::StartToolCall { index, id, name } => {
                         anyhow::ensure!(
                             !in_progress_tool_calls.contains_key(&id),
@@ -214,6 +723,13 @@ pub async fn server_loop(
                         let modification = ChatUIModification::SetPerformanceStats { stats: Some(stats) };
                         ui_batcher.apply(modification)?;
                     }
+                    StreamChunk::Usage(usage) => {
+                        last_usage_tokens = Some(usage.total_tokens);
+                        session_usage.add(&usage);
+                    }
+                    StreamChunk::Truncated => {
+                        truncated = true;
+                    }
                 }
             }
 
@@ -223,18 +739,98 @@ pub async fn server_loop(
                 in_progress_tool_calls.len()
             );
 
+            // A failed response is dropped from the history so the user can simply try again. Tool calls it
+            // started are never executed.
+            if let Some(error) = stream_error {
+                tracing::error!("LLM response failed: {error:?}");
+                for (_, tool_call) in streaming_tool_calls {
+                    ui_batcher.apply(ChatUIModification::StartToolCallExecution {
+                        index: tool_call.ui_index,
+                    })?;
+                    ui_batcher.apply(ChatUIModification::CompleteToolCall {
+                        index: tool_call.ui_index,
+                        result: Err("not executed, the response failed".to_string()),
+                    })?;
+                }
+                ui_batcher.apply(ChatUIModification::AddSystemMessage {
+                    text: format!("**Error:** {error}"),
+                })?;
+                ui_batcher.apply(ChatUIModification::SetGeneratingState {
+                    state: GeneratingState::Idle,
+                })?;
+                break;
+            }
+
+            if truncated && streaming_tool_calls.is_empty() {
+                match current_system_message_index {
+                    Some(ui_index) if continuations < MAX_CONTINUATIONS => {
+                        tracing::info!("Response truncated, requesting continuation {}", continuations + 1);
+                        continuations += 1;
+                        continuation = Some(Continuation {
+                            ui_index,
+                            text: current_system_message_text,
+                        });
+                        continue;
+                    }
+                    _ => {
+                        tracing::warn!("Response truncated, giving up after {continuations} continuations");
+                        ui_batcher.apply(ChatUIModification::AddSystemMessage {
+                            text: "_(response cut off at the token limit)_".to_string(),
+                        })?;
+                    }
+                }
+            }
+
+            // Tool calls whose arguments were cut off at the token limit can't be executed, and edits of files that
+            // couldn't be checkpointed aren't, so we answer them ourselves once the assistant message is in the
+            // history.
+            let mut unexecuted_tool_results = vec![];
             let mut tool_calls = vec![];
             for (_, tool_call) in streaming_tool_calls {
                 let modification = ChatUIModification::StartToolCallExecution {
                     index: tool_call.ui_index,
                 };
                 ui_batcher.apply(modification.clone())?;
-                in_progress_tool_calls.insert(tool_call.id.clone(), tool_call.ui_index);
-                tool_req_tx.send(ToolRequest::ToolCall {
-                    id: tool_call.id.clone(),
-                    name: tool_call.name.clone(),
-                    args: tool_call.args.clone(),
-                })?;
+                if truncated && serde_json::from_str::<serde_json::Value>(&tool_call.args).is_err() {
+                    let error = truncated_tool_call_error(&tool_call.name, llm_provider.max_tokens());
+                    ui_batcher.apply(ChatUIModification::CompleteToolCall {
+                        index: tool_call.ui_index,
+                        result: Err("arguments truncated at the token limit".to_string()),
+                    })?;
+                    unexecuted_tool_results.push((tool_call.id.clone(), error));
+                } else {
+                    // Edits outside of the workspace are refused by the executor, so there's nothing to keep for them.
+                    let edited = tool_prompts::mutated_file(&tool_call.name, &tool_call.args)
+                        .and_then(|path| executor::workspace_path(&workspace, path).ok());
+                    let snapshot = match &edited {
+                        Some(path) => checkpoints.snapshot(path).await,
+                        None => Ok(()),
+                    };
+                    match snapshot {
+                        // An edit /undo couldn't revert isn't made at all.
+                        Err(e) => {
+                            tracing::warn!("Failed to snapshot {edited:?} before editing it: {e:?}");
+                            ui_batcher.apply(ChatUIModification::CompleteToolCall {
+                                index: tool_call.ui_index,
+                                result: Err("not executed, the file couldn't be checkpointed".to_string()),
+                            })?;
+                            let error = format!("The file wasn't edited, it couldn't be checkpointed first: {e}");
+                            unexecuted_tool_results.push((tool_call.id.clone(), error));
+                        }
+                        Ok(()) => {
+                            if let Some(path) = edited {
+                                in_progress_edits.insert(tool_call.id.clone(), path);
+                            }
+                            touched_paths.extend(tool_prompts::touched_path(&tool_call.name, &tool_call.args));
+                            in_progress_tool_calls.insert(tool_call.id.clone(), tool_call.ui_index);
+                            tool_req_tx.send(ToolRequest::ToolCall {
+                                id: tool_call.id.clone(),
+                                name: tool_call.name.clone(),
+                                args: tool_call.args.clone(),
+                            })?;
+                        }
+                    }
+                }
                 tool_calls.push(ChatCompletionMessageToolCall {
                     id: tool_call.id,
                     r#type: ChatCompletionToolType::Function,
@@ -252,6 +848,11 @@ pub async fn server_loop(
                 } else {
                     None
                 };
+                if let Some(first_tool_call) = tool_calls.first()
+                    && !current_reasoning_text.is_empty()
+                {
+                    turn_reasoning.insert(first_tool_call.id.clone(), current_reasoning_text);
+                }
                 let tool_calls = if !tool_calls.is_empty() { Some(tool_calls) } else { None };
                 messages.push(ChatCompletionRequestMessage::Assistant(
                     ChatCompletionRequestAssistantMessage {
@@ -265,6 +866,13 @@ pub async fn server_loop(
                     },
                 ))
             }
+            let has_unexecuted_tool_calls = !unexecuted_tool_results.is_empty();
+            for (id, error) in unexecuted_tool_results {
+                messages.push(ChatCompletionRequestMessage::Tool(ChatCompletionRequestToolMessage {
+                    content: ChatCompletionRequestToolMessageContent::Text(error),
+                    tool_call_id: id,
+                }));
+            }
 
             // Set generating state back to Idle
             let modification = ChatUIModification::SetGeneratingState {
@@ -272,7 +880,7 @@ pub async fn server_loop(
             };
             ui_batcher.apply(modification)?;
 
-            if in_progress_tool_calls.is_empty() {
+            if in_progress_tool_calls.is_empty() && !has_unexecuted_tool_calls {
                 break;
             }
         }
@@ -281,6 +889,292 @@ pub async fn server_loop(
     anyhow::Ok(())
 }
 
+fn instructions_message(file: &InstructionFile) -> ChatCompletionRequestMessage {
+    ChatCompletionRequestMessage::User(ChatCompletionRequestUserMessage {
+        content: ChatCompletionRequestUserMessageContent::Text(file.message_text()),
+        name: None,
+    })
+}
+
+/// A prompt the user typed: where it is in the transcript, and in the conversation.
+#[derive(Debug, Clone, Copy)]
+struct Prompt {
+    ui_index: usize,
+    message_index: usize,
+}
+
+/// Finds the prompts again in the conversation after compaction rewrote it. Recent turns are kept as they were, so
+/// matching from the end finds them; the summarized ones are gone.
+fn find_prompts(
+    prompts: &mut Vec<Prompt>,
+    before: &[ChatCompletionRequestMessage],
+    after: &[ChatCompletionRequestMessage],
+) {
+    let mut end = after.len();
+    let mut found = vec![];
+    for prompt in prompts.iter().rev() {
+        let message = &before[prompt.message_index];
+        let Some(index) = after[..end].iter().rposition(|candidate| candidate == message) else {
+            break;
+        };
+        found.push(Prompt {
+            message_index: index,
+            ..*prompt
+        });
+        end = index;
+    }
+    found.reverse();
+    *prompts = found;
+}
+
+/// The prompts' indices in the conversation without the preamble, which is how branches keep them.
+fn branch_prompts(prompts: &[Prompt], preamble_len: usize) -> Vec<usize> {
+    prompts
+        .iter()
+        .map(|prompt| prompt.message_index - preamble_len)
+        .collect()
+}
+
+/// The messages the conversation starts with, as the model gets them.
+fn describe_context(labels: &[String], preamble: &[ChatCompletionRequestMessage]) -> String {
+    let mut text = String::new();
+    for (label, message) in labels.iter().zip(preamble) {
+        let tokens = compaction::estimate_tokens(std::slice::from_ref(message));
+        text.push_str(&format!(
+            "### {label} (~{tokens} tokens)\n\n````text\n{}\n````\n\n",
+            compaction::message_text(message).trim_end()
+        ));
+    }
+    text
+}
+
+fn describe_branches(branches: &Branches) -> String {
+    let mut text = "Branches, `/branch <n>` switches to one:\n\n".to_string();
+    for (index, branch) in branches.list().iter().enumerate() {
+        text.push_str(&format!("{}. \"{}\"", index + 1, branch.title()));
+        if let Some(parent) = branch.parent {
+            text.push_str(&format!(", forked from {parent}"));
+        }
+        if index + 1 == branches.current() {
+            text.push_str(" (current)");
+        }
+        text.push('\n');
+    }
+    text
+}
+
+/// Tokens used by all requests of the session, as reported by the provider.
+#[derive(Debug, Default)]
+struct SessionUsage {
+    requests: u32,
+    prompt_tokens: u64,
+    cached_tokens: u64,
+    completion_tokens: u64,
+}
+
+impl SessionUsage {
+    fn add(&mut self, usage: &Usage) {
+        self.requests += 1;
+        self.prompt_tokens += u64::from(usage.prompt_tokens);
+        self.completion_tokens += u64::from(usage.completion_tokens);
+        if let Some(details) = &usage.prompt_tokens_details {
+            self.cached_tokens += u64::from(details.cached_tokens);
+        }
+    }
+
+    fn describe(&self) -> String {
+        if self.requests == 0 {
+            return "The provider hasn't reported any token usage yet.".to_string();
+        }
+        format!(
+            "Used {} prompt tokens ({} cached) and {} completion tokens over {} requests.",
+            self.prompt_tokens, self.cached_tokens, self.completion_tokens, self.requests
+        )
+    }
+}
+
+/// Lets the model know its edits were reverted, since the conversation still says it made them.
+fn tell_model_about_revert(messages: &mut Vec<ChatCompletionRequestMessage>, checkpoint: &Checkpoint) {
+    let text = format!(
+        "The user reverted the edits you made while working on \"{}\". These files are back to how they were before: \
+         {}",
+        first_line(&checkpoint.prompt),
+        checkpoint.describe_files()
+    );
+    messages.push(ChatCompletionRequestMessage::User(ChatCompletionRequestUserMessage {
+        content: ChatCompletionRequestUserMessageContent::Text(text),
+        name: None,
+    }));
+}
+
+fn describe_checkpoints(checkpoints: &[Checkpoint]) -> String {
+    if checkpoints.is_empty() {
+        return "No checkpoints yet, the agent hasn't edited any files.".to_string();
+    }
+    let mut text = "Checkpoints, `/restore <n>` puts the files back as they were before one:\n\n".to_string();
+    for (index, checkpoint) in checkpoints.iter().enumerate() {
+        text.push_str(&format!(
+            "{}. \"{}\": {}\n",
+            index + 1,
+            first_line(&checkpoint.prompt),
+            checkpoint.describe_files()
+        ));
+    }
+    text
+}
+
+fn first_line(text: &str) -> &str {
+    text.lines().next().unwrap_or_default()
+}
+
+/// Writes the conversation (without the preamble) as JSON, to `.agent/sessions/<timestamp>.json` unless given a
+/// path. Returns where it went.
+async fn save_history(messages: &[ChatCompletionRequestMessage], path: Option<PathBuf>) -> anyhow::Result<PathBuf> {
+    let path = match path {
+        Some(path) => path,
+        None => {
+            let timestamp = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH)?;
+            PathBuf::from(".agent")
+                .join("sessions")
+                .join(format!("{}.json", timestamp.as_secs()))
+        }
+    };
+    if let Some(parent) = path.parent()
+        && !parent.as_os_str().is_empty()
+    {
+        tokio::fs::create_dir_all(parent).await?;
+    }
+    tokio::fs::write(&path, serde_json::to_string_pretty(messages)?).await?;
+    Ok(path)
+}
+
+/// Handles `/model`: switches to the profile called `name` if there is one, or else to the model called `name` on the
+/// current provider. Returns what to tell the user.
+fn set_model(
+    llm_provider: &mut LLMProvider,
+    profiles: &BTreeMap<String, Settings>,
+    name: Option<String>,
+    reasoning_effort: Option<String>,
+) -> anyhow::Result<(String, Option<CompactionConfig>)> {
+    let current = llm_provider.config();
+    let Some(name) = name else {
+        let profiles = if profiles.is_empty() {
+            "none".to_string()
+        } else {
+            profiles.keys().cloned().collect::<Vec<_>>().join(", ")
+        };
+        let text = format!(
+            "Using **{}** at {}. Profiles: {profiles}. Switch with `/model <name or profile> [effort]`.",
+            model_description(current),
+            current.base_url
+        );
+        return Ok((text, None));
+    };
+
+    // A profile brings its own context window, so when to compact changes with it.
+    let (mut config, compaction) = match profiles.get(&name) {
+        Some(settings) => {
+            let resolved = settings.clone().resolve()?;
+            let config = ModelConfig {
+                // The tool executor was set up with the tools enabled at startup.
+                tools: current.tools.clone(),
+                ..resolved.model
+            };
+            (config, Some(resolved.compaction))
+        }
+        None => {
+            let config = ModelConfig {
+                model: name,
+                ..current.clone()
+            };
+            (config, None)
+        }
+    };
+    if let Some(reasoning_effort) = reasoning_effort {
+        config.reasoning_effort = config::parse_reasoning_effort(&reasoning_effort)?;
+    }
+    tracing::info!("Switching to model {} at {}", config.model, config.base_url);
+    let text = format!("Switched to **{}**.", model_description(&config));
+    llm_provider.set_config(config);
+    Ok((text, compaction))
+}
+
+fn model_description(config: &ModelConfig) -> String {
+    match config.reasoning_effort_name() {
+        Some(effort) => format!("{} ({effort} effort)", config.model),
+        None => config.model.clone(),
+    }
+}
+
+fn ui_model(config: &ModelConfig) -> ChatUIModel {
+    ChatUIModel {
+        name: config.model.clone(),
+        reasoning_effort: config.reasoning_effort_name().map(str::to_string),
+    }
+}
+
+/// The result we report back to the model for a tool call whose arguments were cut off mid-stream.
+fn truncated_tool_call_error(name: &str, max_tokens: Option<u32>) -> String {
+    let limit = match max_tokens {
+        Some(max_tokens) => format!("the output token limit ({max_tokens} tokens)"),
+        None => "the output token limit".to_string(),
+    };
+    serde_json::json!({
+        "error": "arguments_truncated",
+        "tool": name,
+        "message": format!(
+            "The arguments for this {name} call were cut off because your response hit {limit}, so it was not \
+             executed. Split the work into smaller tool calls and try again."
+        ),
+    })
+    .to_string()
+}
+
+async fn compact_messages(
+    llm_provider: &LLMProvider,
+    messages: &mut Vec<ChatCompletionRequestMessage>,
+    prompts: &mut Vec<Prompt>,
+    preamble_len: usize,
+    compaction_config: &CompactionConfig,
+    ui_batcher: &mut UIBatcher,
+) -> anyhow::Result<()> {
+    ui_batcher.apply(ChatUIModification::SetGeneratingState {
+        state: GeneratingState::Compacting,
+    })?;
+    let before = messages.clone();
+    let turns = prompts.iter().map(|prompt| prompt.message_index).collect::<Vec<_>>();
+    let outcome = compaction::compact(llm_provider, messages, preamble_len, &turns, compaction_config).await?;
+    // The instructions of the subdirectories the agent worked in would otherwise go with the turns that loaded them,
+    // and only come back once it touched a file there again. They go right after the summary, where those turns were.
+    let summarized_instructions = before
+        .iter()
+        .enumerate()
+        .skip(preamble_len)
+        .filter(|(index, message)| {
+            !turns.contains(index)
+                && instructions::is_instructions(&compaction::message_text(message))
+                && !messages.contains(message)
+        })
+        .map(|(_, message)| message.clone())
+        .collect::<Vec<_>>();
+    if !summarized_instructions.is_empty() {
+        messages.splice(preamble_len + 1..preamble_len + 1, summarized_instructions);
+    }
+    find_prompts(prompts, &before, messages);
+    if let Some(outcome) = outcome {
+        ui_batcher.apply(ChatUIModification::AddCompactionMarker {
+            summarized_turns: outcome.summarized_turns,
+            elided_tool_results: outcome.elided_tool_results,
+            tokens_before: outcome.tokens_before,
+            tokens_after: outcome.tokens_after,
+        })?;
+    }
+    ui_batcher.apply(ChatUIModification::SetGeneratingState {
+        state: GeneratingState::Idle,
+    })?;
+    Ok(())
+}
+
 struct UIBatcher {
     _sender: tokio::task::JoinHandle<anyhow::Result<()>>,
     _shutdown_tx: oneshot::Sender<()>,
@@ -328,6 +1222,9 @@ impl UIBatcher {
                     Self::merge_modifications(&mut deferred_modifications, modification);
                 }
                 _ = &mut shutdown_rx => {
+                    while let Ok(modification) = modifications_rx.try_recv() {
+                        Self::merge_modifications(&mut deferred_modifications, modification);
+                    }
                     break;
                 }
             }
@@ -344,11 +1241,24 @@ impl UIBatcher {
         if let ChatUIModification::AppendSystemMessage { text: ref new_text, .. } = modification {
             match deferred_modifications.last_mut() {
                 Some(ChatUIModification::AddSystemMessage { text }) => {
-                    text.push_str(&new_text);
+                    text.push_str(new_text);
                     return;
                 }
                 Some(ChatUIModification::AppendSystemMessage { text, .. }) => {
-                    text.push_str(&new_text);
+                    text.push_str(new_text);
+                    return;
+                }
+                _ => (),
+            }
+        }
+        if let ChatUIModification::AppendReasoning { text: ref new_text, .. } = modification {
+            match deferred_modifications.last_mut() {
+                Some(ChatUIModification::AddReasoning { text }) => {
+                    text.push_str(new_text);
+                    return;
+                }
+                Some(ChatUIModification::AppendReasoning { text, .. }) => {
+                    text.push_str(new_text);
                     return;
                 }
                 _ => (),
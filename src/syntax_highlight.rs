@@ -1,3 +1,5 @@
+use std::sync::LazyLock;
+
 use ratatui::text::{
     Line,
     Span,
@@ -12,6 +14,9 @@ use syntect::{
 use syntect_tui::into_span;
 use tracing::debug;
 
+/// Loading the syntax and theme sets takes milliseconds, far too long to do for every message on every frame.
+static SHARED: LazyLock<SyntaxHighlighter> = LazyLock::new(SyntaxHighlighter::new);
+
 pub struct SyntaxHighlighter {
     syntax_set: SyntaxSet,
     theme_set: ThemeSet,
@@ -25,6 +30,11 @@ impl SyntaxHighlighter {
         Self { syntax_set, theme_set }
     }
 
+    /// The highlighter shared by everything that renders code, loaded on first use.
+    pub fn shared() -> &'static Self {
+        &SHARED
+    }
+
     pub fn highlight_code<'a>(&self, code: &'a str, language: Option<&str>) -> Text<'a> {
         debug!(
             "highlight_code called with language: {:?}, code length: {}",
@@ -45,12 +55,10 @@ impl SyntaxHighlighter {
                     return Some(syntax);
                 }
                 let path = std::path::Path::new(lang);
-                if let Some(ext) = path.extension() {
-                    if let Some(ext) = ext.to_str() {
-                        debug!("Looking for syntax by extension: {}", ext);
-                        if let Some(syntax) = self.syntax_set.find_syntax_by_extension(ext) {
-                            return Some(syntax);
-                        }
+                if let Some(ext) = path.extension().and_then(|ext| ext.to_str()) {
+                    debug!("Looking for syntax by extension: {}", ext);
+                    if let Some(syntax) = self.syntax_set.find_syntax_by_extension(ext) {
+                        return Some(syntax);
                     }
                 }
                 None
@@ -0,0 +1,47 @@
+//! Conversation messages for unit tests.
+
+use async_openai::types::{
+    ChatCompletionMessageToolCall,
+    ChatCompletionRequestAssistantMessage,
+    ChatCompletionRequestMessage,
+    ChatCompletionRequestToolMessage,
+    ChatCompletionRequestToolMessageContent,
+    ChatCompletionRequestUserMessage,
+    ChatCompletionRequestUserMessageContent,
+    ChatCompletionToolType,
+    FunctionCall,
+};
+
+pub fn user(text: &str) -> ChatCompletionRequestMessage {
+    ChatCompletionRequestMessage::User(ChatCompletionRequestUserMessage {
+        content: ChatCompletionRequestUserMessageContent::Text(text.to_string()),
+        name: None,
+    })
+}
+
+/// An assistant message making one tool call, with no arguments.
+pub fn tool_call(id: &str, name: &str) -> ChatCompletionRequestMessage {
+    #[allow(deprecated)]
+    ChatCompletionRequestMessage::Assistant(ChatCompletionRequestAssistantMessage {
+        content: None,
+        refusal: None,
+        name: None,
+        audio: None,
+        tool_calls: Some(vec![ChatCompletionMessageToolCall {
+            id: id.to_string(),
+            r#type: ChatCompletionToolType::Function,
+            function: FunctionCall {
+                name: name.to_string(),
+                arguments: "{}".to_string(),
+            },
+        }]),
+        function_call: None,
+    })
+}
+
+pub fn tool_result(id: &str, text: &str) -> ChatCompletionRequestMessage {
+    ChatCompletionRequestMessage::Tool(ChatCompletionRequestToolMessage {
+        content: ChatCompletionRequestToolMessageContent::Text(text.to_string()),
+        tool_call_id: id.to_string(),
+    })
+}
@@ -0,0 +1,729 @@
+//! Code intelligence tools backed by tree-sitter, so the agent can find its way around a codebase by its functions and
+//! types instead of reading whole files: an outline of a file, where something is defined, where a name is used, and
+//! the source of a single item. Rust, TypeScript, JavaScript, Python and Go are understood.
+//!
+//! Definitions are found by parsing, but references only by name: an identifier with the same name counts whatever it
+//! refers to. Searches go through the same files as the project layout, leaving out hidden and gitignored ones.
+
+use std::{
+    ops::Range,
+    path::{
+        Path,
+        PathBuf,
+    },
+};
+
+use anyhow::Context;
+use tree_sitter::{
+    Node,
+    Parser,
+    Tree,
+};
+
+use crate::{
+    layout::{
+        self,
+        plural,
+    },
+    tools::prompts::{
+        FindSymbolArgs,
+        OutlineFileArgs,
+        ReadSymbolArgs,
+    },
+};
+
+/// How many files a search looks through before it stops.
+const MAX_SEARCHED_FILES: usize = 5_000;
+/// Files bigger than this are left out of searches, they're most likely generated or bundled.
+const MAX_FILE_BYTES: u64 = 1_000_000;
+const MAX_DEFINITIONS: usize = 50;
+const MAX_REFERENCES: usize = 200;
+/// How much of a line with a reference is shown.
+const MAX_REFERENCE_LINE_CHARS: usize = 200;
+/// How many characters of an item `read_symbol` shows.
+const MAX_SYMBOL_CHARS: usize = 30_000;
+
+/// The nodes that are just one item wrapped in something else, like an export or decorators. The comments before an
+/// item come before its wrapper.
+const WRAPPER_KINDS: &[&str] = &[
+    "decorated_definition",
+    "export_statement",
+    "lexical_declaration",
+    "type_declaration",
+    "variable_declaration",
+];
+/// The nodes that can document the item after them.
+const DOC_KINDS: &[&str] = &[
+    "attribute_item",
+    "block_comment",
+    "comment",
+    "decorator",
+    "line_comment",
+];
+/// The nodes whose text is a name, for finding references.
+const IDENTIFIER_KINDS: &[&str] = &[
+    "field_identifier",
+    "identifier",
+    "package_identifier",
+    "property_identifier",
+    "shorthand_property_identifier",
+    "shorthand_property_identifier_pattern",
+    "type_identifier",
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Lang {
+    Rust,
+    TypeScript,
+    Tsx,
+    JavaScript,
+    Python,
+    Go,
+}
+
+impl Lang {
+    fn of(path: &Path) -> Option<Self> {
+        match path.extension()?.to_str()? {
+            "rs" => Some(Self::Rust),
+            "ts" | "mts" | "cts" => Some(Self::TypeScript),
+            "tsx" => Some(Self::Tsx),
+            "js" | "jsx" | "mjs" | "cjs" => Some(Self::JavaScript),
+            "py" | "pyi" => Some(Self::Python),
+            "go" => Some(Self::Go),
+            _ => None,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Self::Rust => "Rust",
+            Self::TypeScript | Self::Tsx => "TypeScript",
+            Self::JavaScript => "JavaScript",
+            Self::Python => "Python",
+            Self::Go => "Go",
+        }
+    }
+
+    fn language(self) -> tree_sitter::Language {
+        match self {
+            Self::Rust => tree_sitter_rust::LANGUAGE.into(),
+            Self::TypeScript => tree_sitter_typescript::LANGUAGE_TYPESCRIPT.into(),
+            // JavaScript is close enough to TypeScript for finding items, and JSX needs the TSX grammar.
+            Self::Tsx | Self::JavaScript => tree_sitter_typescript::LANGUAGE_TSX.into(),
+            Self::Python => tree_sitter_python::LANGUAGE.into(),
+            Self::Go => tree_sitter_go::LANGUAGE.into(),
+        }
+    }
+}
+
+/// A function, type, impl or other item defined in a file.
+#[derive(Debug, Clone, PartialEq)]
+struct Symbol {
+    /// What the item is, in the words of its language, like "fn" or "class".
+    kind: &'static str,
+    name: String,
+    /// How the item is shown, like "fn parse" or "impl Display for Config".
+    label: String,
+    /// The type the item is a member of, for finding it as `Type::name`.
+    container: Option<String>,
+    /// The label of the item this one is in.
+    parent: Option<String>,
+    /// How many items this one is in.
+    depth: usize,
+    /// The lines the item spans, starting from 1.
+    lines: Range<usize>,
+    /// The bytes of the item with the comments and attributes before it.
+    source: Range<usize>,
+}
+
+impl Symbol {
+    fn describe(&self, path: &str) -> String {
+        let mut text = format!("{path}:{}-{} {}", self.lines.start, self.lines.end, self.label);
+        if let Some(parent) = &self.parent {
+            text.push_str(&format!(" (in {parent})"));
+        }
+        text
+    }
+
+    /// Whether this is what `query`, a name or `Type::name` or `Type.name`, refers to.
+    fn matches(&self, query: &str) -> bool {
+        match query.rsplit_once("::").or_else(|| query.rsplit_once('.')) {
+            Some((container, name)) => self.name == name && self.container.as_deref() == Some(container),
+            None => self.name == query,
+        }
+    }
+}
+
+/// A parsed source file.
+struct SourceFile {
+    lang: Lang,
+    source: String,
+    tree: Tree,
+}
+
+impl SourceFile {
+    fn parse(path: &Path, source: String) -> anyhow::Result<Self> {
+        let lang = Lang::of(path).with_context(|| {
+            format!(
+                "{} isn't a Rust, TypeScript, JavaScript, Python or Go file",
+                path.display()
+            )
+        })?;
+        let mut parser = Parser::new();
+        parser.set_language(&lang.language())?;
+        let tree = parser
+            .parse(&source, None)
+            .with_context(|| format!("Failed to parse {}", path.display()))?;
+        Ok(Self { lang, source, tree })
+    }
+
+    fn read(path: &Path) -> anyhow::Result<Self> {
+        let source = std::fs::read_to_string(path).with_context(|| format!("Failed to read {}", path.display()))?;
+        Self::parse(path, source)
+    }
+
+    fn text(&self, node: Node) -> &str {
+        &self.source[node.byte_range()]
+    }
+
+    fn symbols(&self) -> Vec<Symbol> {
+        let mut symbols = vec![];
+        self.collect_symbols(self.tree.root_node(), None, 0, &mut symbols);
+        symbols
+    }
+
+    /// Collects the items under `node`. Items in containers like impls and classes are collected with them, but
+    /// nothing inside functions is.
+    fn collect_symbols(&self, node: Node, parent: Option<&Symbol>, depth: usize, symbols: &mut Vec<Symbol>) {
+        let mut cursor = node.walk();
+        for child in node.named_children(&mut cursor) {
+            let member_of = parent.and_then(|parent| parent.container.clone());
+            match self.symbol(child, member_of) {
+                Some((mut symbol, is_container)) => {
+                    symbol.parent = parent.map(|parent| parent.label.clone());
+                    symbol.depth = depth;
+                    symbols.push(symbol.clone());
+                    if is_container {
+                        self.collect_symbols(child, Some(&symbol), depth + 1, symbols);
+                    }
+                }
+                None => self.collect_symbols(child, parent, depth, symbols),
+            }
+        }
+    }
+
+    /// The item `node` defines, if it's one, and whether there are items in it. `member_of` is the type of the
+    /// container it's in. The item's parent and depth are left for the caller.
+    fn symbol(&self, node: Node, member_of: Option<String>) -> Option<(Symbol, bool)> {
+        let name = node.child_by_field_name("name").map(|name| self.text(name).to_string());
+        let (kind, name, container, is_container) = match (self.lang, node.kind()) {
+            (Lang::Rust, "function_item" | "function_signature_item") => ("fn", name?, member_of, false),
+            (Lang::Rust, "struct_item") => ("struct", name?, None, false),
+            (Lang::Rust, "enum_item") => ("enum", name?, None, false),
+            (Lang::Rust, "union_item") => ("union", name?, None, false),
+            (Lang::Rust, "type_item") => ("type", name?, member_of, false),
+            (Lang::Rust, "const_item") => ("const", name?, member_of, false),
+            (Lang::Rust, "static_item") => ("static", name?, None, false),
+            (Lang::Rust, "macro_definition") => ("macro_rules!", name?, None, false),
+            (Lang::Rust, "mod_item") => ("mod", name?, None, true),
+            (Lang::Rust, "trait_item") => {
+                let name = name?;
+                ("trait", name.clone(), Some(name), true)
+            }
+            (Lang::Rust, "impl_item") => {
+                let type_name = self.text(node.child_by_field_name("type")?);
+                let base = type_name.split('<').next().unwrap_or(type_name).trim().to_string();
+                let label = match node.child_by_field_name("trait") {
+                    Some(trait_name) => format!("impl {} for {type_name}", self.text(trait_name)),
+                    None => format!("impl {type_name}"),
+                };
+                let symbol = self.make_symbol(node, "impl", base.clone(), label, Some(base));
+                return Some((symbol, true));
+            }
+            (Lang::Go, "function_declaration") => ("func", name?, None, false),
+            (Lang::Go, "method_declaration") => {
+                let name = name?;
+                let receiver = node
+                    .child_by_field_name("receiver")?
+                    .named_child(0)?
+                    .child_by_field_name("type")?;
+                let receiver = self.text(receiver).trim_start_matches('*');
+                let receiver = receiver.split('[').next().unwrap_or(receiver).to_string();
+                let label = format!("func ({receiver}) {name}");
+                let symbol = self.make_symbol(node, "func", name, label, Some(receiver));
+                return Some((symbol, false));
+            }
+            (Lang::Go, "type_spec" | "type_alias") => ("type", name?, None, false),
+            (Lang::Python, "function_definition") => ("def", name?, member_of, false),
+            (Lang::Python, "class_definition") => {
+                let name = name?;
+                ("class", name.clone(), Some(name), true)
+            }
+            (Lang::TypeScript | Lang::Tsx | Lang::JavaScript, kind) => match kind {
+                "function_declaration" | "generator_function_declaration" | "function_signature" => {
+                    ("function", name?, None, false)
+                }
+                "class_declaration" | "abstract_class_declaration" | "class" => {
+                    let name = name?;
+                    ("class", name.clone(), Some(name), true)
+                }
+                "interface_declaration" => {
+                    let name = name?;
+                    ("interface", name.clone(), Some(name), true)
+                }
+                "method_definition" | "method_signature" | "abstract_method_signature" => {
+                    ("method", name?, member_of, false)
+                }
+                "type_alias_declaration" => ("type", name?, None, false),
+                "enum_declaration" => ("enum", name?, None, false),
+                "internal_module" | "module" => ("namespace", name?, None, true),
+                // Functions assigned to variables, like `const parse = (text) => ...`.
+                "variable_declarator" => {
+                    let value = node.child_by_field_name("value")?;
+                    if !matches!(
+                        value.kind(),
+                        "arrow_function" | "function_expression" | "function" | "generator_function"
+                    ) {
+                        return None;
+                    }
+                    ("function", name?, None, false)
+                }
+                _ => return None,
+            },
+            _ => return None,
+        };
+        let label = format!("{kind} {name}");
+        let symbol = self.make_symbol(node, kind, name, label, container);
+        Some((symbol, is_container))
+    }
+
+    fn make_symbol(
+        &self,
+        node: Node,
+        kind: &'static str,
+        name: String,
+        label: String,
+        container: Option<String>,
+    ) -> Symbol {
+        let mut outer = node;
+        while let Some(wrapper) = outer.parent().filter(|parent| WRAPPER_KINDS.contains(&parent.kind())) {
+            outer = wrapper;
+        }
+        // The comments and attributes right before the item, with no blank line between.
+        let mut start = outer;
+        while let Some(previous) = start.prev_named_sibling()
+            && DOC_KINDS.contains(&previous.kind())
+            && previous.end_position().row + 1 >= start.start_position().row
+        {
+            start = previous;
+        }
+        let line_start = self.source[..start.start_byte()].rfind('\n').map_or(0, |i| i + 1);
+        Symbol {
+            kind,
+            name,
+            label,
+            container,
+            parent: None,
+            depth: 0,
+            lines: outer.start_position().row + 1..outer.end_position().row + 1,
+            source: line_start..outer.end_byte(),
+        }
+    }
+
+    /// The lines with an identifier named `name`, starting from 1.
+    fn reference_lines(&self, name: &str) -> Vec<usize> {
+        let mut lines = vec![];
+        let mut cursor = self.tree.walk();
+        'walk: loop {
+            let node = cursor.node();
+            if IDENTIFIER_KINDS.contains(&node.kind()) && self.text(node) == name {
+                let line = node.start_position().row + 1;
+                if lines.last() != Some(&line) {
+                    lines.push(line);
+                }
+            }
+            if cursor.goto_first_child() {
+                continue;
+            }
+            while !cursor.goto_next_sibling() {
+                if !cursor.goto_parent() {
+                    break 'walk;
+                }
+            }
+        }
+        lines
+    }
+}
+
+/// Runs one of the code intelligence tools in the workspace. Parsing many files takes a while, so this runs on the
+/// blocking pool.
+pub async fn execute_tool(workspace: &Path, name: String, args: String) -> anyhow::Result<String> {
+    let workspace = workspace.to_path_buf();
+    tokio::task::spawn_blocking(move || match name.as_str() {
+        "outline_file" => outline_file(&workspace, serde_json::from_str(&args)?),
+        "find_definition" => find_definition(&workspace, serde_json::from_str(&args)?),
+        "find_references" => find_references(&workspace, serde_json::from_str(&args)?),
+        "read_symbol" => read_symbol(&workspace, serde_json::from_str(&args)?),
+        _ => anyhow::bail!("Unknown tool: {name}"),
+    })
+    .await?
+}
+
+fn outline_file(workspace: &Path, args: OutlineFileArgs) -> anyhow::Result<String> {
+    let path = workspace.join(&args.target_file);
+    let file = SourceFile::read(&path)?;
+    let mut text = format!(
+        "{} ({}, {}):\n",
+        display_path(workspace, &path),
+        file.lang.name(),
+        plural(file.source.lines().count(), "line")
+    );
+    let symbols = file.symbols();
+    if symbols.is_empty() {
+        text.push_str("  No functions or types.\n");
+    }
+    for symbol in symbols {
+        text.push_str(&format!(
+            "{}{}-{} {}\n",
+            "  ".repeat(symbol.depth + 1),
+            symbol.lines.start,
+            symbol.lines.end,
+            symbol.label
+        ));
+    }
+    Ok(text)
+}
+
+fn find_definition(workspace: &Path, args: FindSymbolArgs) -> anyhow::Result<String> {
+    // Only files that mention the name can define it, which saves parsing most of them.
+    let name = args.name.rsplit([':', '.']).next().unwrap_or(&args.name);
+    let search = search_files(workspace, args.path.as_deref())?;
+    let mut definitions = vec![];
+    for (path, file) in search.files_mentioning(name) {
+        let path = display_path(workspace, &path);
+        definitions.extend(
+            file.symbols()
+                .iter()
+                .filter(|symbol| symbol.matches(&args.name))
+                .map(|symbol| symbol.describe(&path)),
+        );
+    }
+    if definitions.is_empty() {
+        return Ok(format!(
+            "No definition of {} found in {}.{}",
+            args.name,
+            plural(search.paths.len(), "file"),
+            search.note()
+        ));
+    }
+    let mut text = String::new();
+    for definition in definitions.iter().take(MAX_DEFINITIONS) {
+        text.push_str(definition);
+        text.push('\n');
+    }
+    if definitions.len() > MAX_DEFINITIONS {
+        text.push_str(&format!("and {} more\n", definitions.len() - MAX_DEFINITIONS));
+    }
+    text.push_str(&search.note());
+    Ok(text)
+}
+
+fn find_references(workspace: &Path, args: FindSymbolArgs) -> anyhow::Result<String> {
+    let search = search_files(workspace, args.path.as_deref())?;
+    let mut text = String::new();
+    let mut count = 0;
+    for (path, file) in search.files_mentioning(&args.name) {
+        let lines = file.reference_lines(&args.name);
+        if lines.is_empty() {
+            continue;
+        }
+        if count >= MAX_REFERENCES {
+            count += lines.len();
+            continue;
+        }
+        let source_lines = file.source.lines().collect::<Vec<_>>();
+        text.push_str(&format!("{}:\n", display_path(workspace, &path)));
+        for line in lines {
+            count += 1;
+            if count > MAX_REFERENCES {
+                continue;
+            }
+            let source_line = source_lines.get(line - 1).map_or("", |source_line| source_line.trim());
+            let shown = source_line.chars().take(MAX_REFERENCE_LINE_CHARS).collect::<String>();
+            let cut = if shown.len() < source_line.len() { "…" } else { "" };
+            text.push_str(&format!("  {line}: {shown}{cut}\n"));
+        }
+    }
+    if count == 0 {
+        return Ok(format!(
+            "{} isn't used in {}.{}",
+            args.name,
+            plural(search.paths.len(), "file"),
+            search.note()
+        ));
+    }
+    if count > MAX_REFERENCES {
+        text.push_str(&format!(
+            "Showing {MAX_REFERENCES} of {count} lines, give a path to narrow the search.\n"
+        ));
+    }
+    text.push_str(&search.note());
+    Ok(text)
+}
+
+fn read_symbol(workspace: &Path, args: ReadSymbolArgs) -> anyhow::Result<String> {
+    let path = workspace.join(&args.target_file);
+    let file = SourceFile::read(&path)?;
+    let mut symbols = file
+        .symbols()
+        .into_iter()
+        .filter(|symbol| symbol.matches(&args.name))
+        .collect::<Vec<_>>();
+    // A type's own definition comes before its impls.
+    symbols.sort_by_key(|symbol| symbol.kind == "impl");
+    let path = display_path(workspace, &path);
+    let Some(symbol) = symbols.first() else {
+        anyhow::bail!("{path} doesn't define {}, outline_file lists what it does", args.name);
+    };
+    let mut text = format!("{}\n", symbol.describe(&path));
+    let source = &file.source[symbol.source.clone()];
+    match source.char_indices().nth(MAX_SYMBOL_CHARS) {
+        Some((end, _)) => {
+            let end = source[..end].rfind('\n').unwrap_or(end);
+            text.push_str(&source[..end]);
+            text.push_str(&format!(
+                "\n\n(Cut off after {MAX_SYMBOL_CHARS} characters, use read_file for the rest.)\n"
+            ));
+        }
+        None => {
+            text.push_str(source);
+            text.push('\n');
+        }
+    }
+    if symbols.len() > 1 {
+        text.push_str("\nAlso defined at:\n");
+        for other in &symbols[1..] {
+            text.push_str(&format!("  {}\n", other.describe(&path)));
+        }
+    }
+    Ok(text)
+}
+
+/// The source files a search goes through.
+struct Search {
+    paths: Vec<PathBuf>,
+    /// Whether there were more than could be searched.
+    truncated: bool,
+}
+
+impl Search {
+    /// The files that contain `name` anywhere, parsed. Files that can't be read or parsed are left out.
+    fn files_mentioning(&self, name: &str) -> impl Iterator<Item = (PathBuf, SourceFile)> {
+        self.paths.iter().filter_map(move |path| {
+            let source = std::fs::read_to_string(path).ok()?;
+            if !source.contains(name) {
+                return None;
+            }
+            match SourceFile::parse(path, source) {
+                Ok(file) => Some((path.clone(), file)),
+                Err(e) => {
+                    tracing::debug!("Not searching {}: {e:?}", path.display());
+                    None
+                }
+            }
+        })
+    }
+
+    fn note(&self) -> String {
+        if self.truncated {
+            format!("Only the first {MAX_SEARCHED_FILES} files were searched, give a path to narrow the search.\n")
+        } else {
+            String::new()
+        }
+    }
+}
+
+/// The source files in `path`, or in the workspace if it's not given. A file given directly is searched even if it's
+/// ignored.
+fn search_files(workspace: &Path, path: Option<&str>) -> anyhow::Result<Search> {
+    let root = workspace.join(path.unwrap_or(""));
+    let metadata = std::fs::metadata(&root).with_context(|| format!("{} doesn't exist", root.display()))?;
+    if metadata.is_file() {
+        return Ok(Search {
+            paths: vec![root],
+            truncated: false,
+        });
+    }
+    let mut paths = vec![];
+    let mut truncated = false;
+    for entry in layout::entries(&root) {
+        let is_source = entry.file_type().is_some_and(|file_type| file_type.is_file())
+            && Lang::of(entry.path()).is_some()
+            && entry.metadata().is_ok_and(|metadata| metadata.len() <= MAX_FILE_BYTES);
+        if !is_source {
+            continue;
+        }
+        if paths.len() >= MAX_SEARCHED_FILES {
+            truncated = true;
+            break;
+        }
+        paths.push(entry.into_path());
+    }
+    Ok(Search { paths, truncated })
+}
+
+/// `path` relative to the workspace if it's in it.
+fn display_path(workspace: &Path, path: &Path) -> String {
+    path.strip_prefix(workspace).unwrap_or(path).display().to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn outline(path: &str, source: &str) -> Vec<String> {
+        let file = SourceFile::parse(Path::new(path), source.to_string()).unwrap();
+        file.symbols()
+            .into_iter()
+            .map(|symbol| {
+                format!(
+                    "{}{}-{} {}",
+                    "  ".repeat(symbol.depth),
+                    symbol.lines.start,
+                    symbol.lines.end,
+                    symbol.label
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_outline() {
+        let rust = "/// A config.\n#[derive(Debug)]\npub struct Config<T> {\n    value: T,\n}\n\nimpl<T> Config<T> \
+                    {\n    pub fn new(value: T) -> Self {\n        fn helper() {}\n        Self { value }\n    \
+                    }\n}\n\nimpl Display for Config<u8> {}\n\nmod tests {\n    fn test() {}\n}\n";
+        assert_eq!(
+            outline("lib.rs", rust),
+            [
+                "3-5 struct Config",
+                "7-12 impl Config<T>",
+                "  8-11 fn new",
+                "14-14 impl Display for Config<u8>",
+                "16-18 mod tests",
+                "  17-17 fn test",
+            ]
+        );
+
+        let typescript = "export interface Shape {\n  area(): number;\n}\n\nexport class Circle implements Shape {\n  \
+                          area() {\n    return 1;\n  }\n}\n\nconst parse = (text: string) => text;\nconst count = \
+                          1;\ntype Id = string;\n";
+        assert_eq!(
+            outline("shapes.ts", typescript),
+            [
+                "1-3 interface Shape",
+                "  2-2 method area",
+                "5-9 class Circle",
+                "  6-8 method area",
+                "11-11 function parse",
+                "13-13 type Id",
+            ]
+        );
+        assert_eq!(
+            outline("app.jsx", "function App() {\n  return <div />;\n}\n"),
+            ["1-3 function App"]
+        );
+
+        let python = "import os\n\n@dataclass\nclass Point:\n    x: int\n\n    def norm(self):\n        def \
+                      inner():\n            pass\n        return 0\n\ndef main():\n    pass\n";
+        assert_eq!(
+            outline("point.py", python),
+            ["3-10 class Point", "  7-10 def norm", "12-13 def main"]
+        );
+
+        let go = "package main\n\ntype Server struct {\n\tport int\n}\n\nfunc (s *Server) Start() error {\n\treturn \
+                  nil\n}\n\nfunc main() {}\n";
+        assert_eq!(
+            outline("main.go", go),
+            ["3-5 type Server", "7-9 func (Server) Start", "11-11 func main"]
+        );
+
+        assert!(SourceFile::parse(Path::new("notes.md"), String::new()).is_err());
+    }
+
+    #[test]
+    fn test_search() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let workspace = temp_dir.path();
+        std::fs::create_dir_all(workspace.join("src")).unwrap();
+        std::fs::create_dir_all(workspace.join("target")).unwrap();
+        // .gitignore files only count in git repositories.
+        std::fs::create_dir(workspace.join(".git")).unwrap();
+        std::fs::write(workspace.join(".gitignore"), "target/\n").unwrap();
+        std::fs::write(
+            workspace.join("src").join("config.rs"),
+            "pub struct Config;\n\nimpl Config {\n    // Loads it.\n    pub fn load() -> Self {\n        Config\n    \
+             }\n}\n",
+        )
+        .unwrap();
+        std::fs::write(
+            workspace.join("src").join("main.rs"),
+            "fn main() {\n    // Config in a comment.\n    let config = Config::load();\n}\n\nfn load() {}\n",
+        )
+        .unwrap();
+        std::fs::write(workspace.join("target").join("generated.rs"), "struct Config;\n").unwrap();
+        let find = |name: &str, path: Option<&str>| FindSymbolArgs {
+            name: name.to_string(),
+            path: path.map(str::to_string),
+        };
+
+        let text = find_definition(workspace, find("Config", None)).unwrap();
+        assert!(text.contains("src/config.rs:1-1 struct Config\n"), "{text}");
+        assert!(text.contains("src/config.rs:3-8 impl Config\n"), "{text}");
+        assert!(!text.contains("generated.rs"), "{text}");
+        let text = find_definition(workspace, find("load", None)).unwrap();
+        assert!(text.contains("src/config.rs:5-7 fn load (in impl Config)\n"), "{text}");
+        assert!(text.contains("src/main.rs:6-6 fn load\n"), "{text}");
+        let text = find_definition(workspace, find("Config::load", None)).unwrap();
+        assert_eq!(text, "src/config.rs:5-7 fn load (in impl Config)\n");
+        let text = find_definition(workspace, find("load", Some("src/main.rs"))).unwrap();
+        assert_eq!(text, "src/main.rs:6-6 fn load\n");
+        let text = find_definition(workspace, find("Missing", None)).unwrap();
+        assert_eq!(text, "No definition of Missing found in 2 files.");
+
+        let text = find_references(workspace, find("Config", None)).unwrap();
+        assert!(
+            text.contains("src/config.rs:\n  1: pub struct Config;\n  3: impl Config {\n  6: Config\n"),
+            "{text}"
+        );
+        assert!(
+            text.contains("src/main.rs:\n  3: let config = Config::load();\n"),
+            "{text}"
+        );
+        assert!(!text.contains("comment") && !text.contains("generated.rs"), "{text}");
+
+        let read = |name: &str| ReadSymbolArgs {
+            target_file: "src/config.rs".to_string(),
+            name: name.to_string(),
+        };
+        assert_eq!(
+            read_symbol(workspace, read("load")).unwrap(),
+            "src/config.rs:5-7 fn load (in impl Config)\n    // Loads it.\n    pub fn load() -> Self {\n        \
+             Config\n    }\n"
+        );
+        let text = read_symbol(workspace, read("Config")).unwrap();
+        assert!(
+            text.starts_with("src/config.rs:1-1 struct Config\npub struct Config;\n\nAlso defined at:\n"),
+            "{text}"
+        );
+        assert!(read_symbol(workspace, read("Missing")).is_err());
+
+        let text = outline_file(
+            workspace,
+            OutlineFileArgs {
+                target_file: "src/main.rs".to_string(),
+            },
+        )
+        .unwrap();
+        assert_eq!(text, "src/main.rs (Rust, 6 lines):\n  1-4 fn main\n  6-6 fn load\n");
+    }
+}
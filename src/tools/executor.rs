@@ -1,33 +1,66 @@
+use std::path::{
+    Component,
+    Path,
+    PathBuf,
+};
+
 use tokio::{
     fs,
     sync::mpsc,
 };
 
-use crate::tools::{
-    prompts::{
-        ListDirArgs,
-        ReadFileArgs,
+use crate::{
+    cassette::{
+        RecordedToolResult,
+        ToolCassette,
     },
-    protocol::{
-        ToolRequest,
-        ToolResponse,
+    diff_render,
+    tools::{
+        code_intel,
+        git,
+        prompts::{
+            EditFileArgs,
+            ListDirArgs,
+            ReadFileArgs,
+        },
+        protocol::{
+            ToolRequest,
+            ToolResponse,
+        },
     },
 };
 
 pub async fn run_executor(
     mut requests: mpsc::UnboundedReceiver<ToolRequest>,
     responses: mpsc::UnboundedSender<ToolResponse>,
+    cassette: ToolCassette,
+    enabled_tools: Vec<String>,
+    workspace: PathBuf,
 ) -> anyhow::Result<()> {
     while let Some(request) = requests.recv().await {
         let start = tokio::time::Instant::now();
         let ToolRequest::ToolCall { id, name, args } = request;
         tracing::info!("Executing tool {name} (id: {id})");
         tracing::debug!("  {args}");
-        let result = execute_tool(name, args).await;
-        let response = ToolResponse::ToolCallResult {
-            id,
-            result: result.map_err(|e| e.to_string()),
+        let result = match &cassette {
+            _ if !enabled_tools.contains(&name) => Err(format!("Tool {name} is not enabled")),
+            ToolCassette::Replay(results) => results
+                .get(&id)
+                .cloned()
+                .unwrap_or_else(|| Err(format!("No recorded result for tool call {id}"))),
+            ToolCassette::Off | ToolCassette::Record(_) => execute_tool(&workspace, name.clone(), args.clone())
+                .await
+                .map_err(|e| e.to_string()),
         };
+        if let ToolCassette::Record(recorder) = &cassette {
+            recorder.record_tool_result(&RecordedToolResult {
+                id: id.clone(),
+                name,
+                args,
+                result: result.clone(),
+            })?;
+        }
+        let response = ToolResponse::ToolCallResult { id, result };
         tracing::info!("Finished in {:?}", start.elapsed());
         tracing::debug!("  {response:?}");
         responses.send(response)?;
@@ -35,11 +68,40 @@ pub async fn run_executor(
     Ok(())
 }
 
-async fn execute_tool(name: String, args: String) -> anyhow::Result<String> {
+/// `path`, relative to the workspace or absolute, if it's in the workspace. Paths that lead out of it, with `..` or
+/// through a symlink, are refused, so that the agent can't change files the user didn't give it.
+pub fn workspace_path(workspace: &Path, path: impl AsRef<Path>) -> anyhow::Result<PathBuf> {
+    let path = path.as_ref();
+    let mut normalized = PathBuf::new();
+    for component in workspace.join(path).components() {
+        match component {
+            Component::ParentDir => {
+                normalized.pop();
+            }
+            Component::CurDir => {}
+            component => normalized.push(component),
+        }
+    }
+    // Symlinks can only be followed as far as the path exists.
+    let canonical = |path: &Path| path.canonicalize().unwrap_or_else(|_| path.to_path_buf());
+    let existing = normalized
+        .ancestors()
+        .find(|ancestor| ancestor.exists())
+        .unwrap_or(&normalized);
+    anyhow::ensure!(
+        normalized.starts_with(workspace) && canonical(existing).starts_with(canonical(workspace)),
+        "{} is outside of the workspace {}",
+        path.display(),
+        workspace.display()
+    );
+    Ok(normalized)
+}
+
+async fn execute_tool(workspace: &Path, name: String, args: String) -> anyhow::Result<String> {
     match name.as_str() {
         "list_dir" => {
             let args: ListDirArgs = serde_json::from_str(&args)?;
-            let mut entries = fs::read_dir(&args.target_directory).await?;
+            let mut entries = fs::read_dir(workspace.join(&args.target_directory)).await?;
             let mut result = String::new();
             result.push_str(&args.target_directory);
             result.push_str(":\n");
@@ -58,12 +120,49 @@ async fn execute_tool(name: String, args: String) -> anyhow::Result<String> {
         }
         "read_file" => {
             let args: ReadFileArgs = serde_json::from_str(&args)?;
-            let mut contents = fs::read_to_string(&args.target_file).await?;
+            let mut contents = fs::read_to_string(workspace.join(&args.target_file)).await?;
             if contents.is_empty() {
                 contents = "File is empty.".to_string();
             }
             Ok(contents)
         }
+        "edit_file" => {
+            let args: EditFileArgs = serde_json::from_str(&args)?;
+            let path = &args.target_file;
+            let file = workspace_path(workspace, path)?;
+            let (before, after) = if args.old_string.is_empty() {
+                let before = match fs::read_to_string(&file).await {
+                    Ok(contents) => contents,
+                    Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+                    Err(e) => return Err(e.into()),
+                };
+                anyhow::ensure!(before.is_empty(), "{path} already exists, give old_string to edit it");
+                (before, args.new_string)
+            } else {
+                let before = fs::read_to_string(&file).await?;
+                let after = match before.matches(&args.old_string).count() {
+                    0 => anyhow::bail!("old_string doesn't occur in {path}"),
+                    1 => before.replacen(&args.old_string, &args.new_string, 1),
+                    _ if args.replace_all => before.replace(&args.old_string, &args.new_string),
+                    count => anyhow::bail!(
+                        "old_string occurs {count} times in {path}, include more context to make it unique or set \
+                         replace_all"
+                    ),
+                };
+                (before, after)
+            };
+            if let Some(parent) = file.parent() {
+                fs::create_dir_all(parent).await?;
+            }
+            fs::write(&file, &after).await?;
+            Ok(diff_render::unified_diff(path, &before, &after))
+        }
+        "git_status" | "git_diff" | "git_log" | "git_show" | "git_blame" => {
+            git::execute_tool(workspace, name, args).await
+        }
+        "outline_file" | "find_definition" | "find_references" | "read_symbol" => {
+            code_intel::execute_tool(workspace, name, args).await
+        }
         _ => anyhow::bail!("Unknown tool: {name}"),
     }
 }
@@ -0,0 +1,681 @@
+//! Read-only git tools, so the agent can see what changed and why without a shell: the status of the working tree,
+//! diffs, the log, single commits, and blame. Everything goes through libgit2 rather than the `git` binary, and every
+//! output is bounded, since a diff or log can be far bigger than the context window.
+
+use std::path::{
+    Path,
+    PathBuf,
+};
+
+use anyhow::Context;
+use git2::{
+    BlameOptions,
+    BranchType,
+    Commit,
+    Delta,
+    Diff,
+    DiffFormat,
+    DiffOptions,
+    Oid,
+    Repository,
+    RepositoryState,
+    Sort,
+    Status,
+    StatusOptions,
+};
+
+use crate::{
+    layout::plural,
+    tools::prompts::{
+        GitBlameArgs,
+        GitDiffArgs,
+        GitLogArgs,
+        GitShowArgs,
+    },
+};
+
+/// How many files the status lists before the rest are just counted.
+const MAX_STATUS_ENTRIES: usize = 200;
+/// How many files the summary before a diff lists before the rest are just counted.
+const MAX_DIFF_FILES: usize = 200;
+/// How much of a diff is shown.
+const MAX_DIFF_BYTES: usize = 30_000;
+const DEFAULT_LOG_COUNT: usize = 20;
+const MAX_LOG_COUNT: usize = 100;
+/// How many commits a log limited to a path looks through for ones that touch it.
+const MAX_LOG_SCANNED: usize = 10_000;
+const MAX_BLAME_LINES: usize = 200;
+
+/// Runs one of the git tools in the repository the workspace is in. libgit2 blocks, so this runs on the blocking
+/// pool.
+pub async fn execute_tool(workspace: &Path, name: String, args: String) -> anyhow::Result<String> {
+    let workspace = workspace.to_path_buf();
+    tokio::task::spawn_blocking(move || {
+        let repo = Repository::discover(&workspace).context("The workspace isn't in a git repository")?;
+        match name.as_str() {
+            "git_status" => status(&repo),
+            "git_diff" => diff(&repo, &workspace, serde_json::from_str(&args)?),
+            "git_log" => log(&repo, &workspace, serde_json::from_str(&args)?),
+            "git_show" => show(&repo, &workspace, serde_json::from_str(&args)?),
+            "git_blame" => blame(&repo, &workspace, serde_json::from_str(&args)?),
+            _ => anyhow::bail!("Unknown tool: {name}"),
+        }
+    })
+    .await?
+}
+
+/// The branch checked out in the repository `workspace` is in, or the commit if it's detached.
+pub fn branch(workspace: &Path) -> Option<String> {
+    let repo = Repository::discover(workspace).ok()?;
+    Some(head_name(&repo))
+}
+
+/// A one-line summary of the uncommitted changes in the repository `workspace` is in, like "2 changed files (1 staged,
+/// 1 untracked)".
+pub fn summary(workspace: &Path) -> Option<String> {
+    let repo = Repository::discover(workspace).ok()?;
+    let files = file_statuses(&repo).ok()?;
+    let count = |flags: Status| files.iter().filter(|(_, status)| status.intersects(flags)).count();
+    let counts = [
+        (count(STAGED), "staged"),
+        (count(NOT_STAGED), "not staged"),
+        (count(Status::WT_NEW), "untracked"),
+        (count(Status::CONFLICTED), "conflicted"),
+    ]
+    .into_iter()
+    .filter(|(count, _)| *count > 0)
+    .map(|(count, label)| format!("{count} {label}"))
+    .collect::<Vec<_>>();
+    let mut summary = match files.len() {
+        0 => "clean".to_string(),
+        count => format!("{} ({})", plural(count, "changed file"), counts.join(", ")),
+    };
+    if let Some(operation) = operation_in_progress(&repo) {
+        summary.push_str(&format!(", {operation} in progress"));
+    }
+    Some(summary)
+}
+
+/// The operation the repository is in the middle of, like a merge or a rebase, if any.
+pub fn operation_in_progress(repo: &Repository) -> Option<&'static str> {
+    match repo.state() {
+        RepositoryState::Clean => None,
+        RepositoryState::Merge => Some("merge"),
+        RepositoryState::Revert | RepositoryState::RevertSequence => Some("revert"),
+        RepositoryState::CherryPick | RepositoryState::CherryPickSequence => Some("cherry-pick"),
+        RepositoryState::Bisect => Some("bisect"),
+        RepositoryState::Rebase | RepositoryState::RebaseInteractive | RepositoryState::RebaseMerge => Some("rebase"),
+        RepositoryState::ApplyMailbox | RepositoryState::ApplyMailboxOrRebase => Some("patch application"),
+    }
+}
+
+const STAGED: Status = Status::INDEX_NEW
+    .union(Status::INDEX_MODIFIED)
+    .union(Status::INDEX_DELETED)
+    .union(Status::INDEX_RENAMED)
+    .union(Status::INDEX_TYPECHANGE);
+const NOT_STAGED: Status = Status::WT_MODIFIED
+    .union(Status::WT_DELETED)
+    .union(Status::WT_RENAMED)
+    .union(Status::WT_TYPECHANGE);
+
+/// The sections of the status, with the changes that go in them. Untracked and conflicted files are just listed.
+const STATUS_SECTIONS: &[(&str, &[(Status, &str)])] = &[
+    (
+        "Staged",
+        &[
+            (Status::INDEX_NEW, "new file"),
+            (Status::INDEX_MODIFIED, "modified"),
+            (Status::INDEX_DELETED, "deleted"),
+            (Status::INDEX_RENAMED, "renamed"),
+            (Status::INDEX_TYPECHANGE, "type changed"),
+        ],
+    ),
+    (
+        "Not staged",
+        &[
+            (Status::WT_MODIFIED, "modified"),
+            (Status::WT_DELETED, "deleted"),
+            (Status::WT_RENAMED, "renamed"),
+            (Status::WT_TYPECHANGE, "type changed"),
+        ],
+    ),
+    ("Untracked", &[(Status::WT_NEW, "")]),
+    ("Conflicted", &[(Status::CONFLICTED, "")]),
+];
+
+fn file_statuses(repo: &Repository) -> anyhow::Result<Vec<(String, Status)>> {
+    let statuses = repo.statuses(Some(
+        StatusOptions::new().include_untracked(true).renames_head_to_index(true),
+    ))?;
+    Ok(statuses
+        .iter()
+        .map(|entry| (String::from_utf8_lossy(entry.path_bytes()).into_owned(), entry.status()))
+        .filter(|(_, status)| !status.is_ignored())
+        .collect())
+}
+
+/// The name of the branch HEAD is on, even if it has no commits yet, or the commit if it's detached.
+pub(crate) fn head_name(repo: &Repository) -> String {
+    if repo.head_detached().unwrap_or(false)
+        && let Ok(head) = repo.head()
+        && let Some(oid) = head.target()
+    {
+        return format!("detached at {}", short_id(oid));
+    }
+    repo.find_reference("HEAD")
+        .ok()
+        .and_then(|head| head.symbolic_target().ok().flatten().map(str::to_string))
+        .map(|target| target.strip_prefix("refs/heads/").unwrap_or(&target).to_string())
+        .unwrap_or_else(|| "unknown".to_string())
+}
+
+fn status(repo: &Repository) -> anyhow::Result<String> {
+    let workdir = repo.workdir().context("The repository has no working tree")?;
+    let mut text = format!("Repository: {}\n", workdir.display());
+    text.push_str(&describe_branch(repo));
+    if let Some(operation) = operation_in_progress(repo) {
+        text.push_str(&format!("A {operation} is in progress.\n"));
+    }
+
+    let files = file_statuses(repo)?;
+    if files.is_empty() {
+        text.push_str("\nNothing to commit, the working tree is clean.\n");
+        return Ok(text);
+    }
+    let mut listed = 0;
+    let mut unlisted = 0;
+    for (title, changes) in STATUS_SECTIONS {
+        let entries = files
+            .iter()
+            .filter_map(|(path, status)| {
+                let (_, change) = changes.iter().find(|(flag, _)| status.contains(*flag))?;
+                Some((path, *change))
+            })
+            .collect::<Vec<_>>();
+        if entries.is_empty() {
+            continue;
+        }
+        text.push_str(&format!("\n{title}:\n"));
+        for (path, change) in entries {
+            if listed == MAX_STATUS_ENTRIES {
+                unlisted += 1;
+                continue;
+            }
+            match change {
+                "" => text.push_str(&format!("  {path}\n")),
+                change => text.push_str(&format!("  {change}: {path}\n")),
+            }
+            listed += 1;
+        }
+    }
+    if unlisted > 0 {
+        text.push_str(&format!(
+            "\n{} not listed, use git_diff with a path to look at parts of the tree.\n",
+            plural(unlisted, "more entry")
+        ));
+    }
+    Ok(text)
+}
+
+/// The branch, and how it compares to its upstream if it has one.
+fn describe_branch(repo: &Repository) -> String {
+    let name = head_name(repo);
+    if name.starts_with("detached at ") {
+        return format!("HEAD {name}\n");
+    }
+    let Ok(head) = repo.head() else {
+        return format!("On branch {name}, no commits yet\n");
+    };
+    let upstream = repo
+        .find_branch(&name, BranchType::Local)
+        .and_then(|branch| branch.upstream())
+        .ok()
+        .and_then(|upstream| Some((upstream.name().ok()??.to_string(), upstream.get().target()?)));
+    let Some(((upstream, upstream_oid), local_oid)) = upstream.zip(head.target()) else {
+        return format!("On branch {name}\n");
+    };
+    match repo.graph_ahead_behind(local_oid, upstream_oid) {
+        Ok((0, 0)) => format!("On branch {name}, up to date with {upstream}\n"),
+        Ok((ahead, behind)) => format!("On branch {name}, {ahead} ahead of and {behind} behind {upstream}\n"),
+        Err(_) => format!("On branch {name}, tracking {upstream}\n"),
+    }
+}
+
+fn diff(repo: &Repository, workspace: &Path, args: GitDiffArgs) -> anyhow::Result<String> {
+    let mut options = diff_options(repo, workspace, args.path.as_deref())?;
+    let diff = if args.staged {
+        let head = repo.head().ok().and_then(|head| head.peel_to_tree().ok());
+        repo.diff_tree_to_index(head.as_ref(), None, Some(&mut options))?
+    } else {
+        repo.diff_index_to_workdir(None, Some(&mut options))?
+    };
+    if diff.deltas().len() == 0 {
+        return Ok(match args.staged {
+            true => "No staged changes.".to_string(),
+            false => "No unstaged changes. Untracked files aren't diffed, see git_status.".to_string(),
+        });
+    }
+    describe_diff(&diff)
+}
+
+pub(crate) fn log(repo: &Repository, workspace: &Path, args: GitLogArgs) -> anyhow::Result<String> {
+    let start = resolve_commit(repo, args.revision.as_deref())?;
+    let path = args
+        .path
+        .as_deref()
+        .map(|path| repo_path(repo, workspace, path))
+        .transpose()?;
+    let max_count = args.max_count.unwrap_or(DEFAULT_LOG_COUNT).clamp(1, MAX_LOG_COUNT);
+    let mut walk = repo.revwalk()?;
+    walk.set_sorting(Sort::TIME)?;
+    walk.push(start.id())?;
+
+    let mut text = String::new();
+    let mut count = 0;
+    for (scanned, oid) in walk.enumerate() {
+        let commit = repo.find_commit(oid?)?;
+        if path.is_some() && scanned == MAX_LOG_SCANNED {
+            text.push_str(&format!(
+                "Stopped looking after {MAX_LOG_SCANNED} commits, give {} as the revision to look further.\n",
+                short_id(commit.id())
+            ));
+            break;
+        }
+        if let Some(path) = &path
+            && !touches(repo, &commit, path)?
+        {
+            continue;
+        }
+        if count == max_count {
+            text.push_str(&format!(
+                "There are older commits, give {}^ as the revision to list them.\n",
+                short_id(commit.id())
+            ));
+            break;
+        }
+        text.push_str(&format!(
+            "{} {} {}: {}\n",
+            short_id(commit.id()),
+            format_date(commit.time(), "%Y-%m-%d"),
+            commit.author().name().unwrap_or("unknown"),
+            commit.summary().ok().flatten().unwrap_or_default()
+        ));
+        count += 1;
+    }
+    if text.is_empty() {
+        text.push_str("No commits found.\n");
+    }
+    Ok(text)
+}
+
+/// Whether `commit` changed anything under `path`, compared to its first parent.
+fn touches(repo: &Repository, commit: &Commit, path: &Path) -> anyhow::Result<bool> {
+    let parent = commit.parents().next().map(|parent| parent.tree()).transpose()?;
+    let diff = repo.diff_tree_to_tree(
+        parent.as_ref(),
+        Some(&commit.tree()?),
+        Some(DiffOptions::new().pathspec(path)),
+    )?;
+    Ok(diff.deltas().len() > 0)
+}
+
+fn show(repo: &Repository, workspace: &Path, args: GitShowArgs) -> anyhow::Result<String> {
+    let commit = resolve_commit(repo, args.revision.as_deref())?;
+    let author = commit.author();
+    let mut text = format!("commit {}\n", commit.id());
+    if commit.parent_count() > 1 {
+        let parents = commit.parent_ids().map(short_id).collect::<Vec<_>>();
+        text.push_str(&format!("Merge: {}\n", parents.join(" ")));
+    }
+    text.push_str(&format!(
+        "Author: {} <{}>\nDate:   {}\n\n",
+        author.name().unwrap_or("unknown"),
+        author.email().unwrap_or_default(),
+        format_date(author.when(), "%Y-%m-%d %H:%M:%S %z")
+    ));
+    for line in commit.message().unwrap_or_default().trim_end().lines() {
+        text.push_str(&format!("    {line}\n"));
+    }
+    text.push('\n');
+
+    let parent = commit.parents().next().map(|parent| parent.tree()).transpose()?;
+    let mut options = diff_options(repo, workspace, args.path.as_deref())?;
+    let diff = repo.diff_tree_to_tree(parent.as_ref(), Some(&commit.tree()?), Some(&mut options))?;
+    if diff.deltas().len() == 0 {
+        text.push_str("No changes.\n");
+    } else {
+        text.push_str(&describe_diff(&diff)?);
+    }
+    Ok(text)
+}
+
+fn blame(repo: &Repository, workspace: &Path, args: GitBlameArgs) -> anyhow::Result<String> {
+    let workdir = repo.workdir().context("The repository has no working tree")?;
+    let path = repo_path(repo, workspace, &args.target_file)?;
+    let contents = std::fs::read(workdir.join(&path)).with_context(|| format!("Failed to read {}", path.display()))?;
+    let blame = repo
+        .blame_file(&path, Some(&mut BlameOptions::new()))
+        .with_context(|| format!("{} isn't tracked by git", path.display()))?;
+    // Blaming the file as it is in the working tree marks the lines changed since the last commit.
+    let blame = blame.blame_buffer(&contents)?;
+
+    let contents = String::from_utf8_lossy(&contents);
+    let lines = contents.lines().collect::<Vec<_>>();
+    anyhow::ensure!(
+        (1..=lines.len()).contains(&args.start_line),
+        "{} has {}, start_line must be between 1 and {}",
+        path.display(),
+        plural(lines.len(), "line"),
+        lines.len()
+    );
+    let end_line = args
+        .end_line
+        .clamp(args.start_line, lines.len())
+        .min(args.start_line + MAX_BLAME_LINES - 1);
+    let width = end_line.to_string().len();
+    let mut text = String::new();
+    for number in args.start_line..=end_line {
+        let origin = match blame.get_line(number) {
+            Some(hunk) if !hunk.final_commit_id().is_zero() => {
+                let (author, date) = hunk
+                    .final_signature()
+                    .map(|signature| {
+                        (
+                            signature.name().unwrap_or("unknown").to_string(),
+                            format_date(signature.when(), "%Y-%m-%d"),
+                        )
+                    })
+                    .unwrap_or_default();
+                format!("{} ({author} {date})", short_id(hunk.final_commit_id()))
+            }
+            _ => "uncommitted".to_string(),
+        };
+        text.push_str(&format!("{origin} {number:>width$}: {}\n", lines[number - 1]));
+    }
+    if end_line < args.end_line.min(lines.len()) {
+        text.push_str(&format!(
+            "Only {MAX_BLAME_LINES} lines are shown at once, continue from line {}.\n",
+            end_line + 1
+        ));
+    }
+    Ok(text)
+}
+
+/// A summary of the lines added and removed per file, cut off after `MAX_DIFF_FILES`, then the patch, cut off after
+/// `MAX_DIFF_BYTES`.
+pub(crate) fn describe_diff(diff: &Diff) -> anyhow::Result<String> {
+    let stats = diff.stats()?;
+    let mut text = format!(
+        "{} changed, +{} -{}\n",
+        plural(stats.files_changed(), "file"),
+        stats.insertions(),
+        stats.deletions()
+    );
+    for (index, delta) in diff.deltas().enumerate().take(MAX_DIFF_FILES) {
+        let old = delta.old_file().path().unwrap_or(Path::new(""));
+        let new = delta.new_file().path().unwrap_or(old);
+        let name = match delta.status() {
+            Delta::Renamed | Delta::Copied => format!("{} -> {}", old.display(), new.display()),
+            _ => new.display().to_string(),
+        };
+        let (_, additions, deletions) = match git2::Patch::from_diff(diff, index)? {
+            Some(patch) => patch.line_stats()?,
+            None => (0, 0, 0),
+        };
+        text.push_str(&format!("  {name} | +{additions} -{deletions}\n"));
+    }
+    let unlisted = diff.deltas().len().saturating_sub(MAX_DIFF_FILES);
+    if unlisted > 0 {
+        text.push_str(&format!("  {} not listed\n", plural(unlisted, "more file")));
+    }
+    text.push('\n');
+
+    let mut patch = String::new();
+    let mut total = 0;
+    diff.print(DiffFormat::Patch, |_, _, line| {
+        let content = String::from_utf8_lossy(line.content());
+        let prefix = match line.origin() {
+            origin @ ('+' | '-' | ' ') => Some(origin),
+            _ => None,
+        };
+        total += content.len() + usize::from(prefix.is_some());
+        if total <= MAX_DIFF_BYTES {
+            patch.extend(prefix);
+            patch.push_str(&content);
+        }
+        true
+    })?;
+    text.push_str(&patch);
+    if total > MAX_DIFF_BYTES {
+        text.push_str(&format!(
+            "\n[The diff was cut off after {MAX_DIFF_BYTES} of {total} bytes, give a path to see the rest.]\n"
+        ));
+    }
+    Ok(text)
+}
+
+/// Diff options limited to `path`, if given.
+fn diff_options(repo: &Repository, workspace: &Path, path: Option<&str>) -> anyhow::Result<DiffOptions> {
+    let mut options = DiffOptions::new();
+    if let Some(path) = path {
+        let path = repo_path(repo, workspace, path)?;
+        if !path.as_os_str().is_empty() {
+            options.pathspec(path);
+        }
+    }
+    Ok(options)
+}
+
+fn resolve_commit<'repo>(repo: &'repo Repository, revision: Option<&str>) -> anyhow::Result<Commit<'repo>> {
+    let revision = revision.unwrap_or("HEAD");
+    repo.revparse_single(revision)
+        .and_then(|object| object.peel_to_commit())
+        .with_context(|| format!("{revision} isn't a commit in this repository"))
+}
+
+/// `path`, relative to the workspace or absolute, relative to the root of the repository.
+pub(crate) fn repo_path(repo: &Repository, workspace: &Path, path: impl AsRef<Path>) -> anyhow::Result<PathBuf> {
+    let workdir = repo.workdir().context("The repository has no working tree")?;
+    let path = workspace.join(path);
+    if let Ok(relative) = path.strip_prefix(workdir) {
+        return Ok(relative.to_path_buf());
+    }
+    // The workspace may have been reached through a symlink.
+    let canonical = |path: &Path| path.canonicalize().unwrap_or_else(|_| path.to_path_buf());
+    let path = canonical(workspace).join(path.strip_prefix(workspace).unwrap_or(&path));
+    path.strip_prefix(canonical(workdir))
+        .map(Path::to_path_buf)
+        .map_err(|_| anyhow::anyhow!("{} isn't in the repository at {}", path.display(), workdir.display()))
+}
+
+fn short_id(oid: Oid) -> String {
+    oid.to_string()[..7].to_string()
+}
+
+fn format_date(time: git2::Time, format: &str) -> String {
+    chrono::FixedOffset::east_opt(time.offset_minutes() * 60)
+        .zip(chrono::DateTime::from_timestamp(time.seconds(), 0))
+        .map(|(offset, date)| date.with_timezone(&offset).format(format).to_string())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use git2::{
+        RepositoryInitOptions,
+        Signature,
+    };
+
+    use super::*;
+
+    fn commit(repo: &Repository, message: &str) -> Oid {
+        let mut index = repo.index().unwrap();
+        let tree = repo.find_tree(index.write_tree().unwrap()).unwrap();
+        let signature = Signature::now("Ada", "ada@example.com").unwrap();
+        let parents = repo.head().ok().map(|head| head.peel_to_commit().unwrap());
+        repo.commit(
+            Some("HEAD"),
+            &signature,
+            &signature,
+            message,
+            &tree,
+            &parents.iter().collect::<Vec<_>>(),
+        )
+        .unwrap()
+    }
+
+    fn stage(repo: &Repository, path: &str) {
+        let mut index = repo.index().unwrap();
+        index.add_path(Path::new(path)).unwrap();
+        index.write().unwrap();
+    }
+
+    #[test]
+    fn test_git_tools() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let workspace = temp_dir.path();
+        let repo = Repository::init_opts(workspace, RepositoryInitOptions::new().initial_head("main")).unwrap();
+        assert_eq!(branch(workspace).unwrap(), "main");
+        assert!(status(&repo).unwrap().contains("On branch main, no commits yet\n"));
+
+        std::fs::write(workspace.join("a.txt"), "one\ntwo\n").unwrap();
+        stage(&repo, "a.txt");
+        let first = commit(&repo, "Add a\n\nWith two lines.");
+        std::fs::write(workspace.join("a.txt"), "one\n2\n").unwrap();
+        stage(&repo, "a.txt");
+        let second = commit(&repo, "Change a");
+        assert_eq!(summary(workspace).unwrap(), "clean");
+
+        std::fs::write(workspace.join("a.txt"), "one\n2\nthree\n").unwrap();
+        std::fs::write(workspace.join("b.txt"), "b\n").unwrap();
+        stage(&repo, "b.txt");
+        std::fs::write(workspace.join("c.txt"), "c\n").unwrap();
+        assert_eq!(
+            summary(workspace).unwrap(),
+            "3 changed files (1 staged, 1 not staged, 1 untracked)"
+        );
+        let text = status(&repo).unwrap();
+        assert!(
+            text.contains(
+                "On branch main\n\nStaged:\n  new file: b.txt\n\nNot staged:\n  modified: a.txt\n\nUntracked:\n  \
+                 c.txt\n"
+            ),
+            "{text}"
+        );
+
+        let text = diff(&repo, workspace, GitDiffArgs::default()).unwrap();
+        assert!(text.starts_with("1 file changed, +1 -0\n  a.txt | +1 -0\n\n"), "{text}");
+        assert!(text.contains("@@ -1,2 +1,3 @@\n one\n 2\n+three\n"), "{text}");
+        let staged = GitDiffArgs {
+            path: Some(workspace.join("b.txt").display().to_string()),
+            staged: true,
+        };
+        assert!(
+            diff(&repo, workspace, staged)
+                .unwrap()
+                .contains("+++ b/b.txt\n@@ -0,0 +1 @@\n+b\n")
+        );
+
+        let text = log(&repo, workspace, GitLogArgs::default()).unwrap();
+        let lines = text.lines().collect::<Vec<_>>();
+        assert_eq!(lines.len(), 2, "{text}");
+        assert!(lines[0].starts_with(&short_id(second)) && lines[0].ends_with(" Ada: Change a"));
+        let text = log(
+            &repo,
+            workspace,
+            GitLogArgs {
+                max_count: Some(1),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert!(
+            text.contains(&format!("give {}^ as the revision", short_id(first))),
+            "{text}"
+        );
+        let text = log(
+            &repo,
+            workspace,
+            GitLogArgs {
+                path: Some("b.txt".to_string()),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(text, "No commits found.\n");
+
+        let text = show(
+            &repo,
+            workspace,
+            GitShowArgs {
+                revision: Some("HEAD~1".to_string()),
+                path: None,
+            },
+        )
+        .unwrap();
+        assert!(text.starts_with(&format!("commit {first}\nAuthor: Ada <ada@example.com>\n")));
+        assert!(
+            text.contains("\n    Add a\n    \n    With two lines.\n\n1 file changed, +2 -0\n"),
+            "{text}"
+        );
+        assert!(text.contains("+one\n+two\n"), "{text}");
+        assert!(
+            show(
+                &repo,
+                workspace,
+                GitShowArgs {
+                    revision: Some("nope".to_string()),
+                    path: None
+                }
+            )
+            .is_err()
+        );
+
+        let blame_args = |start_line, end_line| GitBlameArgs {
+            target_file: "a.txt".to_string(),
+            start_line,
+            end_line,
+        };
+        let text = blame(&repo, workspace, blame_args(2, 10)).unwrap();
+        let lines = text.lines().collect::<Vec<_>>();
+        assert!(
+            lines[0].starts_with(&short_id(second)) && lines[0].ends_with(" 2: 2"),
+            "{text}"
+        );
+        assert_eq!(lines[1], "uncommitted 3: three");
+        assert!(blame(&repo, workspace, blame_args(4, 4)).is_err());
+        assert!(
+            blame(
+                &repo,
+                workspace,
+                GitBlameArgs {
+                    target_file: "c.txt".to_string(),
+                    start_line: 1,
+                    end_line: 1,
+                }
+            )
+            .is_err()
+        );
+    }
+
+    #[test]
+    fn test_describe_diff_lists_limited_files() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let workspace = temp_dir.path();
+        let repo = Repository::init(workspace).unwrap();
+        let mut index = repo.index().unwrap();
+        for i in 0..MAX_DIFF_FILES + 3 {
+            std::fs::write(workspace.join(format!("{i:03}.txt")), "line\n").unwrap();
+            index.add_path(Path::new(&format!("{i:03}.txt"))).unwrap();
+        }
+        let diff = repo.diff_tree_to_index(None, Some(&index), None).unwrap();
+        let text = describe_diff(&diff).unwrap();
+        assert!(text.starts_with("203 files changed, +203 -0\n"), "{text}");
+        assert!(
+            text.contains("  199.txt | +1 -0\n  3 more files not listed\n"),
+            "{text}"
+        );
+        assert!(!text.contains("  200.txt | "), "{text}");
+    }
+}
@@ -1,3 +1,5 @@
+pub mod code_intel;
 pub mod executor;
+pub mod git;
 pub mod prompts;
 pub mod protocol;
@@ -1,3 +1,5 @@
+use std::path::PathBuf;
+
 use async_openai::types::{
     ChatCompletionTool,
     ChatCompletionToolType,
@@ -6,6 +8,74 @@ use async_openai::types::{
 use serde::Deserialize;
 use serde_json::json;
 
+/// Names of every tool the agent can run.
+pub const TOOL_NAMES: &[&str] = &[
+    "read_file",
+    "list_dir",
+    "edit_file",
+    "git_status",
+    "git_diff",
+    "git_log",
+    "git_show",
+    "git_blame",
+    "outline_file",
+    "find_definition",
+    "find_references",
+    "read_symbol",
+];
+
+/// The tools that change files, which are only offered when the config lists them.
+pub const WRITE_TOOLS: &[&str] = &["edit_file"];
+
+/// Definitions of the tools in `names`, in the order given. Unknown names are skipped.
+pub fn tools(names: &[String]) -> Vec<ChatCompletionTool> {
+    names
+        .iter()
+        .filter_map(|name| match name.as_str() {
+            "read_file" => Some(read_file_tool()),
+            "list_dir" => Some(list_dir_tool()),
+            "edit_file" => Some(edit_file_tool()),
+            "git_status" => Some(git_status_tool()),
+            "git_diff" => Some(git_diff_tool()),
+            "git_log" => Some(git_log_tool()),
+            "git_show" => Some(git_show_tool()),
+            "git_blame" => Some(git_blame_tool()),
+            "outline_file" => Some(outline_file_tool()),
+            "find_definition" => Some(find_definition_tool()),
+            "find_references" => Some(find_references_tool()),
+            "read_symbol" => Some(read_symbol_tool()),
+            _ => None,
+        })
+        .collect()
+}
+
+/// The file a call of `name` is going to change, so it can be snapshotted first. `None` for tools that don't change
+/// files.
+pub fn mutated_file(name: &str, args: &str) -> Option<PathBuf> {
+    match name {
+        "edit_file" => {
+            let args: EditFileArgs = serde_json::from_str(args).ok()?;
+            Some(PathBuf::from(args.target_file))
+        }
+        _ => None,
+    }
+}
+
+/// The file or directory a call of `name` reads or changes, if any.
+pub fn touched_path(name: &str, args: &str) -> Option<PathBuf> {
+    let path = match name {
+        "read_file" => serde_json::from_str::<ReadFileArgs>(args).ok()?.target_file,
+        "list_dir" => serde_json::from_str::<ListDirArgs>(args).ok()?.target_directory,
+        "edit_file" => serde_json::from_str::<EditFileArgs>(args).ok()?.target_file,
+        "git_blame" => serde_json::from_str::<GitBlameArgs>(args).ok()?.target_file,
+        "outline_file" => serde_json::from_str::<OutlineFileArgs>(args).ok()?.target_file,
+        "read_symbol" => serde_json::from_str::<ReadSymbolArgs>(args).ok()?.target_file,
+        "find_definition" | "find_references" => serde_json::from_str::<FindSymbolArgs>(args).ok()?.path?,
+        _ => return None,
+    };
+    Some(PathBuf::from(path))
+}
+
 const READ_FILE_PROMPT: &str = r#"
 Reads a file from the local filesystem. You can access any file directly by using this tool.
 If the User provides a path to a file assume that path is valid. It is okay to read a file that does not exist; an error will be returned.
@@ -73,3 +143,378 @@ pub fn list_dir_tool() -> ChatCompletionTool {
 pub struct ListDirArgs {
     pub target_directory: String,
 }
+
+const EDIT_FILE_PROMPT: &str = r#"
+Edits a file by replacing an exact string in it, or creates a new file.
+
+Usage:
+- Read the file first, so 'old_string' matches its contents exactly, including indentation.
+- 'old_string' must occur exactly once in the file. Include enough surrounding lines to make it unique, or set 'replace_all' to replace every occurrence.
+- To create a file, leave 'old_string' empty and put the whole contents in 'new_string'. Missing parent directories are created.
+- Only files in the workspace can be edited or created.
+- The result is a unified diff of the change.
+"#;
+
+pub fn edit_file_tool() -> ChatCompletionTool {
+    ChatCompletionTool {
+        r#type: ChatCompletionToolType::Function,
+        function: FunctionObject {
+            name: "edit_file".to_string(),
+            description: Some(EDIT_FILE_PROMPT.to_string()),
+            parameters: Some(json!({
+                "type": "object",
+                "properties": {
+                    "target_file": {
+                        "type": "string",
+                        "description": "The path of the file to edit or create, relative to the workspace or absolute."
+                    },
+                    "old_string": {
+                        "type": "string",
+                        "description": "The text to replace. Empty to create a new file."
+                    },
+                    "new_string": {
+                        "type": "string",
+                        "description": "The text to replace it with."
+                    },
+                    "replace_all": {
+                        "type": "boolean",
+                        "description": "Replace every occurrence of 'old_string' instead of exactly one. Defaults to false."
+                    }
+                },
+                "required": ["target_file", "old_string", "new_string"],
+            })),
+            strict: None,
+        },
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct EditFileArgs {
+    pub target_file: String,
+    pub old_string: String,
+    pub new_string: String,
+    #[serde(default)]
+    pub replace_all: bool,
+}
+
+const GIT_STATUS_PROMPT: &str = r#"
+Shows the state of the git repository the workspace is in: the current branch and how far it is ahead of or behind its upstream, and the files that are staged, changed but not staged, untracked or in conflict.
+"#;
+
+pub fn git_status_tool() -> ChatCompletionTool {
+    ChatCompletionTool {
+        r#type: ChatCompletionToolType::Function,
+        function: FunctionObject {
+            name: "git_status".to_string(),
+            description: Some(GIT_STATUS_PROMPT.to_string()),
+            parameters: Some(json!({
+                "type": "object",
+                "properties": {},
+            })),
+            strict: None,
+        },
+    }
+}
+
+const GIT_DIFF_PROMPT: &str = r#"
+Shows the uncommitted changes in the git repository as a unified diff, after a summary of the lines added and removed per file.
+
+Usage:
+- By default, shows the changes that aren't staged yet. Set 'staged' to see what would be committed instead.
+- Give 'path' to only see the changes to a file or directory.
+- Long diffs are cut off; narrow them down with 'path'.
+"#;
+
+pub fn git_diff_tool() -> ChatCompletionTool {
+    ChatCompletionTool {
+        r#type: ChatCompletionToolType::Function,
+        function: FunctionObject {
+            name: "git_diff".to_string(),
+            description: Some(GIT_DIFF_PROMPT.to_string()),
+            parameters: Some(json!({
+                "type": "object",
+                "properties": {
+                    "path": {
+                        "type": "string",
+                        "description": "Only show changes to this file or directory."
+                    },
+                    "staged": {
+                        "type": "boolean",
+                        "description": "Show the staged changes instead of the unstaged ones. Defaults to false."
+                    }
+                },
+            })),
+            strict: None,
+        },
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct GitDiffArgs {
+    pub path: Option<String>,
+    #[serde(default)]
+    pub staged: bool,
+}
+
+const GIT_LOG_PROMPT: &str = r#"
+Lists commits of the git repository, newest first, one per line: the short hash, the date, the author and the subject.
+
+Usage:
+- Give 'path' to only list the commits that changed a file or directory.
+- Give 'revision' to start from a branch, tag or commit other than HEAD.
+- Use git_show to see a commit in full.
+"#;
+
+pub fn git_log_tool() -> ChatCompletionTool {
+    ChatCompletionTool {
+        r#type: ChatCompletionToolType::Function,
+        function: FunctionObject {
+            name: "git_log".to_string(),
+            description: Some(GIT_LOG_PROMPT.to_string()),
+            parameters: Some(json!({
+                "type": "object",
+                "properties": {
+                    "path": {
+                        "type": "string",
+                        "description": "Only list commits that changed this file or directory."
+                    },
+                    "revision": {
+                        "type": "string",
+                        "description": "The branch, tag or commit to start from. Defaults to HEAD."
+                    },
+                    "max_count": {
+                        "type": "integer",
+                        "description": "How many commits to list, at most 100. Defaults to 20."
+                    }
+                },
+            })),
+            strict: None,
+        },
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct GitLogArgs {
+    pub path: Option<String>,
+    pub revision: Option<String>,
+    pub max_count: Option<usize>,
+}
+
+const GIT_SHOW_PROMPT: &str = r#"
+Shows a commit of the git repository: its hash, author, date and full message, a summary of the lines added and removed per file, and its diff against its first parent.
+"#;
+
+pub fn git_show_tool() -> ChatCompletionTool {
+    ChatCompletionTool {
+        r#type: ChatCompletionToolType::Function,
+        function: FunctionObject {
+            name: "git_show".to_string(),
+            description: Some(GIT_SHOW_PROMPT.to_string()),
+            parameters: Some(json!({
+                "type": "object",
+                "properties": {
+                    "revision": {
+                        "type": "string",
+                        "description": "The commit, branch or tag to show. Defaults to HEAD."
+                    },
+                    "path": {
+                        "type": "string",
+                        "description": "Only show the changes to this file or directory."
+                    }
+                },
+            })),
+            strict: None,
+        },
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct GitShowArgs {
+    pub revision: Option<String>,
+    pub path: Option<String>,
+}
+
+const GIT_BLAME_PROMPT: &str = r#"
+Shows which commit last changed each line of a file, for a range of lines: the short hash, the author and the date of the commit, then the line number and the line. Lines with uncommitted changes show as "uncommitted".
+"#;
+
+pub fn git_blame_tool() -> ChatCompletionTool {
+    ChatCompletionTool {
+        r#type: ChatCompletionToolType::Function,
+        function: FunctionObject {
+            name: "git_blame".to_string(),
+            description: Some(GIT_BLAME_PROMPT.to_string()),
+            parameters: Some(json!({
+                "type": "object",
+                "properties": {
+                    "target_file": {
+                        "type": "string",
+                        "description": "The path of the file, relative to the workspace or absolute."
+                    },
+                    "start_line": {
+                        "type": "integer",
+                        "description": "The first line to show, counting from 1."
+                    },
+                    "end_line": {
+                        "type": "integer",
+                        "description": "The last line to show. At most 200 lines are shown at once."
+                    }
+                },
+                "required": ["target_file", "start_line", "end_line"],
+            })),
+            strict: None,
+        },
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct GitBlameArgs {
+    pub target_file: String,
+    pub start_line: usize,
+    pub end_line: usize,
+}
+
+const OUTLINE_FILE_PROMPT: &str = r#"
+Lists the functions, types, impls, classes and other items defined in a source file, with the lines each one spans, so the file doesn't have to be read whole to find one of them. Methods are listed under the impl, class or trait they're in.
+
+Works for Rust, TypeScript, JavaScript, Python and Go files.
+"#;
+
+pub fn outline_file_tool() -> ChatCompletionTool {
+    ChatCompletionTool {
+        r#type: ChatCompletionToolType::Function,
+        function: FunctionObject {
+            name: "outline_file".to_string(),
+            description: Some(OUTLINE_FILE_PROMPT.to_string()),
+            parameters: Some(json!({
+                "type": "object",
+                "properties": {
+                    "target_file": {
+                        "type": "string",
+                        "description": "The path of the file, relative to the workspace or absolute."
+                    }
+                },
+                "required": ["target_file"],
+            })),
+            strict: None,
+        },
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct OutlineFileArgs {
+    pub target_file: String,
+}
+
+const FIND_DEFINITION_PROMPT: &str = r#"
+Finds where a function, type, method or other item is defined in the workspace's Rust, TypeScript, JavaScript, Python and Go files, skipping gitignored ones. Lists the file and lines of each definition.
+
+Usage:
+- Give a method as 'Type::method' or 'Type.method' to only find it on that type.
+- Give 'path' to only search a directory or file.
+- Use read_symbol to read a definition once found.
+"#;
+
+pub fn find_definition_tool() -> ChatCompletionTool {
+    ChatCompletionTool {
+        r#type: ChatCompletionToolType::Function,
+        function: FunctionObject {
+            name: "find_definition".to_string(),
+            description: Some(FIND_DEFINITION_PROMPT.to_string()),
+            parameters: Some(json!({
+                "type": "object",
+                "properties": {
+                    "name": {
+                        "type": "string",
+                        "description": "The name of the item."
+                    },
+                    "path": {
+                        "type": "string",
+                        "description": "The directory or file to search. Defaults to the workspace."
+                    }
+                },
+                "required": ["name"],
+            })),
+            strict: None,
+        },
+    }
+}
+
+/// The arguments of `find_definition` and `find_references`.
+#[derive(Debug, Deserialize)]
+pub struct FindSymbolArgs {
+    pub name: String,
+    pub path: Option<String>,
+}
+
+const FIND_REFERENCES_PROMPT: &str = r#"
+Finds the lines in the workspace's Rust, TypeScript, JavaScript, Python and Go files that use a name, skipping gitignored files. Matches are by name only, so anything else of the same name shows up too, but mentions in comments and strings don't.
+
+Usage:
+- Give a plain name, like 'parse' rather than 'Config::parse'.
+- Give 'path' to only search a directory or file.
+"#;
+
+pub fn find_references_tool() -> ChatCompletionTool {
+    ChatCompletionTool {
+        r#type: ChatCompletionToolType::Function,
+        function: FunctionObject {
+            name: "find_references".to_string(),
+            description: Some(FIND_REFERENCES_PROMPT.to_string()),
+            parameters: Some(json!({
+                "type": "object",
+                "properties": {
+                    "name": {
+                        "type": "string",
+                        "description": "The name to look for."
+                    },
+                    "path": {
+                        "type": "string",
+                        "description": "The directory or file to search. Defaults to the workspace."
+                    }
+                },
+                "required": ["name"],
+            })),
+            strict: None,
+        },
+    }
+}
+
+const READ_SYMBOL_PROMPT: &str = r#"
+Reads one function, type, impl, class or other item from a source file, with the comments and attributes right before it, instead of the whole file.
+
+Usage:
+- Give a method as 'Type::method' or 'Type.method' if other types have one of the same name.
+- Use outline_file to see what a file defines.
+"#;
+
+pub fn read_symbol_tool() -> ChatCompletionTool {
+    ChatCompletionTool {
+        r#type: ChatCompletionToolType::Function,
+        function: FunctionObject {
+            name: "read_symbol".to_string(),
+            description: Some(READ_SYMBOL_PROMPT.to_string()),
+            parameters: Some(json!({
+                "type": "object",
+                "properties": {
+                    "target_file": {
+                        "type": "string",
+                        "description": "The path of the file, relative to the workspace or absolute."
+                    },
+                    "name": {
+                        "type": "string",
+                        "description": "The name of the item."
+                    }
+                },
+                "required": ["target_file", "name"],
+            })),
+            strict: None,
+        },
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ReadSymbolArgs {
+    pub target_file: String,
+    pub name: String,
+}
@@ -0,0 +1,748 @@
+//! The transcript shown in the chat area: each message rendered to styled lines, wrapped at the width of the chat area
+//! and cached, so that scrolling knows the real number of rows and drawing only touches the messages in view.
+//!
+//! The assistant message that's streaming in changes with every token. Its markdown is split at the last block that
+//! more text can't change anymore, and only what comes after that is rendered again.
+
+use std::collections::HashSet;
+
+use ratatui::{
+    style::Stylize,
+    text::{
+        Line,
+        Span,
+    },
+    widgets::{
+        Paragraph,
+        Wrap,
+    },
+};
+
+use crate::{
+    diff_render::render_diff,
+    markdown_render::render_markdown_text,
+    syntax_highlight::SyntaxHighlighter,
+    ui_state::{
+        ChatUIMessage,
+        ChatUIToolCall,
+    },
+};
+
+/// Where the transcript is scrolled to.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Scroll {
+    /// Following new output at the bottom.
+    Pinned,
+    /// The top of the view is `row` rows into message `message`. Anchoring to a message rather than an absolute row
+    /// keeps the same text in view when a resize rewraps everything above it.
+    At { message: usize, row: usize },
+}
+
+/// A message's lines and how many rows they wrap to.
+pub struct MessageLayout {
+    pub lines: Vec<Line<'static>>,
+    pub rows: usize,
+    /// Whether the message or the width changed since it was laid out.
+    stale: bool,
+    /// For assistant messages, the finished part of the markdown.
+    markdown: StableMarkdown,
+}
+
+impl MessageLayout {
+    fn stale() -> Self {
+        Self {
+            lines: vec![],
+            rows: 0,
+            stale: true,
+            markdown: StableMarkdown::default(),
+        }
+    }
+}
+
+/// The start of an assistant message's markdown that's done streaming, rendered once.
+#[derive(Default)]
+struct StableMarkdown {
+    source: String,
+    lines: Vec<Line<'static>>,
+    /// The rows `lines` wrap to, and at which width.
+    rows: Option<(u16, usize)>,
+}
+
+impl StableMarkdown {
+    /// Renders `text` and counts its rows at `width`, rendering only what's new since the last call.
+    fn layout(&mut self, text: &str, width: u16) -> (Vec<Line<'static>>, usize) {
+        if !text.starts_with(&self.source) {
+            *self = Self::default();
+        }
+        let boundary = stable_boundary(text);
+        if boundary > self.source.len() {
+            let mut lines = render_markdown_text(text[self.source.len()..boundary].trim_start_matches('\n')).lines;
+            if self.lines.is_empty() {
+                add_assistant_prefix(&mut lines);
+            } else {
+                self.lines.push(Line::default());
+            }
+            self.lines.extend(lines);
+            self.source = text[..boundary].to_string();
+            self.rows = None;
+        }
+        let stable_rows = match self.rows {
+            Some((rows_width, rows)) if rows_width == width => rows,
+            _ => {
+                let rows = row_count(&self.lines, width);
+                self.rows = Some((width, rows));
+                rows
+            }
+        };
+
+        let tail_source = text[self.source.len()..].trim_start_matches('\n');
+        let mut tail = render_markdown_text(tail_source).lines;
+        if self.lines.is_empty() {
+            add_assistant_prefix(&mut tail);
+        } else if !tail_source.trim().is_empty() {
+            // The blank line between blocks, which a block that's only just started may not have rendered yet.
+            tail.insert(0, Line::default());
+        }
+        let rows = stable_rows + row_count(&tail, width);
+        let mut lines = self.lines.clone();
+        lines.extend(tail);
+        (lines, rows)
+    }
+}
+
+/// Where `text` can be split into markdown that renders the same in two parts as in one: the last blank line between
+/// two top-level paragraphs, headings or code blocks that isn't inside a code block. Lists, quotes and indented blocks
+/// can continue past a blank line, so they're never split.
+fn stable_boundary(text: &str) -> usize {
+    let mut boundary = 0;
+    let mut in_fence = false;
+    // The previous non-blank line, and whether a blank line came after it.
+    let mut previous: Option<&str> = None;
+    let mut blank_after_previous = None;
+    let mut offset = 0;
+    for line in text.split_inclusive('\n') {
+        let start = offset;
+        offset += line.len();
+        let content = line.trim_end_matches('\n');
+        if content.trim().is_empty() {
+            if !in_fence && previous.is_some() && blank_after_previous.is_none() {
+                blank_after_previous = Some(start - 1);
+            }
+            continue;
+        }
+        if !in_fence
+            && let Some(blank) = blank_after_previous
+            && previous.is_some_and(is_simple_block_line)
+            && is_simple_block_line(content)
+            // The line has to be complete, or it could still turn into a list item or quote.
+            && line.ends_with('\n')
+        {
+            boundary = blank;
+        }
+        if content.starts_with("```") || content.starts_with("~~~") {
+            in_fence = !in_fence;
+        }
+        previous = Some(content);
+        blank_after_previous = None;
+    }
+    boundary
+}
+
+/// Whether a line can only belong to a top-level block that a blank line ends.
+fn is_simple_block_line(line: &str) -> bool {
+    let Some(first) = line.chars().next() else {
+        return false;
+    };
+    let list_marker = line
+        .trim_start_matches(|ch: char| ch.is_ascii_digit())
+        .starts_with(['.', ')'])
+        && first.is_ascii_digit();
+    !first.is_whitespace() && !matches!(first, '-' | '*' | '+' | '>' | '|' | '<' | '[') && !list_marker
+}
+
+fn add_assistant_prefix(lines: &mut [Line<'static>]) {
+    if let Some(first_line) = lines.first_mut() {
+        first_line.spans.insert(0, "assistant: ".yellow().bold());
+    }
+}
+
+fn row_count(lines: &[Line<'static>], width: u16) -> usize {
+    Paragraph::new(lines.to_vec())
+        .wrap(Wrap { trim: false })
+        .line_count(width)
+}
+
+/// The slice of a message that's in view.
+pub struct VisibleMessage<'a> {
+    pub layout: &'a MessageLayout,
+    /// Rows of the message above the view.
+    pub skip: usize,
+    /// Rows of the message in the view.
+    pub rows: usize,
+}
+
+pub struct Transcript {
+    /// Size of the chat area inside its border.
+    width: u16,
+    height: u16,
+    messages: Vec<MessageLayout>,
+    scroll: Scroll,
+    /// Whether reasoning blocks are expanded. They're collapsed to a single line by default.
+    show_reasoning: bool,
+    /// The tool calls that show their arguments and result.
+    expanded: HashSet<usize>,
+    /// The tool call that keys expand and collapse.
+    focused: Option<usize>,
+}
+
+impl Transcript {
+    pub fn new(show_reasoning: bool) -> Self {
+        Self {
+            width: 0,
+            height: 0,
+            messages: vec![],
+            scroll: Scroll::Pinned,
+            show_reasoning,
+            expanded: HashSet::new(),
+            focused: None,
+        }
+    }
+
+    pub fn toggle_reasoning(&mut self) {
+        self.show_reasoning = !self.show_reasoning;
+        self.invalidate_all();
+    }
+
+    /// Drops the layout of a message that changed.
+    pub fn invalidate(&mut self, index: usize) {
+        if let Some(message) = self.messages.get_mut(index) {
+            message.stale = true;
+        }
+    }
+
+    fn invalidate_all(&mut self) {
+        for message in &mut self.messages {
+            message.stale = true;
+        }
+    }
+
+    /// Expands or collapses the tool call at `index`.
+    pub fn toggle_expanded(&mut self, index: usize) {
+        if !self.expanded.remove(&index) {
+            self.expanded.insert(index);
+        }
+        self.invalidate(index);
+    }
+
+    pub fn focused(&self) -> Option<usize> {
+        self.focused
+    }
+
+    /// Moves the focus to the tool call at `index`, scrolling it into view, or takes it away.
+    pub fn focus(&mut self, index: Option<usize>) {
+        for index in [self.focused, index].into_iter().flatten() {
+            self.invalidate(index);
+        }
+        self.focused = index;
+        let Some(index) = index.filter(|index| *index < self.messages.len()) else {
+            return;
+        };
+        let start = self.messages[..index].iter().map(|message| message.rows).sum::<usize>();
+        let top = self.top_row();
+        if start < top {
+            self.scroll_to(start);
+        } else if start >= top + self.height() {
+            self.scroll_to(start + 1 - self.height());
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.messages.clear();
+        self.expanded.clear();
+        self.focused = None;
+        self.scroll = Scroll::Pinned;
+    }
+
+    /// Drops the messages from `len` on.
+    pub fn truncate(&mut self, len: usize) {
+        self.messages.truncate(len);
+        self.expanded.retain(|index| *index < len);
+        self.focused = self.focused.filter(|index| *index < len);
+    }
+
+    pub fn resize(&mut self, width: u16, height: u16) {
+        if width != self.width {
+            self.invalidate_all();
+        }
+        self.width = width;
+        self.height = height;
+    }
+
+    /// Lays out the messages that are new or changed since the last update.
+    pub fn update(&mut self, messages: &[ChatUIMessage]) {
+        if self.width == 0 {
+            return;
+        }
+        self.messages.truncate(messages.len());
+        self.messages.resize_with(messages.len(), MessageLayout::stale);
+        for (index, (layout, message)) in self.messages.iter_mut().zip(messages).enumerate() {
+            if !layout.stale {
+                continue;
+            }
+            (layout.lines, layout.rows) = match message {
+                ChatUIMessage::System(s) => layout.markdown.layout(&s.text, self.width),
+                _ => {
+                    let lines = message_lines(
+                        message,
+                        self.show_reasoning,
+                        self.expanded.contains(&index),
+                        self.focused == Some(index),
+                    );
+                    let rows = row_count(&lines, self.width);
+                    (lines, rows)
+                }
+            };
+            layout.stale = false;
+        }
+        if let Scroll::At { message, .. } = self.scroll
+            && message >= self.messages.len()
+        {
+            self.scroll = Scroll::Pinned;
+        }
+    }
+
+    pub fn height(&self) -> usize {
+        self.height as usize
+    }
+
+    pub fn total_rows(&self) -> usize {
+        self.messages.iter().map(|message| message.rows).sum()
+    }
+
+    /// The first row in view.
+    pub fn top_row(&self) -> usize {
+        let max_top = self.total_rows().saturating_sub(self.height());
+        match self.scroll {
+            Scroll::Pinned => max_top,
+            Scroll::At { message, row } => {
+                let above = self.messages[..message]
+                    .iter()
+                    .map(|message| message.rows)
+                    .sum::<usize>();
+                let row = row.min(self.messages[message].rows.saturating_sub(1));
+                (above + row).min(max_top)
+            }
+        }
+    }
+
+    pub fn scroll_up(&mut self, rows: usize) {
+        self.scroll_to(self.top_row().saturating_sub(rows));
+    }
+
+    pub fn scroll_down(&mut self, rows: usize) {
+        self.scroll_to(self.top_row() + rows);
+    }
+
+    /// Scrolls so `top` is the first row in view. Reaching the bottom pins the view there, so it follows new output.
+    fn scroll_to(&mut self, top: usize) {
+        if top >= self.total_rows().saturating_sub(self.height()) {
+            self.scroll = Scroll::Pinned;
+            return;
+        }
+        let mut above = 0;
+        for (index, message) in self.messages.iter().enumerate() {
+            let rows = message.rows;
+            if top < above + rows {
+                self.scroll = Scroll::At {
+                    message: index,
+                    row: top - above,
+                };
+                return;
+            }
+            above += rows;
+        }
+    }
+
+    /// The message shown `row` rows from the top of the view.
+    pub fn message_at(&self, row: usize) -> Option<usize> {
+        let row = self.top_row() + row;
+        let mut above = 0;
+        for (index, message) in self.messages.iter().enumerate() {
+            above += message.rows;
+            if row < above {
+                return Some(index);
+            }
+        }
+        None
+    }
+
+    /// The messages in view, top to bottom.
+    pub fn visible(&self) -> Vec<VisibleMessage<'_>> {
+        let top = self.top_row();
+        let bottom = top + self.height();
+        let mut visible = vec![];
+        let mut above = 0;
+        for layout in &self.messages {
+            let start = above;
+            above += layout.rows;
+            if above <= top {
+                continue;
+            }
+            if start >= bottom {
+                break;
+            }
+            let skip = top.saturating_sub(start);
+            visible.push(VisibleMessage {
+                layout,
+                skip,
+                rows: (layout.rows - skip).min(bottom - start.max(top)),
+            });
+        }
+        visible
+    }
+}
+
+/// Renders one message to styled lines, before wrapping. `expanded` only matters for tool calls, `focused` for prompts
+/// and tool calls.
+pub fn message_lines(
+    message: &ChatUIMessage,
+    show_reasoning: bool,
+    expanded: bool,
+    focused: bool,
+) -> Vec<Line<'static>> {
+    let mut lines = vec![];
+    match message {
+        ChatUIMessage::User(u) => {
+            let mut text_lines = u.text.lines();
+            let first = text_lines.next().unwrap_or_default().to_string();
+            let mut header = Line::from(vec!["user: ".cyan().bold(), first.into()]);
+            if focused {
+                header = header.on_dark_gray();
+            }
+            lines.push(header);
+            lines.extend(text_lines.map(|line| Line::from(line.to_string())));
+            if !u.attachments.is_empty() {
+                lines.push(
+                    format!("      attached {}", u.attachments.join(", "))
+                        .dark_gray()
+                        .into(),
+                );
+            }
+        }
+        ChatUIMessage::System(s) => {
+            // Render the system message text as markdown, with the "assistant: " prefix on the first line
+            lines = render_markdown_text(&s.text).lines;
+            add_assistant_prefix(&mut lines);
+        }
+        ChatUIMessage::Reasoning(r) => {
+            if show_reasoning {
+                lines.push("thinking (Ctrl-t to collapse)".dark_gray().italic().into());
+                for line in r.text.lines() {
+                    lines.push(Line::from(format!("│ {line}")).dark_gray());
+                }
+            } else {
+                let line_count = r.text.lines().count();
+                lines.push(
+                    format!("thinking… ({line_count} lines, Ctrl-t to expand)")
+                        .dark_gray()
+                        .italic()
+                        .into(),
+                );
+            }
+        }
+        ChatUIMessage::ToolCall(tool_call) => lines = tool_call_lines(tool_call, expanded, focused),
+        ChatUIMessage::Compaction(c) => {
+            let mut summary = vec![];
+            if c.summarized_turns > 0 {
+                summary.push(format!("summarized {} turns", c.summarized_turns));
+            }
+            if c.elided_tool_results > 0 {
+                summary.push(format!("elided {} tool results", c.elided_tool_results));
+            }
+            lines.push(
+                format!(
+                    "── compacted: {} (~{} → ~{} tokens) ──",
+                    summary.join(", "),
+                    c.tokens_before,
+                    c.tokens_after
+                )
+                .dark_gray()
+                .into(),
+            );
+        }
+    }
+    lines
+}
+
+/// How many lines of an expanded tool call's arguments or result are shown.
+const MAX_RESULT_LINES: usize = 20;
+
+/// How many lines of an edit's diff are shown.
+const MAX_DIFF_LINES: usize = 100;
+
+/// How many characters of the arguments a collapsed tool call shows.
+const MAX_HEADER_ARGS: usize = 80;
+
+/// Renders a tool call as a card: a header with the call and how it went, and once expanded, the pretty-printed
+/// arguments and the result below it. A failed call shows the first line of its error in the header.
+fn tool_call_lines(tool_call: &ChatUIToolCall, expanded: bool, focused: bool) -> Vec<Line<'static>> {
+    let (name, args) = match tool_call {
+        ChatUIToolCall::Generating { name, args }
+        | ChatUIToolCall::Executing { name, args }
+        | ChatUIToolCall::Complete { name, args, .. } => (name, args),
+    };
+    let mut header = Line::from(vec![
+        if expanded { "▾ " } else { "▸ " }.magenta(),
+        "tool: ".magenta().bold(),
+    ]);
+    // Expanded, the arguments get a section of their own.
+    header.push_span(if expanded {
+        format!("{name} ")
+    } else if args.chars().count() > MAX_HEADER_ARGS {
+        format!("{name}({}…) ", args.chars().take(MAX_HEADER_ARGS).collect::<String>())
+    } else {
+        format!("{name}({args}) ")
+    });
+    match tool_call {
+        ChatUIToolCall::Generating { .. } => header.push_span("…".magenta()),
+        ChatUIToolCall::Executing { .. } => header.push_span("running".magenta().bold()),
+        ChatUIToolCall::Complete { result: Ok(_), .. } => header.push_span("ok".green().bold()),
+        ChatUIToolCall::Complete { result: Err(error), .. } => {
+            header.push_span("error".red().bold());
+            if !expanded {
+                header.push_span(format!(": {}", error.lines().next().unwrap_or_default()).red());
+            }
+        }
+    }
+    if focused {
+        header = header.on_dark_gray();
+    }
+
+    // Edits are there to be reviewed, so their diff shows even when collapsed.
+    let diff = match tool_call {
+        ChatUIToolCall::Complete { result: Ok(output), .. } if name == "edit_file" => {
+            Some(diff_body(output, target_file(args).as_deref()))
+        }
+        _ => None,
+    };
+    let mut lines = vec![header];
+    if !expanded {
+        lines.extend(diff.into_iter().flatten());
+        return lines;
+    }
+    lines.push("  ├─ arguments".dark_gray().into());
+    lines.extend(card_body(&pretty_json(args), Some("json"), false));
+    match tool_call {
+        ChatUIToolCall::Complete { result: Ok(output), .. } => {
+            lines.push("  ├─ result".dark_gray().into());
+            lines.extend(diff.unwrap_or_else(|| card_body(output, target_file(args).as_deref(), false)));
+        }
+        ChatUIToolCall::Complete { result: Err(error), .. } => {
+            lines.push("  ├─ error".red().into());
+            lines.extend(card_body(error, None, true));
+        }
+        _ => {}
+    }
+    lines
+}
+
+/// The arguments indented for reading, or as they are if they aren't (yet) valid JSON.
+fn pretty_json(args: &str) -> String {
+    serde_json::from_str::<serde_json::Value>(args)
+        .and_then(|value| serde_json::to_string_pretty(&value))
+        .unwrap_or_else(|_| args.to_string())
+}
+
+/// The file a tool call works on, which tells the language of its result.
+fn target_file(args: &str) -> Option<String> {
+    let args = serde_json::from_str::<serde_json::Value>(args).ok()?;
+    Some(args.get("target_file")?.as_str()?.to_string())
+}
+
+/// The first `MAX_RESULT_LINES` lines of `text` inside a card, highlighted when the language is known.
+fn card_body(text: &str, language: Option<&str>, error: bool) -> Vec<Line<'static>> {
+    let shown = text.lines().take(MAX_RESULT_LINES).collect::<Vec<_>>().join("\n");
+    let body: Vec<Vec<Span<'static>>> = match language {
+        Some(language) => SyntaxHighlighter::shared()
+            .highlight_code(&shown, Some(language))
+            .lines
+            .into_iter()
+            .map(|line| {
+                line.spans
+                    .into_iter()
+                    .map(|span| Span::styled(span.content.trim_end_matches('\n').to_string(), span.style))
+                    .collect()
+            })
+            .collect(),
+        None => shown
+            .lines()
+            .map(|line| {
+                let span = Span::from(line.to_string());
+                vec![if error { span.red() } else { span.dark_gray() }]
+            })
+            .collect(),
+    };
+    let body = body.into_iter().map(Line::from).collect();
+    in_card(body, text.lines().count().saturating_sub(MAX_RESULT_LINES))
+}
+
+/// The first `MAX_DIFF_LINES` lines of a diff inside a card.
+fn diff_body(diff: &str, path: Option<&str>) -> Vec<Line<'static>> {
+    let shown = diff.lines().take(MAX_DIFF_LINES).collect::<Vec<_>>().join("\n");
+    let hidden = diff.lines().count().saturating_sub(MAX_DIFF_LINES);
+    in_card(render_diff(&shown, path), hidden)
+}
+
+/// Indents `body` into a card, noting how many more lines there are that aren't shown.
+fn in_card(mut body: Vec<Line<'static>>, hidden: usize) -> Vec<Line<'static>> {
+    for line in &mut body {
+        line.spans.insert(0, "  │ ".dark_gray());
+    }
+    if hidden > 0 {
+        body.push(Line::from(format!("  │ … {hidden} more lines")).dark_gray().italic());
+    }
+    body
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ui_state::{
+        ChatUISystemMessage,
+        ChatUIUserMessage,
+    };
+
+    fn user_message(text: &str) -> ChatUIMessage {
+        ChatUIMessage::User(ChatUIUserMessage {
+            text: text.to_string(),
+            attachments: vec![],
+        })
+    }
+
+    /// Ten messages 36 cells wide, "user: " included: one row at width 40, two at width 20.
+    fn messages() -> Vec<ChatUIMessage> {
+        (0..10)
+            .map(|i| user_message(&format!("message {i} {}", "x".repeat(20))))
+            .collect()
+    }
+
+    #[test]
+    fn test_wrapped_rows() {
+        let mut transcript = Transcript::new(false);
+        transcript.resize(40, 5);
+        transcript.update(&messages());
+        assert_eq!(transcript.total_rows(), 10);
+        transcript.resize(20, 5);
+        transcript.update(&messages());
+        assert_eq!(transcript.total_rows(), 20);
+        assert_eq!(transcript.top_row(), 15);
+
+        let visible = transcript.visible();
+        assert_eq!(visible.len(), 3);
+        assert_eq!((visible[0].skip, visible[0].rows), (1, 1));
+        assert_eq!((visible[2].skip, visible[2].rows), (0, 2));
+    }
+
+    #[test]
+    fn test_stable_markdown() {
+        // Each text, and whether any of it can be split off. Lists and quotes can't.
+        let texts = [
+            (
+                true,
+                "# Plan\n\nFirst a paragraph\nover two lines.\n\n```rust\nfn main() {\n\n    \
+                 println!();\n}\n```\n\nThen **more** text.\n\n## Done\n\nBye.",
+            ),
+            (
+                false,
+                "Steps:\n\n- one\n\n- two\n\n  still two\n\n1. three\n\n> quoted\n\nlast\n",
+            ),
+            (
+                true,
+                "Some `code` and a [link](https://example.com).\n\n    indented code\n\nAfter.\n\n\n\nGap.\n",
+            ),
+        ];
+        for (splits, text) in texts {
+            // Stream the text in a char at a time, as tokens would arrive.
+            let mut markdown = StableMarkdown::default();
+            for (end, _) in text.char_indices().skip(1).chain([(text.len(), ' ')]) {
+                let (lines, rows) = markdown.layout(&text[..end], 30);
+                let expected = message_lines(
+                    &ChatUIMessage::System(ChatUISystemMessage {
+                        text: text[..end].to_string(),
+                    }),
+                    false,
+                    false,
+                    false,
+                );
+                assert_eq!(lines, expected, "{:?}", &text[..end]);
+                assert_eq!(rows, row_count(&expected, 30), "{:?}", &text[..end]);
+            }
+            assert_eq!(!markdown.source.is_empty(), splits, "{text:?}");
+        }
+    }
+
+    #[test]
+    fn test_scrolling() {
+        let mut messages = messages();
+        let mut transcript = Transcript::new(false);
+        transcript.resize(20, 5);
+        transcript.update(&messages);
+
+        // Scrolled up, new output doesn't move the view.
+        transcript.scroll_up(6);
+        assert_eq!(transcript.top_row(), 9);
+        messages.push(user_message("one more"));
+        transcript.update(&messages);
+        assert_eq!(transcript.top_row(), 9);
+
+        // The view stays on the same message across a resize.
+        transcript.resize(40, 5);
+        transcript.update(&messages);
+        assert_eq!(transcript.top_row(), 4);
+
+        // Back at the bottom, it follows new output again.
+        transcript.scroll_down(100);
+        messages.push(user_message("and another"));
+        transcript.update(&messages);
+        assert_eq!(transcript.top_row(), transcript.total_rows() - 5);
+    }
+
+    #[test]
+    fn test_tool_call_card() {
+        let text = |lines: &[Line<'static>]| lines.iter().map(|line| line.to_string()).collect::<Vec<_>>();
+        let tool_call = |result| {
+            ChatUIMessage::ToolCall(ChatUIToolCall::Complete {
+                name: "read_file".to_string(),
+                args: r#"{"target_file":"src/main.rs"}"#.to_string(),
+                result,
+            })
+        };
+
+        let failed = tool_call(Err("No such file or directory\nwhile reading".to_string()));
+        assert_eq!(
+            text(&message_lines(&failed, false, false, false)),
+            [r#"▸ tool: read_file({"target_file":"src/main.rs"}) error: No such file or directory"#]
+        );
+
+        let source = (1..=25)
+            .map(|i| format!("let x{i} = {i};"))
+            .collect::<Vec<_>>()
+            .join("\n");
+        let lines = message_lines(&tool_call(Ok(source)), false, true, true);
+        let lines = text(&lines);
+        assert_eq!(
+            lines[..6],
+            [
+                "▾ tool: read_file ok",
+                "  ├─ arguments",
+                "  │ {",
+                r#"  │   "target_file": "src/main.rs""#,
+                "  │ }",
+                "  ├─ result",
+            ]
+        );
+        assert_eq!(lines[6], "  │ let x1 = 1;");
+        assert_eq!(lines.len(), 6 + MAX_RESULT_LINES + 1);
+        assert_eq!(lines.last().unwrap(), "  │ … 5 more lines");
+    }
+}
@@ -29,7 +29,7 @@ pub struct TimeInfo {
 }
 
 #[allow(unused)]
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, Deserialize)]
 pub struct Usage {
     pub prompt_tokens: u32,
     pub completion_tokens: u32,
@@ -38,7 +38,7 @@ pub struct Usage {
 }
 
 #[allow(unused)]
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, Deserialize)]
 pub struct PromptTokensDetails {
     pub cached_tokens: u32,
 }
@@ -76,6 +76,9 @@ pub struct ChatCompletionResponseMessage {
     /// The tool calls generated by the model, such as function calls.
     #[serde(skip_serializing_if = "Option::is_none")]
     pub tool_calls: Option<Vec<ChatCompletionMessageToolCall>>,
+    /// The model's reasoning, for providers that return it separately from the content.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reasoning: Option<String>,
 
     /// The role of the author of this message.
     pub role: Role,
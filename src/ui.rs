@@ -1,10 +1,19 @@
-use std::io::Stdout;
+use std::io::{
+    Stdout,
+    Write,
+};
 
 use crossterm::event::{
+    DisableMouseCapture,
+    EnableMouseCapture,
     Event,
     EventStream,
     KeyCode,
     KeyEventKind,
+    KeyModifiers,
+    MouseButton,
+    MouseEvent,
+    MouseEventKind,
 };
 use futures::StreamExt;
 use humansize::{
@@ -12,10 +21,17 @@ use humansize::{
     format_size,
 };
 use ratatui::{
+    Frame,
     buffer::Buffer,
-    layout::Rect,
+    layout::{
+        Position,
+        Rect,
+    },
     prelude::CrosstermBackend,
-    style::Stylize,
+    style::{
+        Modifier,
+        Stylize,
+    },
     symbols::border,
     text::{
         Line,
@@ -23,6 +39,7 @@ use ratatui::{
     },
     widgets::{
         Block,
+        Clear,
         Paragraph,
         Widget,
         Wrap,
@@ -31,145 +48,383 @@ use ratatui::{
 use tokio::sync::mpsc;
 
 use crate::{
+    commands::CommandRegistry,
     control::ControlMessage,
-    markdown_render::render_markdown_text,
+    editor::{
+        History,
+        InputEditor,
+    },
+    mentions,
+    selection::{
+        self,
+        Selection,
+    },
+    transcript::Transcript,
     ui_state::{
         ChatUIMessage,
+        ChatUIModel,
         ChatUIModification,
         ChatUIState,
-        ChatUIToolCall,
         GeneratingState,
     },
 };
 
+/// Display settings that can be set from the config file.
+#[derive(Debug, Clone, Default)]
+pub struct UIConfig {
+    /// Whether reasoning blocks start out expanded.
+    pub show_reasoning: bool,
+}
+
 struct UIState {
     chat: ChatUIState,
-    scroll_offset: usize,
-    input_text: String,
-    input_cursor_position: usize,
+    transcript: Transcript,
+    input: InputEditor,
     waiting_for_ctrl_c: bool,
+    /// Shown next to the input box title until the input changes.
+    input_status: Option<InputStatus>,
+    /// Files that `@` mentions can complete to, once they've been listed.
+    project_files: Option<Vec<String>>,
+    /// Open while the word at the cursor is an `@` mention.
+    file_picker: Option<FilePicker>,
+    /// Where the mention starts that the file picker was closed for with Esc, so it stays closed while typing on.
+    dismissed_mention: Option<usize>,
+    /// The transcript text being selected with the mouse.
+    selection: Option<Selection>,
+    /// Whether the mouse is captured. Turned off, the terminal's own selection works instead.
+    mouse_capture: bool,
+    /// Set while an earlier prompt is being edited in the input, to be sent again from where it was.
+    rewind: Option<Rewind>,
+    /// Open while a drafted commit message is being reviewed in the input.
+    commit: Option<CommitDialog>,
+}
+
+struct Rewind {
+    /// Index of the prompt in the transcript.
+    index: usize,
+    /// Whether to keep the conversation as it is in a branch, rather than dropping what came after the prompt.
+    fork: bool,
+}
+
+struct CommitDialog {
+    branch: String,
+    files: Vec<String>,
+    run_hooks: bool,
+    /// What was in the input before, put back once the dialog closes.
+    draft: String,
+}
+
+/// How many rows a turn of the mouse wheel scrolls.
+const MOUSE_SCROLL_ROWS: usize = 3;
+
+/// The most rows the input box grows to before it scrolls.
+const MAX_INPUT_ROWS: u16 = 10;
+
+/// How many matches the file picker shows.
+const FILE_PICKER_SIZE: usize = 8;
+
+/// How many files the commit dialog lists.
+const COMMIT_DIALOG_FILES: usize = 10;
+
+struct FilePicker {
+    /// Byte offset of the `@` in the input.
+    start: usize,
+    matches: Vec<String>,
+    selected: usize,
+}
+
+enum InputStatus {
+    /// The input is a command that can't be run.
+    Error(String),
+    /// The commands Tab could complete the input to.
+    Completions(Vec<String>),
+    /// Something that just happened, like text being copied.
+    Notice(String),
 }
 
 impl UIState {
-    fn new() -> Self {
+    fn new(config: &UIConfig, history: History) -> Self {
         Self {
             chat: ChatUIState::new(),
-            scroll_offset: 0,
-            input_text: String::new(),
-            input_cursor_position: 0,
+            transcript: Transcript::new(config.show_reasoning),
+            input: InputEditor::new(history),
             waiting_for_ctrl_c: false,
+            input_status: None,
+            project_files: None,
+            file_picker: None,
+            dismissed_mention: None,
+            selection: None,
+            mouse_capture: true,
+            rewind: None,
+            commit: None,
         }
     }
 
     fn apply(&mut self, modification: ChatUIModification) -> anyhow::Result<()> {
+        // New messages get laid out on the next draw, changed ones have to be laid out again.
+        let changed = match &modification {
+            ChatUIModification::AppendSystemMessage { index, .. }
+            | ChatUIModification::AppendReasoning { index, .. }
+            | ChatUIModification::AppendToolCallArgs { index, .. }
+            | ChatUIModification::StartToolCallExecution { index }
+            | ChatUIModification::CompleteToolCall { index, .. } => Some(*index),
+            _ => None,
+        };
+        if let ChatUIModification::ProposeCommit {
+            message,
+            branch,
+            files,
+            run_hooks,
+        } = &modification
+        {
+            self.commit = Some(CommitDialog {
+                branch: branch.clone(),
+                files: files.clone(),
+                run_hooks: *run_hooks,
+                draft: self.input.text().to_string(),
+            });
+            self.input.set_text(message.clone());
+            self.rewind = None;
+        }
+        let clear = matches!(modification, ChatUIModification::Clear);
+        let truncate = match modification {
+            ChatUIModification::Truncate { len } => Some(len),
+            _ => None,
+        };
         self.chat.apply(modification)?;
 
-        // Auto-scroll to bottom when new messages are added
-        let total_lines = self.calculate_total_lines();
-        let visible_height = 20; // Default visible height, will be updated in render
-        self.scroll_to_bottom(total_lines, visible_height);
-
-        Ok(())
-    }
-
-    fn scroll_up(&mut self) {
-        if self.scroll_offset > 0 {
-            self.scroll_offset -= 1;
+        if clear {
+            self.transcript.clear();
+            self.rewind = None;
         }
-    }
-
-    fn scroll_down(&mut self, total_lines: usize, visible_height: usize) {
-        if total_lines > visible_height && self.scroll_offset < total_lines - visible_height {
-            self.scroll_offset += 1;
+        if let Some(len) = truncate {
+            self.transcript.truncate(len);
+            self.rewind = self.rewind.take().filter(|rewind| rewind.index < len);
         }
-    }
-
-    fn scroll_to_bottom(&mut self, total_lines: usize, visible_height: usize) {
-        if total_lines > visible_height {
-            self.scroll_offset = total_lines - visible_height;
-        } else {
-            self.scroll_offset = 0;
+        if let Some(index) = changed {
+            self.transcript.invalidate(index);
         }
+        Ok(())
     }
 
-    fn calculate_total_lines(&self) -> usize {
-        let messages = self.chat.messages();
-        let mut total_lines = 0;
+    /// The transcript's part of the screen, inside the chat area's border.
+    fn transcript_area(&self, area: Rect) -> Rect {
+        let (chat_area, _) = self.layout(area);
+        Block::bordered().inner(chat_area)
+    }
 
-        for message in messages {
-            match message {
-                ChatUIMessage::User(_) => {
-                    total_lines += 1; // Each user message is one line
+    /// Handles a mouse event on a screen of `area`. Returns the selected text when a drag ends, to be copied.
+    fn handle_mouse(&mut self, mouse: MouseEvent, area: Rect) -> Option<String> {
+        let position = Position::new(mouse.column, mouse.row);
+        match mouse.kind {
+            MouseEventKind::ScrollUp => {
+                self.selection = None;
+                self.transcript.scroll_up(MOUSE_SCROLL_ROWS);
+            }
+            MouseEventKind::ScrollDown => {
+                self.selection = None;
+                self.transcript.scroll_down(MOUSE_SCROLL_ROWS);
+            }
+            MouseEventKind::Down(MouseButton::Left) => {
+                self.selection = Some(Selection::new(position));
+            }
+            MouseEventKind::Drag(MouseButton::Left) => {
+                if let Some(selection) = &mut self.selection {
+                    selection.extend(position);
                 }
-                ChatUIMessage::System(s) => {
-                    // Render markdown to count lines
-                    let markdown_text = render_markdown_text(&s.text);
-                    total_lines += markdown_text.lines.len();
+            }
+            MouseEventKind::Up(MouseButton::Left) => {
+                let selection = self.selection?;
+                let transcript_area = self.transcript_area(area);
+                if !selection.is_empty() {
+                    // Read the text back from the screen as it's drawn, wrapped the same way.
+                    let mut buffer = Buffer::empty(area);
+                    (&*self).render(area, &mut buffer);
+                    return Some(selection.text(&buffer, transcript_area));
                 }
-                ChatUIMessage::ToolCall(_) => {
-                    total_lines += 1; // Each tool call is one line
+
+                // A click on a tool call expands or collapses it.
+                self.selection = None;
+                if transcript_area.contains(position)
+                    && let Some(index) = self.transcript.message_at((position.y - transcript_area.y) as usize)
+                    && matches!(self.chat.messages()[index], ChatUIMessage::ToolCall(_))
+                {
+                    self.transcript.focus(Some(index));
+                    self.transcript.toggle_expanded(index);
                 }
             }
+            _ => {}
         }
-
-        total_lines
+        None
     }
 
-    fn insert_char(&mut self, ch: char) {
-        // Prevent newlines to keep input single-line
-        if ch != '\n' {
-            self.input_text.insert(self.input_cursor_position, ch);
-            self.input_cursor_position += 1;
-        }
+    /// Moves the focus to the previous or next prompt or tool call. With none focused, going back starts from the
+    /// latest one and going forward past the last one takes the focus away.
+    fn move_focus(&mut self, back: bool) {
+        let messages = self.chat.messages();
+        let focusable = |index: &usize| matches!(messages[*index], ChatUIMessage::User(_) | ChatUIMessage::ToolCall(_));
+        let next = match (self.transcript.focused(), back) {
+            (Some(focused), true) => (0..focused).rev().find(focusable).or(Some(focused)),
+            (None, true) => (0..messages.len()).rev().find(focusable),
+            (Some(focused), false) => (focused + 1..messages.len()).find(focusable),
+            (None, false) => None,
+        };
+        self.transcript.focus(next);
     }
 
-    fn delete_char_backward(&mut self) {
-        if self.input_cursor_position > 0 {
-            self.input_cursor_position -= 1;
-            self.input_text.remove(self.input_cursor_position);
+    /// Enter on an empty input with something focused: a prompt gets loaded into the input to be edited and sent again,
+    /// a tool call gets expanded or collapsed.
+    fn activate_focused(&mut self) {
+        let Some(index) = self.transcript.focused() else {
+            return;
+        };
+        match &self.chat.messages()[index] {
+            ChatUIMessage::User(user) => {
+                self.input.set_text(user.text.clone());
+                self.rewind = Some(Rewind { index, fork: false });
+                self.transcript.focus(None);
+            }
+            _ => self.transcript.toggle_expanded(index),
         }
     }
 
-    fn delete_char_forward(&mut self) {
-        if self.input_cursor_position < self.input_text.len() {
-            self.input_text.remove(self.input_cursor_position);
-        }
+    /// Closes the commit dialog, putting back what was in the input before it opened.
+    fn close_commit_dialog(&mut self) -> Option<CommitDialog> {
+        let dialog = self.commit.take()?;
+        self.input.set_text(dialog.draft.clone());
+        Some(dialog)
     }
 
-    fn move_cursor_left(&mut self) {
-        if self.input_cursor_position > 0 {
-            self.input_cursor_position -= 1;
+    /// Opens, updates or closes the file picker to match the word at the cursor.
+    fn update_file_picker(&mut self) {
+        // A commit message doesn't mention files.
+        if self.commit.is_some() {
+            self.file_picker = None;
+            return;
         }
-    }
-
-    fn move_cursor_right(&mut self) {
-        if self.input_cursor_position < self.input_text.len() {
-            self.input_cursor_position += 1;
+        let before_cursor = &self.input.text()[..self.input.cursor()];
+        let word_start = before_cursor
+            .char_indices()
+            .rev()
+            .find(|(_, ch)| ch.is_whitespace())
+            .map(|(i, ch)| i + ch.len_utf8())
+            .unwrap_or(0);
+        let query = match before_cursor[word_start..].strip_prefix('@') {
+            // Past the `:` comes a line range, not more of the path.
+            Some(query) if !query.contains(':') => query,
+            _ => {
+                self.file_picker = None;
+                self.dismissed_mention = None;
+                return;
+            }
+        };
+        if self.dismissed_mention == Some(word_start) {
+            return;
         }
+        let files = self.project_files.as_deref().unwrap_or_default();
+        let matches = mentions::fuzzy_match_files(files, query, FILE_PICKER_SIZE);
+        let selected = self
+            .file_picker
+            .as_ref()
+            .map_or(0, |picker| picker.selected)
+            .min(matches.len().saturating_sub(1));
+        self.file_picker = Some(FilePicker {
+            start: word_start,
+            matches,
+            selected,
+        });
     }
 
-    fn move_cursor_to_start(&mut self) {
-        self.input_cursor_position = 0;
+    /// Handles keys meant for the open file picker. Returns whether the key was used.
+    fn handle_file_picker_key(&mut self, code: KeyCode) -> bool {
+        let Some(picker) = &mut self.file_picker else {
+            return false;
+        };
+        match code {
+            KeyCode::Up => {
+                picker.selected = picker.selected.saturating_sub(1);
+            }
+            KeyCode::Down => {
+                picker.selected = (picker.selected + 1).min(picker.matches.len().saturating_sub(1));
+            }
+            KeyCode::Esc => {
+                self.dismissed_mention = Some(picker.start);
+                self.file_picker = None;
+            }
+            KeyCode::Tab | KeyCode::Enter => {
+                let Some(file) = picker.matches.get(picker.selected) else {
+                    return false;
+                };
+                let mention = format!("@{file} ");
+                self.input.replace_range(picker.start..self.input.cursor(), &mention);
+                self.file_picker = None;
+            }
+            _ => return false,
+        }
+        true
     }
 
-    fn move_cursor_to_end(&mut self) {
-        self.input_cursor_position = self.input_text.len();
+    /// Completes a partially typed command name, as far as it's unambiguous.
+    fn complete_command(&mut self, commands: &CommandRegistry) {
+        let text = self.input.text();
+        if !text.starts_with('/') || text.contains(char::is_whitespace) {
+            return;
+        }
+        let completions = commands.complete(text);
+        match completions.as_slice() {
+            [] => {
+                self.input_status = Some(InputStatus::Error(format!("No command matches {text}")));
+            }
+            [completion] => {
+                self.input.set_text(format!("{completion} "));
+            }
+            [first, rest @ ..] => {
+                let mut common = first.clone();
+                for completion in rest {
+                    while !completion.starts_with(&common) {
+                        common.pop();
+                    }
+                }
+                self.input.set_text(common);
+                self.input_status = Some(InputStatus::Completions(completions));
+            }
+        }
     }
 
-    fn clear_input(&mut self) {
-        self.input_text.clear();
-        self.input_cursor_position = 0;
+    /// The height of the input box, which grows with the input up to `MAX_INPUT_ROWS` or half the screen.
+    fn input_height(&self, width: u16, height: u16) -> u16 {
+        let (rows, _) = self.input.layout(width.saturating_sub(2) as usize);
+        (rows.len() as u16).min(MAX_INPUT_ROWS).min(height / 2).max(1) + 2
     }
 
-    fn submit_input(&mut self) -> String {
-        let message = self.input_text.clone();
-        self.clear_input();
-        message
+    /// Splits the screen into the chat area and the input box below it.
+    fn layout(&self, area: Rect) -> (Rect, Rect) {
+        let input_height = self.input_height(area.width, area.height);
+        let chat_area = Rect {
+            x: area.x,
+            y: area.y,
+            width: area.width,
+            height: area.height.saturating_sub(input_height),
+        };
+        let input_area = Rect {
+            x: area.x,
+            y: area.y + chat_area.height,
+            width: area.width,
+            height: input_height,
+        };
+        (chat_area, input_area)
     }
 
-    fn calculate_visible_height(&self, terminal_height: u16) -> u16 {
-        let input_height = 3; // Fixed single-line input height
-        terminal_height.saturating_sub(input_height).saturating_sub(2)
+    /// The input rows in view, scrolled to keep the cursor visible, and where on the screen the cursor goes.
+    fn visible_input(&self, input_area: Rect) -> (Vec<String>, Position) {
+        let (rows, (cursor_row, cursor_column)) = self.input.layout(input_area.width.saturating_sub(2) as usize);
+        let visible_rows = input_area.height.saturating_sub(2) as usize;
+        let scroll = (cursor_row + 1).saturating_sub(visible_rows);
+        let cursor = Position {
+            x: input_area.x + 1 + cursor_column as u16,
+            y: input_area.y + 1 + cursor_row.saturating_sub(scroll) as u16,
+        };
+        (rows.into_iter().skip(scroll).collect(), cursor)
     }
 }
 
@@ -178,17 +433,21 @@ pub async fn ui_loop(
     mut ui_rx: mpsc::UnboundedReceiver<ChatUIModification>,
     control_tx: mpsc::UnboundedSender<ControlMessage>,
     prompt: Option<String>,
+    config: UIConfig,
+    commands: CommandRegistry,
+    history: History,
 ) -> anyhow::Result<()> {
-    let mut ui_state = UIState::new();
+    let mut ui_state = UIState::new(&config, history);
 
     // Send initial prompt if provided
     if let Some(prompt) = prompt {
         control_tx.send(ControlMessage::UserMessage(prompt))?;
     }
     let mut reader = EventStream::new();
-    terminal.draw(|frame| {
-        frame.render_widget(&ui_state, frame.area());
-    })?;
+    // Walking a big workspace takes a moment, so the file picker gets the list once it's ready.
+    let root = std::env::current_dir()?;
+    let mut list_files = tokio::task::spawn_blocking(move || mentions::list_files(&root));
+    terminal.draw(|frame| draw(frame, &mut ui_state))?;
     loop {
         let mut needs_redraw = false;
 
@@ -205,135 +464,259 @@ pub async fn ui_loop(
                     continue;
                 };
                 let event = event?;
-                tracing::info!("Event: {event:?}");
+                match &event {
+                    Event::Mouse(MouseEvent { kind: MouseEventKind::Moved, .. }) => {}
+                    // Pastes can hold tokens and other secrets, which don't belong in the log.
+                    Event::Paste(text) => tracing::info!("Event: Paste ({} bytes)", text.len()),
+                    event => tracing::info!("Event: {event:?}"),
+                }
 
                 // Handle resize events
                 if let Event::Resize(_, _) = event {
                     needs_redraw = true;
                 }
 
+                if let Event::Mouse(mouse) = event {
+                    let size = terminal.size()?;
+                    if let Some(text) = ui_state.handle_mouse(mouse, Rect::new(0, 0, size.width, size.height)) {
+                        write!(terminal.backend_mut(), "{}", selection::osc52_copy(&text))?;
+                        terminal.backend_mut().flush()?;
+                        ui_state.input_status =
+                            Some(InputStatus::Notice(format!("Copied {} characters", text.chars().count())));
+                    }
+                    needs_redraw = !matches!(mouse.kind, MouseEventKind::Moved);
+                }
+
+                if let Event::Paste(text) = &event {
+                    ui_state.input.insert_str(text);
+                    ui_state.update_file_picker();
+                    needs_redraw = true;
+                }
+
                 if let Event::Key(key) = event && key.kind == KeyEventKind::Press {
-                    match key.code {
-                        // Input handling
-                        KeyCode::Char(ch) => {
-                            // Check for quit sequence first
-                            if ui_state.waiting_for_ctrl_c && ch == 'c' && key.modifiers.contains(crossterm::event::KeyModifiers::CONTROL) {
-                                break; // Quit the application
-                            }
+                    ui_state.input_status = None;
+                    ui_state.selection = None;
+                    let ctrl = key.modifiers.contains(KeyModifiers::CONTROL);
+                    let alt = key.modifiers.contains(KeyModifiers::ALT);
+                    let shift = key.modifiers.contains(KeyModifiers::SHIFT);
+
+                    // Ctrl-x Ctrl-c quits, any other key cancels the sequence
+                    if ui_state.waiting_for_ctrl_c && ctrl && key.code == KeyCode::Char('c') {
+                        break;
+                    }
+                    ui_state.waiting_for_ctrl_c = ctrl && key.code == KeyCode::Char('x');
+
+                    match (key.code, ctrl, alt) {
+                        // The file picker gets the keys it uses first
+                        (code, _, _) if ui_state.handle_file_picker_key(code) => {}
+                        (KeyCode::Char('x'), true, false) => {}
 
-                            // Check if this is Ctrl-X (start quit sequence)
-                            if ch == 'x' && key.modifiers.contains(crossterm::event::KeyModifiers::CONTROL) {
-                                ui_state.waiting_for_ctrl_c = true;
-                                needs_redraw = true;
+                        // Scrolling and display
+                        (KeyCode::Char('p'), true, false) => {
+                            ui_state.transcript.scroll_up(1);
+                        }
+                        (KeyCode::Char('n'), true, false) => {
+                            ui_state.transcript.scroll_down(1);
+                        }
+                        (KeyCode::Char('v'), true, false) => {
+                            let page = ui_state.transcript.height();
+                            ui_state.transcript.scroll_down(page);
+                        }
+                        (KeyCode::Char('v'), false, true) => {
+                            let page = ui_state.transcript.height();
+                            ui_state.transcript.scroll_up(page);
+                        }
+                        (KeyCode::Char('t'), true, false) => {
+                            ui_state.transcript.toggle_reasoning();
+                        }
+                        (KeyCode::Up, false, true) | (KeyCode::Char('p'), false, true) => {
+                            ui_state.move_focus(true);
+                        }
+                        (KeyCode::Down, false, true) | (KeyCode::Char('n'), false, true) => {
+                            ui_state.move_focus(false);
+                        }
+                        (KeyCode::Esc, false, false) if ui_state.commit.is_some() => {
+                            ui_state.close_commit_dialog();
+                            control_tx.send(ControlMessage::Notice(
+                                "Didn't commit, nothing was staged.".to_string(),
+                            ))?;
+                        }
+                        (KeyCode::Esc, false, false) if ui_state.rewind.is_some() => {
+                            ui_state.rewind = None;
+                            ui_state.input.clear();
+                        }
+                        (KeyCode::Esc, false, false) => {
+                            ui_state.transcript.focus(None);
+                        }
+                        (KeyCode::Char('o'), true, false) => {
+                            ui_state.mouse_capture = !ui_state.mouse_capture;
+                            if ui_state.mouse_capture {
+                                crossterm::execute!(terminal.backend_mut(), EnableMouseCapture)?;
                             } else {
-                                // Reset quit sequence if any other key is pressed
-                                if ui_state.waiting_for_ctrl_c {
-                                    ui_state.waiting_for_ctrl_c = false;
-                                }
+                                crossterm::execute!(terminal.backend_mut(), DisableMouseCapture)?;
+                            }
+                        }
 
-                                // Check if this is a scroll command
-                                match (ch, key.modifiers.contains(crossterm::event::KeyModifiers::CONTROL), key.modifiers.contains(crossterm::event::KeyModifiers::ALT)) {
-                                    ('p', true, false) => {
-                                        ui_state.scroll_up();
-                                    }
-                                ('n', true, false) => {
-                                    let total_lines = ui_state.calculate_total_lines();
-                                    let terminal_height = terminal.size()?.height;
-                                    let visible_height = ui_state.calculate_visible_height(terminal_height);
-                                    ui_state.scroll_down(total_lines, visible_height as usize);
-                                }
-                                ('v', true, false) => {
-                                    let total_lines = ui_state.calculate_total_lines();
-                                    let terminal_height = terminal.size()?.height;
-                                    let visible_height = ui_state.calculate_visible_height(terminal_height);
-                                    for _ in 0..visible_height {
-                                        ui_state.scroll_down(total_lines, visible_height as usize);
-                                    }
+                        // Shift-Enter only reaches us in terminals that report it, so Alt-Enter and Ctrl-j work too
+                        (KeyCode::Enter, false, _) if shift || alt => {
+                            ui_state.input.insert_char('\n');
+                        }
+                        (KeyCode::Char('j'), true, false) => {
+                            ui_state.input.insert_char('\n');
+                        }
+                        (KeyCode::Enter, false, false) if ui_state.commit.is_some() => {
+                            let message = ui_state.input.text().to_string();
+                            if message.trim().is_empty() {
+                                ui_state.input_status = Some(InputStatus::Error("The commit message is empty".to_string()));
+                            } else if let Some(dialog) = ui_state.close_commit_dialog() {
+                                control_tx.send(ControlMessage::Commit {
+                                    message,
+                                    run_hooks: dialog.run_hooks,
+                                })?;
+                            }
+                        }
+                        (KeyCode::Enter, false, false)
+                            if ui_state.input.text().is_empty() && ui_state.transcript.focused().is_some() =>
+                        {
+                            ui_state.activate_focused();
+                        }
+                        // An edited earlier prompt goes as it is, without looking for commands
+                        (KeyCode::Enter, false, false) if ui_state.rewind.is_some() => {
+                            let text = ui_state.input.submit();
+                            if let Some(rewind) = ui_state.rewind.take()
+                                && !text.trim().is_empty()
+                            {
+                                control_tx.send(ControlMessage::Rewind {
+                                    ui_index: rewind.index,
+                                    text,
+                                    fork: rewind.fork,
+                                })?;
+                            }
+                        }
+                        (KeyCode::Enter, false, false) => {
+                            match commands.parse(ui_state.input.text()) {
+                                Some(Err(error)) => {
+                                    // Keep the input around so it can be fixed.
+                                    ui_state.input_status = Some(InputStatus::Error(error));
                                 }
-                                ('v', false, true) => {
-                                    let terminal_height = terminal.size()?.height;
-                                    let visible_height = ui_state.calculate_visible_height(terminal_height);
-                                    for _ in 0..visible_height {
-                                        ui_state.scroll_up();
-                                    }
+                                Some(Ok(control_message)) => {
+                                    ui_state.input.submit();
+                                    control_tx.send(control_message)?;
                                 }
-                                    _ => {
-                                        // Regular character input
-                                        ui_state.insert_char(ch);
+                                None => {
+                                    let message = ui_state.input.submit();
+                                    if !message.trim().is_empty() {
+                                        control_tx.send(ControlMessage::UserMessage(message))?;
                                     }
                                 }
-                                needs_redraw = true;
                             }
                         }
-                        KeyCode::Enter => {
-                            // Enter: submit message
-                            let message = ui_state.submit_input();
-                            if !message.trim().is_empty() {
-                                control_tx.send(ControlMessage::UserMessage(message))?;
+                        (KeyCode::Tab, false, false) if ui_state.rewind.is_some() => {
+                            if let Some(rewind) = &mut ui_state.rewind {
+                                rewind.fork = !rewind.fork;
                             }
-                            needs_redraw = true;
                         }
-                        KeyCode::Backspace => {
-                            ui_state.delete_char_backward();
-                            needs_redraw = true;
+                        (KeyCode::Tab, false, false) => {
+                            ui_state.complete_command(&commands);
+                        }
+
+                        // Editing, with emacs-style motions and kill/yank
+                        (KeyCode::Char('a'), true, false) | (KeyCode::Home, _, _) => {
+                            ui_state.input.move_line_start();
                         }
-                        KeyCode::Delete => {
-                            ui_state.delete_char_forward();
-                            needs_redraw = true;
+                        (KeyCode::Char('e'), true, false) | (KeyCode::End, _, _) => {
+                            ui_state.input.move_line_end();
                         }
-                        KeyCode::Left => {
-                            ui_state.move_cursor_left();
-                            needs_redraw = true;
+                        (KeyCode::Char('b'), true, false) | (KeyCode::Left, false, false) => {
+                            ui_state.input.move_left();
                         }
-                        KeyCode::Right => {
-                            ui_state.move_cursor_right();
-                            needs_redraw = true;
+                        (KeyCode::Char('f'), true, false) | (KeyCode::Right, false, false) => {
+                            ui_state.input.move_right();
                         }
-                        KeyCode::Home => {
-                            ui_state.move_cursor_to_start();
-                            needs_redraw = true;
+                        (KeyCode::Char('b'), false, true) | (KeyCode::Left, _, _) => {
+                            ui_state.input.move_word_left();
                         }
-                        KeyCode::End => {
-                            ui_state.move_cursor_to_end();
-                            needs_redraw = true;
+                        (KeyCode::Char('f'), false, true) | (KeyCode::Right, _, _) => {
+                            ui_state.input.move_word_right();
+                        }
+                        (KeyCode::Up, _, _) => {
+                            ui_state.input.move_up();
+                        }
+                        (KeyCode::Down, _, _) => {
+                            ui_state.input.move_down();
+                        }
+                        (KeyCode::Char('h'), true, false) | (KeyCode::Backspace, false, false) => {
+                            ui_state.input.delete_backward();
+                        }
+                        (KeyCode::Char('d'), true, false) | (KeyCode::Delete, _, _) => {
+                            ui_state.input.delete_forward();
+                        }
+                        (KeyCode::Char('w'), true, false) | (KeyCode::Backspace, _, _) => {
+                            ui_state.input.kill_word_backward();
+                        }
+                        (KeyCode::Char('d'), false, true) => {
+                            ui_state.input.kill_word_forward();
+                        }
+                        (KeyCode::Char('k'), true, false) => {
+                            ui_state.input.kill_to_line_end();
+                        }
+                        (KeyCode::Char('u'), true, false) => {
+                            ui_state.input.kill_to_line_start();
+                        }
+                        (KeyCode::Char('y'), true, false) => {
+                            ui_state.input.yank();
+                        }
+                        (KeyCode::Char(ch), false, false) => {
+                            ui_state.input.insert_char(ch);
                         }
                         _ => {}
                     }
+                    ui_state.update_file_picker();
+                    needs_redraw = true;
                 }
             }
+            files = &mut list_files, if ui_state.project_files.is_none() => {
+                ui_state.project_files = Some(files.unwrap_or_default());
+                ui_state.update_file_picker();
+                needs_redraw = true;
+            }
         }
 
         // Single redraw at the end of each loop iteration
         if needs_redraw {
-            terminal.draw(|frame| {
-                frame.render_widget(&ui_state, frame.area());
-            })?;
+            terminal.draw(|frame| draw(frame, &mut ui_state))?;
         }
     }
     tracing::info!("UI loop ended");
     anyhow::Ok(())
 }
 
+fn draw(frame: &mut Frame, ui_state: &mut UIState) {
+    let (chat_area, input_area) = ui_state.layout(frame.area());
+    // Inside the chat area's border
+    ui_state
+        .transcript
+        .resize(chat_area.width.saturating_sub(2), chat_area.height.saturating_sub(2));
+    ui_state.transcript.update(ui_state.chat.messages());
+
+    frame.render_widget(&*ui_state, frame.area());
+    let (_, cursor) = ui_state.visible_input(input_area);
+    frame.set_cursor_position(cursor);
+}
+
 impl Widget for &UIState {
     fn render(self, area: Rect, buf: &mut Buffer) {
-        let title = Line::from("Agent").bold();
-
-        // Fixed single-line input height
-        let input_height = 3; // 1 line for input + 2 for border
-
-        let chat_area = Rect {
-            x: area.x,
-            y: area.y,
-            width: area.width,
-            height: area.height.saturating_sub(input_height),
-        };
-        let input_area = Rect {
-            x: area.x,
-            y: area.y + chat_area.height,
-            width: area.width,
-            height: input_height,
+        let title = match self.chat.model() {
+            Some(ChatUIModel {
+                name,
+                reasoning_effort: Some(effort),
+            }) => Line::from(vec![format!(" {name} ").bold(), format!("{effort} ").dark_gray()]),
+            Some(ChatUIModel { name, .. }) => Line::from(format!(" {name} ")).bold(),
+            None => Line::from("Agent").bold(),
         };
 
+        let (chat_area, input_area) = self.layout(area);
+
         // Render chat area
         let chat_block = Block::bordered().title(title.centered()).border_set(border::THICK);
 
@@ -344,84 +727,33 @@ impl Widget for &UIState {
                 .wrap(Wrap { trim: true });
             empty_paragraph.render(chat_area, buf);
         } else {
-            // Build all messages into a single text with styled lines
-            let mut lines = vec![];
-
-            for message in messages {
-                match message {
-                    ChatUIMessage::User(u) => {
-                        lines.push(Line::from(vec!["user: ".cyan().bold(), u.text.clone().into()]));
-                    }
-                    ChatUIMessage::System(s) => {
-                        // Render the system message text as markdown
-                        let markdown_text = render_markdown_text(&s.text);
-
-                        // Add the "assistant: " prefix to the first line
-                        if let Some(first_line) = markdown_text.lines.first() {
-                            let mut prefixed_line = Line::from(vec!["assistant: ".yellow().bold()]);
-                            prefixed_line.spans.extend(first_line.spans.clone());
-                            lines.push(prefixed_line);
-                        }
-
-                        // Add the remaining lines from the markdown
-                        for line in markdown_text.lines.iter().skip(1) {
-                            lines.push(line.clone());
-                        }
-                    }
-                    ChatUIMessage::ToolCall(tc) => match tc {
-                        ChatUIToolCall::Generating { name, args } => {
-                            lines.push(Line::from(vec![
-                                "tool: ".magenta().bold(),
-                                format!("{}({}) …", name, args).magenta(),
-                            ]));
-                        }
-                        ChatUIToolCall::Executing { name, args } => {
-                            lines.push(Line::from(vec![
-                                "tool: ".magenta().bold(),
-                                format!("{}({}) ", name, args).into(),
-                                "running".magenta().bold(),
-                            ]));
-                        }
-                        ChatUIToolCall::Complete { name, args, result } => {
-                            let status = match result {
-                                Ok(_) => "ok".green().bold(),
-                                Err(_) => "error".red().bold(),
-                            };
-                            lines.push(Line::from(vec![
-                                "tool: ".magenta().bold(),
-                                format!("{}({}) ", name, args).into(),
-                                status,
-                            ]));
-                        }
-                    },
-                }
+            // Only the messages in view get drawn, each scrolled past the rows above the view
+            let inner = chat_block.inner(chat_area);
+            chat_block.render(chat_area, buf);
+            let mut y = inner.y;
+            for visible in self.transcript.visible() {
+                let area = Rect {
+                    x: inner.x,
+                    y,
+                    width: inner.width,
+                    height: visible.rows as u16,
+                };
+                Paragraph::new(visible.layout.lines.clone())
+                    .wrap(Wrap { trim: false })
+                    .scroll((visible.skip as u16, 0))
+                    .render(area, buf);
+                y += visible.rows as u16;
             }
-
-            // Apply scrolling - show only the visible portion
-            let visible_height = chat_area.height.saturating_sub(2); // Account for border
-            let total_lines = lines.len();
-
-            // Calculate which lines to show based on scroll offset
-            let start_line = self
-                .scroll_offset
-                .min(total_lines.saturating_sub(visible_height as usize));
-            let end_line = (start_line + visible_height as usize).min(total_lines);
-
-            let visible_lines = if start_line < total_lines {
-                lines[start_line..end_line].to_vec()
-            } else {
-                vec![]
-            };
-
-            let paragraph = Paragraph::new(Text::from(visible_lines))
-                .block(chat_block)
-                .wrap(Wrap { trim: false });
-
-            paragraph.render(chat_area, buf);
+            let visible_height = self.transcript.height();
+            let total_lines = self.transcript.total_rows();
 
             // Add generating status in bottom left (only when generating)
-            if let GeneratingState::Generating = self.chat.generating_state() {
-                let status_text = "Generating...";
+            let status_text = match self.chat.generating_state() {
+                GeneratingState::Idle => None,
+                GeneratingState::Generating => Some("Generating..."),
+                GeneratingState::Compacting => Some("Compacting..."),
+            };
+            if let Some(status_text) = status_text {
                 let status_color = ratatui::style::Color::Yellow;
 
                 let status_area = Rect {
@@ -447,10 +779,15 @@ impl Widget for &UIState {
                 right_elements.push(perf_text);
             }
 
+            if !self.mouse_capture {
+                right_elements.push("mouse off, Ctrl-o to turn on".to_string());
+            }
+
             // Add line indicator if there's overflow
-            if total_lines > visible_height as usize {
-                let start_line = self.scroll_offset + 1; // Convert to 1-based indexing
-                let end_line = (self.scroll_offset + visible_height as usize).min(total_lines);
+            if total_lines > visible_height {
+                let top_line = self.transcript.top_row();
+                let start_line = top_line + 1; // Convert to 1-based indexing
+                let end_line = (top_line + visible_height).min(total_lines);
                 let indicator_text = format!("(lines {}-{} of {})", start_line, end_line, total_lines);
                 right_elements.push(indicator_text);
             }
@@ -470,17 +807,116 @@ impl Widget for &UIState {
             }
         }
 
+        if let Some(selection) = &self.selection {
+            for (y, columns) in selection.rows(self.transcript_area(area)) {
+                for x in columns {
+                    buf[(x, y)].modifier.insert(Modifier::REVERSED);
+                }
+            }
+        }
+
         // Render input area
-        let input_block = Block::bordered().title("Input").border_set(border::THICK);
+        let input_title = match &self.input_status {
+            Some(InputStatus::Error(error)) => Line::from(vec!["Input ".into(), error.clone().red()]),
+            Some(InputStatus::Completions(completions)) => {
+                Line::from(vec!["Input ".into(), completions.join("  ").dark_gray()])
+            }
+            Some(InputStatus::Notice(notice)) => Line::from(vec!["Input ".into(), notice.clone().dark_gray()]),
+            None if self.commit.as_ref().is_some_and(|dialog| !dialog.run_hooks) => Line::from(vec![
+                "Input ".into(),
+                "Commit message: Enter commits without running the hooks, Esc cancels".yellow(),
+            ]),
+            None if self.commit.is_some() => Line::from(vec![
+                "Input ".into(),
+                "Commit message: Enter commits, Esc cancels".yellow(),
+            ]),
+            None => match &self.rewind {
+                Some(Rewind { fork: false, .. }) => Line::from(vec![
+                    "Input ".into(),
+                    "Editing an earlier prompt: Enter drops what came after it, Tab forks instead, Esc cancels"
+                        .yellow(),
+                ]),
+                Some(Rewind { fork: true, .. }) => Line::from(vec![
+                    "Input ".into(),
+                    "Editing an earlier prompt: Enter forks a new branch from it, Tab drops instead, Esc cancels"
+                        .yellow(),
+                ]),
+                None => Line::from("Input"),
+            },
+        };
+        let input_block = Block::bordered().title(input_title).border_set(border::THICK);
 
-        // Create input text with cursor
-        let mut input_text = self.input_text.clone();
-        if self.input_cursor_position <= input_text.len() {
-            input_text.insert(self.input_cursor_position, '|');
-        }
+        let (rows, _) = self.visible_input(input_area);
+        let input_lines = rows.into_iter().map(Line::from).collect::<Vec<_>>();
 
-        let input_paragraph = Paragraph::new(input_text).block(input_block).wrap(Wrap { trim: true });
+        let input_paragraph = Paragraph::new(input_lines).block(input_block);
 
         input_paragraph.render(input_area, buf);
+
+        if let Some(picker) = &self.file_picker {
+            render_file_picker(picker, chat_area, buf);
+        }
+        if let Some(dialog) = &self.commit {
+            render_commit_dialog(dialog, chat_area, buf);
+        }
     }
 }
+
+/// Draws what's about to be committed over the bottom of the chat area, right above the message in the input.
+fn render_commit_dialog(dialog: &CommitDialog, chat_area: Rect, buf: &mut Buffer) {
+    let mut lines = vec![Line::from(vec!["On branch ".into(), dialog.branch.clone().bold()])];
+    for file in dialog.files.iter().take(COMMIT_DIALOG_FILES) {
+        lines.push(Line::from(format!("  {file}")));
+    }
+    if dialog.files.len() > COMMIT_DIALOG_FILES {
+        lines.push(
+            format!("  and {} more", dialog.files.len() - COMMIT_DIALOG_FILES)
+                .dark_gray()
+                .into(),
+        );
+    }
+    let height = (lines.len() as u16 + 2).min(chat_area.height);
+    let area = Rect {
+        x: chat_area.x,
+        y: chat_area.y + chat_area.height - height,
+        width: chat_area.width.min(80),
+        height,
+    };
+    let block = Block::bordered()
+        .title("Commit (edit the message below)".dark_gray())
+        .border_set(border::THICK);
+    Clear.render(area, buf);
+    Paragraph::new(Text::from(lines)).block(block).render(area, buf);
+}
+
+/// Draws the file picker over the bottom of the chat area, right above the input.
+fn render_file_picker(picker: &FilePicker, chat_area: Rect, buf: &mut Buffer) {
+    let lines = if picker.matches.is_empty() {
+        vec!["no matching files".dark_gray().into()]
+    } else {
+        picker
+            .matches
+            .iter()
+            .enumerate()
+            .map(|(i, file)| {
+                if i == picker.selected {
+                    Line::from(file.clone()).reversed()
+                } else {
+                    Line::from(file.clone())
+                }
+            })
+            .collect::<Vec<_>>()
+    };
+    let height = (lines.len() as u16 + 2).min(chat_area.height);
+    let area = Rect {
+        x: chat_area.x,
+        y: chat_area.y + chat_area.height - height,
+        width: chat_area.width.min(60),
+        height,
+    };
+    let block = Block::bordered()
+        .title("Files (Tab to insert, Esc to close)".dark_gray())
+        .border_set(border::THICK);
+    Clear.render(area, buf);
+    Paragraph::new(Text::from(lines)).block(block).render(area, buf);
+}
@@ -4,6 +4,7 @@ use crate::types::PerformanceStats;
 pub enum GeneratingState {
     Idle,
     Generating,
+    Compacting,
 }
 
 #[derive(Debug, Clone)]
@@ -11,6 +12,7 @@ pub struct ChatUIState {
     messages: Vec<ChatUIMessage>,
     generating_state: GeneratingState,
     performance_stats: Option<PerformanceStats>,
+    model: Option<ChatUIModel>,
 }
 
 impl ChatUIState {
@@ -25,12 +27,24 @@ impl ChatUIState {
     pub fn performance_stats(&self) -> &Option<PerformanceStats> {
         &self.performance_stats
     }
+
+    pub fn model(&self) -> Option<&ChatUIModel> {
+        self.model.as_ref()
+    }
 }
 
 #[derive(Debug, Clone)]
 pub enum ChatUIModification {
     AddUserMessage {
         text: String,
+        /// Labels of the files attached through `@` mentions.
+        attachments: Vec<String>,
+    },
+    /// Removes every message.
+    Clear,
+    /// Removes the messages from `len` on.
+    Truncate {
+        len: usize,
     },
 
     AddSystemMessage {
@@ -41,6 +55,14 @@ pub enum ChatUIModification {
         text: String,
     },
 
+    AddReasoning {
+        text: String,
+    },
+    AppendReasoning {
+        index: usize,
+        text: String,
+    },
+
     StartToolCall {
         name: String,
         args: String,
@@ -64,6 +86,32 @@ pub enum ChatUIModification {
     SetPerformanceStats {
         stats: Option<PerformanceStats>,
     },
+
+    SetModel {
+        model: ChatUIModel,
+    },
+
+    /// Opens the dialog to review a drafted commit message and confirm the commit.
+    ProposeCommit {
+        message: String,
+        branch: String,
+        /// The staged files, with what happened to each.
+        files: Vec<String>,
+        run_hooks: bool,
+    },
+
+    AddCompactionMarker {
+        summarized_turns: usize,
+        elided_tool_results: usize,
+        tokens_before: u32,
+        tokens_after: u32,
+    },
+}
+
+impl Default for ChatUIState {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl ChatUIState {
@@ -72,6 +120,7 @@ impl ChatUIState {
             messages: vec![],
             generating_state: GeneratingState::Idle,
             performance_stats: None,
+            model: None,
         }
     }
 
@@ -81,8 +130,15 @@ impl ChatUIState {
 
     pub fn apply(&mut self, modification: ChatUIModification) -> anyhow::Result<()> {
         match modification {
-            ChatUIModification::AddUserMessage { text } => {
-                self.messages.push(ChatUIMessage::User(ChatUIUserMessage { text }));
+            ChatUIModification::AddUserMessage { text, attachments } => {
+                self.messages
+                    .push(ChatUIMessage::User(ChatUIUserMessage { text, attachments }));
+            }
+            ChatUIModification::Clear => {
+                self.messages.clear();
+            }
+            ChatUIModification::Truncate { len } => {
+                self.messages.truncate(len);
             }
             ChatUIModification::AddSystemMessage { text } => {
                 self.messages.push(ChatUIMessage::System(ChatUISystemMessage { text }));
@@ -93,6 +149,15 @@ impl ChatUIState {
                 };
                 system_message.text.push_str(&text);
             }
+            ChatUIModification::AddReasoning { text } => {
+                self.messages.push(ChatUIMessage::Reasoning(ChatUIReasoning { text }));
+            }
+            ChatUIModification::AppendReasoning { index, text } => {
+                let Some(ChatUIMessage::Reasoning(reasoning)) = self.messages.get_mut(index) else {
+                    return Err(anyhow::anyhow!("Message {index} is not a reasoning message"));
+                };
+                reasoning.text.push_str(&text);
+            }
             ChatUIModification::StartToolCall { name, args } => {
                 self.messages
                     .push(ChatUIMessage::ToolCall(ChatUIToolCall::Generating { name, args }));
@@ -140,6 +205,24 @@ impl ChatUIState {
             ChatUIModification::SetPerformanceStats { stats } => {
                 self.performance_stats = stats;
             }
+            ChatUIModification::SetModel { model } => {
+                self.model = Some(model);
+            }
+            // The dialog is the UI's own, the transcript stays as it is.
+            ChatUIModification::ProposeCommit { .. } => {}
+            ChatUIModification::AddCompactionMarker {
+                summarized_turns,
+                elided_tool_results,
+                tokens_before,
+                tokens_after,
+            } => {
+                self.messages.push(ChatUIMessage::Compaction(ChatUICompaction {
+                    summarized_turns,
+                    elided_tool_results,
+                    tokens_before,
+                    tokens_after,
+                }));
+            }
         }
         Ok(())
     }
@@ -149,12 +232,15 @@ impl ChatUIState {
 pub enum ChatUIMessage {
     User(ChatUIUserMessage),
     System(ChatUISystemMessage),
+    Reasoning(ChatUIReasoning),
     ToolCall(ChatUIToolCall),
+    Compaction(ChatUICompaction),
 }
 
 #[derive(Debug, Clone)]
 pub struct ChatUIUserMessage {
     pub text: String,
+    pub attachments: Vec<String>,
 }
 
 #[derive(Debug, Clone)]
@@ -162,6 +248,27 @@ pub struct ChatUISystemMessage {
     pub text: String,
 }
 
+#[derive(Debug, Clone)]
+pub struct ChatUIReasoning {
+    pub text: String,
+}
+
+/// The model the conversation is currently using.
+#[derive(Debug, Clone)]
+pub struct ChatUIModel {
+    pub name: String,
+    pub reasoning_effort: Option<String>,
+}
+
+/// Marks the point in the transcript where earlier history was compacted.
+#[derive(Debug, Clone)]
+pub struct ChatUICompaction {
+    pub summarized_turns: usize,
+    pub elided_tool_results: usize,
+    pub tokens_before: u32,
+    pub tokens_after: u32,
+}
+
 #[derive(Debug, Clone)]
 pub enum ChatUIToolCall {
     Generating {
@@ -0,0 +1,303 @@
+//! Watches the workspace for files changing during the session, say in another editor, so the model can be told about
+//! them with the next prompt instead of going by the layout it got at the start.
+
+use std::{
+    collections::{
+        BTreeMap,
+        BTreeSet,
+    },
+    path::{
+        Path,
+        PathBuf,
+    },
+    sync::{
+        Arc,
+        Mutex,
+    },
+};
+
+use ignore::{
+    WalkBuilder,
+    gitignore::{
+        Gitignore,
+        GitignoreBuilder,
+    },
+};
+use notify::{
+    Event,
+    EventKind,
+    RecursiveMode,
+    Watcher,
+    event::{
+        ModifyKind,
+        RenameMode,
+    },
+};
+
+/// How many changes are listed before the rest are just counted.
+const MAX_LISTED_CHANGES: usize = 50;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Change {
+    Created,
+    Modified,
+    Removed,
+}
+
+/// The changes since they were last taken, by path relative to the workspace. A path has one change, what happened to
+/// it overall: a file that was created and then modified was created, one that was created and removed again is gone.
+#[derive(Debug, Default)]
+struct Changes {
+    paths: BTreeMap<PathBuf, Change>,
+    /// Directories created since, which need watching too.
+    new_dirs: Vec<PathBuf>,
+}
+
+impl Changes {
+    fn record(&mut self, path: PathBuf, change: Change) {
+        let change = match (self.paths.get(&path), change) {
+            (Some(Change::Created), Change::Removed) => {
+                self.paths.remove(&path);
+                return;
+            }
+            (Some(Change::Created), _) => Change::Created,
+            (Some(Change::Removed), Change::Created | Change::Modified) => Change::Modified,
+            (_, change) => change,
+        };
+        self.paths.insert(path, change);
+    }
+}
+
+/// Each directory is watched on its own rather than the whole workspace recursively, so that ignored directories like
+/// `target/` or `node_modules/`, which can hold many thousands, aren't watched at all.
+pub struct WorkspaceWatcher {
+    watcher: notify::RecommendedWatcher,
+    root: PathBuf,
+    gitignore: Gitignore,
+    changes: Arc<Mutex<Changes>>,
+}
+
+impl WorkspaceWatcher {
+    /// Starts watching `workspace`, leaving out hidden and gitignored files like the project layout does.
+    pub fn new(workspace: &Path) -> anyhow::Result<Self> {
+        let changes = Arc::new(Mutex::new(Changes::default()));
+        let gitignore = workspace_gitignore(workspace);
+        let root = workspace.to_path_buf();
+        let recorder = changes.clone();
+        let ignored = gitignore.clone();
+        let watcher = notify::recommended_watcher(move |event: notify::Result<Event>| {
+            let event = match event {
+                Ok(event) => event,
+                Err(e) => {
+                    tracing::warn!("Error watching the workspace: {e:?}");
+                    return;
+                }
+            };
+            let mut changes = recorder.lock().unwrap();
+            for (path, change) in event_changes(&event) {
+                let Ok(relative) = path.strip_prefix(&root) else {
+                    continue;
+                };
+                if is_ignored(&ignored, relative, path) {
+                    continue;
+                }
+                if change == Change::Created && path.is_dir() {
+                    changes.new_dirs.push(path.to_path_buf());
+                }
+                changes.record(relative.to_path_buf(), change);
+            }
+        })?;
+        let mut watcher = Self {
+            watcher,
+            root: workspace.to_path_buf(),
+            gitignore,
+            changes,
+        };
+        watcher.watch_tree(workspace)?;
+        Ok(watcher)
+    }
+
+    /// Describes the changes since the last call for the model, or returns `None` if nothing changed. Changes to the
+    /// `known` files, by their absolute paths, are left out.
+    pub fn take_changes(&mut self, known: &BTreeSet<PathBuf>) -> Option<String> {
+        let mut changes = std::mem::take(&mut *self.changes.lock().unwrap());
+        for dir in std::mem::take(&mut changes.new_dirs) {
+            // Files may have been created in it before it was watched, like by `mkdir -p src && touch src/lib.rs`.
+            match self.watch_tree(&dir) {
+                Ok(files) => {
+                    for path in files {
+                        if let Ok(relative) = path.strip_prefix(&self.root)
+                            && !is_ignored(&self.gitignore, relative, &path)
+                        {
+                            changes.record(relative.to_path_buf(), Change::Created);
+                        }
+                    }
+                }
+                // It may be gone again already.
+                Err(e) => tracing::debug!("Not watching {}: {e:?}", dir.display()),
+            }
+        }
+        changes.paths.retain(|path, _| !known.contains(&self.root.join(path)));
+        describe_changes(&changes)
+    }
+
+    /// Watches `dir` and the directories under it that aren't hidden or ignored, and returns the files in them.
+    fn watch_tree(&mut self, dir: &Path) -> anyhow::Result<Vec<PathBuf>> {
+        let mut files = vec![];
+        for entry in WalkBuilder::new(dir).build() {
+            let entry = entry?;
+            if entry.file_type().is_some_and(|file_type| file_type.is_dir()) {
+                self.watcher.watch(entry.path(), RecursiveMode::NonRecursive)?;
+            } else {
+                files.push(entry.into_path());
+            }
+        }
+        Ok(files)
+    }
+}
+
+/// What an event did to which paths. Events that don't change contents or names, like reads and permission changes,
+/// don't count.
+fn event_changes(event: &Event) -> Vec<(&Path, Change)> {
+    let change = match event.kind {
+        EventKind::Create(_) | EventKind::Modify(ModifyKind::Name(RenameMode::To)) => Change::Created,
+        EventKind::Remove(_) | EventKind::Modify(ModifyKind::Name(RenameMode::From)) => Change::Removed,
+        EventKind::Modify(ModifyKind::Name(RenameMode::Both)) => {
+            return match event.paths.as_slice() {
+                [from, to] => vec![(from.as_path(), Change::Removed), (to.as_path(), Change::Created)],
+                _ => vec![],
+            };
+        }
+        EventKind::Modify(ModifyKind::Metadata(_)) => return vec![],
+        EventKind::Modify(_) => Change::Modified,
+        _ => return vec![],
+    };
+    event.paths.iter().map(|path| (path.as_path(), change)).collect()
+}
+
+fn describe_changes(changes: &Changes) -> Option<String> {
+    if changes.paths.is_empty() {
+        return None;
+    }
+    let mut text = "<files_changed>\nFiles in the workspace that changed since the user's last message:\n".to_string();
+    for (path, change) in changes.paths.iter().take(MAX_LISTED_CHANGES) {
+        let change = match change {
+            Change::Created => "created",
+            Change::Modified => "modified",
+            Change::Removed => "removed",
+        };
+        text.push_str(&format!("- {change}: {}\n", path.display()));
+    }
+    if changes.paths.len() > MAX_LISTED_CHANGES {
+        text.push_str(&format!("- and {} more\n", changes.paths.len() - MAX_LISTED_CHANGES));
+    }
+    text.push_str("</files_changed>\n");
+    Some(text)
+}
+
+/// The workspace's own `.gitignore` and git's exclude file. Nested `.gitignore` files aren't read, which only lets
+/// through more changes than the layout would show.
+fn workspace_gitignore(workspace: &Path) -> Gitignore {
+    let mut builder = GitignoreBuilder::new(workspace);
+    for path in [
+        workspace.join(".gitignore"),
+        workspace.join(".git").join("info").join("exclude"),
+    ] {
+        if path.exists()
+            && let Some(e) = builder.add(&path)
+        {
+            tracing::warn!("Failed to read {}: {e}", path.display());
+        }
+    }
+    builder.build().unwrap_or_else(|e| {
+        tracing::warn!("Invalid gitignore in the workspace: {e}");
+        Gitignore::empty()
+    })
+}
+
+/// Whether changes to `path`, which is `relative` in the workspace, are left out.
+fn is_ignored(gitignore: &Gitignore, relative: &Path, path: &Path) -> bool {
+    is_hidden(relative) || gitignore.matched_path_or_any_parents(path, path.is_dir()).is_ignore()
+}
+
+fn is_hidden(relative: &Path) -> bool {
+    relative
+        .components()
+        .any(|component| component.as_os_str().to_string_lossy().starts_with('.'))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_record_changes() {
+        let mut changes = Changes::default();
+        changes.record(PathBuf::from("new.rs"), Change::Created);
+        changes.record(PathBuf::from("new.rs"), Change::Modified);
+        changes.record(PathBuf::from("scratch.txt"), Change::Created);
+        changes.record(PathBuf::from("scratch.txt"), Change::Removed);
+        changes.record(PathBuf::from("README.md"), Change::Removed);
+        changes.record(PathBuf::from("README.md"), Change::Created);
+        changes.record(PathBuf::from("old.rs"), Change::Modified);
+        changes.record(PathBuf::from("old.rs"), Change::Removed);
+        assert_eq!(
+            describe_changes(&changes).unwrap(),
+            "<files_changed>\nFiles in the workspace that changed since the user's last message:\n- modified: \
+             README.md\n- created: new.rs\n- removed: old.rs\n</files_changed>\n"
+        );
+        assert_eq!(describe_changes(&Changes::default()), None);
+    }
+
+    /// Events arrive on a thread of their own, so this waits a while for them.
+    async fn wait_for_changes(watcher: &mut WorkspaceWatcher, known: &BTreeSet<PathBuf>) -> String {
+        for _ in 0..50 {
+            tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+            if let Some(changes) = watcher.take_changes(known) {
+                return changes;
+            }
+        }
+        panic!("No changes after 5 seconds");
+    }
+
+    #[tokio::test]
+    async fn test_watch_workspace() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let workspace = temp_dir.path();
+        std::fs::create_dir_all(workspace.join("target")).unwrap();
+        std::fs::write(workspace.join(".gitignore"), "target/\n").unwrap();
+        let mut watcher = WorkspaceWatcher::new(workspace).unwrap();
+
+        std::fs::write(workspace.join("target").join("build.log"), "ignored").unwrap();
+        std::fs::write(workspace.join(".hidden"), "ignored").unwrap();
+        std::fs::write(workspace.join("notes.md"), "notes").unwrap();
+        // Like the agent's own edits, which it knows about.
+        std::fs::write(workspace.join("edited.rs"), "known").unwrap();
+        let known = BTreeSet::from([workspace.join("edited.rs")]);
+        let changes = wait_for_changes(&mut watcher, &known).await;
+        assert!(changes.contains("- created: notes.md\n"), "{changes}");
+        assert!(
+            !changes.contains("build.log") && !changes.contains(".hidden") && !changes.contains("edited.rs"),
+            "{changes}"
+        );
+        let known = BTreeSet::new();
+
+        // New directories get watched once their creation has been taken.
+        std::fs::create_dir(workspace.join("docs")).unwrap();
+        let changes = wait_for_changes(&mut watcher, &known).await;
+        assert!(changes.contains("- created: docs\n"), "{changes}");
+        std::fs::write(workspace.join("docs").join("guide.md"), "guide").unwrap();
+        let changes = wait_for_changes(&mut watcher, &known).await;
+        assert!(changes.contains("- created: docs/guide.md\n"), "{changes}");
+
+        // So do the files created in them before that.
+        std::fs::create_dir_all(workspace.join("src").join("parser")).unwrap();
+        std::fs::write(workspace.join("src").join("lib.rs"), "lib").unwrap();
+        std::fs::write(workspace.join("src").join("parser").join("mod.rs"), "parser").unwrap();
+        let changes = wait_for_changes(&mut watcher, &known).await;
+        assert!(
+            changes.contains("- created: src/lib.rs\n") && changes.contains("- created: src/parser/mod.rs\n"),
+            "{changes}"
+        );
+    }
+}
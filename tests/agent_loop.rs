@@ -0,0 +1,939 @@
+mod common;
+
+use std::collections::BTreeMap;
+
+use agent::{
+    cassette::ToolCassette,
+    compaction::CompactionConfig,
+    config::Settings,
+    control::ControlMessage,
+    prompts::SystemPromptConfig,
+    server::SessionConfig,
+    tools::prompts::TOOL_NAMES,
+    ui_state::{
+        ChatUIMessage,
+        ChatUIToolCall,
+        GeneratingState,
+    },
+};
+use common::{
+    MockResponse,
+    MockServer,
+    finish_chunk,
+    last_messages,
+    run_session,
+    run_session_with_config,
+    run_session_with_profiles,
+    session_config,
+    text_chunk,
+    tool_call_chunk,
+    tool_results,
+    user_messages,
+};
+
+fn assert_system_message(message: &ChatUIMessage, expected: &str) {
+    let ChatUIMessage::System(system_message) = message else {
+        panic!("Expected a system message, got {message:?}");
+    };
+    assert_eq!(system_message.text, expected);
+}
+
+fn assert_error_message(message: &ChatUIMessage, expected: &str) {
+    let ChatUIMessage::System(system_message) = message else {
+        panic!("Expected an error message, got {message:?}");
+    };
+    assert!(system_message.text.starts_with("**Error:**"), "{}", system_message.text);
+    assert!(system_message.text.contains(expected), "{}", system_message.text);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_streamed_text() {
+    let server = MockServer::start(vec![MockResponse::Stream(vec![
+        text_chunk("Hello"),
+        text_chunk(", "),
+        text_chunk("world!"),
+        finish_chunk("stop"),
+    ])])
+    .await;
+
+    let ui_state = run_session(server.llm_provider(), ToolCassette::Off, user_messages(&["Hi"])).await;
+
+    let messages = ui_state.messages();
+    assert_eq!(messages.len(), 2, "{messages:#?}");
+    assert!(matches!(&messages[0], ChatUIMessage::User(m) if m.text == "Hi"));
+    assert_system_message(&messages[1], "Hello, world!");
+    assert!(matches!(ui_state.generating_state(), GeneratingState::Idle));
+
+    let requests = server.requests();
+    assert_eq!(requests.len(), 1);
+    assert_eq!(requests[0]["stream"], true);
+    assert_eq!(
+        last_messages(&requests[0], 1),
+        vec![serde_json::json!({ "role": "user", "content": "Hi" })]
+    );
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_parallel_tool_calls() {
+    let server = MockServer::start(vec![
+        // Two calls whose argument deltas are interleaved, the way providers stream parallel calls.
+        MockResponse::Stream(vec![
+            tool_call_chunk(0, Some("call_1"), Some("read_file"), ""),
+            tool_call_chunk(1, Some("call_2"), Some("list_dir"), ""),
+            tool_call_chunk(0, None, None, r#"{"target_file":"#),
+            tool_call_chunk(1, None, None, r#"{"target_directory":"src"}"#),
+            tool_call_chunk(0, None, None, r#""README.md"}"#),
+            finish_chunk("tool_calls"),
+        ]),
+        MockResponse::text("Done."),
+    ])
+    .await;
+    let tool_cassette = tool_results(&[("call_1", Ok("readme contents")), ("call_2", Err("no such directory"))]);
+
+    let ui_state = run_session(server.llm_provider(), tool_cassette, user_messages(&["Look around"])).await;
+
+    let messages = ui_state.messages();
+    assert_eq!(messages.len(), 4, "{messages:#?}");
+    let ChatUIMessage::ToolCall(ChatUIToolCall::Complete { name, args, result }) = &messages[1] else {
+        panic!("Expected a completed tool call, got {:?}", messages[1]);
+    };
+    assert_eq!(name, "read_file");
+    assert_eq!(args, r#"{"target_file":"README.md"}"#);
+    assert_eq!(result.as_deref(), Ok("readme contents"));
+    let ChatUIMessage::ToolCall(ChatUIToolCall::Complete { name, args, result }) = &messages[2] else {
+        panic!("Expected a completed tool call, got {:?}", messages[2]);
+    };
+    assert_eq!(name, "list_dir");
+    assert_eq!(args, r#"{"target_directory":"src"}"#);
+    assert_eq!(result.as_ref().unwrap_err(), "no such directory");
+    assert_system_message(&messages[3], "Done.");
+
+    let requests = server.requests();
+    assert_eq!(requests.len(), 2);
+    let conversation = last_messages(&requests[1], 3);
+    let tool_calls = conversation[0]["tool_calls"].as_array().unwrap();
+    assert_eq!(tool_calls.len(), 2);
+    assert_eq!(tool_calls[0]["id"], "call_1");
+    assert_eq!(tool_calls[0]["function"]["arguments"], r#"{"target_file":"README.md"}"#);
+    assert_eq!(tool_calls[1]["id"], "call_2");
+    assert_eq!(
+        conversation[1],
+        serde_json::json!({ "role": "tool", "content": "readme contents", "tool_call_id": "call_1" })
+    );
+    assert_eq!(
+        conversation[2],
+        serde_json::json!({ "role": "tool", "content": "Error: no such directory", "tool_call_id": "call_2" })
+    );
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_truncated_text_is_continued() {
+    let server = MockServer::start(vec![
+        MockResponse::Stream(vec![text_chunk("The first half"), finish_chunk("length")]),
+        MockResponse::Stream(vec![text_chunk(" and the rest."), finish_chunk("stop")]),
+    ])
+    .await;
+
+    let ui_state = run_session(
+        server.llm_provider(),
+        ToolCassette::Off,
+        user_messages(&["Write a lot"]),
+    )
+    .await;
+
+    let messages = ui_state.messages();
+    assert_eq!(messages.len(), 2, "{messages:#?}");
+    assert_system_message(&messages[1], "The first half and the rest.");
+
+    let requests = server.requests();
+    assert_eq!(requests.len(), 2);
+    let continuation = last_messages(&requests[1], 2);
+    assert_eq!(continuation[0]["role"], "assistant");
+    assert_eq!(continuation[0]["content"], "The first half");
+    assert_eq!(continuation[1]["role"], "user");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_malformed_chunk() {
+    let server = MockServer::start(vec![
+        MockResponse::Stream(vec![
+            text_chunk("Partial"),
+            r#"{"choices": [{"index": 0, "delta": {"content": "#.to_string(),
+            text_chunk(" never seen"),
+            finish_chunk("stop"),
+        ]),
+        MockResponse::text("Second try."),
+    ])
+    .await;
+
+    let ui_state = run_session(
+        server.llm_provider(),
+        ToolCassette::Off,
+        user_messages(&["First", "Try again"]),
+    )
+    .await;
+
+    let messages = ui_state.messages();
+    assert_eq!(messages.len(), 5, "{messages:#?}");
+    assert_system_message(&messages[1], "Partial");
+    assert_error_message(&messages[2], "EOF while parsing");
+    assert!(matches!(&messages[3], ChatUIMessage::User(m) if m.text == "Try again"));
+    assert_system_message(&messages[4], "Second try.");
+
+    // The failed response is not part of the history the model sees next.
+    let requests = server.requests();
+    assert_eq!(requests.len(), 2);
+    assert_eq!(
+        last_messages(&requests[1], 2),
+        vec![
+            serde_json::json!({ "role": "user", "content": "First" }),
+            serde_json::json!({ "role": "user", "content": "Try again" }),
+        ]
+    );
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_malformed_tool_call_chunk_is_not_executed() {
+    let server = MockServer::start(vec![MockResponse::Stream(vec![
+        tool_call_chunk(0, Some("call_1"), Some("read_file"), r#"{"target_file":"#),
+        // A tool call delta without a function.
+        r#"{"choices": [{"index": 0, "delta": {"tool_calls": [{"index": 0}]}, "finish_reason": null}]}"#.to_string(),
+        finish_chunk("tool_calls"),
+    ])])
+    .await;
+    let ui_state = run_session(server.llm_provider(), tool_results(&[]), user_messages(&["Read it"])).await;
+
+    let messages = ui_state.messages();
+    assert_eq!(messages.len(), 3, "{messages:#?}");
+    let ChatUIMessage::ToolCall(ChatUIToolCall::Complete { name, result, .. }) = &messages[1] else {
+        panic!("Expected a completed tool call, got {:?}", messages[1]);
+    };
+    assert_eq!(name, "read_file");
+    assert_eq!(result.as_ref().unwrap_err(), "not executed, the response failed");
+    assert_error_message(&messages[2], "Tool call function is missing");
+    assert_eq!(server.requests().len(), 1);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_unexpected_finish_reason() {
+    let server = MockServer::start(vec![MockResponse::Stream(vec![
+        text_chunk("I can't"),
+        finish_chunk("content_filter"),
+    ])])
+    .await;
+
+    let ui_state = run_session(server.llm_provider(), ToolCassette::Off, user_messages(&["Hi"])).await;
+
+    let messages = ui_state.messages();
+    assert_eq!(messages.len(), 3, "{messages:#?}");
+    assert_system_message(&messages[1], "I can't");
+    assert_error_message(&messages[2], "Unexpected finish reason: ContentFilter");
+    assert!(matches!(ui_state.generating_state(), GeneratingState::Idle));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_unknown_finish_reason() {
+    let server = MockServer::start(vec![MockResponse::Stream(vec![
+        text_chunk("Hmm"),
+        finish_chunk("something_new"),
+    ])])
+    .await;
+
+    let ui_state = run_session(server.llm_provider(), ToolCassette::Off, user_messages(&["Hi"])).await;
+
+    let messages = ui_state.messages();
+    assert_eq!(messages.len(), 3, "{messages:#?}");
+    assert_error_message(&messages[2], "unknown variant `something_new`");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_error_status() {
+    let server = MockServer::start(vec![MockResponse::Error {
+        status: "429 Too Many Requests",
+        body: "Rate limit exceeded".to_string(),
+    }])
+    .await;
+
+    let ui_state = run_session(server.llm_provider(), ToolCassette::Off, user_messages(&["Hi"])).await;
+
+    let messages = ui_state.messages();
+    assert_eq!(messages.len(), 2, "{messages:#?}");
+    assert_error_message(&messages[1], "429 Too Many Requests: Rate limit exceeded");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_set_model() {
+    let server = MockServer::start(vec![MockResponse::text("One."), MockResponse::text("Two.")]).await;
+    let other_server = MockServer::start(vec![
+        MockResponse::Completion("They said first and second.".to_string()),
+        MockResponse::text("Three."),
+    ])
+    .await;
+    // The other profile's context window is so small that the conversation is compacted right away.
+    let profiles = BTreeMap::from([(
+        "other".to_string(),
+        Settings {
+            model: Some("other-model".to_string()),
+            base_url: Some(other_server.base_url()),
+            context_window: Some(10),
+            keep_recent_turns: Some(1),
+            ..Default::default()
+        },
+    )]);
+
+    let ui_state = run_session_with_profiles(
+        server.llm_provider(),
+        ToolCassette::Off,
+        vec![
+            ControlMessage::UserMessage("First".to_string()),
+            ControlMessage::SetModel {
+                name: Some("big-model".to_string()),
+                reasoning_effort: Some("high".to_string()),
+            },
+            ControlMessage::UserMessage("Second".to_string()),
+            ControlMessage::SetModel {
+                name: Some("other".to_string()),
+                reasoning_effort: None,
+            },
+            ControlMessage::SetModel {
+                name: Some("broken".to_string()),
+                reasoning_effort: Some("extreme".to_string()),
+            },
+            ControlMessage::UserMessage("Third".to_string()),
+        ],
+        profiles,
+    )
+    .await;
+
+    let messages = ui_state.messages();
+    assert_eq!(messages.len(), 10, "{messages:#?}");
+    assert_system_message(&messages[2], "Switched to **big-model (high effort)**.");
+    assert_system_message(&messages[5], "Switched to **other-model**.");
+    assert_error_message(&messages[6], "Invalid reasoning effort: extreme");
+    assert!(matches!(messages[8], ChatUIMessage::Compaction(_)), "{messages:#?}");
+    assert_system_message(&messages[9], "Three.");
+    let model = ui_state.model().unwrap();
+    assert_eq!(model.name, "other-model");
+    assert_eq!(model.reasoning_effort, None);
+
+    let requests = server.requests();
+    assert_eq!(requests.len(), 2);
+    assert_eq!(requests[0]["model"], "mock");
+    assert_eq!(requests[1]["model"], "big-model");
+    assert_eq!(requests[1]["reasoning_effort"], "high");
+    // History carries over to the new provider, compacted for its context window.
+    let requests = other_server.requests();
+    assert_eq!(requests.len(), 2);
+    assert_eq!(requests[1]["model"], "other-model");
+    let transcript = requests[0]["messages"][1]["content"].as_str().unwrap();
+    assert!(
+        transcript.contains("User: First") && transcript.contains("Assistant: Two."),
+        "{transcript}"
+    );
+    let conversation = last_messages(&requests[1], 2);
+    assert!(
+        conversation[0]["content"]
+            .as_str()
+            .unwrap()
+            .contains("They said first and second.")
+    );
+    assert_eq!(conversation[1]["content"], "Third");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_clear() {
+    let server = MockServer::start(vec![MockResponse::text("Hello."), MockResponse::text("Hello again.")]).await;
+
+    let ui_state = run_session(
+        server.llm_provider(),
+        ToolCassette::Off,
+        vec![
+            ControlMessage::UserMessage("Hi".to_string()),
+            ControlMessage::Clear,
+            ControlMessage::UserMessage("Hi again".to_string()),
+        ],
+    )
+    .await;
+
+    let messages = ui_state.messages();
+    assert_eq!(messages.len(), 2, "{messages:#?}");
+    assert!(matches!(&messages[0], ChatUIMessage::User(m) if m.text == "Hi again"));
+    assert_system_message(&messages[1], "Hello again.");
+
+    // The cleared conversation is gone from the history too.
+    let requests = server.requests();
+    assert_eq!(requests.len(), 2);
+    let before_clear = requests[0]["messages"].as_array().unwrap().len();
+    let after_clear = requests[1]["messages"].as_array().unwrap();
+    assert_eq!(after_clear.len(), before_clear);
+    assert_eq!(after_clear.last().unwrap()["content"], "Hi again");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_info_commands() {
+    let finish_with_usage = serde_json::json!({
+        "choices": [{ "index": 0, "delta": {}, "finish_reason": "stop" }],
+        "usage": {
+            "prompt_tokens": 1200,
+            "completion_tokens": 30,
+            "total_tokens": 1230,
+            "prompt_tokens_details": { "cached_tokens": 1000 },
+        },
+    })
+    .to_string();
+    let server = MockServer::start(vec![MockResponse::Stream(vec![
+        text_chunk("Hello."),
+        finish_with_usage,
+    ])])
+    .await;
+
+    let ui_state = run_session(
+        server.llm_provider(),
+        ToolCassette::Off,
+        vec![
+            ControlMessage::ShowCost,
+            ControlMessage::UserMessage("Hi".to_string()),
+            ControlMessage::ShowCost,
+            ControlMessage::ListTools,
+        ],
+    )
+    .await;
+
+    let messages = ui_state.messages();
+    assert_eq!(messages.len(), 5, "{messages:#?}");
+    assert_system_message(&messages[0], "The provider hasn't reported any token usage yet.");
+    assert_system_message(
+        &messages[3],
+        "Used 1200 prompt tokens (1000 cached) and 30 completion tokens over 1 requests.",
+    );
+    let ChatUIMessage::System(tools) = &messages[4] else {
+        panic!("Expected a system message, got {:?}", messages[4]);
+    };
+    assert!(tools.text.contains("- `read_file`: Reads a file"), "{}", tools.text);
+    assert!(tools.text.contains("- `list_dir`: Lists files"), "{}", tools.text);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_file_mentions() {
+    let server = MockServer::start(vec![MockResponse::text("It's called agent.")]).await;
+    // Mentions are relative to the session's workspace, not to where the process runs.
+    let temp_dir = tempfile::tempdir().unwrap();
+    let workspace = temp_dir.path();
+    std::fs::write(
+        workspace.join("Cargo.toml"),
+        "[package]\nname = \"agent\"\nversion = \"0.1.0\"\n",
+    )
+    .unwrap();
+    let session_dir = tempfile::tempdir().unwrap();
+    let session_config = SessionConfig {
+        workspace: workspace.to_path_buf(),
+        ..session_config(session_dir.path())
+    };
+
+    let ui_state = run_session_with_config(
+        server.llm_provider(),
+        ToolCassette::Off,
+        user_messages(&["What's the name in @Cargo.toml:1-2? Not @missing.txt."]),
+        session_config,
+    )
+    .await;
+
+    let messages = ui_state.messages();
+    let ChatUIMessage::User(user_message) = &messages[0] else {
+        panic!("Expected a user message, got {:?}", messages[0]);
+    };
+    assert_eq!(user_message.attachments, vec!["Cargo.toml:1-2"]);
+
+    let requests = server.requests();
+    assert_eq!(
+        last_messages(&requests[0], 1),
+        vec![serde_json::json!({
+            "role": "user",
+            "content": [
+                { "type": "text", "text": "What's the name in @Cargo.toml:1-2? Not @missing.txt." },
+                {
+                    "type": "text",
+                    "text": "<attached_file path=\"Cargo.toml\" lines=\"1-2\">\n[package]\nname = \"agent\"\n</attached_file>",
+                },
+            ],
+        })]
+    );
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_edit_file() {
+    let temp_dir = tempfile::tempdir().unwrap();
+    let dir = temp_dir.path();
+    let workspace = dir.join("workspace");
+    std::fs::create_dir_all(&workspace).unwrap();
+    let path = workspace.join("notes").join("todo.txt").display().to_string();
+    let create = serde_json::json!({ "target_file": path, "old_string": "", "new_string": "one\ntwo\nthree\n" });
+    let edit = serde_json::json!({ "target_file": path, "old_string": "two", "new_string": "2" });
+    let escape = serde_json::json!({ "target_file": "notes/../../escape.txt", "old_string": "", "new_string": "x" });
+    let server = MockServer::start(vec![
+        MockResponse::Stream(vec![
+            tool_call_chunk(0, Some("call_1"), Some("edit_file"), &create.to_string()),
+            finish_chunk("tool_calls"),
+        ]),
+        MockResponse::Stream(vec![
+            tool_call_chunk(0, Some("call_2"), Some("edit_file"), &edit.to_string()),
+            finish_chunk("tool_calls"),
+        ]),
+        MockResponse::Stream(vec![
+            tool_call_chunk(0, Some("call_3"), Some("edit_file"), &create.to_string()),
+            finish_chunk("tool_calls"),
+        ]),
+        MockResponse::Stream(vec![
+            tool_call_chunk(0, Some("call_4"), Some("edit_file"), &escape.to_string()),
+            finish_chunk("tool_calls"),
+        ]),
+        MockResponse::text("Done."),
+    ])
+    .await;
+
+    let session_dir = tempfile::tempdir().unwrap();
+
+    let session_config = SessionConfig {
+        workspace: workspace.to_path_buf(),
+        ..session_config(session_dir.path())
+    };
+    let ui_state = run_session_with_config(
+        server.llm_provider(),
+        ToolCassette::Off,
+        user_messages(&["Write it down"]),
+        session_config,
+    )
+    .await;
+
+    assert_eq!(std::fs::read_to_string(&path).unwrap(), "one\n2\nthree\n");
+    let messages = ui_state.messages();
+    let ChatUIMessage::ToolCall(ChatUIToolCall::Complete { result, .. }) = &messages[2] else {
+        panic!("Expected a completed tool call, got {:?}", messages[2]);
+    };
+    assert_eq!(
+        result.as_deref(),
+        Ok(format!("--- {path}\n+++ {path}\n@@ -1,3 +1,3 @@\n one\n-two\n+2\n three\n").as_str())
+    );
+    // Creating a file that's already there fails rather than overwriting it.
+    let ChatUIMessage::ToolCall(ChatUIToolCall::Complete { result, .. }) = &messages[3] else {
+        panic!("Expected a completed tool call, got {:?}", messages[3]);
+    };
+    assert!(result.as_ref().unwrap_err().contains("already exists"), "{result:?}");
+    // Files outside of the workspace can't be written.
+    let ChatUIMessage::ToolCall(ChatUIToolCall::Complete { result, .. }) = &messages[4] else {
+        panic!("Expected a completed tool call, got {:?}", messages[4]);
+    };
+    assert!(
+        result.as_ref().unwrap_err().contains("outside of the workspace"),
+        "{result:?}"
+    );
+    assert!(!dir.join("escape.txt").exists());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_undo_and_restore() {
+    let temp_dir = tempfile::tempdir().unwrap();
+    let dir = temp_dir.path();
+    let path = dir.join("notes.txt").display().to_string();
+    std::fs::write(&path, "one\n").unwrap();
+    let edit = |old: &str, new: &str| {
+        serde_json::json!({ "target_file": path, "old_string": old, "new_string": new }).to_string()
+    };
+    let server = MockServer::start(vec![
+        MockResponse::Stream(vec![
+            tool_call_chunk(0, Some("call_1"), Some("edit_file"), &edit("one", "two")),
+            finish_chunk("tool_calls"),
+        ]),
+        MockResponse::text("Changed it."),
+        MockResponse::Stream(vec![
+            tool_call_chunk(0, Some("call_2"), Some("edit_file"), &edit("two", "three")),
+            finish_chunk("tool_calls"),
+        ]),
+        MockResponse::text("Changed it again."),
+    ])
+    .await;
+
+    let control_messages = vec![
+        ControlMessage::UserMessage("Make it two".to_string()),
+        ControlMessage::UserMessage("Make it three".to_string()),
+        ControlMessage::Undo,
+        ControlMessage::ListCheckpoints,
+        ControlMessage::Restore {
+            number: 1,
+            truncate: true,
+        },
+    ];
+    let session_dir = tempfile::tempdir().unwrap();
+    let session_config = SessionConfig {
+        workspace: dir.to_path_buf(),
+        ..session_config(session_dir.path())
+    };
+    let ui_state = run_session_with_config(
+        server.llm_provider(),
+        ToolCassette::Off,
+        control_messages,
+        session_config,
+    )
+    .await;
+
+    assert_eq!(std::fs::read_to_string(&path).unwrap(), "one\n");
+    let messages = ui_state.messages();
+    // Everything from the first prompt on is gone, but for the note about the restore.
+    assert_eq!(messages.len(), 1, "{messages:#?}");
+    assert_system_message(
+        &messages[0],
+        "Restored checkpoint 1 and went back to before \"Make it two\".",
+    );
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_undo_after_compaction() {
+    let temp_dir = tempfile::tempdir().unwrap();
+    let workspace = temp_dir.path();
+    let path = workspace.join("notes.txt");
+    std::fs::write(&path, "one\n").unwrap();
+    let edit = serde_json::json!({ "target_file": path, "old_string": "one", "new_string": "two" }).to_string();
+    let server = MockServer::start(vec![
+        MockResponse::text("Hi."),
+        MockResponse::Completion("They said hello.".to_string()),
+        MockResponse::Stream(vec![
+            tool_call_chunk(0, Some("call_1"), Some("edit_file"), &edit),
+            finish_chunk("tool_calls"),
+        ]),
+        MockResponse::text("Changed it."),
+    ])
+    .await;
+
+    // Every request is over the threshold, so the first turn is summarized right before the second one's request.
+    let session_dir = tempfile::tempdir().unwrap();
+    let session_config = SessionConfig {
+        workspace: workspace.to_path_buf(),
+        compaction: CompactionConfig {
+            context_window: 10,
+            threshold: 0.8,
+            keep_recent_turns: 1,
+        },
+        ..session_config(session_dir.path())
+    };
+    let control_messages = vec![
+        ControlMessage::UserMessage("Hello".to_string()),
+        ControlMessage::UserMessage("Make it two".to_string()),
+        ControlMessage::Undo,
+    ];
+    let ui_state = run_session_with_config(
+        server.llm_provider(),
+        ToolCassette::Off,
+        control_messages,
+        session_config,
+    )
+    .await;
+
+    assert_eq!(server.requests().len(), 4);
+    assert_eq!(std::fs::read_to_string(&path).unwrap(), "one\n");
+    let messages = ui_state.messages();
+    assert_system_message(
+        messages.last().unwrap(),
+        &format!("Reverted the edits to `{}`.", path.display()),
+    );
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_rewind_and_fork() {
+    let server = MockServer::start(vec![
+        MockResponse::text("First answer."),
+        MockResponse::text("Second answer."),
+        MockResponse::text("Edited answer."),
+        MockResponse::text("Rewritten answer."),
+    ])
+    .await;
+
+    let control_messages = vec![
+        ControlMessage::UserMessage("First".to_string()),
+        ControlMessage::UserMessage("Second".to_string()),
+        // Transcript: First, its answer, Second, its answer.
+        ControlMessage::Rewind {
+            ui_index: 2,
+            text: "Second, edited".to_string(),
+            fork: true,
+        },
+        // Transcript: First, its answer, the fork notice, Second, edited, its answer.
+        ControlMessage::Rewind {
+            ui_index: 0,
+            text: "First, rewritten".to_string(),
+            fork: false,
+        },
+        ControlMessage::SwitchBranch { number: 1 },
+    ];
+    let ui_state = run_session(server.llm_provider(), ToolCassette::Off, control_messages).await;
+
+    let requests = server.requests();
+    assert_eq!(requests.len(), 4);
+    let conversation = last_messages(&requests[2], 3);
+    assert_eq!(conversation[0]["content"], "First");
+    assert_eq!(conversation[1]["content"], "First answer.");
+    assert_eq!(conversation[2]["content"], "Second, edited");
+    // Rewinding without forking drops the rest of the branch.
+    let conversation = last_messages(&requests[3], 2);
+    assert_ne!(conversation[0]["content"], "Second, edited");
+    assert_eq!(conversation[1]["content"], "First, rewritten");
+
+    // Branch 1 kept the conversation as it was before the fork.
+    let messages = ui_state.messages();
+    assert_eq!(messages.len(), 5, "{messages:#?}");
+    let ChatUIMessage::User(user) = &messages[2] else {
+        panic!("Expected a user message, got {:?}", messages[2]);
+    };
+    assert_eq!(user.text, "Second");
+    assert_system_message(&messages[3], "Second answer.");
+    assert_system_message(&messages[4], "Switched to branch 1.");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_project_instructions() {
+    let temp_dir = tempfile::tempdir().unwrap();
+    let workspace = temp_dir.path();
+    std::fs::create_dir_all(workspace.join("db")).unwrap();
+    std::fs::write(workspace.join("AGENTS.md"), "Run the tests.").unwrap();
+    std::fs::write(workspace.join("db").join("AGENTS.md"), "Never drop tables.").unwrap();
+    let read = |path: &str| serde_json::json!({ "target_file": workspace.join(path) }).to_string();
+    let server = MockServer::start(vec![
+        MockResponse::Stream(vec![
+            tool_call_chunk(0, Some("call_1"), Some("read_file"), &read("db/schema.sql")),
+            finish_chunk("tool_calls"),
+        ]),
+        MockResponse::Stream(vec![
+            tool_call_chunk(0, Some("call_2"), Some("read_file"), &read("db/seed.sql")),
+            finish_chunk("tool_calls"),
+        ]),
+        MockResponse::text("Done."),
+    ])
+    .await;
+
+    let session_dir = tempfile::tempdir().unwrap();
+
+    let session_config = SessionConfig {
+        workspace: workspace.to_path_buf(),
+        ..session_config(session_dir.path())
+    };
+    let tool_cassette = tool_results(&[("call_1", Ok("create table")), ("call_2", Ok("insert"))]);
+    run_session_with_config(
+        server.llm_provider(),
+        tool_cassette,
+        user_messages(&["Check the schema"]),
+        session_config,
+    )
+    .await;
+
+    let requests = server.requests();
+    let instructions = |request: &serde_json::Value| {
+        request["messages"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .filter_map(|message| message["content"].as_str())
+            .filter(|content| content.starts_with("<instructions"))
+            .count()
+    };
+    // The workspace's instructions are there from the start.
+    assert_eq!(instructions(&requests[0]), 1);
+    let workspace_instructions = format!(
+        "<instructions source=\"{}\">\nRun the tests.\n</instructions>\n",
+        workspace.join("AGENTS.md").display()
+    );
+    assert!(
+        requests[0]
+            .to_string()
+            .contains(&serde_json::to_string(&workspace_instructions).unwrap())
+    );
+    // The subdirectory's come after the result of the first tool call under it, and only once.
+    let conversation = last_messages(&requests[1], 2);
+    assert_eq!(conversation[0]["role"], "tool");
+    assert!(
+        conversation[1]["content"]
+            .as_str()
+            .unwrap()
+            .contains("Never drop tables.")
+    );
+    assert_eq!(instructions(&requests[2]), 2);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_instructions_survive_compaction() {
+    let temp_dir = tempfile::tempdir().unwrap();
+    let workspace = temp_dir.path();
+    std::fs::create_dir_all(workspace.join("db")).unwrap();
+    std::fs::write(workspace.join("db").join("AGENTS.md"), "Never drop tables.").unwrap();
+    let read = serde_json::json!({ "target_file": workspace.join("db/schema.sql") }).to_string();
+    let server = MockServer::start(vec![
+        MockResponse::Stream(vec![
+            tool_call_chunk(0, Some("call_1"), Some("read_file"), &read),
+            finish_chunk("tool_calls"),
+        ]),
+        MockResponse::text("Done."),
+        MockResponse::Completion("They checked the schema.".to_string()),
+        MockResponse::text("You're welcome."),
+    ])
+    .await;
+
+    // Every request is over the threshold, so the first turn is summarized as soon as there's a second one.
+    let session_dir = tempfile::tempdir().unwrap();
+    let session_config = SessionConfig {
+        workspace: workspace.to_path_buf(),
+        compaction: CompactionConfig {
+            context_window: 10,
+            threshold: 0.8,
+            keep_recent_turns: 1,
+        },
+        ..session_config(session_dir.path())
+    };
+    run_session_with_config(
+        server.llm_provider(),
+        tool_results(&[("call_1", Ok("create table"))]),
+        user_messages(&["Check the schema", "Thanks"]),
+        session_config,
+    )
+    .await;
+
+    let requests = server.requests();
+    assert_eq!(requests.len(), 4);
+    let contents = requests[3]["messages"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .filter_map(|message| message["content"].as_str())
+        .collect::<Vec<_>>();
+    assert!(
+        contents
+            .iter()
+            .any(|content| content.contains("They checked the schema.")),
+        "{contents:#?}"
+    );
+    assert_eq!(
+        contents
+            .iter()
+            .filter(|content| content.contains("Never drop tables."))
+            .count(),
+        1,
+        "{contents:#?}"
+    );
+    assert_eq!(contents.last(), Some(&"Thanks"));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_custom_system_prompt() {
+    let server = MockServer::start(vec![MockResponse::text("Looks good.")]).await;
+    let session_dir = tempfile::tempdir().unwrap();
+    let session_config = SessionConfig {
+        system_prompt: SystemPromptConfig {
+            template: Some("You review code in {{workspace}}, using {{ tools }}.".to_string()),
+            append: false,
+        },
+        ..session_config(session_dir.path())
+    };
+    let control_messages = vec![
+        ControlMessage::ShowContext,
+        ControlMessage::UserMessage("Review it".to_string()),
+    ];
+    let ui_state = run_session_with_config(
+        server.llm_provider(),
+        ToolCassette::Off,
+        control_messages,
+        session_config,
+    )
+    .await;
+
+    let system_prompt = format!(
+        "You review code in {}, using {}.",
+        std::env::current_dir().unwrap().display(),
+        TOOL_NAMES.join(", ")
+    );
+    let requests = server.requests();
+    assert_eq!(requests[0]["messages"][0]["role"], "system");
+    assert_eq!(requests[0]["messages"][0]["content"], system_prompt.as_str());
+
+    let ChatUIMessage::System(context) = &ui_state.messages()[0] else {
+        panic!("Expected the context, got {:?}", ui_state.messages()[0]);
+    };
+    assert!(context.text.starts_with("### System prompt (~"), "{}", context.text);
+    assert!(context.text.contains(&system_prompt));
+    assert!(context.text.contains("### Project layout"));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_commit() {
+    let temp_dir = tempfile::tempdir().unwrap();
+    let workspace = temp_dir.path();
+    let repo = git2::Repository::init_opts(workspace, git2::RepositoryInitOptions::new().initial_head("main")).unwrap();
+    let mut config = repo.config().unwrap();
+    config.set_str("user.name", "Ada").unwrap();
+    config.set_str("user.email", "ada@example.com").unwrap();
+    let create = serde_json::json!({
+        "target_file": workspace.join("notes.md").display().to_string(),
+        "old_string": "",
+        "new_string": "hello\n",
+    });
+    // Fails, so the file isn't the agent's to commit.
+    std::fs::write(workspace.join("todo.md"), "mine\n").unwrap();
+    let failed_edit = serde_json::json!({
+        "target_file": workspace.join("todo.md").display().to_string(),
+        "old_string": "missing",
+        "new_string": "theirs",
+    });
+    let server = MockServer::start(vec![
+        MockResponse::Stream(vec![
+            tool_call_chunk(0, Some("call_1"), Some("edit_file"), &create.to_string()),
+            tool_call_chunk(1, Some("call_2"), Some("edit_file"), &failed_edit.to_string()),
+            finish_chunk("tool_calls"),
+        ]),
+        MockResponse::text("Done."),
+        MockResponse::Completion("```\nAdd notes\n```".to_string()),
+    ])
+    .await;
+    let session_dir = tempfile::tempdir().unwrap();
+    let session_config = SessionConfig {
+        workspace: workspace.to_path_buf(),
+        ..session_config(session_dir.path())
+    };
+    let session = session_config.dir.file_name().unwrap().to_string_lossy().into_owned();
+    let control_messages = vec![
+        ControlMessage::UserMessage("Write notes".to_string()),
+        ControlMessage::DraftCommit { run_hooks: true },
+        // As edited in the dialog.
+        ControlMessage::Commit {
+            message: "Add the notes\n\nEdited in review.".to_string(),
+            run_hooks: true,
+        },
+        ControlMessage::DraftCommit { run_hooks: true },
+    ];
+    let ui_state = run_session_with_config(
+        server.llm_provider(),
+        ToolCassette::Off,
+        control_messages,
+        session_config,
+    )
+    .await;
+
+    let requests = server.requests();
+    let draft = requests[2]["messages"][1]["content"].as_str().unwrap();
+    assert!(
+        draft.contains("<user_requests>\n- Write notes\n</user_requests>"),
+        "{draft}"
+    );
+    assert!(draft.contains("new file mode 100644"), "{draft}");
+    assert!(draft.contains("+hello\n"), "{draft}");
+    assert!(!draft.contains("todo.md"), "{draft}");
+
+    let head = repo.head().unwrap().peel_to_commit().unwrap();
+    assert_eq!(
+        head.message().unwrap(),
+        format!("Add the notes\n\nEdited in review.\n\nAgent-Session: {session}\n")
+    );
+    assert!(head.tree().unwrap().get_name("todo.md").is_none());
+    let messages = ui_state.messages();
+    assert_system_message(
+        &messages[messages.len() - 2],
+        &format!("Committed `{}` on `main`: Add the notes", &head.id().to_string()[..7]),
+    );
+    // Everything the agent edited is committed now.
+    assert_error_message(messages.last().unwrap(), "Nothing to commit");
+}
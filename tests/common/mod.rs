@@ -0,0 +1,313 @@
+//! Test support shared by the integration tests: a scripted OpenAI-compatible mock server and a headless harness
+//! that runs `server_loop` without a terminal.
+
+#![allow(dead_code)]
+
+use std::{
+    collections::{
+        BTreeMap,
+        HashMap,
+    },
+    net::SocketAddr,
+    path::Path,
+    sync::{
+        Arc,
+        Mutex,
+    },
+};
+
+use agent::{
+    cassette::{
+        self,
+        ToolCassette,
+    },
+    compaction::CompactionConfig,
+    config::Settings,
+    control::ControlMessage,
+    llm_provider::{
+        LLMProvider,
+        ModelConfig,
+    },
+    prompts::SystemPromptConfig,
+    server::{
+        self,
+        SessionConfig,
+    },
+    tools::{
+        self,
+        prompts::TOOL_NAMES,
+    },
+    ui_state::ChatUIState,
+};
+use tokio::{
+    io::AsyncWriteExt,
+    net::{
+        TcpListener,
+        TcpStream,
+    },
+    sync::mpsc,
+    task::JoinHandle,
+};
+
+/// One scripted answer of the mock server.
+pub enum MockResponse {
+    /// Streams each entry as the data of one server-sent event, in order.
+    Stream(Vec<String>),
+    /// Answers with an error status line and a plain text body.
+    Error { status: &'static str, body: String },
+    /// Answers a request that doesn't stream, like drafting a commit message, with `text`.
+    Completion(String),
+}
+
+impl MockResponse {
+    /// A streamed text response that finishes normally.
+    pub fn text(text: &str) -> Self {
+        Self::Stream(vec![text_chunk(text), finish_chunk("stop")])
+    }
+}
+
+pub fn text_chunk(text: &str) -> String {
+    delta_chunk(serde_json::json!({ "content": text }))
+}
+
+/// A tool call delta. `id` and `name` are only sent with the first delta of a call.
+pub fn tool_call_chunk(index: u32, id: Option<&str>, name: Option<&str>, args: &str) -> String {
+    delta_chunk(serde_json::json!({
+        "tool_calls": [{
+            "index": index,
+            "id": id,
+            "type": id.map(|_| "function"),
+            "function": { "name": name, "arguments": args },
+        }],
+    }))
+}
+
+pub fn finish_chunk(reason: &str) -> String {
+    serde_json::json!({
+        "choices": [{ "index": 0, "delta": {}, "finish_reason": reason }],
+    })
+    .to_string()
+}
+
+fn delta_chunk(delta: serde_json::Value) -> String {
+    serde_json::json!({
+        "choices": [{ "index": 0, "delta": delta, "finish_reason": null }],
+    })
+    .to_string()
+}
+
+/// A local server that answers each `/chat/completions` request with the next scripted response and keeps the
+/// request bodies it received.
+pub struct MockServer {
+    addr: SocketAddr,
+    requests: Arc<Mutex<Vec<serde_json::Value>>>,
+    task: JoinHandle<()>,
+}
+
+impl MockServer {
+    pub async fn start(responses: Vec<MockResponse>) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let requests = Arc::new(Mutex::new(vec![]));
+        let task = tokio::spawn({
+            let requests = requests.clone();
+            async move {
+                for response in responses {
+                    let Ok((mut stream, _)) = listener.accept().await else {
+                        break;
+                    };
+                    let body = cassette::read_http_request(&mut stream).await.unwrap();
+                    requests.lock().unwrap().push(serde_json::from_slice(&body).unwrap());
+                    serve(&mut stream, response).await.unwrap();
+                }
+                // Anything past the script is a bug in the code under test.
+                while let Ok((mut stream, _)) = listener.accept().await {
+                    let _ = cassette::read_http_request(&mut stream).await;
+                    let _ = cassette::write_http_response(
+                        &mut stream,
+                        "500 Internal Server Error",
+                        "text/plain",
+                        "No more scripted responses",
+                    )
+                    .await;
+                }
+            }
+        });
+        Self { addr, requests, task }
+    }
+
+    pub fn base_url(&self) -> String {
+        format!("http://{}", self.addr)
+    }
+
+    pub fn llm_provider(&self) -> LLMProvider {
+        LLMProvider::new(model_config("mock", self.base_url()), None).unwrap()
+    }
+
+    /// The bodies of all requests received so far.
+    pub fn requests(&self) -> Vec<serde_json::Value> {
+        self.requests.lock().unwrap().clone()
+    }
+}
+
+impl Drop for MockServer {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+async fn serve(stream: &mut TcpStream, response: MockResponse) -> anyhow::Result<()> {
+    match response {
+        MockResponse::Stream(events) => {
+            stream
+                .write_all(b"HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\nConnection: close\r\n\r\n")
+                .await?;
+            // Each event goes out in its own write so the client sees them as separate chunks.
+            for event in events {
+                stream.write_all(format!("data: {event}\n\n").as_bytes()).await?;
+                stream.flush().await?;
+            }
+            stream.write_all(b"data: [DONE]\n\n").await?;
+            stream.shutdown().await?;
+            Ok(())
+        }
+        MockResponse::Error { status, body } => {
+            cassette::write_http_response(stream, status, "text/plain", &body).await
+        }
+        MockResponse::Completion(text) => {
+            let body = serde_json::json!({
+                "id": "mock",
+                "object": "chat.completion",
+                "created": 0,
+                "model": "mock",
+                "choices": [{
+                    "index": 0,
+                    "message": { "role": "assistant", "content": text },
+                    "finish_reason": "stop",
+                }],
+            });
+            cassette::write_http_response(stream, "200 OK", "application/json", &body.to_string()).await
+        }
+    }
+}
+
+pub fn model_config(model: &str, base_url: String) -> ModelConfig {
+    ModelConfig {
+        model: model.to_string(),
+        api_key: String::new(),
+        base_url,
+        reasoning_effort: None,
+        send_reasoning: false,
+        max_tokens: None,
+        tools: TOOL_NAMES.iter().map(|name| name.to_string()).collect(),
+    }
+}
+
+/// Tool results keyed by tool call id, handed out instead of running the tools.
+pub fn tool_results(results: &[(&str, Result<&str, &str>)]) -> ToolCassette {
+    let results = results
+        .iter()
+        .map(|(id, result)| {
+            let result = result.map(str::to_string).map_err(str::to_string);
+            (id.to_string(), result)
+        })
+        .collect::<HashMap<_, _>>();
+    ToolCassette::Replay(Arc::new(results))
+}
+
+pub fn user_messages(prompts: &[&str]) -> Vec<ControlMessage> {
+    prompts
+        .iter()
+        .map(|prompt| ControlMessage::UserMessage(prompt.to_string()))
+        .collect()
+}
+
+/// Runs a headless session that handles `control_messages` in order and returns the final UI state once the server
+/// loop has finished.
+pub async fn run_session(
+    llm_provider: LLMProvider,
+    tool_cassette: ToolCassette,
+    control_messages: Vec<ControlMessage>,
+) -> ChatUIState {
+    run_session_with_profiles(llm_provider, tool_cassette, control_messages, BTreeMap::new()).await
+}
+
+/// Like `run_session`, with config profiles that `/model` can switch to.
+pub async fn run_session_with_profiles(
+    llm_provider: LLMProvider,
+    tool_cassette: ToolCassette,
+    control_messages: Vec<ControlMessage>,
+    profiles: BTreeMap<String, Settings>,
+) -> ChatUIState {
+    let session_dir = tempfile::tempdir().unwrap();
+    let session_config = SessionConfig {
+        profiles,
+        ..session_config(session_dir.path())
+    };
+    run_session_with_config(llm_provider, tool_cassette, control_messages, session_config).await
+}
+
+/// A session in this workspace with its files in `dir`, the built-in system prompt and no profiles.
+pub fn session_config(dir: &Path) -> SessionConfig {
+    SessionConfig {
+        compaction: CompactionConfig {
+            context_window: 128_000,
+            threshold: 0.8,
+            keep_recent_turns: 2,
+        },
+        profiles: BTreeMap::new(),
+        system_prompt: SystemPromptConfig::default(),
+        dir: dir.to_path_buf(),
+        workspace: std::env::current_dir().unwrap(),
+        user_config_dir: None,
+        watch_files: false,
+    }
+}
+
+/// Like `run_session`, with the given session config.
+pub async fn run_session_with_config(
+    llm_provider: LLMProvider,
+    tool_cassette: ToolCassette,
+    control_messages: Vec<ControlMessage>,
+    session_config: SessionConfig,
+) -> ChatUIState {
+    let (ui_tx, mut ui_rx) = mpsc::unbounded_channel();
+    let (control_tx, control_rx) = mpsc::unbounded_channel();
+    let (tool_req_tx, tool_req_rx) = mpsc::unbounded_channel();
+    let (tool_resp_tx, tool_resp_rx) = mpsc::unbounded_channel();
+
+    let enabled_tools = TOOL_NAMES.iter().map(|name| name.to_string()).collect();
+    let executor = tokio::spawn(tools::executor::run_executor(
+        tool_req_rx,
+        tool_resp_tx,
+        tool_cassette,
+        enabled_tools,
+        session_config.workspace.clone(),
+    ));
+    let server = tokio::spawn(server::server_loop(
+        ui_tx,
+        control_rx,
+        tool_req_tx,
+        tool_resp_rx,
+        llm_provider,
+        session_config,
+    ));
+    for control_message in control_messages {
+        control_tx.send(control_message).unwrap();
+    }
+    drop(control_tx);
+
+    let mut ui_state = ChatUIState::new();
+    while let Some(modification) = ui_rx.recv().await {
+        ui_state.apply(modification).unwrap();
+    }
+    server.await.unwrap().unwrap();
+    executor.abort();
+    ui_state
+}
+
+/// The last `n` messages of a request body.
+pub fn last_messages(request: &serde_json::Value, n: usize) -> Vec<serde_json::Value> {
+    let messages = request["messages"].as_array().unwrap();
+    messages[messages.len() - n..].to_vec()
+}
@@ -0,0 +1,66 @@
+mod common;
+
+use agent::{
+    cassette::{
+        Cassette,
+        ReplayServer,
+    },
+    llm_provider::LLMProvider,
+    ui_state::{
+        ChatUIMessage,
+        ChatUIToolCall,
+    },
+};
+use common::{
+    model_config,
+    run_session,
+    user_messages,
+};
+
+fn replay_provider(replay_server: &ReplayServer) -> LLMProvider {
+    LLMProvider::new(model_config("replay", replay_server.base_url()), None).unwrap()
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_replay_read_file_session() {
+    let cassette = Cassette::load("tests/cassettes/read_file").unwrap();
+    let tool_cassette = cassette.tool_cassette();
+    let replay_server = ReplayServer::start(cassette).await.unwrap();
+
+    let ui_state = run_session(
+        replay_provider(&replay_server),
+        tool_cassette,
+        user_messages(&["What does README.md say?"]),
+    )
+    .await;
+
+    let messages = ui_state.messages();
+    assert_eq!(messages.len(), 4, "{messages:#?}");
+    assert!(matches!(&messages[0], ChatUIMessage::User(m) if m.text == "What does README.md say?"));
+    assert!(matches!(&messages[1], ChatUIMessage::System(m) if m.text == "Let me read it."));
+    let ChatUIMessage::ToolCall(ChatUIToolCall::Complete { name, args, result }) = &messages[2] else {
+        panic!("Expected a completed tool call, got {:?}", messages[2]);
+    };
+    assert_eq!(name, "read_file");
+    assert_eq!(args, r#"{"target_file":"README.md"}"#);
+    assert!(result.as_ref().unwrap().contains("Ctrl-x Ctrl-c to exit"));
+    assert!(matches!(&messages[3], ChatUIMessage::System(m) if m.text.contains("**key bindings**")));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_replay_rejects_diverging_request() {
+    let cassette = Cassette::load("tests/cassettes/read_file").unwrap();
+    let tool_cassette = cassette.tool_cassette();
+    let replay_server = ReplayServer::start(cassette).await.unwrap();
+
+    let ui_state = run_session(
+        replay_provider(&replay_server),
+        tool_cassette,
+        user_messages(&["Something else entirely"]),
+    )
+    .await;
+
+    let messages = ui_state.messages();
+    assert_eq!(messages.len(), 2, "{messages:#?}");
+    assert!(matches!(&messages[1], ChatUIMessage::System(m) if m.text.contains("doesn't match the cassette")));
+}